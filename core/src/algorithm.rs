@@ -1,4 +1,4 @@
-use std::hash::{BuildHasher, Hasher};
+use core::hash::{BuildHasher, Hasher};
 pub struct FastHasher {
     state: usize,
 }
@@ -1,10 +1,30 @@
 use crate::{
-    bus::{Bus, MemoryRegion},
     config,
+    snapshot::{SnapshotReader, SnapshotWriter},
+    trace::TARGET_APU,
 };
-use log::{info, warn};
+use alloc::{boxed::Box, vec, vec::Vec};
+use log::info;
+#[cfg(feature = "std")]
 use rubato::{FftFixedInOut, Resampler};
 
+#[cfg(feature = "std")]
+type Sampler = FftFixedInOut<f32>;
+#[cfg(not(feature = "std"))]
+type Sampler = crate::resampler::LinearResampler;
+
+// the apu reads its registers straight from the IO region (addr is the offset into it), so that it
+// can be clocked without a reference to the whole bus
+#[inline(always)]
+fn io_byte(io: &[u8], addr: usize) -> u8 {
+    io[addr]
+}
+
+#[inline(always)]
+fn io_halfword(io: &[u8], addr: usize) -> u16 {
+    u16::from_le_bytes([io[addr], io[addr + 1]])
+}
+
 // StereoTuple.0 is right, StereoTuple.1 is left
 struct StereoTuple(Option<i16>, Option<i16>);
 impl StereoTuple {
@@ -39,6 +59,11 @@ impl StereoTuple {
             _ => unreachable!(),
         }
     }
+    pub fn quantize(&mut self, bits: u16) {
+        let mask = !((1 << bits) - 1);
+        self.0 = self.0.map(|val| val & mask);
+        self.1 = self.1.map(|val| val & mask);
+    }
     /*pub fn multiply(&mut self, channel: usize, val: i16) {
         match channel {
             0 => {
@@ -53,10 +78,10 @@ impl StereoTuple {
     pub fn clip(&mut self) {
         self.0 = self
             .0
-            .map(|val| std::cmp::max(0, std::cmp::min(0x3ff, val)));
+            .map(|val| core::cmp::max(0, core::cmp::min(0x3ff, val)));
         self.1 = self
             .1
-            .map(|val| std::cmp::max(0, std::cmp::min(0x3ff, val)));
+            .map(|val| core::cmp::max(0, core::cmp::min(0x3ff, val)));
     }
 }
 
@@ -100,8 +125,27 @@ impl FifoQueue {
         self.capacity = 0;
         self.mem.fill(0);
     }
+
+    pub fn write_snapshot(&self, w: &mut SnapshotWriter) {
+        self.mem.iter().for_each(|&x| w.u8(x as u8));
+        w.usize(self.write_ind);
+        w.usize(self.read_ind);
+        w.usize(self.capacity);
+    }
+    pub fn read_snapshot(&mut self, r: &mut SnapshotReader) -> Result<(), &'static str> {
+        for x in self.mem.iter_mut() {
+            *x = r.u8()? as i8;
+        }
+        self.write_ind = r.usize()? & 31;
+        self.read_ind = r.usize()? & 31;
+        self.capacity = r.usize()?.min(32);
+        Ok(())
+    }
 }
 
+// receives chunks of (left, right) samples, see GBA::set_audio_ready_callback
+pub type AudioReadyCallback = Box<dyn FnMut(&[(f32, f32)]) + Send>;
+
 pub struct SoundBufferIt<'a> {
     data: &'a [Vec<Vec<f32>>],
     index_outer: usize,
@@ -132,6 +176,55 @@ impl<'a> SoundBufferIt<'a> {
         self.data.iter().map(|x| x.len()).sum()
     }
 }
+
+// sound channels, see GBA::audio_scope
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SoundChannel {
+    Square1 = 0,
+    Square2 = 1,
+    Wave = 2,
+    // not emulated yet, always silent
+    Noise = 3,
+    DirectSoundA = 4,
+    DirectSoundB = 5,
+}
+
+pub const NUM_SOUND_CHANNELS: usize = 6;
+
+// the last config::AUDIO_SCOPE_SAMPLES samples of every channel, at config::AUDIO_SAMPLE_RATE.
+// each buffer holds up to twice that many, and drops its older half when full, so that the samples
+// stay contiguous
+pub struct SoundScope {
+    buffers: [Vec<f32>; NUM_SOUND_CHANNELS],
+}
+
+impl SoundScope {
+    fn new() -> SoundScope {
+        SoundScope {
+            buffers: core::array::from_fn(|_| Vec::with_capacity(2 * config::AUDIO_SCOPE_SAMPLES)),
+        }
+    }
+
+    fn push(&mut self, samples: &[f32; NUM_SOUND_CHANNELS]) {
+        for (buffer, sample) in self.buffers.iter_mut().zip(samples) {
+            if buffer.len() == 2 * config::AUDIO_SCOPE_SAMPLES {
+                buffer.drain(..config::AUDIO_SCOPE_SAMPLES);
+            }
+            buffer.push(*sample);
+        }
+    }
+
+    pub fn samples(&self) -> [&[f32]; NUM_SOUND_CHANNELS] {
+        core::array::from_fn(|i| {
+            let buffer = &self.buffers[i];
+            &buffer[buffer.len().saturating_sub(config::AUDIO_SCOPE_SAMPLES)..]
+        })
+    }
+}
+
+// number of resampled chunks that sound_out_buff holds
+const SOUND_OUT_BUFF_EXTERN_SIZE: usize = 16 * 1024 * 1024 / config::AUDIO_SAMPLE_CHUNKS;
+
 pub struct Apu {
     //  ------- square sound channels
     square_length: [u32; 2],
@@ -159,9 +252,24 @@ pub struct Apu {
     sound_in_buff: Vec<Vec<f32>>,
     sound_out_buff: Vec<Vec<Vec<f32>>>,
     sound_out_buff_index: usize,
-    sampler: FftFixedInOut<f32>,
+    sampler: Sampler,
+    sample_rate_output: usize,
+    // (left, right) output of the mixer, and the input samples it has been held for. see clock
+    held_output: (f32, f32),
+    held_samples: u32,
+    // stereo samples resampled since the last call to take_samples_out
+    samples_out: usize,
 
     pub extern_audio_enabled: bool,
+
+    // called with every resampled chunk of (left, right) samples as it is completed
+    pub audio_ready_callback: Option<AudioReadyCallback>,
+    callback_buff: Vec<(f32, f32)>,
+
+    // recent samples of every channel, only kept while Some
+    pub scope: Option<SoundScope>,
+    // emulator level mute, indexed by SoundChannel. see GBA::set_channel_enabled
+    pub channel_enabled: [bool; NUM_SOUND_CHANNELS],
 }
 
 impl Apu {
@@ -175,19 +283,7 @@ impl Apu {
         };
         let sampler = SincFixedIn::new(sample_rate_output as f64 / config::AUDIO_SAMPLE_RATE as f64, 1f64, params, 1024, 2).unwrap();
         */
-        let sampler = FftFixedInOut::new(
-            config::AUDIO_SAMPLE_RATE as usize,
-            sample_rate_output,
-            config::AUDIO_SAMPLE_CHUNKS,
-            2,
-        )
-        .unwrap();
-        let sound_out_buff_extern_size = 16 * 1024 * 1024 / config::AUDIO_SAMPLE_CHUNKS;
-
-        info!(
-            "sampler input required size: {}",
-            sampler.input_frames_next()
-        );
+        let sampler = Apu::new_sampler(sample_rate_output);
         Apu {
             square_length: [0; 2],
             square_rate: [0; 2],
@@ -209,14 +305,47 @@ impl Apu {
             direct_sound_timer: [None; 2],
 
             sound_in_buff: sampler.input_buffer_allocate(),
-            sound_out_buff: vec![sampler.output_buffer_allocate(); sound_out_buff_extern_size],
+            sound_out_buff: vec![sampler.output_buffer_allocate(); SOUND_OUT_BUFF_EXTERN_SIZE],
             sound_out_buff_index: 0,
             sampler,
+            sample_rate_output,
+            held_output: (0., 0.),
+            held_samples: 0,
+            samples_out: 0,
 
             extern_audio_enabled: true,
+
+            audio_ready_callback: None,
+            callback_buff: Vec::new(),
+
+            scope: None,
+            channel_enabled: [true; NUM_SOUND_CHANNELS],
         }
     }
 
+    fn new_sampler(sample_rate_output: usize) -> Sampler {
+        let sampler = Sampler::new(
+            config::AUDIO_SAMPLE_RATE as usize,
+            sample_rate_output,
+            config::AUDIO_SAMPLE_CHUNKS,
+            2,
+        )
+        .unwrap();
+        info!(target: TARGET_APU, "sampler input required size: {}", sampler.input_frames_next());
+        sampler
+    }
+
+    // samples that have not been collected yet, including the partially filled input chunk, are
+    // dropped
+    pub fn set_sample_rate(&mut self, sample_rate_output: usize) {
+        self.sampler = Apu::new_sampler(sample_rate_output);
+        self.sample_rate_output = sample_rate_output;
+        self.sound_in_buff = self.sampler.input_buffer_allocate();
+        self.sound_out_buff =
+            vec![self.sampler.output_buffer_allocate(); SOUND_OUT_BUFF_EXTERN_SIZE];
+        self.sound_out_buff_index = 0;
+    }
+
     #[inline(always)]
     pub fn get_audio_buffer(&mut self) -> Option<SoundBufferIt> {
         if self.extern_audio_enabled {
@@ -230,24 +359,89 @@ impl Apu {
         }
     }
 
+    // power on state. the output sample rate, audio callback, speedup muting and channel mutes are
+    // kept, as is whether the scope is enabled
+    pub fn reset(&mut self) {
+        let mut apu = Apu::new(self.sample_rate_output);
+        apu.audio_ready_callback = self.audio_ready_callback.take();
+        apu.extern_audio_enabled = self.extern_audio_enabled;
+        apu.set_scope_enabled(self.scope.is_some());
+        apu.channel_enabled = self.channel_enabled;
+        *self = apu;
+    }
+
+    pub fn set_scope_enabled(&mut self, enable: bool) {
+        if enable != self.scope.is_some() {
+            self.scope = enable.then(SoundScope::new);
+        }
+    }
+
+    pub fn take_samples_out(&mut self) -> usize {
+        core::mem::take(&mut self.samples_out)
+    }
+
+    // muting drops the buffered output. the resampler is reset on unmuting so that it doesn't mix
+    // in the samples from before it was muted
+    pub fn set_extern_audio_enabled(&mut self, enable: bool) {
+        if enable == self.extern_audio_enabled {
+            return;
+        }
+        self.extern_audio_enabled = enable;
+        if enable {
+            self.set_sample_rate(self.sample_rate_output);
+        } else {
+            self.sound_out_buff_index = 0;
+        }
+        self.samples_out = 0;
+    }
+
     #[inline(always)]
     pub fn clear_buffer(&mut self) {
         self.sound_out_buff_index = 0;
     }
 
+    // contribution of a channel to the mix: val, or 0 if the channel is muted. muted channels still
+    // run, and still show up in the scope
+    #[inline(always)]
+    fn mix(&self, channel: SoundChannel, val: i16) -> i16 {
+        if self.channel_enabled[channel as usize] {
+            val
+        } else {
+            0
+        }
+    }
+
+    // plays the next Direct Sound sample of the channels driven by the timer that overflowed
+    #[inline(always)]
+    pub fn timer_overflow(&mut self, timer_no: usize) {
+        for i in 0..2 {
+            if self.direct_sound_timer[i] == Some(timer_no) {
+                if let Some(val) = self.direct_sound_fifo[i].pop_front() {
+                    self.direct_sound_fifo_cur[i] = val;
+                }
+            }
+        }
+    }
+
     // called every config::AUDIO_SAMPLE_CLOCKS clocks
     #[inline(always)]
-    pub fn clock(&mut self, bus: &mut Bus) {
+    pub fn clock(&mut self, io: &[u8]) {
         self.square_disable[0] = false;
         self.square_disable[1] = false;
         let mut cur_tuple = StereoTuple::new();
-        let snd_stat = bus.read_byte_raw(0x84, MemoryRegion::IO);
+        // each channel's sample before panning and the master volume, see SoundScope
+        let mut scope_samples = [0f32; NUM_SOUND_CHANNELS];
+        let snd_stat = io_byte(io, 0x84);
+        // SOUNDBIAS. bits 1-9: bias level, bits 14-15: amplitude resolution
+        let snd_bias = io_halfword(io, 0x88);
+        let bias = (snd_bias & 0b1111111110) as i16;
+        let resolution = (snd_bias >> 14) & 0b11;
         if (snd_stat >> 7) & 1 > 0 {
             // sound enabled
-            let snd_dmg_cnt = bus.read_halfword_raw(0x80, MemoryRegion::IO);
+            let snd_dmg_cnt = io_halfword(io, 0x80);
             //info!("snd_dmg_cnt: {:#018b}", snd_dmg_cnt);
             //info!("bias: {:#018b}", bus.read_halfword_raw(0x4000088));
-            let snd_ds_cnt = bus.read_halfword_raw(0x82, MemoryRegion::IO);
+            let snd_ds_cnt = io_halfword(io, 0x82);
 
             let dmg_vol = [
                 snd_dmg_cnt as i16 & 0b111,
@@ -264,14 +458,14 @@ impl Apu {
                 if !enable_right_left[0] && !enable_right_left[1] {
                     continue;
                 }
-                let snd_cur_freq = bus.read_halfword(0x04000064 + 8 * i);
+                let snd_cur_freq = io_halfword(io, 0x64 + 8 * i);
 
                 if (snd_cur_freq >> 0xe) & 1 > 0 && self.square_length[i] == 0 {
                     continue;
                 }
                 // process sweep
                 if i == 0 {
-                    let snd_sweep = bus.read_byte(0x04000060);
+                    let snd_sweep = io_byte(io, 0x60);
                     let sweep_cnt_hit = ((snd_sweep as u32 >> 4) & 0b111) << 17;
                     let sweep_num = snd_sweep & 0b111;
                     if sweep_cnt_hit != 0
@@ -292,7 +486,7 @@ impl Apu {
                         self.square_sweep_cnt[i] = 0;
                     }
                 }
-                let snd_cur_cnt = bus.read_halfword(0x04000062 + i * 6);
+                let snd_cur_cnt = io_halfword(io, 0x62 + i * 6);
 
                 // process envelope changes
                 let envelope_cnt_hit = ((snd_cur_cnt as u32 >> 8) & 0b111) << 18;
@@ -322,25 +516,31 @@ impl Apu {
                     _ => unreachable!(),
                 };
 
+                let final_square_vol = match snd_ds_cnt & 0b11 {
+                    0b00 => self.square_envelope[i] >> 2,
+                    0b01 => self.square_envelope[i] >> 1,
+                    0b10 => self.square_envelope[i],
+                    0b11 => {
+                        hot_debug!(
+                            target: TARGET_APU,
+                            "sound channel 1-4 has a volume of 0b11: forbidden"
+                        );
+                        self.square_envelope[i]
+                    }
+                    _ => unreachable!(),
+                } as i16;
+                let sample = if self.square_sweep_cnt[i] % period_clocks < active_clocks {
+                    final_square_vol
+                } else {
+                    -final_square_vol
+                };
+                scope_samples[SoundChannel::Square1 as usize + i] = sample as f32 / 15.;
+
                 // sound channels
+                let channel = [SoundChannel::Square1, SoundChannel::Square2][i];
                 for j in 0..2 {
-                    if !enable_right_left[j] {
-                        continue;
-                    }
-                    let final_square_vol = match snd_ds_cnt & 0b11 {
-                        0b00 => self.square_envelope[i] >> 2,
-                        0b01 => self.square_envelope[i] >> 1,
-                        0b10 => self.square_envelope[i],
-                        0b11 => {
-                            warn!("sound channel 1-4 has a volume of 0b11: forbidden");
-                            self.square_envelope[i]
-                        }
-                        _ => unreachable!(),
-                    } as i16;
-                    if self.square_sweep_cnt[i] % period_clocks < active_clocks {
-                        cur_tuple.add(j, final_square_vol * dmg_vol[j]);
-                    } else {
-                        cur_tuple.add(j, -final_square_vol * dmg_vol[j]);
+                    if enable_right_left[j] {
+                        cur_tuple.add(j, self.mix(channel, sample * dmg_vol[j]));
                     }
                 }
 
@@ -351,7 +551,8 @@ impl Apu {
             }
 
             // wave channel
-            self.process_wave_channel(&mut cur_tuple, bus);
+            scope_samples[SoundChannel::Wave as usize] =
+                self.process_wave_channel(&mut cur_tuple, io) as f32 / 15.;
 
             // Direct Sound
             for i in 0..2 {
@@ -362,22 +563,25 @@ impl Apu {
                 if !enable_right_left[0] && !enable_right_left[1] {
                     continue;
                 }
+                // volume is bit 2 for channel A and bit 3 for channel B
+                let final_sample = match (snd_ds_cnt >> (2 + i)) & 1 {
+                    0 => self.direct_sound_fifo_cur[i] >> 1,
+                    1 => self.direct_sound_fifo_cur[i],
+                    _ => unreachable!(),
+                };
+                scope_samples[SoundChannel::DirectSoundA as usize + i] = final_sample as f32 / 128.;
                 // sound right and left channels
                 for (j, item) in enable_right_left.iter().enumerate() {
                     if !*item {
                         continue;
                     }
-                    let final_sample = match (snd_ds_cnt >> (2 + j)) & 1 {
-                        0 => self.direct_sound_fifo_cur[i] >> 1,
-                        1 => self.direct_sound_fifo_cur[i],
-                        _ => unreachable!(),
-                    };
                     /*if final_sample as i16 != 0 {
-                        //info!("playing from direct sound: {:#x}, ds_cnt: {:#018b}, channel: {}, snd_bias: {:#018b}", final_sample, snd_ds_cnt, i, bus.read_halfword(0x04000088));
+                        //info!("playing from direct sound: {:#x}, ds_cnt: {:#018b}, channel: {}, snd_bias: {:#018b}", final_sample, snd_ds_cnt, i, io_halfword(io, 0x88));
                     } else {
                         //info!("direct sound zero");
                     }*/
-                    cur_tuple.add(j, (final_sample as i16) * 4);
+                    let channel = [SoundChannel::DirectSoundA, SoundChannel::DirectSoundB][i];
+                    cur_tuple.add(j, self.mix(channel, (final_sample as i16) * 4));
                 }
             }
 
@@ -386,27 +590,39 @@ impl Apu {
             //cur_tuple.multiply(1, (snd_dmg_cnt >> 4) as i16 & 0b111);
 
             // process bias
-            let snd_bias = bus.read_word_raw(0x88, MemoryRegion::IO);
-            let bias = snd_bias & 0b1111111111;
-            cur_tuple.add_bias(0, bias as i16);
-            cur_tuple.add_bias(1, bias as i16);
+            cur_tuple.add_bias(0, bias);
+            cur_tuple.add_bias(1, bias);
 
-            // clip values into range [0, 0x3ff]
+            // clip values into range [0, 0x3ff], then drop the bits below the resolution: 9 bits
+            // at resolution 0 down to 6 bits at resolution 3
             cur_tuple.clip();
+            cur_tuple.quantize(1 + resolution);
         }
         //else{
         //    info!("sound is off");
         //}
+        if let Some(scope) = self.scope.as_mut() {
+            scope.push(&scope_samples);
+        }
 
-        // output channel 0 is left not right
-        self.sound_in_buff[1].push(match cur_tuple.0 {
-            None => 0f32,
-            Some(val) => (val as f32 - 512.) / 512.,
-        });
-        self.sound_in_buff[0].push(match cur_tuple.1 {
+        // the bias only sets where the output clips: like the capacitor on the hardware's output,
+        // the output is centred on the bias level rather than on 0x200
+        let to_output = |val: Option<i16>| match val {
             None => 0f32,
-            Some(val) => (val as f32 - 512.) / 512.,
-        });
+            Some(val) => (val - bias) as f32 / 512.,
+        };
+        // the mixer samples at 32768Hz << resolution. rates below config::AUDIO_SAMPLE_RATE hold
+        // each sample for several input samples, higher ones can't be represented
+        let hold_samples = (config::AUDIO_SAMPLE_RATE >> (15 + resolution)).max(1);
+        self.held_samples += 1;
+        if self.held_samples >= hold_samples {
+            self.held_samples = 0;
+            self.held_output = (to_output(cur_tuple.1), to_output(cur_tuple.0));
+        }
+
+        // output channel 0 is left not right
+        self.sound_in_buff[0].push(self.held_output.0);
+        self.sound_in_buff[1].push(self.held_output.1);
 
         if self.sound_in_buff[0].len() == self.sampler.input_frames_next() {
             if self.extern_audio_enabled {
@@ -417,6 +633,14 @@ impl Apu {
                         None,
                     )
                     .unwrap();
+                if let Some(callback) = self.audio_ready_callback.as_mut() {
+                    let chunk = &self.sound_out_buff[self.sound_out_buff_index];
+                    self.callback_buff.clear();
+                    self.callback_buff
+                        .extend(chunk[0].iter().copied().zip(chunk[1].iter().copied()));
+                    callback(&self.callback_buff);
+                }
+                self.samples_out += self.sound_out_buff[self.sound_out_buff_index][0].len();
                 self.sound_out_buff_index += 1;
             }
             self.sound_in_buff[0].clear();
@@ -427,31 +651,32 @@ impl Apu {
     }
 
     #[inline(always)]
-    fn process_wave_channel(&mut self, cur_tuple: &mut StereoTuple, bus: &mut Bus) {
-        let snd_cur_cnt_l = bus.read_byte_raw(0x70, MemoryRegion::IO);
+    // returns the sample of the channel, 0 if it is silent
+    fn process_wave_channel(&mut self, cur_tuple: &mut StereoTuple, io: &[u8]) -> i16 {
+        let snd_cur_cnt_l = io_byte(io, 0x70);
         if snd_cur_cnt_l >> 7 == 0 {
             //info!("wave channel disabled");
-            return;
+            return 0;
         }
 
-        let snd_dmg_cnt = bus.read_halfword_raw(0x80, MemoryRegion::IO);
+        let snd_dmg_cnt = io_halfword(io, 0x80);
         let dmg_vol = [
             snd_dmg_cnt as i16 & 0b111,
             (snd_dmg_cnt >> 4) as i16 & 0b111,
         ];
         //info!("snd_dmg_cnt: {:#018b}", snd_dmg_cnt);
-        let snd_ds_cnt = bus.read_halfword_raw(0x82, MemoryRegion::IO);
+        let snd_ds_cnt = io_halfword(io, 0x82);
         let enable_right_left = [(snd_dmg_cnt >> 10) & 1 > 0, (snd_dmg_cnt >> 14) & 1 > 0];
         // sound is not enabled on any channel (left or right)
         if !enable_right_left[0] && !enable_right_left[1] {
-            return;
+            return 0;
         }
-        let snd_cur_freq = bus.read_halfword(0x04000074);
+        let snd_cur_freq = io_halfword(io, 0x74);
 
         if (snd_cur_freq >> 0xe) & 1 > 0 && self.wave_length == 0 {
-            return;
+            return 0;
         }
-        let snd_cur_cnt_h = bus.read_halfword(0x04000072);
+        let snd_cur_cnt_h = io_halfword(io, 0x72);
         let bank = (snd_cur_cnt_l >> 5) & (snd_cur_cnt_l >> 6) & 1;
 
         let period_clocks = (2048 - self.wave_rate) << 3;
@@ -480,7 +705,7 @@ impl Apu {
             0b01 => final_wave_vol >> 1,
             0b10 => final_wave_vol,
             0b11 => {
-                warn!("sound channel 1-4 has a volume of 0b11: forbidden");
+                hot_debug!(target: TARGET_APU, "sound channel 1-4 has a volume of 0b11: forbidden");
                 final_wave_vol
             }
             _ => unreachable!(),
@@ -506,21 +731,22 @@ impl Apu {
             if final_wave_vol != 0 {
                 //info!("playing wave sample: {:#018b}", final_wave_vol * dmg_vol[j]);
             }
-            cur_tuple.add(j, final_wave_vol * dmg_vol[j]);
+            cur_tuple.add(j, self.mix(SoundChannel::Wave, final_wave_vol * dmg_vol[j]));
         }
 
         self.wave_sweep_cnt += config::AUDIO_SAMPLE_CLOCKS;
         if self.wave_length > 0 {
             self.wave_length -= config::AUDIO_SAMPLE_CLOCKS;
         }
+        final_wave_vol
     }
 
     // reset envelope, rate and length
     // channel num must be 0 or 1
     #[inline(always)]
-    pub fn reset_square_channel(&mut self, channel_num: usize, bus: &Bus) {
-        let snd_cur_cnt = bus.read_halfword_raw(0x62 + channel_num * 6, MemoryRegion::IO);
-        let snd_cur_freq = bus.read_halfword_raw(0x64 + channel_num * 8, MemoryRegion::IO);
+    pub fn reset_square_channel(&mut self, channel_num: usize, io: &[u8]) {
+        let snd_cur_cnt = io_halfword(io, 0x62 + channel_num * 6);
+        let snd_cur_freq = io_halfword(io, 0x64 + channel_num * 8);
         self.square_envelope[channel_num] = snd_cur_cnt as u32 >> 0xc;
         self.square_length[channel_num] = (64 - (snd_cur_cnt as u32 & 0b111111)) << 16;
         self.square_rate[channel_num] = snd_cur_freq as u32 & 0b11111111111;
@@ -529,9 +755,168 @@ impl Apu {
     }
 
     #[inline(always)]
-    pub fn reset_wave_channel(&mut self, bus: &Bus) {
-        self.wave_length = (256 - bus.read_byte_raw(0x72, MemoryRegion::IO) as u32) << 16;
-        self.wave_rate = bus.read_halfword_raw(0x74, MemoryRegion::IO) as u32 & 0b11111111111;
+    pub fn reset_wave_channel(&mut self, io: &[u8]) {
+        self.wave_length = (256 - io_byte(io, 0x72) as u32) << 16;
+        self.wave_rate = io_halfword(io, 0x74) as u32 & 0b11111111111;
         self.wave_sweep_cnt = 0;
     }
+
+    // the resampler and the audio that has not been collected yet are not part of a snapshot
+    pub fn write_snapshot(&self, w: &mut SnapshotWriter) {
+        for i in 0..2 {
+            w.u32(self.square_length[i]);
+            w.u32(self.square_rate[i]);
+            w.u32(self.square_envelope[i]);
+            w.u32(self.square_sweep_cnt[i]);
+            w.u32(self.square_envelope_cnt[i]);
+            w.bool(self.square_disable[i]);
+        }
+        w.u32(self.wave_length);
+        w.u32(self.wave_rate);
+        w.u32(self.wave_sweep_cnt);
+        self.wave_bank.iter().for_each(|bank| w.bytes(bank));
+        for i in 0..2 {
+            self.direct_sound_fifo[i].write_snapshot(w);
+            w.u8(self.direct_sound_fifo_cur[i] as u8);
+            w.bool(self.direct_sound_timer[i].is_some());
+            w.usize(self.direct_sound_timer[i].unwrap_or(0));
+        }
+    }
+
+    pub fn read_snapshot(&mut self, r: &mut SnapshotReader) -> Result<(), &'static str> {
+        for i in 0..2 {
+            self.square_length[i] = r.u32()?;
+            self.square_rate[i] = r.u32()?;
+            self.square_envelope[i] = r.u32()?;
+            self.square_sweep_cnt[i] = r.u32()?;
+            self.square_envelope_cnt[i] = r.u32()?;
+            self.square_disable[i] = r.bool()?;
+        }
+        self.wave_length = r.u32()?;
+        self.wave_rate = r.u32()?;
+        self.wave_sweep_cnt = r.u32()?;
+        for bank in self.wave_bank.iter_mut() {
+            r.bytes(bank)?;
+        }
+        for i in 0..2 {
+            self.direct_sound_fifo[i].read_snapshot(r)?;
+            self.direct_sound_fifo_cur[i] = r.u8()? as i8;
+            let has_timer = r.bool()?;
+            let timer = r.usize()?;
+            self.direct_sound_timer[i] = has_timer.then_some(timer);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SoundChannel, StereoTuple};
+    use crate::test_util::{gba_with_bios, run_frames};
+    use crate::GBA;
+    use alloc::vec::Vec;
+
+    // plays the square channels in `channels` (bit 0: square 1, bit 1: square 2) at full volume
+    fn square_gba(channels: u16) -> GBA {
+        let mut gba = gba_with_bios(&[(0, &[0xeafffffe])]); // b .
+        gba.poke8(0x4000084, 0x80); // SOUNDCNT_X: sound on
+        gba.poke16(0x4000080, 0x77 | (channels << 8) | (channels << 12)); // SOUNDCNT_L
+        gba.poke16(0x4000082, 0x2); // SOUNDCNT_H: full PSG volume
+        gba.poke16(0x4000062, 0xf080); // SOUND1CNT_H: 50% duty, envelope at 15
+        gba.poke16(0x4000064, 0x86d6); // SOUND1CNT_X: 440Hz, start
+        gba.poke16(0x4000068, 0xf080); // SOUND2CNT_L
+        gba.poke16(0x400006c, 0x8600); // SOUND2CNT_H: 341Hz, start
+        gba
+    }
+
+    #[test]
+    fn direct_sound_b_volume_is_bit_3() {
+        // the last scope sample of Direct Sound B with a FIFO full of 0x40
+        let level = |sound_cnt_h: u16| {
+            let mut gba = gba_with_bios(&[(0, &[0xeafffffe])]); // b .
+            gba.poke8(0x4000084, 0x80); // SOUNDCNT_X: sound on
+            gba.poke16(0x4000082, sound_cnt_h | 0xb000); // SOUNDCNT_H: B on timer 0, reset FIFO B
+            for _ in 0..8 {
+                gba.poke32(0x40000a4, 0x40404040); // FIFO_B
+            }
+            gba.poke16(0x4000100, 0xff00); // TM0CNT_L
+            gba.poke16(0x4000102, 0x80); // TM0CNT_H: start
+            gba.set_audio_scope(true);
+            run_frames(&mut gba, 1);
+            *gba.audio_scope()[SoundChannel::DirectSoundB as usize]
+                .last()
+                .unwrap()
+        };
+        // channel A's volume bit leaves channel B at 50%
+        assert_eq!(level(1 << 2), 0.25);
+        assert_eq!(level(1 << 3), 0.5);
+    }
+
+    #[test]
+    fn quantizes_to_the_resolution() {
+        // resolution 0 keeps 9 bits, resolution 3 keeps 6
+        let mut tuple = StereoTuple(Some(0x3ff), Some(0x155));
+        tuple.quantize(1);
+        assert_eq!((tuple.0, tuple.1), (Some(0x3fe), Some(0x154)));
+        tuple.quantize(4);
+        assert_eq!((tuple.0, tuple.1), (Some(0x3f0), Some(0x150)));
+    }
+
+    #[test]
+    fn silence_is_centred_on_the_bias() {
+        for bias in [0x100, 0x200, 0x300] {
+            let mut gba = gba_with_bios(&[(0, &[0xeafffffe])]); // b .
+            gba.poke8(0x4000084, 0x80); // SOUNDCNT_X: sound on
+            gba.poke16(0x4000088, bias); // SOUNDBIAS
+            run_frames(&mut gba, 2);
+            let samples = gba.get_sound_buffer().unwrap();
+            assert!(samples.len() > 0);
+            for (left, right) in samples {
+                assert_eq!((left, right), (0., 0.), "{:#x}", bias);
+            }
+        }
+    }
+
+    #[test]
+    fn scope_shows_only_the_playing_channel() {
+        let mut gba = square_gba(0b01);
+        gba.set_audio_scope(true);
+        run_frames(&mut gba, 2);
+        let square1 = SoundChannel::Square1 as usize;
+        for (channel, samples) in gba.audio_scope().iter().enumerate() {
+            assert!(!samples.is_empty());
+            let playing = samples.iter().any(|x| *x != 0.);
+            assert_eq!(playing, channel == square1, "{}", channel);
+        }
+    }
+
+    #[test]
+    fn muting_all_but_one_channel_plays_only_that_channel() {
+        let output = |gba: &mut GBA| {
+            run_frames(gba, 2);
+            gba.get_sound_buffer().unwrap().collect::<Vec<_>>()
+        };
+        let mut both = square_gba(0b11);
+        both.set_audio_scope(true);
+        for channel in [
+            SoundChannel::Square2,
+            SoundChannel::Wave,
+            SoundChannel::Noise,
+            SoundChannel::DirectSoundA,
+            SoundChannel::DirectSoundB,
+        ] {
+            both.set_channel_enabled(channel, false);
+        }
+        let only_square1 = output(&mut square_gba(0b01));
+        assert!(only_square1.iter().any(|x| *x != (0., 0.)));
+        assert_eq!(output(&mut both), only_square1);
+        // muted channels keep running
+        let square2 = both.audio_scope()[SoundChannel::Square2 as usize];
+        assert!(square2.iter().any(|x| *x != 0.));
+
+        let mut muted = square_gba(0b11);
+        muted.set_channel_enabled(SoundChannel::Square1, false);
+        muted.set_channel_enabled(SoundChannel::Square2, false);
+        assert!(output(&mut muted).iter().all(|x| *x == (0., 0.)));
+    }
 }
@@ -1,19 +1,40 @@
-use std::ops::{Index, IndexMut};
-
+use alloc::{boxed::Box, string::ToString, vec, vec::Vec};
+use core::{
+    fmt,
+    ops::{Index, IndexMut},
+};
+
+#[cfg(feature = "debug_instr")]
+use log::debug;
 use log::{info, warn};
 
-use crate::{algorithm, apu::Apu, config, cpu::Cpu, dma_channel::DMA_Channel, timer::Timer};
+use crate::{
+    algorithm,
+    apu::Apu,
+    config,
+    dma_channel::{DMA_Channel, TimingMode},
+    eeprom::{Eeprom, EepromAction},
+    error::{BusError, InitError},
+    input_handler::KeypadProvider,
+    memory::CpuBusView,
+    prefetch::Prefetch,
+    profiler::AccessProfile,
+    snapshot::{SnapshotReader, SnapshotWriter},
+    timer::Timer,
+    trace::{TARGET_APU, TARGET_BUS},
+    uninit_fill::UninitFill,
+};
 
 //const MEM_MAX: usize = 268435456;
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum ChunkSize {
     Word = 4,
     Halfword = 2,
     Byte = 1,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum MemoryRegion {
     Bios = 0,
     BoardWram = 1,
@@ -26,9 +47,52 @@ pub enum MemoryRegion {
     CartridgeSram = 8,
     Illegal = 9,
     CartridgeUpper = 10,
+    // internal memory control register at 0x04000800, mirrored every 64KiB
+    MemoryControl = 11,
+}
+
+impl fmt::Display for MemoryRegion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            MemoryRegion::Bios => "BIOS",
+            MemoryRegion::BoardWram => "EWRAM",
+            MemoryRegion::ChipWram => "IWRAM",
+            MemoryRegion::IO => "IO",
+            MemoryRegion::Palette => "Palette",
+            MemoryRegion::Vram => "VRAM",
+            MemoryRegion::Oam => "OAM",
+            MemoryRegion::Cartridge => "Cartridge",
+            MemoryRegion::CartridgeSram => "Cartridge SRAM",
+            MemoryRegion::Illegal => "Illegal",
+            MemoryRegion::CartridgeUpper => "Cartridge (upper)",
+            MemoryRegion::MemoryControl => "Memory control",
+        };
+        write!(f, "{}", name)
+    }
 }
 
-#[derive(Clone, Copy, PartialEq)]
+impl MemoryRegion {
+    // (cpu address the region starts at, size of the region as seen by the cpu).
+    // None for regions that aren't backed by memory of their own
+    pub fn cpu_range(&self) -> Option<(usize, usize)> {
+        match self {
+            MemoryRegion::Bios => Some((0x0, 0x4000)),
+            MemoryRegion::BoardWram => Some((0x02000000, 0x40000)),
+            MemoryRegion::ChipWram => Some((0x03000000, 0x8000)),
+            MemoryRegion::IO => Some((0x04000000, 0x400)),
+            MemoryRegion::Palette => Some((0x05000000, 0x400)),
+            MemoryRegion::Vram => Some((0x06000000, 0x18000)),
+            MemoryRegion::Oam => Some((0x07000000, 0x400)),
+            MemoryRegion::Cartridge => Some((0x08000000, 0x2000000)),
+            MemoryRegion::CartridgeSram => Some((0x0e000000, 0x10000)),
+            MemoryRegion::Illegal | MemoryRegion::CartridgeUpper | MemoryRegion::MemoryControl => {
+                None
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum CartridgeType {
     Eeprom512,
     Eeprom8192,
@@ -37,7 +101,61 @@ pub enum CartridgeType {
     Flash128,
 }
 
-fn derive_cartridge_type(cartridge: &[u8]) -> CartridgeType {
+// where the cartridge (backup) type in use came from
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CartridgeTypeSource {
+    // found a backup id string in the ROM
+    Detected,
+    // no id string was found, config::DEFAULT_CARTRIDGE_TYPE is used
+    Default,
+    // given by the user
+    Override,
+    // from the GameConfig of the game, see GBA::apply_game_config
+    GameConfig,
+}
+
+// graphics memory regions written to by the cpu or DMA since the flags were last cleared
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct GraphicsDirty {
+    pub palette: bool,
+    pub vram: bool,
+    pub oam: bool,
+}
+
+// accepts both the short names and the backup id strings found in ROMs (eg. "FLASH1M" or "FLASH1M_V").
+// the second value is false if the size of an EEPROM was not given (EEPROM_V), in which case it is
+// worked out from the first EEPROM access.
+fn parse_cartridge_type(cartridge_type_str: &str) -> Option<(CartridgeType, bool)> {
+    let cartridge_type_str = cartridge_type_str.trim().to_ascii_uppercase();
+    let trimmed_str = cartridge_type_str.split(' ').next().unwrap();
+    let trimmed_str = trimmed_str.strip_suffix("_V").unwrap_or(trimmed_str);
+    match trimmed_str {
+        "SRAM" => Some((CartridgeType::Sram, true)),
+        "FLASH" => Some((CartridgeType::Flash64, true)),
+        "FLASH512" => Some((CartridgeType::Flash64, true)),
+        "FLASH1M" => Some((CartridgeType::Flash128, true)),
+        "EEPROM" => Some((CartridgeType::Eeprom8192, false)),
+        "EEPROM512" => Some((CartridgeType::Eeprom512, true)),
+        "EEPROM8192" => Some((CartridgeType::Eeprom8192, true)),
+        _ => None,
+    }
+}
+
+// bits of the IO byte at addr that the cpu can write. the others are status bits set by the
+// hardware (through the raw store methods), or unused
+#[inline(always)]
+fn io_write_mask(addr: usize) -> u8 {
+    match addr {
+        0x4 => 0b00111000,  // DISPSTAT: the vblank, hblank and vcount flags are read only
+        0x6 | 0x7 => 0,     // VCOUNT
+        0x84 => 0b10000000, // SOUNDCNT_X: the channel on flags are read only
+        0x85..=0x87 => 0,
+        0x130 | 0x131 => 0, // KEYINPUT
+        _ => 0b11111111,
+    }
+}
+
+fn derive_cartridge_type(cartridge: &[u8]) -> (CartridgeType, CartridgeTypeSource) {
     let matches = [
         "SRAM_V".as_bytes(),
         "FLASH_V".as_bytes(),
@@ -47,14 +165,20 @@ fn derive_cartridge_type(cartridge: &[u8]) -> CartridgeType {
     ];
     let res = algorithm::u8_search(cartridge, &matches);
     match res {
-        None => config::DEFAULT_CARTRIDGE_TYPE,
-        Some(res) => match res {
-            0 => CartridgeType::Sram,
-            1 | 2 => CartridgeType::Flash64,
-            3 => CartridgeType::Flash128,
-            4 => CartridgeType::Eeprom8192,
-            _ => unreachable!("logical error, invalid result from u8_search"),
-        },
+        None => {
+            warn!(target: TARGET_BUS, "could not detect backup type from ROM, using default");
+            (config::DEFAULT_CARTRIDGE_TYPE, CartridgeTypeSource::Default)
+        }
+        Some(res) => (
+            match res {
+                0 => CartridgeType::Sram,
+                1 | 2 => CartridgeType::Flash64,
+                3 => CartridgeType::Flash128,
+                4 => CartridgeType::Eeprom8192,
+                _ => unreachable!("logical error, invalid result from u8_search"),
+            },
+            CartridgeTypeSource::Detected,
+        ),
     }
 }
 
@@ -76,6 +200,12 @@ const MEM_REGION_OFFSET: [usize; 10] = [
 ];
 const MEM_REGION_TOTAL: usize = 0x2084c00;
 
+// value of the internal memory control register after boot: EWRAM enabled with 2 wait states
+const MEMORY_CONTROL_DEFAULT: u32 = 0x0d000020;
+
+// ROMs larger than this leave only the last 256 bytes of the upper cartridge region to the EEPROM
+const EEPROM_LARGE_ROM_SIZE: usize = 0x1000000;
+
 // const MEM_REGION_OFFSET: [usize; 10] = [
 //     0x0, 0x4000, 0x44000, 0x4c000, 0x4c400, 0x4c800, 0x64800, 0x64c00, 0x1064c00, 0x1084c00,
 // ];
@@ -128,6 +258,7 @@ pub struct Bus {
     mapped_mem: FlatMemory,
 
     pub cartridge_type: CartridgeType,
+    pub cartridge_type_source: CartridgeTypeSource,
 
     // 0-2: cartridge command flags
     // 3: cartridge page number (for 218kb only, 0 or 1)
@@ -138,19 +269,59 @@ pub struct Bus {
     //     val=3: write single byte mode
     //     val=4: select page number mode
     cartridge_type_state: [u8; 7],
-    pub eeprom_is_read: bool,
-    pub eeprom_read_offset: usize,
-    pub eeprom_write_successful: bool,
+    // (manufacturer, device) reported in flash ID mode. defaults to Macronix chips if None
+    pub flash_ids: Option<(u8, u8)>,
+    pub eeprom: Eeprom,
+    pub eeprom_size_known: bool,
+    // EEPROMs of ROMs larger than 16MiB are only mapped to the last 256 bytes of the region
+    eeprom_large_rom: bool,
+
+    // set whenever the game modifies its SRAM/flash/EEPROM
+    pub save_dirty: bool,
+    // like save_dirty, but cleared by the GBA at the end of every frame. see
+    // GBA::set_save_write_callback
+    pub save_written: bool,
+
+    // internal memory control register. bits 24-27 set the EWRAM wait states, see access_cycles
+    pub memory_control: u32,
+    // when set, opcode fetches take the wait states of the memory they are read from, see
+    // opcode_fetch_waits, and so do data accesses to work RAM, see note_data_access
+    pub memory_timing: bool,
+    pub prefetch: Prefetch,
+    // wait states of the data accesses to work RAM since the cpu last collected them
+    data_waits: u32,
+
+    pub graphics_dirty: GraphicsDirty,
+
+    // called on reads of KEYINPUT instead of using the keys committed by the input handler, see
+    // GBA::set_keypad_provider
+    pub keypad_provider: Option<KeypadProvider>,
+
+    // access counts, only collected while Some. see GBA::enable_access_profiler
+    pub access_profile: Option<Box<AccessProfile>>,
+    // what the volatile memories start out as, see GBA::set_uninit_fill
+    pub uninit_fill: UninitFill,
 
     pub is_any_dma_active: bool,
     pub hblank_dma: bool,
     pub vblank_dma: bool,
+    pub video_capture_dma: bool,
     pub dma_channels: [DMA_Channel; 4],
+    // bit n is set when the control register of channel n is written such that the channel is
+    // rebuilt, see run_dma
+    dma_control_written: u8,
 
     pub is_any_timer_active: bool,
     timers: [Timer; 4],
 
-    pub cpu: Cpu,
+    // the cpu is not part of the bus, it publishes what accesses depend on here
+    pub cpu_view: CpuBusView,
+    // IE & IF & IME and IE & IF, see update_interrupt
+    pub interrupt_requested: bool,
+    pub interrupt_pending: bool,
+    // set by a write to HALTCNT until the cpu takes it, see MemoryInterface::take_halt_request
+    pub halt_requested: bool,
+
     pub apu: Apu,
 }
 
@@ -161,7 +332,7 @@ impl Bus {
         save_state: Option<&[u8]>,
         cartridge_type_str: Option<&str>,
         apu: Apu,
-    ) -> Bus {
+    ) -> Result<Bus, InitError> {
         //let mut mem = vec![0; MEM_MAX];
 
         // let mut mapped_mem = [
@@ -186,80 +357,224 @@ impl Bus {
         // load ROM
         let mut reader = BufReader::new(File::open(rom_path).unwrap());
         reader.read(&mut mapped_mem[MemoryRegion::Cartridge as usize][..]).unwrap();*/
+        Bus::check_bios(bios_bin)?;
         mapped_mem[MemoryRegion::Bios as usize][..].copy_from_slice(bios_bin);
-        mapped_mem[MemoryRegion::Cartridge as usize][..rom_bin.len()].copy_from_slice(rom_bin);
+        let (cartridge_type, cartridge_type_source, eeprom_size_known) =
+            Bus::cartridge_type_for(rom_bin, cartridge_type_str)?;
+        Bus::load_cartridge(&mut mapped_mem, rom_bin, save_state);
 
-        let cartridge_type = match cartridge_type_str {
-            None => derive_cartridge_type(&mapped_mem[MemoryRegion::Cartridge as usize][..]),
-            Some(cartridge_type_str) => {
-                let cartridge_type_str = cartridge_type_str.trim().to_ascii_uppercase();
-                let trimmed_str = cartridge_type_str.split(' ').next().unwrap();
-                match trimmed_str {
-                    "SRAM" => CartridgeType::Sram,
-                    "FLASH" => CartridgeType::Flash64,
-                    "FLASH512" => CartridgeType::Flash64,
-                    "FLASH1M" => CartridgeType::Flash128,
-                    "EEPROM512" => CartridgeType::Eeprom512,
-                    "EEPROM8192" => CartridgeType::Eeprom8192,
-                    _ => unreachable!(),
-                }
-            }
-        };
-
-        // load save state
-        if let Some(buf) = save_state {
-            mapped_mem[MemoryRegion::CartridgeSram as usize][..].copy_from_slice(buf);
-        }
+        info!(target: TARGET_BUS, "backup type: {}", cartridge_type as u32);
 
-        info!("backup type: {}", cartridge_type as u32);
-
-        Bus {
+        Ok(Bus {
             mapped_mem,
 
             cartridge_type,
+            cartridge_type_source,
             cartridge_type_state: [0; 7],
-            eeprom_is_read: false,
-            eeprom_read_offset: 0,
-            eeprom_write_successful: false,
+            flash_ids: None,
+            eeprom: Eeprom::new(),
+            eeprom_size_known,
+            eeprom_large_rom: rom_bin.len() > EEPROM_LARGE_ROM_SIZE,
+
+            save_dirty: false,
+            save_written: false,
+            memory_control: MEMORY_CONTROL_DEFAULT,
+            memory_timing: false,
+            prefetch: Prefetch::default(),
+            data_waits: 0,
+            graphics_dirty: GraphicsDirty::default(),
+
+            keypad_provider: None,
+            access_profile: None,
+            uninit_fill: UninitFill::Zero,
 
             is_any_dma_active: false,
             hblank_dma: false,
             vblank_dma: false,
+            video_capture_dma: false,
             dma_channels: [
                 DMA_Channel::new_disabled(0),
                 DMA_Channel::new_disabled(1),
                 DMA_Channel::new_disabled(2),
                 DMA_Channel::new_disabled(3),
             ],
+            dma_control_written: 0,
 
             is_any_timer_active: false,
             timers: [Timer::new(0), Timer::new(1), Timer::new(2), Timer::new(3)],
 
-            cpu: Cpu::new(),
+            cpu_view: CpuBusView::default(),
+            interrupt_requested: false,
+            interrupt_pending: false,
+            halt_requested: false,
             apu,
+        })
+    }
+
+    fn check_bios(bios_bin: &[u8]) -> Result<(), InitError> {
+        let bios = MemoryRegion::Bios as usize;
+        if bios_bin.len() != MEM_REGION_OFFSET[bios + 1] - MEM_REGION_OFFSET[bios] {
+            return Err(InitError::BiosWrongSize {
+                size: bios_bin.len(),
+            });
         }
+        Ok(())
+    }
+
+    // replaces the BIOS in place. the open bus latch of the cpu still holds the old BIOS's last
+    // fetch, see GBA::set_bios
+    pub fn set_bios(&mut self, bios_bin: &[u8]) -> Result<(), InitError> {
+        Bus::check_bios(bios_bin)?;
+        self.mapped_mem[MemoryRegion::Bios as usize].copy_from_slice(bios_bin);
+        Ok(())
+    }
+
+    // checks that the ROM fits, and works out its backup type. (type, source, eeprom_size_known)
+    fn cartridge_type_for(
+        rom_bin: &[u8],
+        cartridge_type_str: Option<&str>,
+    ) -> Result<(CartridgeType, CartridgeTypeSource, bool), InitError> {
+        let cartridge = MemoryRegion::Cartridge as usize;
+        if rom_bin.len() > MEM_REGION_OFFSET[cartridge + 1] - MEM_REGION_OFFSET[cartridge] {
+            return Err(InitError::RomTooLarge {
+                size: rom_bin.len(),
+            });
+        }
+        Ok(match cartridge_type_str {
+            None => {
+                let (cartridge_type, source) = derive_cartridge_type(rom_bin);
+                // only EEPROM comes in sizes that the ROM doesn't tell apart
+                let eeprom_size_known = !matches!(
+                    cartridge_type,
+                    CartridgeType::Eeprom512 | CartridgeType::Eeprom8192
+                );
+                (cartridge_type, source, eeprom_size_known)
+            }
+            Some(cartridge_type_str) => {
+                let (cartridge_type, eeprom_size_known) = parse_cartridge_type(cartridge_type_str)
+                    .ok_or_else(|| {
+                        InitError::UnknownCartridgeType(cartridge_type_str.to_string())
+                    })?;
+                (
+                    cartridge_type,
+                    CartridgeTypeSource::Override,
+                    eeprom_size_known,
+                )
+            }
+        })
+    }
+
+    // the cartridge and save memory must be zeroed
+    fn load_cartridge(mapped_mem: &mut FlatMemory, rom_bin: &[u8], save_state: Option<&[u8]>) {
+        mapped_mem[MemoryRegion::Cartridge as usize][..rom_bin.len()].copy_from_slice(rom_bin);
+        if let Some(buf) = save_state {
+            mapped_mem[MemoryRegion::CartridgeSram as usize][..].copy_from_slice(buf);
+        }
+    }
+
+    // swaps in a new ROM and puts everything except the BIOS back into its power on state. nothing
+    // is changed if the ROM or cartridge type are invalid
+    pub fn load_rom(
+        &mut self,
+        rom_bin: &[u8],
+        save_state: Option<&[u8]>,
+        cartridge_type_str: Option<&str>,
+    ) -> Result<(), InitError> {
+        let (cartridge_type, cartridge_type_source, eeprom_size_known) =
+            Bus::cartridge_type_for(rom_bin, cartridge_type_str)?;
+        self.mapped_mem.mem[MEM_REGION_OFFSET[MemoryRegion::BoardWram as usize]..].fill(0);
+        self.fill_uninit();
+        Bus::load_cartridge(&mut self.mapped_mem, rom_bin, save_state);
+
+        info!(target: TARGET_BUS, "backup type: {}", cartridge_type as u32);
+
+        self.cartridge_type = cartridge_type;
+        self.cartridge_type_source = cartridge_type_source;
+        self.cartridge_type_state = [0; 7];
+        self.flash_ids = None;
+        self.eeprom = Eeprom::new();
+        self.eeprom_size_known = eeprom_size_known;
+        self.eeprom_large_rom = rom_bin.len() > EEPROM_LARGE_ROM_SIZE;
+
+        self.save_dirty = false;
+        self.save_written = false;
+        self.memory_control = MEMORY_CONTROL_DEFAULT;
+        self.prefetch = Prefetch::default();
+        self.data_waits = 0;
+        self.graphics_dirty = GraphicsDirty {
+            palette: true,
+            vram: true,
+            oam: true,
+        };
+
+        self.is_any_dma_active = false;
+        self.hblank_dma = false;
+        self.vblank_dma = false;
+        self.video_capture_dma = false;
+        self.dma_channels = core::array::from_fn(DMA_Channel::new_disabled);
+        self.dma_control_written = 0;
+
+        self.is_any_timer_active = false;
+        self.timers = core::array::from_fn(|i| Timer::new(i as u8));
+
+        self.cpu_view = CpuBusView::default();
+        self.interrupt_requested = false;
+        self.interrupt_pending = false;
+        self.halt_requested = false;
+        self.apu.reset();
+        Ok(())
+    }
+
+    // overwrites the volatile memories with uninit_fill. IO registers are left alone
+    pub fn fill_uninit(&mut self) {
+        for region in [
+            MemoryRegion::BoardWram,
+            MemoryRegion::ChipWram,
+            MemoryRegion::Palette,
+            MemoryRegion::Vram,
+            MemoryRegion::Oam,
+        ] {
+            self.uninit_fill
+                .fill(&mut self.mapped_mem[region as usize][..]);
+        }
+        self.graphics_dirty = GraphicsDirty {
+            palette: true,
+            vram: true,
+            oam: true,
+        };
     }
 
     // -------- public memory read/write interfaces, intended for user instructions.
 
     #[inline(always)]
     pub fn read_byte(&mut self, addr: usize) -> u8 {
+        self.note_data_access(addr, ChunkSize::Byte);
         let (addr, region) = self.addr_match(addr, ChunkSize::Byte, true);
+        self.note_gamepak_read(region);
+        self.note_access(addr, region, false);
         self.internal_read_byte(addr, region)
     }
 
     #[inline(always)]
     pub fn read_halfword(&mut self, addr: usize) -> u16 {
+        self.note_data_access(addr, ChunkSize::Halfword);
         let (addr, region) = self.addr_match(addr, ChunkSize::Halfword, true);
-        assert!(addr & 1 == 0);
+        self.note_gamepak_read(region);
+        self.note_access(addr, region, false);
+        hot_assert!(addr & 1 == 0);
+        let addr = addr & !1;
         self.internal_read_byte(addr, region) as u16
             + ((self.internal_read_byte(addr + 1, region) as u16) << 8)
     }
 
     #[inline(always)]
     pub fn read_word(&mut self, addr: usize) -> u32 {
+        self.note_data_access(addr, ChunkSize::Word);
         let (addr, region) = self.addr_match(addr, ChunkSize::Word, true);
-        assert!(addr & 0b11 == 0);
+        self.note_gamepak_read(region);
+        self.note_access(addr, region, false);
+        hot_assert!(addr & 0b11 == 0);
+        let addr = addr & !0b11;
         self.internal_read_byte(addr, region) as u32
             + ((self.internal_read_byte(addr + 1, region) as u32) << 8)
             + ((self.internal_read_byte(addr + 2, region) as u32) << 16)
@@ -268,28 +583,72 @@ impl Bus {
 
     #[inline(always)]
     pub fn store_byte(&mut self, addr: usize, val: u8) {
+        self.note_data_access(addr, ChunkSize::Byte);
         let (addr, region) = self.addr_match(addr, ChunkSize::Byte, false);
+        self.note_access(addr, region, true);
         self.internal_write_byte(addr, region, val);
     }
 
     #[inline(always)]
     pub fn store_halfword(&mut self, addr: usize, val: u16) {
+        self.note_data_access(addr, ChunkSize::Halfword);
         let (addr, region) = self.addr_match(addr, ChunkSize::Halfword, false);
-        assert!(addr & 1 == 0);
+        self.note_access(addr, region, true);
+        hot_assert!(addr & 1 == 0);
+        let addr = addr & !1;
         self.internal_write_byte(addr, region, (val & 0b11111111) as u8);
         self.internal_write_byte(addr + 1, region, ((val >> 8) & 0b11111111) as u8);
     }
 
     #[inline(always)]
     pub fn store_word(&mut self, addr: usize, val: u32) {
+        self.note_data_access(addr, ChunkSize::Word);
         let (addr, region) = self.addr_match(addr, ChunkSize::Word, false);
-        assert!(addr & 0b11 == 0);
+        self.note_access(addr, region, true);
+        hot_assert!(addr & 0b11 == 0);
+        let addr = addr & !0b11;
         self.internal_write_byte(addr, region, (val & 0b11111111) as u8);
         self.internal_write_byte(addr + 1, region, ((val >> 8) & 0b11111111) as u8);
         self.internal_write_byte(addr + 2, region, ((val >> 16) & 0b11111111) as u8);
         self.internal_write_byte(addr + 3, region, ((val >> 24) & 0b11111111) as u8);
     }
 
+    // -------- checked read interfaces, for tooling and untrusted scripts. these return errors
+    //          instead of asserting on misaligned or unmapped accesses
+
+    pub fn try_read_byte(&mut self, addr: usize) -> Result<u8, BusError> {
+        let (addr_m, region) = self.try_addr_match(addr, ChunkSize::Byte)?;
+        Ok(self.internal_read_byte(addr_m, region))
+    }
+
+    pub fn try_read_halfword(&mut self, addr: usize) -> Result<u16, BusError> {
+        let (addr_m, region) = self.try_addr_match(addr, ChunkSize::Halfword)?;
+        Ok(self.internal_read_byte(addr_m, region) as u16
+            + ((self.internal_read_byte(addr_m + 1, region) as u16) << 8))
+    }
+
+    pub fn try_read_word(&mut self, addr: usize) -> Result<u32, BusError> {
+        let (addr_m, region) = self.try_addr_match(addr, ChunkSize::Word)?;
+        Ok(self.internal_read_byte(addr_m, region) as u32
+            + ((self.internal_read_byte(addr_m + 1, region) as u32) << 8)
+            + ((self.internal_read_byte(addr_m + 2, region) as u32) << 16)
+            + ((self.internal_read_byte(addr_m + 3, region) as u32) << 24))
+    }
+
+    fn try_addr_match(
+        &self,
+        addr: usize,
+        chunk_size: ChunkSize,
+    ) -> Result<(usize, MemoryRegion), BusError> {
+        if addr & (chunk_size as usize - 1) != 0 {
+            return Err(BusError::Misaligned(addr));
+        }
+        match self.addr_match(addr, chunk_size, true) {
+            (_, MemoryRegion::Illegal) => Err(BusError::Unmapped(addr)),
+            res => Ok(res),
+        }
+    }
+
     // -------- fast read/write interfaces, intended for use by system (not user instructions)
     //          note: these functions do not perform any wrapping at all.
 
@@ -334,15 +693,21 @@ impl Bus {
     // -------- miscellaneous public methods to communicate with other components of GBA system
     #[inline(always)]
     pub fn cpu_interrupt(&mut self, interrupt: u16) {
-        let reg_if = self.read_halfword_raw(0x202, MemoryRegion::IO);
-        let cur_reg_if = interrupt & self.read_halfword_raw(0x200, MemoryRegion::IO);
-        // self.store_halfword(0x04000202, cur_reg_if & !(reg_if));
-        self.mapped_mem[(MemoryRegion::IO as usize, 0x202)] ^= (cur_reg_if & !(reg_if)) as u8;
-        self.mapped_mem[(MemoryRegion::IO as usize, 0x203)] ^=
-            ((cur_reg_if & !(reg_if)) >> 8) as u8;
-        self.mapped_mem[(MemoryRegion::IO as usize, 0x202)] &= !0b10000000;
-        self.mapped_mem[(MemoryRegion::IO as usize, 0x203)] &= !0b00100000;
-        self.cpu.interrupt_requested = self.cpu.check_interrupt(self);
+        // IF latches every request, whether or not it is enabled in IE. IE only decides whether
+        // the request interrupts the cpu, see update_interrupt
+        let reg_if = self.read_halfword_raw(0x202, MemoryRegion::IO) | interrupt;
+        self.store_halfword_raw(0x202, MemoryRegion::IO, reg_if);
+        self.update_interrupt();
+    }
+
+    // recomputes the cpu's interrupt lines, after IE, IF or IME changed
+    #[inline(always)]
+    fn update_interrupt(&mut self) {
+        let pending = self.read_halfword_raw(0x202, MemoryRegion::IO)
+            & self.read_halfword_raw(0x200, MemoryRegion::IO)
+            > 0;
+        self.interrupt_pending = pending;
+        self.interrupt_requested = pending && self.read_byte_raw(0x208, MemoryRegion::IO) & 1 == 1;
     }
 
     #[inline(always)]
@@ -350,85 +715,236 @@ impl Bus {
         if !self.is_any_timer_active {
             return;
         }
-        unsafe {
-            for i in 0..4 {
-                let ptr = &mut self.timers[i] as *mut Timer;
-                if (*ptr).is_enabled
-                    && (*ptr).clock(self)
-                    && i != 3
-                    && self.timers[i + 1].is_cascading
-                {
-                    let ptr = &mut self.timers[i + 1] as *mut Timer;
-                    (*ptr).cascade();
-                }
+        for i in 0..4 {
+            let timer = &mut self.timers[i];
+            if !timer.is_enabled || !timer.clock(&mut self.mapped_mem[MemoryRegion::IO as usize]) {
+                continue;
+            }
+            // overflow
+            self.apu.timer_overflow(i);
+            if self.timers[i].raise_interrupt {
+                self.cpu_interrupt(1 << (3 + i));
+            }
+            if i != 3 && self.timers[i + 1].is_cascading {
+                self.timers[i + 1].cascade();
             }
         }
     }
 
     #[inline(always)]
-    fn check_dma(&mut self) -> bool {
+    pub fn check_dma(&self) -> bool {
         self.is_any_dma_active && self.dma_channels.iter().any(|x| x.check_is_active(self))
     }
 
+    // executes every active dma channel. at least one must be active. returns number of clock cycles
+    #[inline(always)]
+    pub fn run_dma(&mut self) -> u32 {
+        let mut res = 0;
+        let mut ex1 = false;
+        //info!("dma start");
+        for i in 0..4 {
+            if !self.dma_channels[i].check_is_active(self) {
+                continue;
+            }
+            // the channel is moved out while it runs, since the transfer goes through the bus and
+            // may itself write to the dma registers. a disabled channel is a few plain fields, so
+            // this is as cheap as the raw pointer it replaces, without aliasing the bus
+            let mut dma_channel =
+                core::mem::replace(&mut self.dma_channels[i], DMA_Channel::new_disabled(i));
+            self.dma_control_written &= !(1 << i);
+            res += dma_channel.execute_dma(self);
+            // if the transfer wrote the channel's control register (re-armed or stopped it), the
+            // channel that write built wins over the one that just finished
+            if self.dma_control_written & (1 << i) == 0 {
+                self.dma_channels[i] = dma_channel;
+            }
+            ex1 = true;
+        }
+        //info!("dma end");
+        hot_assert!(ex1);
+        self.hblank_dma = false;
+        self.vblank_dma = false;
+        self.video_capture_dma = false;
+        self.set_is_any_dma_active();
+        // transfer_cycles already includes the wait states
+        self.data_waits = 0;
+        //info!("dma executed");
+        res
+    }
+
+    // note: for clarify, channels 1-4 will be representing using numbers 0-3
     #[inline(always)]
-    pub fn dma_clock(&mut self) -> u32 {
-        let cpu_interrupt_requested =
-            !self.cpu.read_flag(crate::cpu::Flag::I) && self.cpu.interrupt_requested;
-        if !cpu_interrupt_requested && self.check_dma() {
-            let mut res = 0;
-            let mut ex1 = false;
-            //info!("dma start");
-            for i in 0..4 {
-                if !self.dma_channels[i].check_is_active(self) {
-                    continue;
+    pub fn apu_clock(&mut self) {
+        self.apu.clock(&self.mapped_mem[MemoryRegion::IO as usize]);
+    }
+
+    // backing memory of a region, starting at the region's cpu address. None for the regions that
+    // are not plain memory. this can be longer than the cpu range (see MemoryRegion::cpu_range): the
+    // save memory holds both banks of 128KiB flash
+    pub fn region_mem(&self, region: MemoryRegion) -> Option<&[u8]> {
+        region.cpu_range()?;
+        Some(&self.mapped_mem[region as usize])
+    }
+
+    pub fn region_mem_mut(&mut self, region: MemoryRegion) -> Option<&mut [u8]> {
+        region.cpu_range()?;
+        Some(&mut self.mapped_mem[region as usize])
+    }
+
+    // region that a cpu read from addr would hit, without performing the access
+    pub fn classify_address(&self, addr: usize) -> MemoryRegion {
+        self.addr_match(addr, ChunkSize::Word, true).1
+    }
+
+    // -------- memory timing
+
+    // cycles taken by a single access (1 + wait states). game pak wait states come from WAITCNT,
+    // EWRAM wait states from the internal memory control register;
+    // sequential accesses use the second wait state of the region. the game pak and EWRAM have a 16
+    // bit bus, so word accesses take two accesses, the second being sequential
+    pub fn access_cycles(&self, addr: usize, chunk_size: ChunkSize, sequential: bool) -> u32 {
+        let word = chunk_size == ChunkSize::Word;
+        match addr >> 24 {
+            0x2 => {
+                // 15 - n wait states. n = 15 locks up hardware, which isn't emulated
+                let waits = 15 - ((self.memory_control >> 24) & 0xf).min(14);
+                if word {
+                    2 * (1 + waits)
+                } else {
+                    1 + waits
                 }
-                // unsafe in order to prevent unnecessary cloning
-                unsafe {
-                    let ptr = &mut self.dma_channels[i] as *mut DMA_Channel;
-                    res += (*ptr).execute_dma(self);
+            }
+            // IWRAM has a 32 bit bus and no wait states
+            0x3 => 1,
+            0x5 | 0x6 if word => 2,
+            0x8..=0xd => {
+                let waitcnt = self.read_halfword_raw(0x204, MemoryRegion::IO);
+                let ws = ((addr >> 24) - 0x8) >> 1;
+                let non_seq = [4, 3, 2, 8][(waitcnt as usize >> (2 + 3 * ws)) & 0b11];
+                let seq = if (waitcnt >> (4 + 3 * ws)) & 1 > 0 {
+                    1
+                } else {
+                    [2, 4, 8][ws]
+                };
+                let first = 1 + if sequential { seq } else { non_seq };
+                if word {
+                    first + 1 + seq
+                } else {
+                    first
                 }
-                ex1 = true;
-                // safe code here:
-                /*
-                let mut dma_channel = bus.dma_channels[i].clone();
-                res += dma_channel.execute_dma(bus);
-                bus.dma_channels[i] = dma_channel
-                */
-            }
-            //info!("dma end");
-            assert!(ex1);
-            self.hblank_dma = false;
-            self.vblank_dma = false;
-            self.set_is_any_dma_active();
-            //info!("dma executed");
-            res
-        } else {
-            config::DMA_CHECK_INTERVAL_CLOCKS
+            }
+            0xe | 0xf => {
+                let waitcnt = self.read_halfword_raw(0x204, MemoryRegion::IO);
+                1 + [4, 3, 2, 8][waitcnt as usize & 0b11]
+            }
+            _ => 1,
         }
     }
 
+    // data accesses of the cpu (and DMA, whose waits are dropped in run_dma) to work RAM, which
+    // wait like opcode fetches do. other regions are not timed yet
     #[inline(always)]
-    pub fn cpu_clock(&mut self) -> u32 {
-        let ptr = &mut self.cpu as *mut Cpu;
-        unsafe { (*ptr).clock(self) }
+    fn note_data_access(&mut self, addr: usize, chunk_size: ChunkSize) {
+        if self.memory_timing && matches!(addr >> 24, 0x2 | 0x3) {
+            self.data_waits += self.access_cycles(addr, chunk_size, false) - 1;
+        }
     }
 
-    // note: for clarify, channels 1-4 will be representing using numbers 0-3
+    // wait states of the data accesses since the last call, on top of the 1 cycle per access that
+    // the instructions count for themselves
     #[inline(always)]
-    pub fn apu_clock(&mut self) {
-        let ptr = &mut self.apu as *mut Apu;
-        unsafe {
-            (*ptr).clock(self);
+    pub fn data_access_waits(&mut self) -> u32 {
+        core::mem::take(&mut self.data_waits)
+    }
+
+    #[inline(always)]
+    fn note_gamepak_read(&mut self, region: MemoryRegion) {
+        if self.memory_timing
+            && (region == MemoryRegion::Cartridge
+                || region == MemoryRegion::CartridgeUpper
+                || region == MemoryRegion::CartridgeSram)
+        {
+            self.prefetch.note_gamepak_read();
+        }
+    }
+
+    #[inline(always)]
+    fn note_access(&mut self, addr: usize, region: MemoryRegion, is_write: bool) {
+        if let Some(profile) = &mut self.access_profile {
+            profile.note_access(addr, region, is_write);
         }
     }
 
     #[inline(always)]
+    fn prefetch_enabled(&self) -> bool {
+        (self.read_halfword_raw(0x204, MemoryRegion::IO) >> 14) & 1 > 0
+    }
+
+    // cycles taken by fetching an opcode of size bytes at addr, on top of the 1 cycle per access
+    // that the instructions count for themselves. 0 unless memory timing is enabled
+    #[inline(always)]
+    pub fn opcode_fetch_waits(&mut self, addr: usize, size: usize) -> u32 {
+        if !self.memory_timing {
+            return 0;
+        }
+        let chunk_size = if size == 4 {
+            ChunkSize::Word
+        } else {
+            ChunkSize::Halfword
+        };
+        if !(0x8..=0xd).contains(&(addr >> 24)) {
+            return self.access_cycles(addr, chunk_size, true) - 1;
+        }
+        let sequential = self.prefetch.start_fetch(addr, size);
+        let cycles = if sequential && self.prefetch_enabled() {
+            let seq_cycles = self.access_cycles(addr, ChunkSize::Halfword, true);
+            self.prefetch.take(size, seq_cycles)
+        } else {
+            self.prefetch.flush();
+            self.access_cycles(addr, chunk_size, sequential)
+        };
+        cycles - 1
+    }
+
+    // the cpu took `cycles` to execute an instruction, during which the prefetch buffer can fill
+    #[inline(always)]
+    pub fn cpu_cycles_passed(&mut self, cycles: u32) {
+        let addr = self.prefetch.next_addr;
+        if self.memory_timing && (0x8..=0xd).contains(&(addr >> 24)) && self.prefetch_enabled() {
+            let seq_cycles = self.access_cycles(addr, ChunkSize::Halfword, true);
+            self.prefetch.run(cycles, seq_cycles);
+        }
+    }
+
+    #[inline(always)]
+    pub fn sram(&self) -> &[u8] {
+        &self.mapped_mem[MemoryRegion::CartridgeSram as usize][..]
+    }
+
     pub fn export_sram(&self, buff: &mut [u8]) {
         buff.copy_from_slice(&self.mapped_mem[MemoryRegion::CartridgeSram as usize][..]);
     }
 
     // -------- helper functions
     #[inline(always)]
+    fn note_save_write(&mut self) {
+        self.save_dirty = true;
+        self.save_written = true;
+    }
+
+    #[inline(always)]
+    // video capture dma stops by itself at line 162, even if it is repeating
+    pub fn stop_video_capture_dma(&mut self) {
+        if self.dma_channels[3].is_enabled
+            && self.dma_channels[3].timing_mode == TimingMode::VideoCapture
+        {
+            self.dma_channels[3].is_enabled = false;
+            let dma_cnt_upper = self.read_byte_raw(0xbb + 12 * 3, MemoryRegion::IO);
+            self.store_byte_raw(0xbb + 12 * 3, MemoryRegion::IO, dma_cnt_upper & !(1 << 7));
+            self.set_is_any_dma_active();
+        }
+    }
+
     pub fn set_is_any_dma_active(&mut self) {
         self.is_any_dma_active = false;
         for i in 0..4 {
@@ -466,6 +982,12 @@ impl Bus {
                 //     0x10d => (self.timers[3].timer_count >> 8) as u8,
                 //     _ => self.mapped_mem[(region as usize, addr)],
                 // }
+                if addr == 0x130 {
+                    if let Some(provider) = &mut self.keypad_provider {
+                        let keys = provider() & 0b1111111111;
+                        self.store_halfword_raw(0x130, MemoryRegion::IO, keys);
+                    }
+                }
                 self.mapped_mem[(region as usize, addr)]
                 // } else {
                 //     self.mapped_mem[(region as usize, addr)]
@@ -479,7 +1001,8 @@ impl Bus {
                         self.internal_read_byte_flash(addr)
                     }
                     _ => {
-                        warn!(
+                        hot_warn!(
+                            target: TARGET_BUS,
                             "reading from SRAM is forbidden for cartridge type {}",
                             self.cartridge_type as u32
                         );
@@ -490,12 +1013,14 @@ impl Bus {
             MemoryRegion::Bios => {
                 let offset = (addr & 0b11) << 3;
                 //let range = 0b11111111 << (offset);
-                if self.cpu.actual_pc >= 0x4000 {
-                    warn!(
+                if self.cpu_view.pc >= 0x4000 {
+                    hot_debug!(
+                        target: TARGET_BUS,
                         "attempt for CPU to read BIOS from outside, {} {:#x}",
-                        offset, self.cpu.last_fetched_bios_instr
+                        offset,
+                        self.cpu_view.last_fetched_bios_instr
                     );
-                    ((self.cpu.last_fetched_bios_instr >> offset) & 0b11111111) as u8
+                    ((self.cpu_view.last_fetched_bios_instr >> offset) & 0b11111111) as u8
                 } else {
                     //self.cpu.last_fetched_bios_instr &= !range;
                     //self.cpu.last_fetched_bios_instr = (self.mapped_mem[region as usize][addr] as u32) << offset;
@@ -503,21 +1028,22 @@ impl Bus {
                 }
             }
             MemoryRegion::CartridgeUpper => {
-                if self.eeprom_write_successful && (addr == 0x1000000 || addr == 0x1ffff00) {
-                    self.eeprom_write_successful = false;
-                    1
-                } else if (self.cartridge_type == CartridgeType::Eeprom512
-                    || self.cartridge_type == CartridgeType::Eeprom8192)
-                    && (addr == 0x1000001 || addr == 0x1ffff01)
-                {
-                    0
+                if self.is_eeprom_addr(addr) {
+                    // the bit is in the lower byte of the halfword
+                    if addr & 1 == 0 {
+                        self.eeprom.read_bit() as u8
+                    } else {
+                        0
+                    }
                 } else {
                     self.mapped_mem[(MemoryRegion::Cartridge as usize, addr)]
                 }
             }
+            MemoryRegion::MemoryControl => (self.memory_control >> (addr << 3)) as u8,
             MemoryRegion::Illegal => {
                 let range = (addr & 0b11) << 3;
-                (self.cpu.pipeline_instr.get(1).unwrap() >> range) as u8
+                // open bus returns the prefetched opcode, see Cpu::update_bus_view
+                (self.cpu_view.open_bus >> range) as u8
             }
             _ => self.mapped_mem[(region as usize, addr)],
         }
@@ -534,12 +1060,15 @@ impl Bus {
                                 // todo: add handling for STOP state (pause sound, PPU and cpu)
                             } else {
                                 // request that CPU is paused until next interrupt
-                                self.cpu.halt();
+                                self.halt_requested = true;
                             }
                         }
 
-                        0x208 => {
-                            self.cpu.interrupt_requested = self.cpu.check_interrupt(self);
+                        // IE and IME: store first, so the pending interrupt check sees the new value
+                        0x200 | 0x201 | 0x208 => {
+                            self.mapped_mem[(region as usize, addr)] = val;
+                            self.update_interrupt();
+                            return;
                         }
 
                         // special handling for REG_IF, interrupt handling
@@ -559,7 +1088,7 @@ impl Bus {
                             self.mapped_mem[(region as usize, addr)] =
                                 (self.mapped_mem[(region as usize, addr)] ^ val)
                                     & self.mapped_mem[(region as usize, addr)];
-                            self.cpu.interrupt_requested = self.cpu.check_interrupt(self);
+                            self.update_interrupt();
                             return;
                         }
 
@@ -584,6 +1113,7 @@ impl Bus {
                                 return;
                             };
                             self.dma_channels[channel_no] = dma_channel;
+                            self.dma_control_written |= 1 << channel_no;
                             self.set_is_any_dma_active();
                             //info!("set dma flags");
                             return;
@@ -592,29 +1122,30 @@ impl Bus {
                         // special handling for writing to timer count
                         0x100 | 0x101 | 0x104 | 0x105 | 0x108 | 0x109 | 0x10c | 0x10d => {
                             let timer_no = (addr - 0x100) >> 2;
-                            unsafe {
-                                let ptr = &mut self.timers[timer_no] as *mut Timer;
-                                if addr & 1 == 0 {
-                                    (*ptr).reload_val &= !0b11111111;
-                                    (*ptr).reload_val |= val as u16;
-                                } else {
-                                    (*ptr).reload_val &= 0b11111111;
-                                    (*ptr).reload_val |= (val as u16) << 8;
-                                }
+                            let timer = &mut self.timers[timer_no];
+                            if addr & 1 == 0 {
+                                timer.reload_val &= !0b11111111;
+                                timer.reload_val |= val as u16;
+                            } else {
+                                timer.reload_val &= 0b11111111;
+                                timer.reload_val |= (val as u16) << 8;
                             }
+                            // the register reads back the count, which is kept in sync by the timer
+                            return;
                         }
 
                         // special handling for timer control
                         0x102 | 0x106 | 0x10a | 0x10e => {
                             let timer_no = (addr - 0x102) >> 2;
-                            unsafe {
-                                let ptr = &mut self.timers[timer_no] as *mut Timer;
-                                (*ptr).set_period(val & 0b11);
-                                (*ptr).is_cascading = (val >> 2) & 1 > 0;
-                                (*ptr).raise_interrupt = (val >> 6) & 1 > 0;
-                                (*ptr).set_is_enabled(self, (val >> 7) & 1 > 0);
-                                self.set_is_any_timer_active();
-                            }
+                            let timer = &mut self.timers[timer_no];
+                            timer.set_period(val & 0b11);
+                            timer.is_cascading = (val >> 2) & 1 > 0;
+                            timer.raise_interrupt = (val >> 6) & 1 > 0;
+                            timer.set_is_enabled(
+                                &mut self.mapped_mem[MemoryRegion::IO as usize],
+                                (val >> 7) & 1 > 0,
+                            );
+                            self.set_is_any_timer_active();
                         }
 
                         // special handling for square sound channels; reset
@@ -626,10 +1157,10 @@ impl Bus {
                                 _ => unreachable!(),
                             };
                             if (val >> 7) & 1 > 0 {
-                                let ptr = &mut self.apu as *mut Apu;
-                                unsafe {
-                                    (*ptr).reset_square_channel(square_chan_num, self);
-                                };
+                                self.apu.reset_square_channel(
+                                    square_chan_num,
+                                    &self.mapped_mem[MemoryRegion::IO as usize],
+                                );
                             }
                             return;
                         }
@@ -638,10 +1169,9 @@ impl Bus {
                         0x75 => {
                             self.mapped_mem[(region as usize, addr)] = val;
                             if (val >> 7) & 1 > 0 {
-                                let ptr = &mut self.apu as *mut Apu;
-                                unsafe {
-                                    (*ptr).reset_wave_channel(self);
-                                };
+                                self.apu.reset_wave_channel(
+                                    &self.mapped_mem[MemoryRegion::IO as usize],
+                                );
                             }
                             return;
                         }
@@ -684,7 +1214,8 @@ impl Bus {
                             } else {
                                 //self.apu.direct_sound_fifo[channel_num].pop_back();
                                 //self.apu.direct_sound_fifo[channel_num].push_back(val as i8);
-                                warn!(
+                                hot_debug!(
+                                    target: TARGET_APU,
                                     "sound fifo: {}, attempt to add sample at 32 capacity",
                                     channel_num
                                 );
@@ -722,7 +1253,9 @@ impl Bus {
                         _ => {}
                     }
                 }
-                self.mapped_mem[(region as usize, addr)] = val;
+                let mask = io_write_mask(addr);
+                let old_val = self.mapped_mem[(region as usize, addr)];
+                self.mapped_mem[(region as usize, addr)] = (old_val & !mask) | (val & mask);
             }
             MemoryRegion::Bios => {
                 // do nothing, writing to BIOS is illegal
@@ -735,35 +1268,101 @@ impl Bus {
                     }
                     CartridgeType::Sram => {
                         self.mapped_mem[(region as usize, addr)] = val;
+                        self.note_save_write();
                     }
                     _ => {
-                        warn!(
+                        hot_warn!(
+                            target: TARGET_BUS,
                             "writing to SRAM is forbidden for cartridge type {}",
                             self.cartridge_type as u32
                         );
                     }
                 }
             }
+            MemoryRegion::MemoryControl => {
+                let shift = addr << 3;
+                self.memory_control =
+                    (self.memory_control & !(0xff << shift)) | ((val as u32) << shift);
+            }
+            MemoryRegion::CartridgeUpper => {
+                // only EEPROM addresses are mapped for writes, see addr_match. the bit is in the
+                // lower byte of the halfword
+                if addr & 1 == 0 {
+                    self.eeprom_write_bit(val & 1 > 0);
+                }
+            }
             MemoryRegion::Illegal => {
                 //warn!("illegal memory write");
             }
+            MemoryRegion::Palette => {
+                self.mapped_mem[(region as usize, addr)] = val;
+                self.graphics_dirty.palette = true;
+            }
+            MemoryRegion::Vram => {
+                self.mapped_mem[(region as usize, addr)] = val;
+                self.graphics_dirty.vram = true;
+            }
+            MemoryRegion::Oam => {
+                self.mapped_mem[(region as usize, addr)] = val;
+                self.graphics_dirty.oam = true;
+            }
             _ => {
                 self.mapped_mem[(region as usize, addr)] = val;
             }
         };
     }
 
+    // addr is relative to the start of the upper cartridge region
+    #[inline(always)]
+    fn is_eeprom_addr(&self, addr: usize) -> bool {
+        (self.cartridge_type == CartridgeType::Eeprom512
+            || self.cartridge_type == CartridgeType::Eeprom8192)
+            && addr
+                >= if self.eeprom_large_rom {
+                    0x1ffff00
+                } else {
+                    0x1000000
+                }
+    }
+
+    fn eeprom_write_bit(&mut self, bit: bool) {
+        let addr_bits = if self.cartridge_type == CartridgeType::Eeprom512 {
+            6
+        } else {
+            14
+        };
+        match self.eeprom.write_bit(bit, addr_bits) {
+            EepromAction::None => {}
+            EepromAction::Read(offset) => {
+                // blocks are stored as little endian u64s
+                let block = self.read_word_raw(offset, MemoryRegion::CartridgeSram) as u64
+                    + ((self.read_word_raw(offset + 4, MemoryRegion::CartridgeSram) as u64) << 32);
+                self.eeprom.start_read(block);
+            }
+            EepromAction::Write(offset, block) => {
+                self.store_word_raw(offset, MemoryRegion::CartridgeSram, block as u32);
+                self.store_word_raw(
+                    offset + 4,
+                    MemoryRegion::CartridgeSram,
+                    (block >> 32) as u32,
+                );
+                self.note_save_write();
+            }
+        }
+    }
+
     fn internal_read_byte_flash(&self, addr: usize) -> u8 {
         match self.cartridge_type_state[4] {
             //0 => {
 
             //},
             1 => {
-                let (device, man) = match self.cartridge_type {
-                    CartridgeType::Flash64 => {
+                let (device, man) = match (self.flash_ids, self.cartridge_type) {
+                    (Some((man, device)), _) => (device, man),
+                    (None, CartridgeType::Flash64) => {
                         (0x1c, 0xc2) // Macronix 64kb
                     }
-                    CartridgeType::Flash128 => {
+                    (None, CartridgeType::Flash128) => {
                         (0x09, 0xc2) // Macronix 128kb
                     }
                     _ => unreachable!("cartridge type is not flash"),
@@ -772,7 +1371,10 @@ impl Bus {
                     0x0 => man,
                     0x1 => device,
                     _ => {
-                        warn!("invalid addr for read in device/manufacturer mode");
+                        hot_warn!(
+                            target: TARGET_BUS,
+                            "invalid addr for read in device/manufacturer mode"
+                        );
                         0
                     }
                 }
@@ -818,6 +1420,7 @@ impl Bus {
                     }
                     _ => unreachable!("cartridge type is not flash"),
                 }
+                self.note_save_write();
                 self.cartridge_type_state[4] = 0;
             }
             _ => {
@@ -856,6 +1459,7 @@ impl Bus {
                                     self.mapped_mem[(MemoryRegion::CartridgeSram as usize, i)] =
                                         0xff;
                                 }
+                                self.note_save_write();
                                 self.cartridge_type_state[0] = 0;
                                 self.cartridge_type_state[1] = 0;
                                 self.cartridge_type_state[2] = 0;
@@ -864,14 +1468,27 @@ impl Bus {
                         }
                         // bank switching
                         4 => {
-                            assert!(addr == 0x0);
-                            if addr == 0x0 {
-                                assert!(val <= 1);
+                            if addr != 0x0 {
+                                hot_warn!(
+                                    target: TARGET_BUS,
+                                    "flash bank switch written to invalid addr: {:#x}",
+                                    addr
+                                );
+                                self.cartridge_type_state[4] = 0;
+                            } else if val > 1 {
+                                hot_warn!(
+                                    target: TARGET_BUS,
+                                    "invalid flash bank: {}, bank is unchanged",
+                                    val
+                                );
+                                self.cartridge_type_state[4] = 0;
+                            } else {
                                 self.cartridge_type_state[3] = val;
                                 self.cartridge_type_state[4] = 0;
                             }
                         }
-                        _ => warn!(
+                        _ => hot_warn!(
+                            target: TARGET_BUS,
                             "invalid cartridge type state for write: {}",
                             self.cartridge_type_state[4]
                         ),
@@ -894,8 +1511,9 @@ impl Bus {
             0x80 => {
                 self.cartridge_type_state[4] = 2;
             }
+            // erase entire chip: only valid right after the erase command (0x80)
             0x10 => {
-                if self.cartridge_type_state[4] == 1 {
+                if self.cartridge_type_state[4] == 2 {
                     let (start, end) = match self.cartridge_type{
                         CartridgeType::Flash64 => (config::FLASH64_MEM_START, config::FLASH64_MEM_END),
                         CartridgeType::Flash128 => (config::FLASH128_MEM_START, config::FLASH128_MEM_END),
@@ -904,6 +1522,7 @@ impl Bus {
                     for i in start..end {
                         self.mapped_mem[(MemoryRegion::CartridgeSram as usize, i)] = 0xff;
                     }
+                    self.note_save_write();
                 }
                 self.cartridge_type_state[4] = 0;
             }
@@ -920,6 +1539,10 @@ impl Bus {
         self.cartridge_type_state[2] = 0;
     }
 
+    // maps a cpu address to its region and the offset within it. regions smaller than their slice
+    // of the address space are mirrored: EWRAM every 256KiB, IWRAM every 32KiB, palette and OAM
+    // every 1KiB, VRAM every 128KiB (with the upper 32KiB mirroring the OBJ tiles), ROM every
+    // 32MiB and SRAM every 32KiB (flash banks are 64KiB). IO is not mirrored
     #[inline(always)]
     fn addr_match(
         &self,
@@ -934,7 +1557,7 @@ impl Bus {
             0 | 1 => {
                 if addr >= 0x4000 {
                     #[cfg(feature = "debug_instr")]
-                    warn!("illegal memory address: {:#x}", addr);
+                    debug!(target: TARGET_BUS, "illegal memory address: {:#x}", addr);
                     (addr, MemoryRegion::Illegal)
                 } else {
                     (addr, MemoryRegion::Bios)
@@ -943,7 +1566,9 @@ impl Bus {
             2 => ((addr & 0x3ffff), MemoryRegion::BoardWram),
             3 => ((addr & 0x7fff), MemoryRegion::ChipWram),
             4 => {
-                if addr >= 0x04000400 {
+                if addr & 0xfffc == 0x800 {
+                    ((addr & 0b11), MemoryRegion::MemoryControl)
+                } else if addr >= 0x04000400 {
                     (addr, MemoryRegion::Illegal)
                 } else {
                     // NOTE: not mirrored (maybe todo)
@@ -987,7 +1612,7 @@ impl Bus {
                 ((addr & 0x1ffffff), MemoryRegion::Cartridge)
             }
             12 | 13 => {
-                if !is_read {
+                if !is_read && !self.is_eeprom_addr(addr & 0x1ffffff) {
                     return (0, MemoryRegion::Illegal);
                 }
                 //(addr, MemoryRegion::Cartridge)
@@ -1011,13 +1636,404 @@ impl Bus {
                     }
                     _ => {},
                 }*/
-                ((addr & 0xffff), MemoryRegion::CartridgeSram)
+                let mask = if self.cartridge_type == CartridgeType::Sram {
+                    0x7fff
+                } else {
+                    0xffff
+                };
+                ((addr & mask), MemoryRegion::CartridgeSram)
             }
             _ => {
                 #[cfg(feature = "debug_instr")]
-                warn!("illegal memory access: {:#x} {:#x}", addr, self.cpu.instr);
+                debug!(
+                    target: TARGET_BUS,
+                    "illegal memory access: {:#x} at pc {:#x}",
+                    addr,
+                    self.cpu_view.pc
+                );
                 (0, MemoryRegion::Illegal)
             }
         }
     }
+
+    // regions that are part of a snapshot. the BIOS and ROM are loaded from their files instead
+    const SNAPSHOT_REGIONS: [MemoryRegion; 7] = [
+        MemoryRegion::BoardWram,
+        MemoryRegion::ChipWram,
+        MemoryRegion::IO,
+        MemoryRegion::Palette,
+        MemoryRegion::Vram,
+        MemoryRegion::Oam,
+        MemoryRegion::CartridgeSram,
+    ];
+
+    pub fn write_snapshot(&self, w: &mut SnapshotWriter) {
+        for region in Bus::SNAPSHOT_REGIONS {
+            w.bytes(&self.mapped_mem[region as usize]);
+        }
+        w.u8(self.cartridge_type as u8);
+        w.bytes(&self.cartridge_type_state);
+        self.eeprom.write_snapshot(w);
+        w.bool(self.eeprom_size_known);
+        w.u32(self.memory_control);
+        self.prefetch.write_snapshot(w);
+        w.bool(self.is_any_dma_active);
+        w.bool(self.hblank_dma);
+        w.bool(self.vblank_dma);
+        w.bool(self.video_capture_dma);
+        self.dma_channels.iter().for_each(|x| x.write_snapshot(w));
+        w.bool(self.is_any_timer_active);
+        self.timers.iter().for_each(|x| x.write_snapshot(w));
+        w.bool(self.halt_requested);
+        self.apu.write_snapshot(w);
+    }
+
+    pub fn read_snapshot(&mut self, r: &mut SnapshotReader) -> Result<(), &'static str> {
+        for region in Bus::SNAPSHOT_REGIONS {
+            r.bytes(&mut self.mapped_mem[region as usize])?;
+        }
+        self.cartridge_type = match r.u8()? {
+            0 => CartridgeType::Eeprom512,
+            1 => CartridgeType::Eeprom8192,
+            2 => CartridgeType::Sram,
+            3 => CartridgeType::Flash64,
+            4 => CartridgeType::Flash128,
+            _ => return Err("invalid cartridge type in snapshot"),
+        };
+        r.bytes(&mut self.cartridge_type_state)?;
+        self.eeprom.read_snapshot(r)?;
+        self.eeprom_size_known = r.bool()?;
+        self.memory_control = r.u32()?;
+        self.prefetch.read_snapshot(r)?;
+        self.is_any_dma_active = r.bool()?;
+        self.hblank_dma = r.bool()?;
+        self.vblank_dma = r.bool()?;
+        self.video_capture_dma = r.bool()?;
+        for x in self.dma_channels.iter_mut() {
+            x.read_snapshot(r)?;
+        }
+        self.is_any_timer_active = r.bool()?;
+        for x in self.timers.iter_mut() {
+            x.read_snapshot(r)?;
+        }
+        self.halt_requested = r.bool()?;
+        self.apu.read_snapshot(r)?;
+        self.update_interrupt();
+
+        self.graphics_dirty = GraphicsDirty {
+            palette: true,
+            vram: true,
+            oam: true,
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{bios, gba_with_bios, rom, run_frames};
+    use crate::{InterruptSource, GBA};
+    use alloc::{format, string::String};
+
+    fn gba_with_cartridge(cartridge_type_str: &str) -> GBA {
+        GBA::try_new(
+            &bios(&[]),
+            &[0; 0x200],
+            None,
+            None,
+            Some(cartridge_type_str),
+            48000,
+        )
+        .unwrap()
+    }
+
+    // the unlock sequence followed by a flash command
+    fn flash_command(gba: &mut GBA, command: u8) {
+        gba.poke8(0xe005555, 0xaa);
+        gba.poke8(0xe002aaa, 0x55);
+        gba.poke8(0xe005555, command);
+    }
+
+    // the strings listed in the --cartridge-type-str help of the frontends
+    #[test]
+    fn parses_every_documented_cartridge_type() {
+        for (s, expected) in [
+            ("SRAM_V", (CartridgeType::Sram, true)),
+            ("FLASH_V", (CartridgeType::Flash64, true)),
+            ("FLASH512_V", (CartridgeType::Flash64, true)),
+            ("FLASH1M_V", (CartridgeType::Flash128, true)),
+            ("EEPROM_V", (CartridgeType::Eeprom8192, false)),
+            ("EEPROM512", (CartridgeType::Eeprom512, true)),
+            ("EEPROM8192", (CartridgeType::Eeprom8192, true)),
+        ] {
+            assert_eq!(parse_cartridge_type(s), Some(expected), "{}", s);
+            // the _V suffix is optional
+            let other = match s.strip_suffix("_V") {
+                Some(short) => String::from(short),
+                None => format!("{}_V", s),
+            };
+            assert_eq!(parse_cartridge_type(&other), Some(expected), "{}", other);
+        }
+        assert_eq!(
+            parse_cartridge_type("flash1m_v"),
+            Some((CartridgeType::Flash128, true))
+        );
+        assert_eq!(parse_cartridge_type("FLASH2M"), None);
+    }
+
+    #[test]
+    fn reports_configured_flash_ids() {
+        let mut gba = gba_with_cartridge("FLASH1M_V");
+        flash_command(&mut gba, 0x90);
+        assert_eq!((gba.peek8(0xe000000), gba.peek8(0xe000001)), (0xc2, 0x09));
+        flash_command(&mut gba, 0xf0);
+
+        gba.set_flash_ids(0x62, 0x13);
+        flash_command(&mut gba, 0x90);
+        assert_eq!((gba.peek8(0xe000000), gba.peek8(0xe000001)), (0x62, 0x13));
+        flash_command(&mut gba, 0xf0);
+        // back to reading the flash itself
+        assert_eq!(gba.peek8(0xe000000), 0);
+    }
+
+    // programs a byte into the current bank
+    fn flash_write(gba: &mut GBA, addr: u32, val: u8) {
+        flash_command(gba, 0xa0);
+        gba.poke8(addr, val);
+    }
+
+    fn flash_switch_bank(gba: &mut GBA, bank: u8) {
+        flash_command(gba, 0xb0);
+        gba.poke8(0xe000000, bank);
+    }
+
+    #[test]
+    fn chip_erase_fills_flash_with_ff() {
+        let mut gba = gba_with_cartridge("FLASH1M_V");
+        flash_write(&mut gba, 0xe000000, 0x12);
+        flash_write(&mut gba, 0xe00ffff, 0x34);
+        flash_switch_bank(&mut gba, 1);
+        flash_write(&mut gba, 0xe001234, 0x56);
+        assert_eq!(gba.peek8(0xe001234), 0x56);
+
+        flash_command(&mut gba, 0x80);
+        flash_command(&mut gba, 0x10);
+        for bank in 0..2 {
+            flash_switch_bank(&mut gba, bank);
+            for addr in 0xe000000..0xe010000 {
+                assert_eq!(gba.peek8(addr), 0xff, "bank {} addr {:#x}", bank, addr);
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_flash_bank_is_ignored() {
+        let mut gba = gba_with_cartridge("FLASH1M_V");
+        flash_switch_bank(&mut gba, 1);
+        flash_write(&mut gba, 0xe000000, 0x42);
+
+        flash_switch_bank(&mut gba, 5);
+        assert_eq!(gba.peek8(0xe000000), 0x42);
+        // the bank switch command written to a different address
+        flash_command(&mut gba, 0xb0);
+        gba.poke8(0xe000001, 0);
+        assert_eq!(gba.peek8(0xe000000), 0x42);
+
+        // the flash still takes commands
+        flash_switch_bank(&mut gba, 0);
+        assert_eq!(gba.peek8(0xe000000), 0);
+        flash_write(&mut gba, 0xe000000, 0x43);
+        assert_eq!(gba.peek8(0xe000000), 0x43);
+    }
+
+    #[test]
+    fn reads_through_region_mirrors() {
+        let mut gba = gba_with_cartridge("SRAM_V");
+        for (addr, mirror) in [
+            (0x2000010, 0x2040010),
+            (0x3000010, 0x3008010),
+            (0x5000010, 0x5000410),
+            (0x6000010, 0x6020010),
+            (0x6010010, 0x6018010),
+            (0x7000010, 0x7000410),
+        ] {
+            gba.poke16(addr, addr as u16 ^ 0x5a5a);
+            assert_eq!(gba.peek16(mirror), addr as u16 ^ 0x5a5a, "{:#x}", mirror);
+        }
+        gba.poke8(0xe000010, 0x42);
+        assert_eq!(gba.peek8(0xe008010), 0x42);
+        assert_eq!(gba.peek8(0xe018010), 0x42);
+    }
+
+    #[test]
+    fn cpu_writes_to_read_only_io_bits_are_ignored() {
+        let mut gba = gba_with_bios(&[(
+            0,
+            &[
+                0xe3a00301, // mov r0, #0x4000000
+                0xe3e01000, // mvn r1, #0
+                0xe1c010b6, // strh r1, [r0, #6] (VCOUNT)
+                0xe1d020b6, // ldrh r2, [r0, #6]
+                0xe1c010b4, // strh r1, [r0, #4] (DISPSTAT)
+                0xe1d030b4, // ldrh r3, [r0, #4]
+                0xe2804c01, // add r4, r0, #0x100
+                0xe1d473b0, // ldrh r7, [r4, #0x30]
+                0xe1c413b0, // strh r1, [r4, #0x30] (KEYINPUT)
+                0xe1d453b0, // ldrh r5, [r4, #0x30]
+                0xe3a06403, // mov r6, #0x3000000
+                0xe88600ac, // stmia r6, {r2, r3, r5, r7}
+                0xeafffffe, // b .
+            ],
+        )]);
+        run_frames(&mut gba, 1);
+        assert_eq!(gba.peek32(0x3000000), 0);
+        // the vblank flag stays clear on line 0. the hblank and vcount match flags keep what the
+        // ppu set
+        assert_eq!(gba.peek32(0x3000004) & !0b110, 0xff38);
+        assert_eq!(gba.peek32(0x3000008), gba.peek32(0x300000c));
+    }
+
+    #[test]
+    fn eeprom_reads_back_written_block() {
+        let mut gba = gba_with_cartridge("EEPROM512");
+        let send = |gba: &mut GBA, val: u64, len: usize| {
+            for i in (0..len).rev() {
+                gba.poke16(0xd000000, ((val >> i) & 1) as u16);
+            }
+        };
+        let data = 0x0123456789abcdef;
+        // write: 0b10, block 5, data, stop bit
+        send(&mut gba, 0b10, 2);
+        send(&mut gba, 5, 6);
+        send(&mut gba, data, 64);
+        send(&mut gba, 0, 1);
+        assert_eq!(gba.peek16(0xd000000), 1);
+
+        // read: 0b11, block 5, stop bit. then 4 ignored bits and the data, MSB first
+        send(&mut gba, 0b11, 2);
+        send(&mut gba, 5, 6);
+        send(&mut gba, 0, 1);
+        let bits: Vec<_> = (0..68).map(|_| gba.peek16(0xd000000)).collect();
+        assert_eq!(bits[..4], [0; 4]);
+        let read = bits[4..]
+            .iter()
+            .fold(0, |val, &bit| (val << 1) | bit as u64);
+        assert_eq!(read, data);
+        // ready again once the read is done
+        assert_eq!(gba.peek16(0xd000000), 1);
+    }
+
+    // iterations during one frame of a loop loading from the work RAM at region << 24
+    fn load_loop_iterations(region: u32, memory_timing: bool, memory_control: u32) -> u32 {
+        let mut gba = gba_with_bios(&[(
+            0,
+            &[
+                0xe3a05400 | region, // mov r5, #(region << 24)
+                0xe3a04403,          // mov r4, #0x3000000
+                0xe3a06000,          // mov r6, #0
+                0xe5951100,          // ldr r1, [r5, #0x100]
+                0xe2866001,          // add r6, r6, #1
+                0xe5846000,          // str r6, [r4]
+                0xeafffffb,          // b 0xc
+            ],
+        )]);
+        gba.set_memory_timing(memory_timing);
+        gba.poke32(0x4000800, memory_control);
+        run_frames(&mut gba, 1);
+        gba.peek32(0x3000000)
+    }
+
+    #[test]
+    fn ewram_overclock_takes_one_wait_state() {
+        let iterations = |memory_timing, memory_control| {
+            load_loop_iterations(0x2, memory_timing, memory_control)
+        };
+        let (normal, overclocked) = (iterations(true, 0x0d000020), iterations(true, 0x0e000020));
+        assert!(overclocked > normal, "{} {}", overclocked, normal);
+        // without memory timing, every access takes a single cycle
+        assert_eq!(iterations(false, 0x0d000020), iterations(false, 0x0e000020));
+
+        let mut bus = Bus::new(&bios(&[]), &[0; 0x200], None, None, Apu::new(48000)).unwrap();
+        assert_eq!(bus.access_cycles(0x2000000, ChunkSize::Word, false), 6);
+        bus.store_word(0x4000800, 0x0e000020);
+        assert_eq!(bus.access_cycles(0x2000000, ChunkSize::Halfword, false), 2);
+        assert_eq!(bus.access_cycles(0x2000000, ChunkSize::Word, true), 4);
+    }
+
+    #[test]
+    fn dma_reads_open_bus_with_empty_pipeline() {
+        // every instruction of the loop is a branch, which empties the pipeline, so the hblank DMA
+        // always reads open bus with nothing prefetched
+        let mut gba = gba_with_bios(&[(0, &[0xeafffffe])]); // b .
+        gba.poke32(0x40000bc, 0x10000000); // DMA1SAD
+        gba.poke32(0x40000c0, 0x3000000); // DMA1DAD
+        gba.poke32(0x40000c4, 0xa4000001); // 1 word at the next hblank
+        run_frames(&mut gba, 1);
+        // the last fetched BIOS opcode
+        assert_eq!(gba.peek32(0x3000000), 0xeafffffe);
+    }
+
+    #[test]
+    fn ewram_loads_are_slower_than_iwram_loads() {
+        let (iwram, ewram) = (
+            load_loop_iterations(0x3, true, 0x0d000020),
+            load_loop_iterations(0x2, true, 0x0d000020),
+        );
+        assert!(iwram > ewram, "{} {}", iwram, ewram);
+        // without memory timing, every access takes a single cycle
+        assert_eq!(
+            load_loop_iterations(0x3, false, 0x0d000020),
+            load_loop_iterations(0x2, false, 0x0d000020)
+        );
+
+        let bus = Bus::new(&bios(&[]), &[0; 0x200], None, None, Apu::new(48000)).unwrap();
+        assert_eq!(bus.access_cycles(0x3000000, ChunkSize::Word, false), 1);
+        assert_eq!(bus.access_cycles(0x2000000, ChunkSize::Halfword, false), 3);
+    }
+
+    #[test]
+    fn bios_reads_from_outside_return_the_last_fetched_word() {
+        let bios = bios(&[(
+            0,
+            &[
+                0xe28f0001, // add r0, pc, #1
+                0xe12fff10, // bx r0
+                0x47084901, // thumb 0x8: ldr r1, [pc, #4]; 0xa: bx r1
+                0x22221111, // padding, fetched while the bx at 0xa executes
+                0x08000000,
+            ],
+        )]);
+        let rom = rom(&[
+            0xe3a00000, // mov r0, #0
+            0xe5901000, // ldr r1, [r0]
+            0xe3a02403, // mov r2, #0x3000000
+            0xe5821000, // str r1, [r2]
+            0xeafffffe, // b .
+        ]);
+        let mut gba = GBA::try_new(&bios, &rom, None, None, None, 48000).unwrap();
+        run_frames(&mut gba, 1);
+        // the thumb fetch at 0xe latches the whole word at 0xc
+        assert_eq!(gba.peek32(0x3000000), 0x22221111);
+    }
+
+    #[test]
+    fn acknowledging_one_interrupt_leaves_the_others_pending() {
+        let mut gba = gba_with_bios(&[]);
+        gba.raise_interrupt(InterruptSource::Timer0);
+        gba.raise_interrupt(InterruptSource::Dma0);
+        gba.raise_interrupt(InterruptSource::Keypad);
+        assert_eq!(gba.peek16(0x4000202), 0x1108);
+
+        gba.poke16(0x4000202, 1 << InterruptSource::Timer0 as u16);
+        assert_eq!(gba.peek16(0x4000202), 0x1100);
+
+        // a byte write to the upper half acknowledges only the bits it sets
+        gba.poke8(0x4000203, 1 << (InterruptSource::Dma0 as u16 - 8));
+        assert_eq!(gba.peek16(0x4000202), 0x1000);
+
+        // writing 0 acknowledges nothing
+        gba.poke16(0x4000202, 0);
+        assert_eq!(gba.peek16(0x4000202), 0x1000);
+    }
 }
@@ -11,6 +11,12 @@ pub const CPU_EXECUTION_INTERVAL_US: u64 =
 // number of frames to pass before recording new FPS value
 pub const FPS_RECORD_INTERVAL: u32 = 120;
 
+// default save files live in <rom dir>/SAVE_FILE_DIR/<rom name>.SAVE_FILE_EXT
+#[cfg(feature = "std")]
+pub const SAVE_FILE_DIR: &str = "rustsav";
+#[cfg(feature = "std")]
+pub const SAVE_FILE_EXT: &str = "rustsav";
+
 pub const DEFAULT_CARTRIDGE_TYPE: CartridgeType = CartridgeType::Sram;
 
 // note: the below memory addresses cannot be accessed by the user.
@@ -27,10 +33,22 @@ pub const AUDIO_SAMPLE_RATE: u32 = 1 << AUDIO_SAMPLE_RATE_POW2;
 pub const AUDIO_SAMPLE_CLOCKS_POW2: u32 = 24 - AUDIO_SAMPLE_RATE_POW2;
 pub const AUDIO_SAMPLE_CLOCKS: u32 = 1 << AUDIO_SAMPLE_CLOCKS_POW2;
 pub const AUDIO_SAMPLE_CHUNKS: usize = 1024;
+// samples per channel returned by GBA::audio_scope, about 8ms
+pub const AUDIO_SCOPE_SAMPLES: usize = 512;
 
 pub const NUM_SAVE_STATES: usize = 5;
 pub const SAVE_STATE_SIZE: usize = 128 * 1024 * NUM_SAVE_STATES;
 
+// frames without a write to save memory before GBA::set_save_write_callback's callback is called
+pub const SAVE_WRITE_DEBOUNCE_FRAMES: u64 = 30;
+
+// number of most recent frames that GBA::frame_time_stats covers
+pub const FRAME_TIME_WINDOW: usize = 120;
+
+// largest supersampling factor for GBA::set_internal_scale. each pixel of the supersampled layers
+// costs n*n samples, so larger factors are impractically slow anyway
+pub const MAX_INTERNAL_SCALE: u32 = 8;
+
 // number of frames to pass before rendering in speedup mode
 pub const FRAME_RENDER_INTERVAL_SPEEDUP: u32 = 8;
 
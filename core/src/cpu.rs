@@ -1,14 +1,14 @@
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
 
-use log::warn;
-
 use crate::{
-    bus::{Bus, MemoryRegion},
     config,
-    dma_channel::DMA_Channel,
+    memory::{CpuBusView, MemoryInterface, TestMemory},
+    snapshot::{SnapshotReader, SnapshotWriter},
+    trace::TARGET_CPU,
 };
-use std::{cmp::min, collections::VecDeque, num::Wrapping};
+use alloc::collections::VecDeque;
+use core::{cmp::min, num::Wrapping};
 
 #[derive(Copy, Clone, PartialEq)]
 enum Register {
@@ -62,6 +62,24 @@ enum OperatingMode {
     Und = 6,
 }
 
+// the full register file, for setting up and checking single instructions. see GBA::fuzz_exec_one
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct CpuState {
+    // r0-r15 of the user/system bank. r15 is the address of the instruction to execute, not the
+    // pipelined pc
+    pub r: [u32; 16],
+    // r8-r14 of fiq mode
+    pub r_fiq: [u32; 7],
+    // r13-r14 of the other privileged modes
+    pub r_svc: [u32; 2],
+    pub r_abt: [u32; 2],
+    pub r_irq: [u32; 2],
+    pub r_und: [u32; 2],
+    pub cpsr: u32,
+    // fiq, svc, abt, irq, und
+    pub spsr: [u32; 5],
+}
+
 #[derive(PartialEq, Eq)]
 pub enum Flag {
     N = 31,
@@ -93,7 +111,6 @@ pub struct Cpu {
     thumb_modify_flags: bool,
 
     halt: bool,
-    pub interrupt_requested: bool,
     //interrupt: u16, // same format as REG_IE and REG_IF. But, it is cleared to 0 everytime an interrupt begins executing to prevent infinite loop.
     #[cfg(feature = "debug_instr")]
     pub debug_cnt: u32,
@@ -102,6 +119,23 @@ pub struct Cpu {
 
     pub last_fetched_bios_instr: u32,
     dma_check_counter: u32,
+
+    // set when a branch jumps to itself while IRQs are masked; the CPU can never leave such a loop
+    pub self_branch_detected: bool,
+
+    // idle loop skipping: a branch to itself is treated like halting until the next interrupt
+    idle_loop: bool,
+    pub idle_skip: bool,
+    pub sleep_cycles: u32,
+
+    // wait states of the opcode fetches of the current instruction, see Bus::opcode_fetch_waits
+    fetch_waits: u32,
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Cpu {
@@ -265,7 +299,6 @@ impl Cpu {
             thumb_modify_flags: true,
 
             halt: false,
-            interrupt_requested: false,
 
             #[cfg(feature = "debug_instr")]
             debug_cnt: 0,
@@ -274,6 +307,14 @@ impl Cpu {
 
             last_fetched_bios_instr: 0,
             dma_check_counter: 0,
+
+            self_branch_detected: false,
+
+            idle_loop: false,
+            idle_skip: false,
+            sleep_cycles: config::CPU_HALT_SLEEP_CYCLES,
+
+            fetch_waits: 0,
         };
         //res.set_reg(13, 0x03007F00);
         //res.reg[Register::R13_svc as usize] = 0x02FFFFF0;
@@ -285,7 +326,7 @@ impl Cpu {
 
     // ---------- main loop (clock)
     #[inline(always)]
-    pub fn clock(&mut self, bus: &mut Bus) -> u32 {
+    pub fn clock<M: MemoryInterface + ?Sized>(&mut self, bus: &mut M) -> u32 {
         //use std::iter::repeat;
 
         #[cfg(feature = "debug_instr")]
@@ -293,56 +334,158 @@ impl Cpu {
             self.bios_end = true;
         }
         // check for halting (pause cpu)
-        /*if bus.check_cpu_halt_request() {
+        if bus.take_halt_request() {
             self.halt();
-        }*/
+        }
 
         //self.debug(&format!("halting: {}\n", self.halt));
         //self.debug(&format!("IE: {:#018b}\n", bus.read_halfword(0x04000200)));
 
-        let clocks = if !self.read_flag(Flag::I) && self.interrupt_requested {
+        // any enabled request ends a halt, whether or not it is taken
+        if bus.interrupt_pending() {
+            self.halt = false;
+        }
+        let clocks = if !self.read_flag(Flag::I) && bus.interrupt_requested() {
             self.halt = false;
             //self.bus_set_reg_if(bus);
             //info!("interrupt: {:#018b}", bus.read_halfword(0x04000200));
             //self.debug = true;
             self.execute_hardware_interrupt()
         } else if self.check_dma(bus) {
-            self.execute_dma(bus)
-        } else if self.halt {
-            config::CPU_HALT_SLEEP_CYCLES // consume clock cycles; do nothing
+            bus.run_dma()
+        } else if self.halt || (self.idle_skip && self.idle_loop) {
+            self.sleep_cycles // consume clock cycles; do nothing
         } else {
-            match self.read_flag(Flag::T) {
+            self.fetch_waits = 0;
+            let cycles = match self.read_flag(Flag::T) {
                 false => self.decode_execute_instruction_arm(bus),
                 true => self.decode_execute_instruction_thumb(bus),
-            }
+            };
+            // the game pak bus is free during work RAM accesses, so the prefetch buffer keeps
+            // filling during their wait states
+            let cycles = cycles + bus.data_access_waits();
+            bus.cpu_cycles_passed(cycles);
+            cycles + self.fetch_waits
         };
 
-        assert!(clocks > 0);
-        clocks
+        // dma run outside of the cpu sees the state after this instruction
+        self.update_bus_view(bus);
+
+        hot_assert!(clocks > 0);
+        clocks.max(1)
+    }
+
+    // publishes the state the bus looks at during accesses, see CpuBusView
+    #[inline(always)]
+    pub fn update_bus_view<M: MemoryInterface + ?Sized>(&self, bus: &mut M) {
+        // open bus returns the prefetched opcode. the pipeline is empty right after a flush, so fall
+        // back to the last fetched BIOS opcode
+        let open_bus = self
+            .pipeline_instr
+            .get(1)
+            .or(self.pipeline_instr.back())
+            .copied()
+            .unwrap_or(self.last_fetched_bios_instr);
+        bus.set_cpu_view(CpuBusView {
+            pc: self.actual_pc,
+            open_bus,
+            last_fetched_bios_instr: self.last_fetched_bios_instr,
+        });
+    }
+
+    // ---------- single instruction execution, for fuzzing and test vectors
+
+    /// Executes exactly one instruction at state.r[15] in the mode and state (ARM or THUMB) given by
+    /// state.cpsr, fetching it from `mem` (see TestMemory::store_instr). Interrupts, DMA and halting
+    /// are not processed. Returns the resulting registers (with r[15] being the address of the next
+    /// instruction) and the number of clock cycles.
+    pub fn execute_single_for_test(state: &CpuState, mem: &mut TestMemory) -> (CpuState, u32) {
+        let mut cpu = Cpu::new();
+        cpu.load_state(state);
+        let cycles = if cpu.read_flag(Flag::T) {
+            cpu.decode_execute_instruction_thumb(mem)
+        } else {
+            cpu.decode_execute_instruction_arm(mem)
+        };
+        (cpu.state(), cycles)
+    }
+
+    fn load_state(&mut self, state: &CpuState) {
+        self.reg[..15].copy_from_slice(&state.r[..15]);
+        self.actual_pc = state.r[15];
+        self.reg[Register::R8_fiq as usize..=Register::R13_fiq as usize]
+            .copy_from_slice(&state.r_fiq[..6]);
+        self.reg[Register::R14_fiq as usize] = state.r_fiq[6];
+        for (i, banked) in [state.r_svc, state.r_abt, state.r_irq, state.r_und]
+            .iter()
+            .enumerate()
+        {
+            self.reg[Register::R13_svc as usize + i] = banked[0];
+            self.reg[Register::R14_svc as usize + i] = banked[1];
+        }
+        self.reg[Register::SPSR_fiq as usize..=Register::SPSR_und as usize]
+            .copy_from_slice(&state.spsr);
+        self.set_cpsr(state.cpsr);
+    }
+
+    fn state(&self) -> CpuState {
+        let mut state = CpuState::default();
+        state.r[..15].copy_from_slice(&self.reg[..15]);
+        state.r[15] = self.actual_pc;
+        state.r_fiq[..6]
+            .copy_from_slice(&self.reg[Register::R8_fiq as usize..=Register::R13_fiq as usize]);
+        state.r_fiq[6] = self.reg[Register::R14_fiq as usize];
+        for (i, banked) in [
+            &mut state.r_svc,
+            &mut state.r_abt,
+            &mut state.r_irq,
+            &mut state.r_und,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            *banked = [
+                self.reg[Register::R13_svc as usize + i],
+                self.reg[Register::R14_svc as usize + i],
+            ];
+        }
+        state.cpsr = self.reg[Register::Cpsr as usize];
+        state
+            .spsr
+            .copy_from_slice(&self.reg[Register::SPSR_fiq as usize..=Register::SPSR_und as usize]);
+        state
     }
 
     // -------------- ARM INSTRUCTIONS -----------------
 
     #[inline(always)]
-    fn fetch_arm_instr(&mut self, bus: &mut Bus) {
+    fn fetch_arm_instr<M: MemoryInterface + ?Sized>(&mut self, bus: &mut M) {
+        // the bus view is up to date from the end of the last clock, it only changes with the
+        // pipeline from here on
         if self.pipeline_instr.is_empty() {
-            self.pipeline_instr
-                .push_back(bus.read_word(self.actual_pc as usize));
-            self.pipeline_instr
-                .push_back(bus.read_word(self.actual_pc as usize + 4));
+            for offset in [0, 4] {
+                let addr = self.actual_pc as usize + offset;
+                self.pipeline_instr.push_back(bus.read_word(addr));
+                self.fetch_waits += bus.opcode_fetch_waits(addr, 4);
+                self.update_bus_view(bus);
+            }
         }
-        self.pipeline_instr
-            .push_back(bus.read_word(self.actual_pc as usize + 8));
+        let addr = self.actual_pc as usize + 8;
+        self.pipeline_instr.push_back(bus.read_word(addr));
+        self.fetch_waits += bus.opcode_fetch_waits(addr, 4);
         self.instr = self.pipeline_instr.pop_front().unwrap();
+        // reads of the BIOS from outside return the last opcode the BIOS fetched, which is the
+        // prefetch at pc + 8 of the last BIOS instruction, eg. 0xe3a02004 after returning from a swi
         if self.actual_pc < 0x4000 {
-            self.last_fetched_bios_instr =
-                bus.read_word_raw(self.actual_pc as usize + 8, MemoryRegion::Bios) as u32;
+            self.last_fetched_bios_instr = bus.read_bios_word(self.actual_pc as usize + 8);
         }
+        // for the data accesses of the instruction
+        self.update_bus_view(bus);
     }
 
     // completes one instruction. Returns number of clock cycles
     #[inline(always)]
-    fn decode_execute_instruction_arm(&mut self, bus: &mut Bus) -> u32 {
+    fn decode_execute_instruction_arm<M: MemoryInterface + ?Sized>(&mut self, bus: &mut M) -> u32 {
         // get rid of the trailing bits, these may be set to 1 but must always be treated as 0
         self.actual_pc &= !0b11;
         self.fetch_arm_instr(bus);
@@ -437,9 +580,11 @@ impl Cpu {
                         self.execute_block_data_transfer(bus)
                     }
                     _ => {
-                        print!(
+                        hot_warn!(
+                            target: TARGET_CPU,
                             "Error undefined instruction {:#034b} at pc {}",
-                            self.instr, self.actual_pc
+                            self.instr,
+                            self.actual_pc
                         );
                         0
                     }
@@ -480,7 +625,9 @@ impl Cpu {
         if (offset >> 25) & 1 == 1 {
             offset |= 0b111111 << 26;
         }
+        let branch_pc = self.actual_pc;
         self.actual_pc = (Wrapping(self.read_pc()) + Wrapping(offset)).0;
+        self.check_self_branch(branch_pc);
         self.pipeline_instr.clear();
         self.increment_pc = false;
         3
@@ -490,12 +637,7 @@ impl Cpu {
     fn execute_branch_exchange(&mut self) -> u32 {
         assert!(!self.read_flag(Flag::T));
         let addr = self.read_reg(self.instr & 0b1111);
-        if addr & 1 > 0 {
-            self.set_flag(Flag::T, true);
-        };
-        self.actual_pc = (addr >> 1) << 1;
-        self.pipeline_instr.clear();
-        self.increment_pc = false;
+        self.set_pc_interworking(addr);
         3
     }
 
@@ -605,7 +747,7 @@ impl Cpu {
     fn op_cmn(&mut self) -> u32 {
         let res = Wrapping(self.operand1) + Wrapping(self.operand2);
         let res = res.0;
-        if self.dataproc_set_cond() && self.reg_dest != Register::R15 as u32 {
+        if !self.compare_restores_cpsr() && self.dataproc_set_cond() {
             self.set_flag(Flag::N, res >> 31 > 0);
             self.set_flag(Flag::Z, res == 0);
             //self.set_flag(Flag::C, (self.operand1 >> 31 > 0 || self.operand2 >> 31 > 0) && res >> 31 == 0);
@@ -615,12 +757,6 @@ impl Cpu {
                 (self.operand1 >> 31 == self.operand2 >> 31) && res >> 31 != self.operand1 >> 31,
             );
         }
-        if self.reg_dest == 0b1111 {
-            if let Some(reg) = self.spsr_map.get(self.op_mode as usize).unwrap() {
-                let spsr = self.reg[*reg as usize];
-                self.set_cpsr(spsr);
-            }
-        }
         //self._op_set_pc(res);
         0
     }
@@ -631,7 +767,7 @@ impl Cpu {
         //print!(" op1: {}, op2: {}, res: {}, set_cond: {}", self.operand1, self.operand2, res, self.dataproc_set_cond());
         let res = res.0;
         //info!("{:#x} {:#x} {:#x}", self.operand1, self.operand2, res);
-        if self.dataproc_set_cond() && self.reg_dest != Register::R15 as u32 {
+        if !self.compare_restores_cpsr() && self.dataproc_set_cond() {
             self.set_flag(Flag::N, res >> 31 > 0);
             self.set_flag(Flag::Z, res == 0);
             self.set_flag(Flag::C, self.operand2 <= self.operand1);
@@ -640,12 +776,6 @@ impl Cpu {
                 (self.operand1 >> 31 != self.operand2 >> 31) && res >> 31 == self.operand2 >> 31,
             );
         }
-        if self.reg_dest == 0b1111 {
-            if let Some(reg) = self.spsr_map.get(self.op_mode as usize).unwrap() {
-                let spsr = self.reg[*reg as usize];
-                self.set_cpsr(spsr);
-            }
-        }
         //self._op_set_pc(res);
         //info!("{}", self.read_flag(Flag::Z));
         0
@@ -803,35 +933,43 @@ impl Cpu {
     #[inline(always)]
     fn op_teq(&mut self) -> u32 {
         let res = self.operand1 ^ self.operand2;
-        if self.dataproc_set_cond() && self.reg_dest != Register::R15 as u32 {
+        if !self.compare_restores_cpsr() && self.dataproc_set_cond() {
             self.set_flag(Flag::N, res >> 31 > 0);
             self.set_flag(Flag::Z, res == 0);
             self.set_flag(Flag::C, self.shifter_carry > 0);
         }
-        if self.reg_dest == 0b1111 {
-            if let Some(reg) = self.spsr_map.get(self.op_mode as usize).unwrap() {
-                let spsr = self.reg[*reg as usize];
-                self.set_cpsr(spsr);
-            }
-        }
         0
     }
 
     #[inline(always)]
     fn op_tst(&mut self) -> u32 {
         let res = self.operand1 & self.operand2;
-        if self.dataproc_set_cond() && self.reg_dest != Register::R15 as u32 {
+        if !self.compare_restores_cpsr() && self.dataproc_set_cond() {
             self.set_flag(Flag::N, res >> 31 > 0);
             self.set_flag(Flag::Z, res == 0);
             self.set_flag(Flag::C, self.shifter_carry > 0);
         }
-        if self.reg_dest == 0b1111 {
-            if let Some(reg) = self.spsr_map.get(self.op_mode as usize).unwrap() {
-                let spsr = self.reg[*reg as usize];
+        0
+    }
+
+    // TST, TEQ, CMP and CMN with Rd = r15 (the "P" forms of 26 bit code, eg. TEQP) restore the CPSR
+    // from the SPSR instead of setting the flags. the ARM ARM only says Rd should be zero for these,
+    // but the ARM7TDMI treats them like any other S bit data processing op writing r15, and mGBA
+    // emulates them the same way. without an SPSR (user and system mode) the flags are set as
+    // usual. returns true if the CPSR was restored
+    #[inline(always)]
+    fn compare_restores_cpsr(&mut self) -> bool {
+        if self.reg_dest != Register::R15 as u32 {
+            return false;
+        }
+        match self.spsr_map[self.op_mode as usize] {
+            Some(reg) => {
+                let spsr = self.reg[reg as usize];
                 self.set_cpsr(spsr);
+                true
             }
+            None => false,
         }
-        0
     }
 
     #[inline(always)]
@@ -845,7 +983,7 @@ impl Cpu {
                     let spsr = self.reg[*reg as usize];
                     self.set_cpsr(spsr);
                 } else {
-                    warn!("s bit should not be set");
+                    hot_warn!(target: TARGET_CPU, "s bit should not be set");
                 }
             }
         }
@@ -887,7 +1025,8 @@ impl Cpu {
             match self.spsr_map[self.op_mode as usize] {
                 Some(opmode) => opmode,
                 None => {
-                    warn!(
+                    hot_warn!(
+                        target: TARGET_CPU,
                         "msr called on R=1, but this mode has no SPSR {}",
                         self.instr
                     );
@@ -907,7 +1046,7 @@ impl Cpu {
 
         let mask = (self.instr >> 16) & 0b1111;
         if mask != 0b1001 && mask != 0b1000 {
-            warn!("MSR with invalid mask");
+            hot_warn!(target: TARGET_CPU, "MSR with invalid mask");
             //return 1;
         }
         //info!("  pc: {:#x}, instr: {:#034b}, mask: {:#06b}", self.actual_pc, self.instr, mask);
@@ -1016,7 +1155,7 @@ impl Cpu {
 
     // ---------- data transfers
     #[inline(always)]
-    fn execute_ldr_str(&mut self, bus: &mut Bus) -> u32 {
+    fn execute_ldr_str<M: MemoryInterface + ?Sized>(&mut self, bus: &mut M) -> u32 {
         //info!("{:#034b}", self.instr);
         //self.instr &= !(1 << 21);
         let mut cycles = 0;
@@ -1101,11 +1240,8 @@ impl Cpu {
             (true, false) => {
                 let mut res = bus.read_word(addr).rotate_right(rotate);
                 if reg == Register::R15 as u32 {
-                    res &= 0xfffffffc;
-                    self.actual_pc = res;
-                    // NOTE: may not be correct, maybe comment out
-                    self.pipeline_instr.clear();
-                    self.increment_pc = false;
+                    self.set_pc_aligned(res);
+                    res = self.actual_pc;
                     cycles += 2;
                 }
                 self.set_reg(reg, res);
@@ -1132,7 +1268,10 @@ impl Cpu {
     }
 
     #[inline(always)]
-    fn execute_halfword_signed_transfer(&mut self, bus: &mut Bus) -> u32 {
+    fn execute_halfword_signed_transfer<M: MemoryInterface + ?Sized>(
+        &mut self,
+        bus: &mut M,
+    ) -> u32 {
         let offset = if (self.instr >> 22) & 1 == 0 {
             self.read_reg(self.instr & 0b1111)
         } else {
@@ -1209,7 +1348,12 @@ impl Cpu {
                 self.set_reg(reg, res);
             }
             _ => {
-                warn!("Error undefined combination in execute_halfword_signed_transfer with instr {:#034b} at pc {}\n", self.instr, self.actual_pc);
+                hot_warn!(
+                    target: TARGET_CPU,
+                    "Error undefined combination in execute_halfword_signed_transfer with instr {:#034b} at pc {}\n",
+                    self.instr,
+                    self.actual_pc
+                );
             }
         };
 
@@ -1226,7 +1370,7 @@ impl Cpu {
     }
 
     #[inline(always)]
-    fn execute_block_data_transfer(&mut self, bus: &mut Bus) -> u32 {
+    fn execute_block_data_transfer<M: MemoryInterface + ?Sized>(&mut self, bus: &mut M) -> u32 {
         // base reg
         let base_reg = (self.instr >> 16) & 0b1111;
         let mut addr = self.read_reg(base_reg);
@@ -1283,6 +1427,13 @@ impl Cpu {
         //    self.set_reg(base_reg, offset_addr);
         //}
 
+        // S bit (sources disagree on the corner cases, this is the behaviour chosen here):
+        // - STM^ stores the user mode registers, with or without r15 in the list
+        // - LDM^ without r15 loads the user mode registers
+        // - LDM^ with r15 loads the current mode registers, then restores CPSR from SPSR once r15
+        //   (always the last register) is loaded. in modes without an SPSR, CPSR is unchanged
+        // - writeback is unpredictable with a user bank transfer; the base register of the current
+        //   mode is written back
         for i in 0..16 {
             if (1 << i) & reg_list > 0 {
                 let reg = self.reg_map[if S && (!r15_appear || !L) {
@@ -1293,11 +1444,14 @@ impl Cpu {
                 if L {
                     self.reg[reg as usize] = bus.read_word(addr + delt);
                     if i == 15 {
-                        self.reg[reg as usize] &= 0xfffffffc;
-                        // NOTE: may not be correct, maybe comment out
-                        self.actual_pc = self.reg[reg as usize];
-                        self.pipeline_instr.clear();
-                        self.increment_pc = false;
+                        if S {
+                            if let Some(spsr) = self.spsr_map[self.op_mode as usize] {
+                                self.set_cpsr(self.reg[spsr as usize]);
+                            }
+                        }
+                        // the restored CPSR decides the alignment of the new pc
+                        self.set_pc_aligned(self.reg[reg as usize]);
+                        self.reg[reg as usize] = self.actual_pc;
                     }
                 } else {
                     let mut res = self.reg[reg as usize];
@@ -1315,13 +1469,6 @@ impl Cpu {
             }
         }
 
-        if S && r15_appear && L {
-            // todo: check
-            if let Some(reg) = self.spsr_map[self.op_mode as usize] {
-                self.set_cpsr(self.reg[reg as usize]);
-            }
-        }
-
         if L {
             if r15_appear {
                 4 + cnt
@@ -1334,7 +1481,7 @@ impl Cpu {
     }
 
     #[inline(always)]
-    fn execute_swp(&mut self, bus: &mut Bus) -> u32 {
+    fn execute_swp<M: MemoryInterface + ?Sized>(&mut self, bus: &mut M) -> u32 {
         let B = (self.instr >> 22) & 1 == 1;
         self.reg_dest = (self.instr >> 12) & 0b1111;
         let res = self.read_reg(self.instr & 0b1111);
@@ -1377,9 +1524,11 @@ impl Cpu {
             }
             0b1110 => true,
             _ => {
-                warn!(
+                hot_warn!(
+                    target: TARGET_CPU,
                     "cond field not valid: instr: {:#034b}, pc: {:#x}",
-                    self.instr, self.actual_pc
+                    self.instr,
+                    self.actual_pc
                 );
                 false
             }
@@ -1579,24 +1728,36 @@ impl Cpu {
     }*/
 
     #[inline(always)]
-    fn fetch_thumb_instr(&mut self, bus: &mut Bus) {
+    fn fetch_thumb_instr<M: MemoryInterface + ?Sized>(&mut self, bus: &mut M) {
         if self.pipeline_instr.is_empty() {
-            let data = bus.read_halfword(self.actual_pc as usize) as u32;
-            self.pipeline_instr.push_back(data + (data << 16));
-            let data = bus.read_halfword(self.actual_pc as usize + 2) as u32;
-            self.pipeline_instr.push_back(data + (data << 16));
+            for offset in [0, 2] {
+                let addr = self.actual_pc as usize + offset;
+                let data = bus.read_halfword(addr) as u32;
+                self.pipeline_instr.push_back(data + (data << 16));
+                self.fetch_waits += bus.opcode_fetch_waits(addr, 2);
+                self.update_bus_view(bus);
+            }
         }
-        let data = bus.read_halfword(self.actual_pc as usize + 4) as u32;
+        let addr = self.actual_pc as usize + 4;
+        let data = bus.read_halfword(addr) as u32;
         self.pipeline_instr.push_back(data + (data << 16));
+        self.fetch_waits += bus.opcode_fetch_waits(addr, 2);
         self.instr = self.pipeline_instr.pop_front().unwrap() as u16 as u32;
+        // the BIOS bus is 32 bits wide, so the latch holds the whole word around the prefetched
+        // halfword, not the halfword mirrored like the pipeline entry
         if self.actual_pc < 0x4000 {
             self.last_fetched_bios_instr =
-                bus.read_word_raw(self.actual_pc as usize + 4, MemoryRegion::Bios) as u32;
+                bus.read_bios_word((self.actual_pc as usize + 4) & !0b11);
         }
+        // for the data accesses of the instruction
+        self.update_bus_view(bus);
     }
 
     #[inline(always)]
-    fn decode_execute_instruction_thumb(&mut self, bus: &mut Bus) -> u32 {
+    fn decode_execute_instruction_thumb<M: MemoryInterface + ?Sized>(
+        &mut self,
+        bus: &mut M,
+    ) -> u32 {
         // get rid of the trailing bits, these may be set to 1 but must always be treated as 0
         self.actual_pc &= !0b01;
         self.fetch_thumb_instr(bus);
@@ -1743,17 +1904,19 @@ impl Cpu {
     }
 
     #[inline(always)]
-    fn execute_thumb_undefined_instr(&mut self, _: &mut Bus) -> u32 {
-        print!(
+    fn execute_thumb_undefined_instr<M: MemoryInterface + ?Sized>(&mut self, _: &mut M) -> u32 {
+        hot_warn!(
+            target: TARGET_CPU,
             "Error undefined instruction {:#034b} at pc {}",
-            self.instr, self.actual_pc
+            self.instr,
+            self.actual_pc
         );
         0
     }
 
     // ---------- move shifted register
     #[inline(always)]
-    fn execute_thumb_lsl_lsr_asr_imm5(&mut self, _: &mut Bus) -> u32 {
+    fn execute_thumb_lsl_lsr_asr_imm5<M: MemoryInterface + ?Sized>(&mut self, _: &mut M) -> u32 {
         #[cfg(feature = "debug_instr")]
         self.debug("        thumb LSL LSR ASR imm5");
         self.reg_dest = self.instr & 0b111;
@@ -1861,7 +2024,7 @@ impl Cpu {
 
     // ---------- add, sub- imm3
     #[inline(always)]
-    fn execute_thumb_add_sub_imm3(&mut self, _: &mut Bus) -> u32 {
+    fn execute_thumb_add_sub_imm3<M: MemoryInterface + ?Sized>(&mut self, _: &mut M) -> u32 {
         #[cfg(feature = "debug_instr")]
         self.debug("        thumb ADD SUB");
         self.reg_dest = self.instr & 0b111;
@@ -1885,7 +2048,10 @@ impl Cpu {
 
     // ---------- mov, cmp, add, sub- imm8
     #[inline(always)]
-    fn execute_thumb_mov_cmp_add_sub_imm8(&mut self, _: &mut Bus) -> u32 {
+    fn execute_thumb_mov_cmp_add_sub_imm8<M: MemoryInterface + ?Sized>(
+        &mut self,
+        _: &mut M,
+    ) -> u32 {
         #[cfg(feature = "debug_instr")]
         self.debug("        thumb MOV CMP ADD SUB imm8");
         self.operand2 = self.instr & 0b11111111;
@@ -1905,7 +2071,7 @@ impl Cpu {
     }
 
     #[inline(always)]
-    fn execute_thumb_alu_general(&mut self, _: &mut Bus) -> u32 {
+    fn execute_thumb_alu_general<M: MemoryInterface + ?Sized>(&mut self, _: &mut M) -> u32 {
         #[cfg(feature = "debug_instr")]
         self.debug("        thumb ALU general");
         self.operand2 = self.read_reg((self.instr >> 3) & 0b111);
@@ -2001,7 +2167,7 @@ impl Cpu {
     }
 
     #[inline(always)]
-    fn execute_thumb_hi_bx(&mut self, _: &mut Bus) -> u32 {
+    fn execute_thumb_hi_bx<M: MemoryInterface + ?Sized>(&mut self, _: &mut M) -> u32 {
         #[cfg(feature = "debug_instr")]
         self.debug("        thumb Hi reg operations or BX");
         self.reg_dest = self.instr & 0b111;
@@ -2026,14 +2192,7 @@ impl Cpu {
                 self.op_mov()
             }
             0b11 => {
-                if self.operand2 & 1 == 0 {
-                    self.set_flag(Flag::T, false);
-                    //self.instr_set = InstructionSet::Arm;
-                }
-                self.actual_pc = (self.operand2 >> 1) << 1;
-                //print!(" bx from thumb");
-                self.pipeline_instr.clear();
-                self.increment_pc = false;
+                self.set_pc_interworking(self.operand2);
                 3
             }
             _ => 0,
@@ -2047,7 +2206,7 @@ impl Cpu {
     }
 
     #[inline(always)]
-    fn execute_thumb_pc_relative_load(&mut self, bus: &mut Bus) -> u32 {
+    fn execute_thumb_pc_relative_load<M: MemoryInterface + ?Sized>(&mut self, bus: &mut M) -> u32 {
         #[cfg(feature = "debug_instr")]
         self.debug("        thumb pc relative load");
         let offset = (self.instr & 0b11111111) << 2;
@@ -2060,7 +2219,10 @@ impl Cpu {
     }
 
     #[inline(always)]
-    fn execute_thumb_load_store_reg_offset(&mut self, bus: &mut Bus) -> u32 {
+    fn execute_thumb_load_store_reg_offset<M: MemoryInterface + ?Sized>(
+        &mut self,
+        bus: &mut M,
+    ) -> u32 {
         #[cfg(feature = "debug_instr")]
         self.debug("        thumb load/store reg offset");
         let L = (self.instr >> 11) & 1 > 0;
@@ -2102,7 +2264,7 @@ impl Cpu {
     }
 
     #[inline(always)]
-    fn execute_thumb_load_store_signed(&mut self, bus: &mut Bus) -> u32 {
+    fn execute_thumb_load_store_signed<M: MemoryInterface + ?Sized>(&mut self, bus: &mut M) -> u32 {
         #[cfg(feature = "debug_instr")]
         self.debug("        thumb load/store reg signed byte/halfword");
         let H = (self.instr >> 11) & 1 > 0;
@@ -2158,7 +2320,7 @@ impl Cpu {
     }
 
     #[inline(always)]
-    fn execute_thumb_load_store_imm5(&mut self, bus: &mut Bus) -> u32 {
+    fn execute_thumb_load_store_imm5<M: MemoryInterface + ?Sized>(&mut self, bus: &mut M) -> u32 {
         #[cfg(feature = "debug_instr")]
         self.debug("        thumb load/store reg imm5");
         let B = (self.instr >> 12) & 1 > 0;
@@ -2205,7 +2367,10 @@ impl Cpu {
     }
 
     #[inline(always)]
-    fn execute_thumb_load_store_halfword_imm5(&mut self, bus: &mut Bus) -> u32 {
+    fn execute_thumb_load_store_halfword_imm5<M: MemoryInterface + ?Sized>(
+        &mut self,
+        bus: &mut M,
+    ) -> u32 {
         #[cfg(feature = "debug_instr")]
         self.debug("        thumb load/store halfword imm5");
         self.reg_dest = self.instr & 0b111;
@@ -2237,7 +2402,7 @@ impl Cpu {
     // R13 will be used here. May need to be modified.
     // STR, LDR
     #[inline(always)]
-    fn execute_thumb_load_store_sp(&mut self, bus: &mut Bus) -> u32 {
+    fn execute_thumb_load_store_sp<M: MemoryInterface + ?Sized>(&mut self, bus: &mut M) -> u32 {
         #[cfg(feature = "debug_instr")]
         self.debug("        thumb load/store word sp offset");
         let L = (self.instr >> 11) & 1 > 0;
@@ -2263,7 +2428,7 @@ impl Cpu {
     }
 
     #[inline(always)]
-    fn execute_thumb_load_address(&mut self, _: &mut Bus) -> u32 {
+    fn execute_thumb_load_address<M: MemoryInterface + ?Sized>(&mut self, _: &mut M) -> u32 {
         #[cfg(feature = "debug_instr")]
         self.debug("        thumb load address sp/pc");
         let SP = (self.instr >> 11) & 1 > 0;
@@ -2280,7 +2445,7 @@ impl Cpu {
     }
 
     #[inline(always)]
-    fn execute_thumb_sp_offset(&mut self, _: &mut Bus) -> u32 {
+    fn execute_thumb_sp_offset<M: MemoryInterface + ?Sized>(&mut self, _: &mut M) -> u32 {
         #[cfg(feature = "debug_instr")]
         self.debug("        thumb sp offset");
         let offset = Wrapping((self.instr & 0b1111111) << 2);
@@ -2296,12 +2461,15 @@ impl Cpu {
     }
 
     #[inline(always)]
-    fn execute_thumb_push_pop(&mut self, bus: &mut Bus) -> u32 {
+    fn execute_thumb_push_pop<M: MemoryInterface + ?Sized>(&mut self, bus: &mut M) -> u32 {
         #[cfg(feature = "debug_instr")]
         self.debug("        thumb push/pop");
         let L = (self.instr >> 11) & 1 > 0;
         let R = (self.instr >> 8) & 1 > 0;
         let reg_list = self.instr & 0b11111111;
+        if reg_list == 0 && !R {
+            return self.execute_thumb_push_pop_empty(bus, L);
+        }
         let mut cnt = R as u32;
         for i in 0..8 {
             if reg_list & (1 << i) > 0 {
@@ -2327,14 +2495,13 @@ impl Cpu {
             }
         }
         if R {
+            // popping into pc never changes state on ARMv4, bit 0 is just dropped
             if L {
-                let res = bus.read_word(addr);
-                self.actual_pc = res & 0xfffffffe;
-                self.pipeline_instr.clear();
-                self.increment_pc = false;
+                let res = bus.read_word(addr & !0b11);
+                self.set_pc_aligned(res);
             } else {
                 let res = self.read_reg(14);
-                bus.store_word(addr, res);
+                bus.store_word(addr & !0b11, res);
             }
             addr += 4;
         }
@@ -2352,8 +2519,32 @@ impl Cpu {
         }
     }
 
+    // ARMv4 quirk: with an empty list, push/pop transfer pc instead, but move sp as if all 16
+    // registers were transferred. a pushed pc is the address of the instruction + 6
+    fn execute_thumb_push_pop_empty<M: MemoryInterface + ?Sized>(
+        &mut self,
+        bus: &mut M,
+        L: bool,
+    ) -> u32 {
+        let sp = self.read_reg(13);
+        if L {
+            let res = bus.read_word(sp as usize & !0b11);
+            self.set_pc_aligned(res);
+            self.set_reg(13, sp.wrapping_add(0x40));
+            3
+        } else {
+            let sp = sp.wrapping_sub(0x40);
+            bus.store_word(sp as usize & !0b11, self.read_reg(15) + 2);
+            self.set_reg(13, sp);
+            2
+        }
+    }
+
     #[inline(always)]
-    fn execute_thumb_load_store_multiple(&mut self, bus: &mut Bus) -> u32 {
+    fn execute_thumb_load_store_multiple<M: MemoryInterface + ?Sized>(
+        &mut self,
+        bus: &mut M,
+    ) -> u32 {
         #[cfg(feature = "debug_instr")]
         self.debug("        thumb multiple load/store");
         let reg_list = self.instr & 0b11111111;
@@ -2366,11 +2557,11 @@ impl Cpu {
         for i in 0..8 {
             if reg_list & (1 << i) > 0 {
                 num_reg += 1;
-                assert!(i != base_reg);
+                hot_assert!(i != base_reg);
             }
         }
         if num_reg == 0 {
-            warn!("critical error: num_reg == 0");
+            hot_warn!(target: TARGET_CPU, "critical error: num_reg == 0");
             return 1;
         }
         //assert!(num_reg > 0);
@@ -2401,7 +2592,7 @@ impl Cpu {
     }
 
     #[inline(always)]
-    fn execute_thumb_cond_branch(&mut self, _: &mut Bus) -> u32 {
+    fn execute_thumb_cond_branch<M: MemoryInterface + ?Sized>(&mut self, _: &mut M) -> u32 {
         #[cfg(feature = "debug_instr")]
         self.debug("        thumb cond branch");
         if self.check_cond((self.instr >> 8) & 0b1111) {
@@ -2411,7 +2602,9 @@ impl Cpu {
                 offset |= (!0) << 9;
             }
             let res = Wrapping(self.actual_pc + 4) + Wrapping(offset);
+            let branch_pc = self.actual_pc;
             self.actual_pc = res.0;
+            self.check_self_branch(branch_pc);
             self.pipeline_instr.clear();
             self.increment_pc = false;
             3
@@ -2421,7 +2614,7 @@ impl Cpu {
     }
 
     #[inline(always)]
-    fn execute_thumb_uncond_branch(&mut self, _: &mut Bus) -> u32 {
+    fn execute_thumb_uncond_branch<M: MemoryInterface + ?Sized>(&mut self, _: &mut M) -> u32 {
         #[cfg(feature = "debug_instr")]
         self.debug("        thumb uncond branch");
         let mut offset = (self.instr & 0b11111111111) << 1;
@@ -2430,7 +2623,9 @@ impl Cpu {
             //print!(" offset: {:#x}, !0: {:#x}", offset, !0);
         }
         let res = Wrapping(self.reg[Register::R15 as usize]) + Wrapping(offset);
+        let branch_pc = self.actual_pc;
         self.actual_pc = res.0;
+        self.check_self_branch(branch_pc);
         //print!(" actual_pc: {:#x}", self.actual_pc);
         self.pipeline_instr.clear();
         self.increment_pc = false;
@@ -2438,7 +2633,7 @@ impl Cpu {
     }
 
     #[inline(always)]
-    fn execute_thumb_uncond_branch_link(&mut self, _: &mut Bus) -> u32 {
+    fn execute_thumb_uncond_branch_link<M: MemoryInterface + ?Sized>(&mut self, _: &mut M) -> u32 {
         #[cfg(feature = "debug_instr")]
         self.debug("        thumb long branch and link");
         let H = (self.instr >> 11) & 1 > 0;
@@ -2454,10 +2649,12 @@ impl Cpu {
                 self.set_reg(14, offset.0);
             }
             true => {
+                // LR normally holds PC + (upper offset) from the first half, but it may have been
+                // changed in between (or the second half executed on its own), so bit 0 must be ignored
                 let offset = Wrapping(self.read_reg(14)) + Wrapping(offset << 1);
                 //print!(" value placed into R15: {:#010x}", offset);
                 self.set_reg(14, (self.actual_pc + 2) | 1);
-                self.actual_pc = offset.0;
+                self.actual_pc = offset.0 & !1;
                 self.pipeline_instr.clear();
                 self.increment_pc = false;
             }
@@ -2467,24 +2664,31 @@ impl Cpu {
     }
 
     #[inline(always)]
-    fn execute_thumb_software_interrupt(&mut self, _: &mut Bus) -> u32 {
+    fn execute_thumb_software_interrupt<M: MemoryInterface + ?Sized>(&mut self, _: &mut M) -> u32 {
         #[cfg(feature = "debug_instr")]
         self.debug("        thumb SWI");
         self.execute_software_interrupt()
     }
 
-    // ---------- interrupts and halting
+    // `B .` can only be left through an interrupt, and with the I flag set not even that
     #[inline(always)]
-    pub fn halt(&mut self) {
-        self.halt = true;
+    fn check_self_branch(&mut self, branch_pc: u32) {
+        self.idle_loop = self.actual_pc == branch_pc;
+        if self.idle_loop && self.read_flag(Flag::I) {
+            self.self_branch_detected = true;
+        }
     }
 
+    // true if the cpu is halted or spinning in an idle loop, ie. it is only waiting for an interrupt
     #[inline(always)]
-    pub fn check_interrupt(&self, bus: &Bus) -> bool {
-        //!self.read_flag(Flag::I) && // check that interrupt flag is turned off (on means interrupts are disabled)
-        bus.read_byte_raw(0x208, MemoryRegion::IO) & 1 == 1 && // check that IME interrupt is turned on
-        bus.read_halfword_raw(0x202, MemoryRegion::IO) & bus.read_halfword_raw(0x200, MemoryRegion::IO) > 0
-        // check that an interrupt for an active interrupt type has been requested
+    pub fn is_idle(&self) -> bool {
+        self.halt || (self.idle_skip && self.idle_loop)
+    }
+
+    // ---------- interrupts and halting
+    #[inline(always)]
+    pub fn halt(&mut self) {
+        self.halt = true;
     }
 
     // Mode: SVC (supervisor) for software interrupt
@@ -2492,6 +2696,7 @@ impl Cpu {
     #[inline(always)]
     fn execute_hardware_interrupt(&mut self) -> u32 {
         //info!("hardware interrupt");
+        self.idle_loop = false;
         self.reg[Register::R14_irq as usize] = self.actual_pc + 4;
         let mut cpsr = self.reg[Register::Cpsr as usize];
         self.reg[Register::SPSR_irq as usize] = cpsr;
@@ -2544,47 +2749,15 @@ impl Cpu {
 
     // ---------- DMA
     #[inline(always)]
-    pub fn check_dma(&mut self, bus: &Bus) -> bool {
+    pub fn check_dma<M: MemoryInterface + ?Sized>(&mut self, bus: &M) -> bool {
         self.dma_check_counter += 1;
         (self.halt || (self.dma_check_counter & (config::DMA_CHECK_INTERVAL_CLOCKS - 1) == 0))
-            && bus.is_any_dma_active
-            && bus.dma_channels.iter().any(|x| x.check_is_active(bus))
-    }
-
-    #[inline(always)]
-    pub fn execute_dma(&mut self, bus: &mut Bus) -> u32 {
-        let mut res = 0;
-        let mut ex1 = false;
-        //info!("dma start");
-        for i in 0..4 {
-            if !bus.dma_channels[i].check_is_active(bus) {
-                continue;
-            }
-            // unsafe in order to prevent unnecessary cloning
-            unsafe {
-                let ptr = &mut bus.dma_channels[i] as *mut DMA_Channel;
-                res += (*ptr).execute_dma(bus);
-            }
-            ex1 = true;
-            // safe code here:
-            /*
-            let mut dma_channel = bus.dma_channels[i].clone();
-            res += dma_channel.execute_dma(bus);
-            bus.dma_channels[i] = dma_channel
-            */
-        }
-        //info!("dma end");
-        assert!(ex1);
-        bus.hblank_dma = false;
-        bus.vblank_dma = false;
-        bus.set_is_any_dma_active();
-        //info!("dma executed");
-        res
+            && bus.dma_pending()
     }
 
     // ---------- misc
     #[cfg(feature = "debug_instr")]
-    pub fn print_pc(&mut self, bus: &mut Bus) {
+    pub fn print_pc<M: MemoryInterface + ?Sized>(&mut self, bus: &mut M) {
         {
             if self.debug_cnt == 0 || !self.bios_end {
                 //info!("PC: {:#010x}\n  instr: {:#034b}", self.actual_pc, self.instr);
@@ -2594,21 +2767,26 @@ impl Cpu {
             self.debug_cnt -= 1;
             if self.read_flag(Flag::T) {
                 info!(
+                    target: TARGET_CPU,
                     "Executing instruction at pc {:#010x}\n   instr: {:#018b} ",
-                    self.actual_pc, self.instr
+                    self.actual_pc,
+                    self.instr
                 );
             } else {
                 info!(
+                    target: TARGET_CPU,
                     "Executing instruction at pc {:#010x}\n   instr: {:#034b} ",
-                    self.actual_pc, self.instr
+                    self.actual_pc,
+                    self.instr
                 );
             }
-            info!("    ");
+            info!(target: TARGET_CPU, "    ");
             for i in 0..16 {
                 print!("R{}: {:x}, ", i, self.read_reg(i));
             }
-            info!();
+            info!(target: TARGET_CPU, "");
             info!(
+                target: TARGET_CPU,
                 "N: {}, Z: {}, C: {}, V: {}, CPSR: {:#034b}, IE: {:#018b}, IF: {:#018b}, IME: {}",
                 self.read_flag(Flag::N),
                 self.read_flag(Flag::Z),
@@ -2620,11 +2798,12 @@ impl Cpu {
                 bus.read_byte(0x4000208) & 1
             );
             info!(
+                target: TARGET_CPU,
                 " win_in: {:#018b}, win_out: {:#018b}",
                 bus.read_halfword(0x04000048),
                 bus.read_halfword(0x0400004a)
             );
-            info!();
+            info!(target: TARGET_CPU, "");
         }
     }
 
@@ -2650,6 +2829,27 @@ impl Cpu {
         self.reg[Register::R15 as usize] = pc;
     }
 
+    // branch from BX: bit 0 of `value` selects the instruction set, 1 for thumb and 0 for arm. on
+    // ARMv4T (the ARM7TDMI), BX is the only interworking branch, see set_pc_aligned
+    #[inline(always)]
+    fn set_pc_interworking(&mut self, value: u32) {
+        self.set_flag(Flag::T, value & 1 > 0);
+        self.set_pc_aligned(value);
+    }
+
+    // branch that keeps the instruction set: loads into the pc (LDR, LDM, POP). ARMv5 would switch
+    // to thumb on bit 0 here, ARMv4T ignores it. `value` is aligned to the instruction size
+    #[inline(always)]
+    fn set_pc_aligned(&mut self, value: u32) {
+        self.actual_pc = if self.read_flag(Flag::T) {
+            value & !1
+        } else {
+            value & !0b11
+        };
+        self.pipeline_instr.clear();
+        self.increment_pc = false;
+    }
+
     /*fn read_sp(&self) -> u32 {
         self.reg[Register::R14 as usize]
     }
@@ -2698,12 +2898,301 @@ impl Cpu {
             0b11011 => OperatingMode::Und,
             0b11111 => OperatingMode::Sys,
             _ => {
-                warn!(
+                hot_warn!(
+                    target: TARGET_CPU,
                     "invalid op mode: {}, instr: {:#034b}, pc: {:#x}",
-                    val, self.instr, self.actual_pc
+                    val,
+                    self.instr,
+                    self.actual_pc
                 );
                 OperatingMode::Sys
             }
         };
     }
+
+    pub fn write_snapshot(&self, w: &mut SnapshotWriter) {
+        self.reg.iter().for_each(|&x| w.u32(x));
+        w.u32(self.instr);
+        w.u32(self.shifter_carry);
+        w.u32(self.operand1);
+        w.u32(self.operand2);
+        w.u32(self.reg_dest);
+        w.u32(self.actual_pc);
+        w.usize(self.pipeline_instr.len());
+        self.pipeline_instr.iter().for_each(|&x| w.u32(x));
+        w.u8(self.op_mode as u8);
+        w.bool(self.increment_pc);
+        w.bool(self.thumb_modify_flags);
+        w.bool(self.halt);
+        w.u32(self.last_fetched_bios_instr);
+        w.u32(self.dma_check_counter);
+        w.bool(self.self_branch_detected);
+        w.bool(self.idle_loop);
+    }
+
+    pub fn read_snapshot(&mut self, r: &mut SnapshotReader) -> Result<(), &'static str> {
+        for x in self.reg.iter_mut() {
+            *x = r.u32()?;
+        }
+        self.instr = r.u32()?;
+        self.shifter_carry = r.u32()?;
+        self.operand1 = r.u32()?;
+        self.operand2 = r.u32()?;
+        self.reg_dest = r.u32()?;
+        self.actual_pc = r.u32()?;
+        let pipeline_len = r.usize()?;
+        if pipeline_len > 3 {
+            return Err("invalid cpu pipeline in snapshot");
+        }
+        self.pipeline_instr.clear();
+        for _ in 0..pipeline_len {
+            self.pipeline_instr.push_back(r.u32()?);
+        }
+        self.op_mode = match r.u8()? {
+            0 => OperatingMode::Usr,
+            1 => OperatingMode::Fiq,
+            2 => OperatingMode::Irq,
+            3 => OperatingMode::Svc,
+            4 => OperatingMode::Abt,
+            5 => OperatingMode::Sys,
+            6 => OperatingMode::Und,
+            _ => return Err("invalid cpu mode in snapshot"),
+        };
+        self.increment_pc = r.bool()?;
+        self.thumb_modify_flags = r.bool()?;
+        self.halt = r.bool()?;
+        self.last_fetched_bios_instr = r.u32()?;
+        self.dma_check_counter = r.u32()?;
+        self.self_branch_detected = r.bool()?;
+        self.idle_loop = r.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    const USR: u32 = 0x10;
+    const IRQ: u32 = 0x12;
+    const T: u32 = 1 << 5;
+
+    // executes `steps` instructions, starting at r[15]
+    fn run(state: CpuState, mem: &mut TestMemory, steps: usize) -> CpuState {
+        (0..steps).fold(state, |state, _| {
+            Cpu::execute_single_for_test(&state, mem).0
+        })
+    }
+
+    fn thumb_state(pc: u32, regs: &[(usize, u32)]) -> CpuState {
+        let mut state = CpuState {
+            cpsr: USR | T,
+            ..Default::default()
+        };
+        regs.iter().for_each(|&(i, val)| state.r[i] = val);
+        state.r[15] = pc;
+        state
+    }
+
+    // the two halves of a thumb BL with the given offset from the address of the first half + 4
+    fn thumb_bl(offset: i32) -> [u32; 2] {
+        let offset = offset as u32;
+        [
+            0xf000 | ((offset >> 12) & 0x7ff),
+            0xf800 | ((offset >> 1) & 0x7ff),
+        ]
+    }
+
+    fn thumb_mem(pc: u32, instrs: &[u32]) -> TestMemory {
+        let mut mem = TestMemory::with_bytes(&[]);
+        for (i, &instr) in instrs.iter().enumerate() {
+            mem.store_instr(pc + i as u32 * 2, instr, true);
+        }
+        mem
+    }
+
+    #[test]
+    fn thumb_bl_reaches_both_ends_of_its_range() {
+        let pc = 0x8100000;
+        for (offset, target) in [
+            (0x3ffffe, 0x8500002),
+            (-0x400000, 0x7d00004),
+            (0, 0x8100004),
+            (-4, 0x8100000),
+        ] {
+            let mut mem = thumb_mem(pc, &thumb_bl(offset));
+            let state = run(thumb_state(pc, &[]), &mut mem, 2);
+            assert_eq!(state.r[15], target, "offset {:#x}", offset);
+            // returns to the instruction after the second half, in thumb state
+            assert_eq!(state.r[14], (pc + 4) | 1);
+            assert_eq!(state.cpsr & T, T);
+        }
+    }
+
+    #[test]
+    fn thumb_bl_second_half_adds_to_lr() {
+        let pc = 0x8000100;
+        // LR was changed between the halves, its bit 0 is ignored
+        let mut mem = thumb_mem(pc, &[0xf810]);
+        let state = run(thumb_state(pc, &[(14, 0x8001001)]), &mut mem, 1);
+        assert_eq!(state.r[15], 0x8001020);
+        assert_eq!(state.r[14], (pc + 2) | 1);
+    }
+
+    // a register (r0-r15 or cpsr) or a memory word, and its value
+    fn parse_vector_field(field: &str) -> (&str, u32) {
+        let (name, val) = field.split_once('=').expect("field is not name=value");
+        (
+            name,
+            u32::from_str_radix(val, 16).expect("value is not hex"),
+        )
+    }
+
+    fn reg_index(name: &str) -> Option<usize> {
+        name.strip_prefix('r')
+            .map(|i| i.parse().expect("invalid register"))
+    }
+
+    #[test]
+    fn runs_vector_file() {
+        let vectors = include_str!("../testdata/cpu_vectors.txt");
+        let mut count = 0;
+        for line in vectors.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (inputs, outputs) = line.split_once("->").expect("vector has no outputs");
+            let mut inputs = inputs.split_whitespace();
+            let thumb = match inputs.next() {
+                Some("thumb") => true,
+                Some("arm") => false,
+                _ => panic!("vector must start with arm or thumb: {}", line),
+            };
+            let instr = u32::from_str_radix(inputs.next().unwrap(), 16).unwrap();
+
+            let mut state = CpuState {
+                cpsr: if thumb { USR | T } else { USR },
+                ..Default::default()
+            };
+            state.r[15] = 0x100;
+            let mut mem_writes = Vec::new();
+            for (name, val) in inputs.map(parse_vector_field) {
+                match (name, reg_index(name)) {
+                    ("cpsr", _) => state.cpsr = val,
+                    (_, Some(i)) => state.r[i] = val,
+                    _ => {
+                        let addr = u32::from_str_radix(&name[1..], 16).unwrap();
+                        mem_writes.extend((0..4).map(|i| (addr + i, (val >> (i * 8)) as u8)));
+                    }
+                }
+            }
+            let mut mem = TestMemory::with_bytes(&mem_writes);
+            mem.store_instr(state.r[15], instr, thumb);
+
+            let mut expected = state;
+            expected.r[15] += if thumb { 2 } else { 4 };
+            let (res, _) = Cpu::execute_single_for_test(&state, &mut mem);
+            for (name, val) in outputs.split_whitespace().map(parse_vector_field) {
+                match (name, reg_index(name)) {
+                    ("cpsr", _) => expected.cpsr = val,
+                    (_, Some(i)) => expected.r[i] = val,
+                    _ => {
+                        let addr = u32::from_str_radix(&name[1..], 16).unwrap();
+                        assert_eq!(mem.read_word(addr as usize), val, "{}", line);
+                    }
+                }
+            }
+            assert_eq!(res, expected, "{}", line);
+            count += 1;
+        }
+        assert!(count > 0);
+    }
+
+    // IRQ mode with distinct user and IRQ r13/r14, returning to thumb code, with r0 pointing at IWRAM
+    fn irq_state() -> CpuState {
+        let mut state = CpuState {
+            cpsr: IRQ,
+            r_irq: [0x113, 0x114],
+            ..Default::default()
+        };
+        state.spsr[3] = USR | T;
+        state.r[0] = 0x3000000;
+        state.r[13] = 0x13;
+        state.r[14] = 0x14;
+        state.r[15] = 0x100;
+        state
+    }
+
+    fn arm_mem(pc: u32, instr: u32, words: &[(u32, u32)]) -> TestMemory {
+        let bytes: Vec<_> = words
+            .iter()
+            .flat_map(|&(addr, val)| (0..4).map(move |i| (addr + i, (val >> (i * 8)) as u8)))
+            .collect();
+        let mut mem = TestMemory::with_bytes(&bytes);
+        mem.store_instr(pc, instr, false);
+        mem
+    }
+
+    #[test]
+    fn stm_with_s_bit_stores_user_registers() {
+        let state = irq_state();
+        let mut expected = state;
+        expected.r[15] += 4;
+
+        let mut mem = arm_mem(0x100, 0xe8c06000, &[]); // stmia r0, {r13, r14}^
+        assert_eq!(run(state, &mut mem, 1), expected);
+        assert_eq!(mem.read_word(0x3000000), 0x13);
+        assert_eq!(mem.read_word(0x3000004), 0x14);
+
+        // r15 is stored as the address of the instruction + 12
+        let mut mem = arm_mem(0x100, 0xe8c0c000, &[]); // stmia r0, {r14, r15}^
+        assert_eq!(run(state, &mut mem, 1), expected);
+        assert_eq!(mem.read_word(0x3000000), 0x14);
+        assert_eq!(mem.read_word(0x3000004), 0x10c);
+    }
+
+    #[test]
+    fn ldm_with_s_bit_loads_user_registers_or_restores_cpsr() {
+        let state = irq_state();
+        let words = [(0x3000000, 0xaa), (0x3000004, 0x202)];
+
+        // without r15, the user registers are loaded and the mode is kept
+        let mut mem = arm_mem(0x100, 0xe8d06000, &words); // ldmia r0, {r13, r14}^
+        let mut expected = state;
+        expected.r[13] = 0xaa;
+        expected.r[14] = 0x202;
+        expected.r[15] += 4;
+        assert_eq!(run(state, &mut mem, 1), expected);
+
+        // with r15, the IRQ registers are loaded and CPSR is restored from SPSR. the restored
+        // thumb state keeps bit 1 of the new pc
+        let mut mem = arm_mem(0x100, 0xe8d0c000, &words); // ldmia r0, {r14, r15}^
+        let mut expected = state;
+        expected.r_irq[1] = 0xaa;
+        expected.cpsr = USR | T;
+        expected.r[15] = 0x202;
+        assert_eq!(run(state, &mut mem, 1), expected);
+    }
+
+    #[test]
+    fn compare_with_rd_r15_restores_cpsr_from_spsr() {
+        // r0 == r1, so a comparison setting the flags would show up as Z
+        let mut state = irq_state();
+        state.spsr[3] = USR;
+        state.r[1] = 0x3000000;
+        let mut expected = state;
+        expected.cpsr = USR;
+        expected.r[15] += 4;
+        for instr in [
+            0xe110f001, // tstp r0, r1
+            0xe130f001, // teqp r0, r1
+            0xe150f001, // cmpp r0, r1
+            0xe170f001, // cmnp r0, r1
+        ] {
+            let mut mem = arm_mem(0x100, instr, &[]);
+            assert_eq!(run(state, &mut mem, 1), expected, "{:#x}", instr);
+        }
+    }
 }
@@ -1,13 +1,33 @@
 #![allow(non_camel_case_types)]
 
 use crate::bus::{Bus, CartridgeType, ChunkSize, MemoryRegion};
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::trace::TARGET_DMA;
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum TimingMode {
     Immediate,
     VBlank,
     HBlank,
     FIFO,
+    // DMA3 special timing: one transfer per scanline from line 2 to 161
+    VideoCapture,
+}
+
+// read only snapshot of a dma channel, for debuggers. see GBA::dma_channels
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DmaInfo {
+    // current addresses and remaining number of transfers of the channel. these are latched from
+    // the registers when the channel is enabled, and advanced as the channel transfers
+    pub src_addr: u32,
+    pub dest_addr: u32,
+    pub num_transfers: u32,
+    // DMAxCNT_H as last written by the game
+    pub control: u16,
+    pub timing_mode: TimingMode,
+    pub chunk_size: ChunkSize,
+    pub is_repeating: bool,
+    pub is_enabled: bool,
 }
 
 #[derive(Clone)]
@@ -17,7 +37,8 @@ pub struct DMA_Channel {
     pub dest_addr: u32,
     src_increment: u32,  // -1, 0, 1.
     dest_increment: u32, // -1, 0, 1.
-    num_transfers: u16,
+    // a count of 0 in the register means the maximum, which doesn't fit in 16 bits for DMA3
+    num_transfers: u32,
     chunk_size: ChunkSize,
     pub timing_mode: TimingMode,
     raise_interrupt: bool,
@@ -48,18 +69,20 @@ impl DMA_Channel {
         let src_addr = bus.read_word_raw(0xb0 + 12 * channel_no, MemoryRegion::IO);
         let dest_addr = bus.read_word_raw(0xb4 + 12 * channel_no, MemoryRegion::IO);
         let dma_cnt = bus.read_word_raw(0xb8 + 12 * channel_no, MemoryRegion::IO);
-        let mut num_transfers = dma_cnt as u16;
+        let mut num_transfers = dma_cnt & 0xffff;
+        let is_fifo_dest = dest_addr == 0x040000a0 || dest_addr == 0x040000a4;
         let timing_mode = match (dma_cnt >> 0x1c) & 0b11 {
             0b00 => TimingMode::Immediate,
             0b01 => TimingMode::VBlank,
             0b10 => TimingMode::HBlank,
+            0b11 if channel_no == 3 => TimingMode::VideoCapture,
             0b11 => {
                 // turn dma channel off
                 //is_enabled = false;
                 //let mut dma_cnt_upper = bus.read_byte_raw(0x040000bb + 12 * channel_no);
                 //dma_cnt_upper &= !(1 << 7);
                 //bus.store_byte_raw(0x040000bb + 12 * channel_no, dma_cnt_upper);
-                assert!(dest_addr == 0x040000a0 || dest_addr == 0x040000a4);
+                hot_assert!(is_fifo_dest);
                 //println!("dma fifo addr: {:#x}", src_addr)
                 num_transfers = 4;
                 TimingMode::FIFO
@@ -85,7 +108,26 @@ impl DMA_Channel {
             is_repeating: false,
 
             repeat_reset_dest: false,
-            is_enabled: true,
+            // a sound FIFO transfer to anywhere but a FIFO is not run
+            is_enabled: timing_mode != TimingMode::FIFO || is_fifo_dest,
+        }
+    }
+
+    pub fn info(&self, bus: &Bus) -> DmaInfo {
+        let control = bus.read_halfword_raw(0xba + 12 * self.channel_no, MemoryRegion::IO);
+        DmaInfo {
+            src_addr: self.src_addr,
+            dest_addr: self.dest_addr,
+            num_transfers: self.num_transfers,
+            control,
+            timing_mode: self.timing_mode,
+            chunk_size: match self.timing_mode {
+                TimingMode::FIFO => ChunkSize::Word,
+                _ if (control >> 10) & 1 > 0 => ChunkSize::Word,
+                _ => ChunkSize::Halfword,
+            },
+            is_repeating: self.timing_mode == TimingMode::FIFO || (control >> 9) & 1 > 0,
+            is_enabled: self.is_enabled,
         }
     }
 
@@ -105,7 +147,10 @@ impl DMA_Channel {
                     TimingMode::FIFO => {
                         match self.channel_no {
                             0 => {
-                                println!("FIFO channel is invalid for DMA channel_no of 0");
+                                hot_warn!(
+                                    target: TARGET_DMA,
+                                    "FIFO channel is invalid for DMA channel_no of 0"
+                                );
                                 false
                             }
                             // sound FIFO mode
@@ -115,16 +160,11 @@ impl DMA_Channel {
                                     .len()
                                     <= 16
                             }
-                            // video transfer mode
-                            3 => {
-                                bus.hblank_dma && {
-                                    let vcount = bus.read_byte_raw(0x5, MemoryRegion::IO);
-                                    vcount >= 2 && vcount < 162
-                                }
-                            }
+                            // channel 3 uses TimingMode::VideoCapture instead
                             _ => unreachable!(),
                         }
                     }
+                    TimingMode::VideoCapture => bus.video_capture_dma,
                 }
                 //}
             }
@@ -144,7 +184,7 @@ impl DMA_Channel {
             // if this is a repeat run, need to re-load the number of transfers
             self.num_transfers = match self.timing_mode {
                 TimingMode::FIFO => 4,
-                _ => dma_cnt as u16,
+                _ => dma_cnt & 0xffff,
             };
             if self.repeat_reset_dest {
                 self.dest_addr = bus.read_word_raw(0xb4 + 12 * self.channel_no, MemoryRegion::IO);
@@ -173,7 +213,7 @@ impl DMA_Channel {
             0b01 => !0, // -1
             0b10 => 0,
             0b11 => {
-                println!("illegal DMA channel src_increment of 0b11");
+                hot_warn!(target: TARGET_DMA, "illegal DMA channel src_increment of 0b11");
                 0
             }
             _ => unreachable!(),
@@ -185,15 +225,15 @@ impl DMA_Channel {
                 true => ChunkSize::Word,
                 false => ChunkSize::Halfword,
             };
-        } else if self.channel_no == 1 || self.channel_no == 2 {
-            assert!(self.chunk_size == ChunkSize::Word);
-            assert!(self.num_transfers == 4);
-            assert!(self.dest_addr == 0x040000a0 || self.dest_addr == 0x040000a4);
-            assert!(self.check_is_active(bus));
         } else {
-            panic!("video transfer DMA not implemented");
+            hot_assert!(self.chunk_size == ChunkSize::Word);
+            hot_assert!(self.num_transfers == 4);
+            hot_assert!(self.dest_addr == 0x040000a0 || self.dest_addr == 0x040000a4);
+            hot_assert!(self.check_is_active(bus));
         }
 
+        self.clamp_transfer();
+
         self.raise_interrupt = (dma_cnt >> 0x1e) & 1 > 0;
 
         self.is_repeating = self.timing_mode == TimingMode::FIFO
@@ -205,119 +245,32 @@ impl DMA_Channel {
         if self.channel_no == 3 {
             //println!("dma channel 3, src addr: {:#x}, dest addr: {:#x}", self.src_addr, self.dest_addr);
         }
+        let (src_start, dest_start) = (self.src_addr, self.dest_addr);
+        let (src_mask, dest_mask, _) = self.register_masks();
         //
+        // EEPROM size detection: requests sent to the EEPROM are 9 (set read address) or 73
+        // (write) bits long with a 6 bit address, and 17 or 81 bits with a 14 bit address. the
+        // transfer itself goes through the bus like any other, see Eeprom
         if self.channel_no == 3
-            && ((self.src_addr >= 0xd000000 && self.src_addr <= 0xdffffff)
-                || (self.dest_addr >= 0xd000000 && self.dest_addr <= 0xdffffff))
+            && !bus.eeprom_size_known
+            && self.dest_addr >= 0xd000000
+            && self.dest_addr <= 0xdffffff
             && (bus.cartridge_type == CartridgeType::Eeprom512
                 || bus.cartridge_type == CartridgeType::Eeprom8192)
         {
-            //println!("chunksize: {}, src_inc: {}, dest_inc: {}", self.chunk_size as u32, self.src_increment as i32, self.dest_increment as i32);
-            if self.chunk_size == ChunkSize::Halfword
-                && self.src_increment == 1
-                && self.dest_increment == 1
-            {
-                //if self.dest_addr >= 0xd000000 && self.dest_addr <= 0xdffffff && self.src_addr >= 0xd000000 && self.src_addr <= 0xdffffff{
-                //    println!("eeprom src and dest both in eeprom region");
-                //}
-                //println!("dma num transfers: {}", self.num_transfers);
-                // EEPROM write
-                if self.dest_addr >= 0xd000000 && self.dest_addr <= 0xdffffff {
-                    bus.eeprom_is_read = false;
-                    let mut res: u64 = 0;
-                    let mut sram_addr = 0;
-                    let mut j = 0;
-                    let mut is_read = false;
-                    for i in 0..self.num_transfers {
-                        let data = bus.read_halfword(self.src_addr as usize);
-                        res <<= 1;
-                        res |= data as u64 & 1;
-                        j += 1;
-                        if i == 1 {
-                            if res == 0b10 {
-                                is_read = false;
-                                bus.eeprom_is_read = false;
-                                //println!("eeprom write");
-                            } else if res == 0b11 {
-                                is_read = true;
-                                bus.eeprom_is_read = true;
-                                //println!("eeprom read set addr");
-                            } else {
-                                println!(
-                                    "DMA channel 3 EEPROM no matching bits, res: {:#05b}",
-                                    res
-                                );
-                                break;
-                            }
-                            j = 0;
-                            res = 0;
-                        } else if (i == 7 && bus.cartridge_type == CartridgeType::Eeprom512)
-                            || (i == 15 && bus.cartridge_type == CartridgeType::Eeprom8192)
-                        {
-                            //assert!(res < 0x400);
-                            sram_addr = res << 3;
-                            j = 0;
-                            res = 0;
-                            if is_read {
-                                //println!("EEPROM setting read mem addr");
-                                bus.eeprom_read_offset = sram_addr as usize;
-                            }
-                        } else if !is_read && j == 64 {
-                            //println!("EEPROM writing to memory, i: {}, num_transfers: {}", i, self.num_transfers);
-                            j = 0;
-                            let base_addr = sram_addr;
-                            bus.store_word_raw(
-                                base_addr as usize,
-                                MemoryRegion::CartridgeSram,
-                                res as u32,
-                            );
-                            bus.store_word_raw(
-                                base_addr as usize + 4,
-                                MemoryRegion::CartridgeSram,
-                                (res >> 32) as u32,
-                            );
-                            //println!("write res: {:#18x}", res);
-                            //println!("write base addr: {:#x}", base_addr);
-                            bus.eeprom_write_successful = true;
-                        }
-
-                        self.src_addr += 2;
-                        self.dest_addr += 2;
-                    }
+            match self.num_transfers {
+                9 | 73 => {
+                    bus.cartridge_type = CartridgeType::Eeprom512;
+                    bus.eeprom_size_known = true;
                 }
-                // EEPROM read
-                else if bus.eeprom_is_read {
-                    //println!("eeprom read");
-                    //bus.eeprom_is_read = false;
-                    let mut j = 0;
-                    let base_addr = bus.eeprom_read_offset;
-                    //println!("read base addr: {:#x}", base_addr);
-                    let res = bus.read_word_raw(base_addr as usize, MemoryRegion::CartridgeSram)
-                        as u64
-                        + ((bus.read_word_raw(base_addr as usize + 4, MemoryRegion::CartridgeSram)
-                            as u64)
-                            << 32);
-                    //println!("read res: {:#18x}", res);
-                    for i in 0..self.num_transfers {
-                        j += 1;
-                        let mut data = 0;
-                        if i == 3 {
-                            j = 0;
-                        } else if i > 3 && j <= 64 {
-                            //println!("j: {}", j);
-                            data = ((res >> (64 - j)) & 1) as u16;
-                        }
-
-                        bus.store_halfword(self.dest_addr as usize, data);
-
-                        self.src_addr += 2;
-                        self.dest_addr += 2;
-                    }
+                17 | 81 => {
+                    bus.cartridge_type = CartridgeType::Eeprom8192;
+                    bus.eeprom_size_known = true;
                 }
-            } else {
-                println!("fatal error: eeprom DMA 3 has invalid config. chunksize: {}, src_inc: {}, dest_inc: {}", self.chunk_size as u32, self.src_increment as i32, self.dest_increment as i32);
+                _ => {}
             }
-        } else if self.timing_mode != TimingMode::FIFO {
+        }
+        if self.timing_mode != TimingMode::FIFO {
             for _ in 0..self.num_transfers {
                 //println!("dest: {:#x}, src: {:#x}, data: {:#010x}", self.dest_addr, self.src_addr, bus.read_word(self.src_addr));
                 match self.chunk_size {
@@ -330,11 +283,11 @@ impl DMA_Channel {
                         bus.store_word(self.dest_addr as usize, data);
                     }
                     _ => {
-                        println!("DMA chunk size must be Word or Halfword");
+                        hot_warn!(target: TARGET_DMA, "DMA chunk size must be Word or Halfword");
                     }
                 };
-                self.src_addr += self.src_increment * self.chunk_size as u32;
-                self.dest_addr += self.dest_increment * self.chunk_size as u32;
+                self.src_addr = self.next_addr(self.src_addr, self.src_increment, src_mask);
+                self.dest_addr = self.next_addr(self.dest_addr, self.dest_increment, dest_mask);
             }
         } else {
             let channel_num = (self.dest_addr as usize - 0x040000a0) >> 2;
@@ -364,11 +317,11 @@ impl DMA_Channel {
                             .push_back(((halfword >> 8) & 0b11111111) as i8);
                     }
                     _ => {
-                        println!("DMA chunk size must be Word or Halfword");
+                        hot_warn!(target: TARGET_DMA, "DMA chunk size must be Word or Halfword");
                     }
                 };
 
-                self.src_addr += self.src_increment * self.chunk_size as u32;
+                self.src_addr = self.next_addr(self.src_addr, self.src_increment, src_mask);
             }
         }
 
@@ -384,6 +337,183 @@ impl DMA_Channel {
             bus.cpu_interrupt(1 << (8 + self.channel_no));
         }
 
-        (self.num_transfers as u32 - 1) * 2 + 4
+        self.transfer_cycles(bus, src_start, dest_start)
+    }
+
+    // (source address, dest address, count) bits of the channel's registers. the rest of the bits
+    // the game writes are ignored by hardware
+    fn register_masks(&self) -> (u32, u32, u32) {
+        match self.channel_no {
+            0 => (0x7ffffff, 0x7ffffff, 0x3fff),
+            3 => (0xfffffff, 0xfffffff, 0xffff),
+            _ => (0xfffffff, 0x7ffffff, 0x3fff),
+        }
+    }
+
+    // keeps the transfer inside what hardware can address: the addresses and count are cut to the
+    // register widths and the addresses aligned to the chunk size, like hardware does. without
+    // this, junk in the registers (a buggy game, a fuzzer) trips the alignment asserts of the bus.
+    // a count of 0 after the cut means the maximum, 0x4000 for DMA0-2 and 0x10000 for DMA3
+    fn clamp_transfer(&mut self) {
+        let (src_mask, dest_mask, count_mask) = self.register_masks();
+        let align = !(self.chunk_size as u32 - 1);
+        let src_addr = self.src_addr & src_mask & align;
+        let dest_addr = self.dest_addr & dest_mask & align;
+        let num_transfers = self.num_transfers & count_mask;
+        if (src_addr, dest_addr, num_transfers)
+            != (self.src_addr, self.dest_addr, self.num_transfers)
+        {
+            hot_warn!(
+                target: TARGET_DMA,
+                "DMA channel {} clamped: src {:#x} -> {:#x}, dest {:#x} -> {:#x}, count {:#x} -> {:#x}",
+                self.channel_no,
+                self.src_addr,
+                src_addr,
+                self.dest_addr,
+                dest_addr,
+                self.num_transfers,
+                num_transfers
+            );
+        }
+        self.src_addr = src_addr;
+        self.dest_addr = dest_addr;
+        self.num_transfers = match num_transfers {
+            0 => count_mask + 1,
+            _ => num_transfers,
+        };
+    }
+
+    // the address counters are as wide as the registers, so a transfer running off either end
+    // wraps around instead of overflowing
+    fn next_addr(&self, addr: u32, increment: u32, mask: u32) -> u32 {
+        addr.wrapping_add(increment.wrapping_mul(self.chunk_size as u32)) & mask
+    }
+
+    // 2N + 2(n-1)S + 2I: the first unit is a non-sequential read and write, the rest are
+    // sequential, plus 2 internal cycles of startup (4 if both source and dest are in the game pak)
+    fn transfer_cycles(&self, bus: &Bus, src_addr: u32, dest_addr: u32) -> u32 {
+        let (src_addr, dest_addr) = (src_addr as usize, dest_addr as usize);
+        let chunk_size = self.chunk_size;
+        let first = bus.access_cycles(src_addr, chunk_size, false)
+            + bus.access_cycles(dest_addr, chunk_size, false);
+        let rest = bus.access_cycles(src_addr, chunk_size, true)
+            + bus.access_cycles(dest_addr, chunk_size, true);
+        let is_gamepak = |addr: usize| (0x8000000..0xe000000).contains(&addr);
+        let internal = if is_gamepak(src_addr) && is_gamepak(dest_addr) {
+            4
+        } else {
+            2
+        };
+        first + self.num_transfers.saturating_sub(1) * rest + internal
+    }
+
+    pub fn write_snapshot(&self, w: &mut SnapshotWriter) {
+        w.u32(self.src_addr);
+        w.u32(self.dest_addr);
+        w.u32(self.src_increment);
+        w.u32(self.dest_increment);
+        w.u32(self.num_transfers);
+        w.u8(self.chunk_size as u8);
+        w.u8(self.timing_mode as u8);
+        w.bool(self.raise_interrupt);
+        w.bool(self.is_repeating);
+        w.bool(self.repeat_reset_dest);
+        w.bool(self.is_enabled);
+    }
+
+    pub fn read_snapshot(&mut self, r: &mut SnapshotReader) -> Result<(), &'static str> {
+        self.src_addr = r.u32()?;
+        self.dest_addr = r.u32()?;
+        self.src_increment = r.u32()?;
+        self.dest_increment = r.u32()?;
+        self.num_transfers = r.u32()?;
+        self.chunk_size = match r.u8()? {
+            4 => ChunkSize::Word,
+            2 => ChunkSize::Halfword,
+            1 => ChunkSize::Byte,
+            _ => return Err("invalid dma chunk size in snapshot"),
+        };
+        self.timing_mode = match r.u8()? {
+            0 => TimingMode::Immediate,
+            1 => TimingMode::VBlank,
+            2 => TimingMode::HBlank,
+            3 => TimingMode::FIFO,
+            4 => TimingMode::VideoCapture,
+            _ => return Err("invalid dma timing mode in snapshot"),
+        };
+        self.raise_interrupt = r.bool()?;
+        self.is_repeating = r.bool()?;
+        self.repeat_reset_dest = r.bool()?;
+        self.is_enabled = r.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DMA_Channel;
+    use crate::apu::Apu;
+    use crate::bus::{Bus, MemoryRegion};
+    use crate::test_util::bios;
+
+    const WORD: u32 = 1 << 26;
+    const ENABLE: u32 = 1 << 31;
+
+    // runs an immediate DMA3 transfer and returns the cycles it took
+    fn dma3_cycles(waitcnt: u16, src: u32, dest: u32, control: u32) -> u32 {
+        let mut bus = Bus::new(&bios(&[]), &[0; 0x200], None, None, Apu::new(48000)).unwrap();
+        bus.store_halfword_raw(0x204, MemoryRegion::IO, waitcnt);
+        bus.store_word_raw(0xd4, MemoryRegion::IO, src);
+        bus.store_word_raw(0xd8, MemoryRegion::IO, dest);
+        bus.store_word_raw(0xdc, MemoryRegion::IO, control | ENABLE);
+        DMA_Channel::new_enabled(3, &mut bus).execute_dma(&mut bus)
+    }
+
+    #[test]
+    fn times_transfer_from_wait_states() {
+        // IWRAM to IWRAM, 8 halfwords: 2 + 7 * 2 + 2
+        assert_eq!(dma3_cycles(0, 0x3000000, 0x3000100, 8), 18);
+        // ROM to EWRAM, 16 words at the reset wait states. ROM words are 8 cycles non-sequential
+        // and 6 sequential, EWRAM words 6 either way: 14 + 15 * 12 + 2
+        assert_eq!(dma3_cycles(0, 0x8000000, 0x2000000, 16 | WORD), 196);
+        // ROM to IWRAM, 4 words with WS0 at 3 non-sequential and 1 sequential: 7 + 3 * 5 + 2
+        assert_eq!(dma3_cycles(0b1_01_00, 0x8000000, 0x3000000, 4 | WORD), 24);
+    }
+
+    #[test]
+    fn clamps_junk_registers_to_their_widths() {
+        let mut bus = Bus::new(&bios(&[]), &[0; 0x200], None, None, Apu::new(48000)).unwrap();
+        // unaligned addresses with junk in the top bits, a decrementing dest and a count wider
+        // than DMA0's 14 bits
+        bus.store_word_raw(0xb0, MemoryRegion::IO, 0xf3000003);
+        bus.store_word_raw(0xb4, MemoryRegion::IO, 0xf0000005);
+        bus.store_word_raw(0xb8, MemoryRegion::IO, 0xffff | WORD | (1 << 21) | ENABLE);
+        let mut channel = DMA_Channel::new_enabled(0, &mut bus);
+        channel.execute_dma(&mut bus);
+        assert_eq!(channel.src_addr, 0x3000000 + 0x3fff * 4);
+        // the dest wraps around below 0
+        assert_eq!(channel.dest_addr, 0x8000004 - 0x3fff * 4);
+
+        // a count of 0 after the cut is the maximum
+        for (channel_no, count, expected) in [
+            (0, 0, 0x4000),
+            // only the low 14 bits count for DMA0-2
+            (1, 0x4000, 0x4000),
+            (3, 0, 0x10000),
+        ] {
+            let mut bus = Bus::new(&bios(&[]), &[0; 0x200], None, None, Apu::new(48000)).unwrap();
+            bus.store_word_raw(0xb0 + 12 * channel_no, MemoryRegion::IO, 0x2000000);
+            bus.store_word_raw(0xb4 + 12 * channel_no, MemoryRegion::IO, 0x3000000);
+            bus.store_word_raw(0xb8 + 12 * channel_no, MemoryRegion::IO, count | ENABLE);
+            let mut channel = DMA_Channel::new_enabled(channel_no, &mut bus);
+            channel.execute_dma(&mut bus);
+            // halfwords, so the dest ends up 2 bytes per transfer further
+            assert_eq!(
+                channel.dest_addr,
+                0x3000000 + expected * 2,
+                "DMA{}",
+                channel_no
+            );
+        }
     }
 }
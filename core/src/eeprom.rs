@@ -0,0 +1,184 @@
+// serial EEPROM backup. it is accessed one bit at a time through bit 0 of halfwords in the upper
+// cartridge region, usually with DMA 3. requests are sent MSB first:
+//
+//   read:  0b11, block address, 0. the next 68 bits read are 4 ignored bits and 64 bits of data
+//   write: 0b10, block address, 64 bits of data, 0
+//
+// the block address is 6 bits for 512 byte EEPROMs and 14 bits, of which the lower 10 are used, for
+// 8KiB ones. reading while no read is in progress returns 1 (ready). writes complete immediately, so
+// a game polling for the end of a write sees ready straight away.
+
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::trace::TARGET_BUS;
+
+// bytes per block
+pub const EEPROM_BLOCK_SIZE: usize = 8;
+
+// 4 ignored bits followed by 64 bits of data
+const READ_LEN: usize = 68;
+
+// only the lower 10 bits of the block address are used
+const MAX_BLOCK: usize = 0x3ff;
+
+// what the bus has to do with the backup memory after a bit was written. offsets are in bytes
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EepromAction {
+    None,
+    // load the block at the offset and pass it to Eeprom::start_read
+    Read(usize),
+    // store the data in the block at the offset
+    Write(usize, u64),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum State {
+    Command,
+    Address { is_read: bool },
+    Data,
+    Stop { is_read: bool },
+}
+
+pub struct Eeprom {
+    state: State,
+    // bits of the current part of the request, and how many have been received
+    bits: u64,
+    num_bits: usize,
+    // offset of the block of the current request, and the data of a write request
+    offset: usize,
+    data: u64,
+    // block being read, and the number of bits read so far
+    read: Option<(u64, usize)>,
+}
+
+impl Eeprom {
+    pub fn new() -> Eeprom {
+        Eeprom {
+            state: State::Command,
+            bits: 0,
+            num_bits: 0,
+            offset: 0,
+            data: 0,
+            read: None,
+        }
+    }
+
+    // addr_bits: 6 for 512 byte EEPROMs, 14 for 8KiB ones
+    pub fn write_bit(&mut self, bit: bool, addr_bits: usize) -> EepromAction {
+        self.bits = (self.bits << 1) | bit as u64;
+        self.num_bits += 1;
+        let mut action = EepromAction::None;
+        self.state = match self.state {
+            State::Command if self.num_bits == 2 => match self.bits {
+                0b11 => State::Address { is_read: true },
+                0b10 => State::Address { is_read: false },
+                _ => {
+                    hot_warn!(
+                        target: TARGET_BUS,
+                        "EEPROM request with invalid command {:#04b}",
+                        self.bits
+                    );
+                    State::Command
+                }
+            },
+            State::Address { is_read } if self.num_bits == addr_bits => {
+                self.offset = (self.bits as usize & MAX_BLOCK) * EEPROM_BLOCK_SIZE;
+                if is_read {
+                    State::Stop { is_read }
+                } else {
+                    State::Data
+                }
+            }
+            State::Data if self.num_bits == 64 => {
+                self.data = self.bits;
+                State::Stop { is_read: false }
+            }
+            State::Stop { is_read } => {
+                action = if is_read {
+                    EepromAction::Read(self.offset)
+                } else {
+                    self.read = None;
+                    EepromAction::Write(self.offset, self.data)
+                };
+                State::Command
+            }
+            // the current part of the request isn't complete yet
+            _ => return EepromAction::None,
+        };
+        self.bits = 0;
+        self.num_bits = 0;
+        action
+    }
+
+    pub fn start_read(&mut self, block: u64) {
+        self.read = Some((block, 0));
+    }
+
+    pub fn read_bit(&mut self) -> u16 {
+        match self.read {
+            Some((block, pos)) => {
+                self.read = if pos + 1 < READ_LEN {
+                    Some((block, pos + 1))
+                } else {
+                    None
+                };
+                if pos < READ_LEN - 64 {
+                    0
+                } else {
+                    ((block >> (READ_LEN - 1 - pos)) & 1) as u16
+                }
+            }
+            None => 1,
+        }
+    }
+
+    pub fn write_snapshot(&self, w: &mut SnapshotWriter) {
+        let (state, is_read) = match self.state {
+            State::Command => (0, false),
+            State::Address { is_read } => (1, is_read),
+            State::Data => (2, false),
+            State::Stop { is_read } => (3, is_read),
+        };
+        w.u8(state);
+        w.bool(is_read);
+        w.u64(self.bits);
+        w.usize(self.num_bits);
+        w.usize(self.offset);
+        w.u64(self.data);
+        w.bool(self.read.is_some());
+        let (block, pos) = self.read.unwrap_or((0, 0));
+        w.u64(block);
+        w.usize(pos);
+    }
+
+    pub fn read_snapshot(&mut self, r: &mut SnapshotReader) -> Result<(), &'static str> {
+        let state = r.u8()?;
+        let is_read = r.bool()?;
+        self.state = match state {
+            0 => State::Command,
+            1 => State::Address { is_read },
+            2 => State::Data,
+            3 => State::Stop { is_read },
+            _ => return Err("invalid EEPROM state in snapshot"),
+        };
+        self.bits = r.u64()?;
+        self.num_bits = r.usize()?;
+        if self.num_bits > 64 {
+            return Err("invalid EEPROM request length in snapshot");
+        }
+        self.offset = r.usize()?;
+        if self.offset > MAX_BLOCK * EEPROM_BLOCK_SIZE
+            || !self.offset.is_multiple_of(EEPROM_BLOCK_SIZE)
+        {
+            return Err("invalid EEPROM block in snapshot");
+        }
+        self.data = r.u64()?;
+        let is_reading = r.bool()?;
+        let block = r.u64()?;
+        let pos = r.usize()?;
+        if is_reading && pos >= READ_LEN {
+            return Err("invalid EEPROM read position in snapshot");
+        }
+        self.read = if is_reading { Some((block, pos)) } else { None };
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+use alloc::string::String;
+use core::fmt;
+
+// errors that can happen while constructing the emulator
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InitError {
+    UnknownCartridgeType(String),
+    // the cartridge address space is 32MiB, the ROM (in bytes) does not fit
+    RomTooLarge { size: usize },
+    // the BIOS must be exactly 16KiB, it is this many bytes
+    BiosWrongSize { size: usize },
+    // the audio sample rate must not be 0
+    InvalidSampleRate,
+}
+
+impl fmt::Display for InitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InitError::UnknownCartridgeType(s) => write!(
+                f,
+                "unknown cartridge type \"{}\", expected one of: SRAM_V, FLASH_V, FLASH512_V, FLASH1M_V, EEPROM_V, EEPROM512, EEPROM8192",
+                s
+            ),
+            InitError::RomTooLarge { size } => write!(
+                f,
+                "ROM is {} bytes, larger than the maximum of {} bytes",
+                size,
+                crate::rom::MAX_ROM_SIZE
+            ),
+            InitError::BiosWrongSize { size } => write!(
+                f,
+                "BIOS is {} bytes, expected {} bytes",
+                size,
+                16 * 1024
+            ),
+            InitError::InvalidSampleRate => write!(f, "the audio sample rate must not be 0"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for InitError {}
+
+// errors for bus accesses that are checked instead of asserted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusError {
+    // address does not map to any memory
+    Unmapped(usize),
+    // address is not aligned to the access size
+    Misaligned(usize),
+}
+
+impl fmt::Display for BusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BusError::Unmapped(addr) => write!(f, "unmapped address: {:#010x}", addr),
+            BusError::Misaligned(addr) => write!(f, "misaligned address: {:#010x}", addr),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for BusError {}
+
+// errors from the convenience constructors that also read files from disk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GbaError {
+    // reading the file at path failed
+    Io { path: String, reason: String },
+    Init(InitError),
+}
+
+impl fmt::Display for GbaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GbaError::Io { path, reason } => write!(f, "{}: {}", path, reason),
+            GbaError::Init(why) => write!(f, "{}", why),
+        }
+    }
+}
+
+impl From<InitError> for GbaError {
+    fn from(why: InitError) -> Self {
+        GbaError::Init(why)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for GbaError {}
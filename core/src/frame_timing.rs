@@ -0,0 +1,69 @@
+// wall time spent in GBA::process_frame, for performance overlays. the core has no clock of its
+// own, so the frontend hands one in with GBA::enable_frame_timing, eg. Instant::elapsed on
+// desktop or performance.now() on the web.
+
+use alloc::{boxed::Box, collections::VecDeque, vec::Vec};
+
+use crate::config;
+
+// returns the current time in microseconds, from any fixed starting point
+pub type FrameClock = Box<dyn FnMut() -> u64 + Send>;
+
+// over the last config::FRAME_TIME_WINDOW frames, in microseconds. all 0 if no frame was timed
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct FrameTimeStats {
+    pub frames: usize,
+    pub min: u64,
+    pub p50: u64,
+    pub p95: u64,
+    pub max: u64,
+    pub avg: u64,
+}
+
+// index of the given fraction (0 to 1) into a sorted list of len items, which must not be empty
+pub fn percentile_index(len: usize, fraction: f64) -> usize {
+    ((len as f64 * fraction) as usize).min(len - 1)
+}
+
+pub(crate) struct FrameTiming {
+    clock: FrameClock,
+    times: VecDeque<u64>,
+}
+
+impl FrameTiming {
+    pub fn new(clock: FrameClock) -> FrameTiming {
+        FrameTiming {
+            clock,
+            times: VecDeque::with_capacity(config::FRAME_TIME_WINDOW),
+        }
+    }
+
+    pub fn now(&mut self) -> u64 {
+        (self.clock)()
+    }
+
+    pub fn record(&mut self, start: u64) {
+        if self.times.len() == config::FRAME_TIME_WINDOW {
+            self.times.pop_front();
+        }
+        let end = self.now();
+        self.times.push_back(end.saturating_sub(start));
+    }
+
+    pub fn stats(&self) -> FrameTimeStats {
+        if self.times.is_empty() {
+            return FrameTimeStats::default();
+        }
+        let mut sorted: Vec<u64> = self.times.iter().copied().collect();
+        sorted.sort_unstable();
+        let len = sorted.len();
+        FrameTimeStats {
+            frames: len,
+            min: sorted[0],
+            p50: sorted[percentile_index(len, 0.5)],
+            p95: sorted[percentile_index(len, 0.95)],
+            max: sorted[len - 1],
+            avg: sorted.iter().sum::<u64>() / len as u64,
+        }
+    }
+}
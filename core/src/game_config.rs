@@ -0,0 +1,131 @@
+// per-game settings, keyed by the 4 character game code in the cartridge header (eg. "BPEE").
+//
+// frontends look up the config of the loaded game with GameConfig::lookup (or build their own) and
+// pass it to GBA::apply_game_config. settings given explicitly by the user, like a cartridge type,
+// take precedence over the config.
+
+use crate::bus::CartridgeType;
+
+// offset and length of the game code in the cartridge header
+pub const GAME_CODE_OFFSET: usize = 0xac;
+const GAME_CODE_LEN: usize = 4;
+
+pub type GameCode = [u8; GAME_CODE_LEN];
+
+// extra hardware on the cartridge. none of this is emulated yet, see GBA::apply_game_config
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct CartridgeHardware {
+    pub rtc: bool,
+    pub solar_sensor: bool,
+    pub tilt: bool,
+    pub gyro: bool,
+    pub rumble: bool,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct GameConfig {
+    pub cartridge_type: Option<CartridgeType>,
+    // (manufacturer, device) reported in flash ID mode, see GBA::set_flash_ids
+    pub flash_ids: Option<(u8, u8)>,
+    pub idle_skip: Option<bool>,
+    pub hardware: CartridgeHardware,
+}
+
+const NO_HARDWARE: CartridgeHardware = CartridgeHardware {
+    rtc: false,
+    solar_sensor: false,
+    tilt: false,
+    gyro: false,
+    rumble: false,
+};
+
+const RTC: CartridgeHardware = CartridgeHardware {
+    rtc: true,
+    ..NO_HARDWARE
+};
+
+const fn config(cartridge_type: Option<CartridgeType>, hardware: CartridgeHardware) -> GameConfig {
+    GameConfig {
+        cartridge_type,
+        flash_ids: None,
+        idle_skip: None,
+        hardware,
+    }
+}
+
+// games whose backup type isn't detected correctly, or that have extra hardware
+const BUILT_IN_CONFIGS: &[(&GameCode, GameConfig)] = &[
+    // pokemon ruby, sapphire, emerald
+    (b"AXVE", config(Some(CartridgeType::Flash128), RTC)),
+    (b"AXPE", config(Some(CartridgeType::Flash128), RTC)),
+    (b"BPEE", config(Some(CartridgeType::Flash128), RTC)),
+    // pokemon firered, leafgreen
+    (b"BPRE", config(Some(CartridgeType::Flash128), NO_HARDWARE)),
+    (b"BPGE", config(Some(CartridgeType::Flash128), NO_HARDWARE)),
+    // boktai 1 and 2
+    (
+        b"U3IE",
+        config(
+            None,
+            CartridgeHardware {
+                rtc: true,
+                solar_sensor: true,
+                ..NO_HARDWARE
+            },
+        ),
+    ),
+    (
+        b"U32E",
+        config(
+            None,
+            CartridgeHardware {
+                rtc: true,
+                solar_sensor: true,
+                ..NO_HARDWARE
+            },
+        ),
+    ),
+    // yoshi topsy-turvy
+    (
+        b"KYGE",
+        config(
+            None,
+            CartridgeHardware {
+                tilt: true,
+                ..NO_HARDWARE
+            },
+        ),
+    ),
+    // warioware twisted
+    (
+        b"RZWE",
+        config(
+            Some(CartridgeType::Sram),
+            CartridgeHardware {
+                gyro: true,
+                rumble: true,
+                ..NO_HARDWARE
+            },
+        ),
+    ),
+];
+
+// game code from the header of a ROM, None if the ROM is too short to have a header
+pub fn game_code(rom: &[u8]) -> Option<GameCode> {
+    rom.get(GAME_CODE_OFFSET..GAME_CODE_OFFSET + GAME_CODE_LEN)
+        .map(|code| code.try_into().unwrap())
+}
+
+impl GameConfig {
+    // config from the built-in table for a game code
+    pub fn lookup(game_code: &GameCode) -> Option<GameConfig> {
+        BUILT_IN_CONFIGS
+            .iter()
+            .find(|(code, _)| *code == game_code)
+            .map(|(_, config)| *config)
+    }
+
+    pub fn for_rom(rom: &[u8]) -> Option<GameConfig> {
+        game_code(rom).and_then(|code| GameConfig::lookup(&code))
+    }
+}
@@ -1,35 +1,104 @@
 //use log::info;
 
+use alloc::{boxed::Box, collections::VecDeque, vec, vec::Vec};
+use log::{warn, LevelFilter};
+
 use crate::{
-    apu::{Apu, SoundBufferIt},
-    bus::Bus,
+    apu::{Apu, AudioReadyCallback, SoundBufferIt, SoundChannel, NUM_SOUND_CHANNELS},
+    bus::{Bus, CartridgeType, CartridgeTypeSource, ChunkSize, GraphicsDirty, MemoryRegion},
     config,
-    input_handler::{InputHandler, KeyInput},
-    ppu::{Ppu, ScreenBuffer},
+    cpu::{Cpu, CpuState},
+    dma_channel::DmaInfo,
+    error::{BusError, InitError},
+    frame_timing::{FrameClock, FrameTimeStats, FrameTiming},
+    game_config::{self, GameCode, GameConfig},
+    input_handler::{InputHandler, KeyInput, KeypadProvider, NUM_GBA_KEYS, NUM_KEY_INPUTS},
+    memory::TestMemory,
+    ppu::{ColourCorrection, Ppu, ScreenBuffer},
+    profiler::AccessProfile,
+    scheduler::{Scheduler, Workflow},
+    search::{MemorySearch, SearchPredicate},
+    snapshot::{SnapshotReader, SnapshotWriter},
+    trace::{self, TARGET_BUS},
+    uninit_fill::UninitFill,
 };
 
-// smaller values have priority.
-#[derive(Clone, Copy)]
-enum Workflow {
-    Timer = 0,
-    DMA = 1,
-    Cpu = 2,
-    Apu = 3,
-    Ppu = 4,
-    Normaliser = 5,
+// result of GBA::pump_frame
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FrameResult {
+    // a frame was emulated. the screen and sound buffers can be collected. audio_samples is the
+    // number of stereo samples the frame added to the sound buffer, see
+    // GBA::last_frame_audio_samples
+    Ran {
+        micros_ahead: u64,
+        audio_samples: usize,
+    },
+    // the emulator is ahead of the given time, nothing was run
+    NotDue {
+        micros_until_due: u64,
+    },
+}
+
+// receives the cartridge save memory after the game has written to it, see
+// GBA::set_save_write_callback
+pub type SaveWriteCallback = Box<dyn FnMut(&[u8]) + Send>;
+
+// timing profile, see GBA::set_accuracy
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Accuracy {
+    // fixed cycle counts: every memory access takes a single cycle and a halted cpu sleeps a fixed
+    // number of cycles at a time
+    Fast,
+    // memory timing with the game pak prefetch buffer, and halts that wait for the next event
+    Accurate,
+}
+
+// interrupt sources, numbered by their bit in IE and IF. see GBA::raise_interrupt
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InterruptSource {
+    VBlank = 0,
+    HBlank = 1,
+    VCount = 2,
+    Timer0 = 3,
+    Timer1 = 4,
+    Timer2 = 5,
+    Timer3 = 6,
+    Serial = 7,
+    Dma0 = 8,
+    Dma1 = 9,
+    Dma2 = 10,
+    Dma3 = 11,
+    Keypad = 12,
+    GamePak = 13,
 }
 
 pub struct GBA {
     bus: Bus,
-    //cpu: CPU,
+    // not part of the bus, so that it can be clocked with a plain borrow of it. the bus sees what it
+    // needs of the cpu through MemoryInterface
+    cpu: Cpu,
     ppu: Ppu,
     input_handler: InputHandler,
+    // (frame, key, is_pressed, from_macro), sorted by frame. events played back by a macro skip the
+    // macro lookup, so that a macro can press its own trigger or another macro's
+    scheduled_inputs: VecDeque<(u64, KeyInput, bool, bool)>,
+    // keypad masks played back when the key is pressed, indexed by KeyInput. empty if there is no
+    // macro for the key
+    macros: [Vec<u16>; NUM_KEY_INPUTS],
+    // frame at which the macro playing back ends
+    macro_end_frame: u64,
 
     save_state: Vec<Vec<u8>>,
     save_state_updated: bool,
+    // bank the cartridge save memory was loaded from, if any
+    save_state_bank: Option<usize>,
+    save_write_callback: Option<SaveWriteCallback>,
+    // only Some while enabled, see GBA::enable_frame_timing
+    frame_timing: Option<FrameTiming>,
+    // frame of the last write to save memory that the callback hasn't been called for yet
+    last_save_write_frame: Option<u64>,
 
-    //heap: BinaryHeap<Reverse<(u32, Workflow)>>,
-    workflow_times: [(u32, Workflow); 6],
+    scheduler: Scheduler,
     //time_until_non_cpu_execution: u32,
     last_finished_time: u64,  // microseconds, continuous time
     last_fps_print_time: u64, // microseconds
@@ -37,11 +106,25 @@ pub struct GBA {
     frame_counter: u32, // this is used to for counting; it is sometimes reset to 0
     total_frames_passed: u64, // this is always increasing
     fps: Option<f64>,
+    // speedup mode as applied at the end of the last frame, see process_frame
+    speedup: bool,
 
     started: bool,
+
+    // watchdog: maximum number of cpu cycles that may pass in a single call to process_frame
+    max_cycles_per_frame: Option<u64>,
+    cycles_this_frame: u64,
+    deadlock_detection: bool,
+
+    last_frame_audio_samples: usize,
+
+    idle_skip: bool,
+
+    memory_search: Option<MemorySearch>,
 }
 
 impl GBA {
+    /// panics if the emulator cannot be constructed, see try_new
     pub fn new(
         bios_bin: &[u8],
         rom_bin: &[u8],
@@ -50,35 +133,57 @@ impl GBA {
         cartridge_type_str: Option<&str>,
         audio_sample_rate: usize,
     ) -> GBA {
+        GBA::try_new(
+            bios_bin,
+            rom_bin,
+            save_state,
+            save_state_bank,
+            cartridge_type_str,
+            audio_sample_rate,
+        )
+        .unwrap_or_else(|why| panic!("{}", why))
+    }
+
+    pub fn try_new(
+        bios_bin: &[u8],
+        rom_bin: &[u8],
+        save_state: Option<Vec<Vec<u8>>>,
+        save_state_bank: Option<usize>,
+        cartridge_type_str: Option<&str>,
+        audio_sample_rate: usize,
+    ) -> Result<GBA, InitError> {
+        if audio_sample_rate == 0 {
+            return Err(InitError::InvalidSampleRate);
+        }
         let apu = Apu::new(audio_sample_rate);
 
         let save_state =
             save_state.unwrap_or_else(|| vec![vec![0; 128 * 1024]; config::NUM_SAVE_STATES]);
         let initial_save_state = save_state_bank.map(|x| save_state[x].as_slice());
 
-        GBA {
+        let mut gba = GBA {
             bus: Bus::new(
                 bios_bin,
                 rom_bin,
                 initial_save_state,
                 cartridge_type_str,
                 apu,
-            ),
-            //cpu: CPU::new(),
+            )?,
+            cpu: Cpu::new(),
             ppu: Ppu::new(),
             input_handler: InputHandler::new(),
+            scheduled_inputs: VecDeque::new(),
+            macros: core::array::from_fn(|_| Vec::new()),
+            macro_end_frame: 0,
 
             save_state,
             save_state_updated: false,
+            save_state_bank,
+            save_write_callback: None,
+            frame_timing: None,
+            last_save_write_frame: None,
 
-            workflow_times: [
-                (0, Workflow::Timer),
-                (0, Workflow::DMA),
-                (0, Workflow::Cpu),
-                (0, Workflow::Apu),
-                (0, Workflow::Ppu),
-                (0, Workflow::Normaliser),
-            ],
+            scheduler: Scheduler::new(),
             //time_until_non_cpu_execution: 0,
 
             //last_finished_time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap(),
@@ -88,19 +193,103 @@ impl GBA {
 
             frame_counter: 0,
             fps: None,
+            speedup: false,
             total_frames_passed: 0,
 
             started: false,
-        }
+
+            max_cycles_per_frame: None,
+            cycles_this_frame: 0,
+            deadlock_detection: false,
+
+            last_frame_audio_samples: 0,
+
+            idle_skip: false,
+
+            memory_search: None,
+        };
+        gba.cpu.update_bus_view(&mut gba.bus);
+        Ok(gba)
 
         // zero out input registers (NOTE: handled by BIOS)
         //res.input_handler.process_input(&res.key_receiver, &mut res.bus);
     }
 
+    /// swaps in another 16KiB BIOS without rebuilding the emulator, eg. to compare BIOS dumps
+    /// mid-session. the rest of the state is kept, so a game running BIOS code at the time sees the
+    /// new code from its next fetch
+    pub fn set_bios(&mut self, bios_bin: &[u8]) -> Result<(), InitError> {
+        self.bus.set_bios(bios_bin)?;
+        // the open bus latch held the old BIOS's last fetch
+        self.cpu.last_fetched_bios_instr = 0;
+        self.cpu.update_bus_view(&mut self.bus);
+        Ok(())
+    }
+
+    /// replaces the game with another one without constructing a new emulator. everything except the
+    /// BIOS and the settings (idle skip, watchdogs, rendering, sample rate) is put back into its power
+    /// on state; the arguments are the same as for try_new. if the previous game wrote to its
+    /// cartridge save memory, it is first exported into the bank it was loaded from (bank 0 if
+    /// none). the previous game's save states are returned if they were updated since
+    /// get_updated_save_state last returned them, so that the frontend can write them to disk. on
+    /// error, the previous game keeps running
+    pub fn load_rom(
+        &mut self,
+        rom_bin: &[u8],
+        save_state: Option<Vec<Vec<u8>>>,
+        save_state_bank: Option<usize>,
+        cartridge_type_str: Option<&str>,
+    ) -> Result<Option<Vec<Vec<u8>>>, InitError> {
+        if self.bus.save_dirty {
+            self.bus
+                .export_sram(&mut self.save_state[self.save_state_bank.unwrap_or(0)]);
+            self.save_state_updated = true;
+        }
+        // a save that is still being debounced belongs to the previous game, so report it now
+        if self.bus.save_written || self.last_save_write_frame.is_some() {
+            self.bus.save_written = false;
+            self.last_save_write_frame = None;
+            if let Some(callback) = self.save_write_callback.as_mut() {
+                callback(self.bus.sram());
+            }
+        }
+
+        let save_state =
+            save_state.unwrap_or_else(|| vec![vec![0; 128 * 1024]; config::NUM_SAVE_STATES]);
+        let initial_save_state = save_state_bank.map(|x| save_state[x].as_slice());
+        self.bus
+            .load_rom(rom_bin, initial_save_state, cartridge_type_str)?;
+        self.cpu = Cpu::new();
+        self.cpu.update_bus_view(&mut self.bus);
+
+        let previous_save_state = core::mem::replace(&mut self.save_state, save_state);
+        let previous_save_state = self.save_state_updated.then_some(previous_save_state);
+        self.save_state_updated = false;
+        self.save_state_bank = save_state_bank;
+
+        self.ppu.reset();
+        self.scheduler = Scheduler::new();
+        self.scheduled_inputs.clear();
+        self.macro_end_frame = 0;
+        self.frame_counter = 0;
+        self.total_frames_passed = 0;
+        self.cycles_this_frame = 0;
+        self.last_frame_audio_samples = 0;
+        self.memory_search = None;
+        self.set_idle_skip(self.idle_skip);
+
+        Ok(previous_save_state)
+    }
+
     pub fn has_started(&self) -> bool {
         self.started
     }
 
+    /// backup type in use, and whether it was detected from the ROM, defaulted or given by the user
+    pub fn cartridge_type(&self) -> (CartridgeType, CartridgeTypeSource) {
+        (self.bus.cartridge_type, self.bus.cartridge_type_source)
+    }
+
     // todo: this is not a pure function despite its name. this should be changed
     pub fn get_screen_buffer(&mut self) -> Option<&ScreenBuffer> {
         self.ppu.get_screen_buffer()
@@ -114,6 +303,117 @@ impl GBA {
         self.bus.apu.clear_buffer();
     }
 
+    /// colour correction of the screen buffers returned by get_screen_buffer, see
+    /// ScreenBuffer::read_pixel_u8 and ScreenBuffer::convert_into
+    pub fn set_colour_correction(&mut self, colour_correction: ColourCorrection) {
+        self.ppu.set_colour_correction(colour_correction);
+    }
+
+    /// snapshot of the state of the 4 dma channels
+    pub fn dma_channels(&self) -> [DmaInfo; 4] {
+        core::array::from_fn(|i| self.bus.dma_channels[i].info(&self.bus))
+    }
+
+    /// full machine snapshot (save state) that can be restored with restore_snapshot. the BIOS and
+    /// ROM are not included, so a snapshot can only be restored into an emulator running the same
+    /// game. should be called between frames
+    pub fn snapshot(&self) -> Vec<u8> {
+        self.write_snapshot(None)
+    }
+
+    /// same as snapshot, with a thumbnail of the current screen in the header that snapshot_thumbnail
+    /// can read back, eg. for a save menu
+    pub fn snapshot_with_thumbnail(&self) -> Vec<u8> {
+        self.write_snapshot(Some(&self.ppu.screen_buffer().thumbnail()))
+    }
+
+    /// 120x80 RGB thumbnail (see ScreenBuffer::thumbnail) stored in a snapshot made with
+    /// snapshot_with_thumbnail, without restoring it. None if the snapshot has no thumbnail or is
+    /// not a valid snapshot
+    pub fn snapshot_thumbnail(snapshot: &[u8]) -> Option<Vec<u8>> {
+        let mut r = SnapshotReader::new(snapshot).ok()?;
+        r.thumbnail().ok()?.map(|x| x.to_vec())
+    }
+
+    fn write_snapshot(&self, thumbnail: Option<&[u8]>) -> Vec<u8> {
+        let mut w = SnapshotWriter::new();
+        w.thumbnail(thumbnail);
+        self.scheduler.write_snapshot(&mut w);
+        self.ppu.write_snapshot(&mut w);
+        self.bus.write_snapshot(&mut w);
+        self.cpu.write_snapshot(&mut w);
+        w.finish()
+    }
+
+    /// restores a snapshot made with snapshot. the snapshot is only committed if all of it is
+    /// valid, on error the emulator is left as it was
+    pub fn restore_snapshot(&mut self, snapshot: &[u8]) -> Result<(), &'static str> {
+        // the components read straight into themselves, so the current state is kept as a scratch
+        // copy to go back to. a snapshot made by this version always reads back
+        let current = self.snapshot();
+        self.read_snapshot(snapshot).inspect_err(|_| {
+            self.read_snapshot(&current)
+                .expect("failed to roll back a snapshot restore");
+        })
+    }
+
+    fn read_snapshot(&mut self, snapshot: &[u8]) -> Result<(), &'static str> {
+        let mut r = SnapshotReader::new(snapshot)?;
+        r.thumbnail()?;
+        self.scheduler.read_snapshot(&mut r)?;
+        self.ppu.read_snapshot(&mut r)?;
+        self.bus.read_snapshot(&mut r)?;
+        self.cpu.read_snapshot(&mut r)?;
+        self.cpu.update_bus_view(&mut self.bus);
+        r.finish()
+    }
+
+    /// changes the output sample rate, eg. after switching audio devices. samples that were not
+    /// collected with get_sound_buffer yet are dropped. a rate of 0 is rejected and the old rate is
+    /// kept
+    pub fn set_sample_rate(&mut self, sample_rate: usize) -> Result<(), InitError> {
+        if sample_rate == 0 {
+            return Err(InitError::InvalidSampleRate);
+        }
+        self.bus.apu.set_sample_rate(sample_rate);
+        Ok(())
+    }
+
+    /// push alternative to get_sound_buffer: cb is called from within process_frame with the
+    /// (left, right) samples of every chunk of audio as soon as the apu completes it. the samples are
+    /// still added to the sound buffer, so frontends using the callback should keep calling
+    /// reset_sound_buffer
+    pub fn set_audio_ready_callback(&mut self, cb: AudioReadyCallback) {
+        self.bus.apu.audio_ready_callback = Some(cb);
+    }
+
+    /// keeps the most recent samples of every sound channel, see audio_scope. off by default
+    pub fn set_audio_scope(&mut self, enable: bool) {
+        self.bus.apu.set_scope_enabled(enable);
+    }
+
+    /// the last few hundred samples of every channel, indexed by SoundChannel, at 65536Hz. samples
+    /// are in [-1, 1] and taken before panning and the master volume, at the channel's own volume.
+    /// the wave channel is unsigned, so it stays in [0, 1]. the slices are empty while the scope is
+    /// disabled, and shorter until enough samples have been produced
+    pub fn audio_scope(&self) -> [&[f32]; NUM_SOUND_CHANNELS] {
+        match &self.bus.apu.scope {
+            Some(scope) => scope.samples(),
+            None => [&[]; NUM_SOUND_CHANNELS],
+        }
+    }
+
+    /// mutes or unmutes a channel in the final mix, on top of the game's own channel enables, eg. to
+    /// play only the music or to find the channel behind a glitch. muted channels keep running, so
+    /// unmuting picks up where the game is. all channels are enabled by default
+    pub fn set_channel_enabled(&mut self, channel: SoundChannel, enabled: bool) {
+        self.bus.apu.channel_enabled[channel as usize] = enabled;
+    }
+
+    pub fn channel_enabled(&self, channel: SoundChannel) -> bool {
+        self.bus.apu.channel_enabled[channel as usize]
+    }
+
     pub fn get_updated_save_state(&mut self) -> Option<&[Vec<u8>]> {
         if self.save_state_updated {
             self.save_state_updated = false;
@@ -127,17 +427,530 @@ impl GBA {
         &self.save_state
     }
 
+    /// true if the game has written to its save memory (SRAM, flash or EEPROM) since the last
+    /// clear_save_dirty. frontends can use this to debounce writing saves to disk.
+    pub fn save_is_dirty(&self) -> bool {
+        self.bus.save_dirty
+    }
+
+    pub fn clear_save_dirty(&mut self) {
+        self.bus.save_dirty = false;
+    }
+
+    /// cb is called from within process_frame with the whole cartridge save memory (SRAM, flash or
+    /// EEPROM) once the game has finished saving: after a write, the call waits until
+    /// config::SAVE_WRITE_DEBOUNCE_FRAMES frames pass without another one, so a save spread over
+    /// many writes and frames results in a single call. independent of save_is_dirty and the save
+    /// states
+    pub fn set_save_write_callback(&mut self, cb: SaveWriteCallback) {
+        self.save_write_callback = Some(cb);
+        self.last_save_write_frame = None;
+    }
+
+    fn check_save_write(&mut self) {
+        if core::mem::take(&mut self.bus.save_written) {
+            self.last_save_write_frame = Some(self.total_frames_passed);
+        }
+        let Some(frame) = self.last_save_write_frame else {
+            return;
+        };
+        if self.total_frames_passed - frame < config::SAVE_WRITE_DEBOUNCE_FRAMES {
+            return;
+        }
+        self.last_save_write_frame = None;
+        if let Some(callback) = self.save_write_callback.as_mut() {
+            callback(self.bus.sram());
+        }
+    }
+
+    /// which of palette, VRAM and OAM have been written since the last call. the flags are cleared
+    /// on every call
+    pub fn graphics_dirty(&mut self) -> GraphicsDirty {
+        core::mem::take(&mut self.bus.graphics_dirty)
+    }
+
     pub fn get_fps(&mut self) -> Option<f64> {
         self.fps.take()
     }
 
-    // must be called prior to updating keys in each frame
-    pub fn input_frame_preprocess(&mut self) {
-        self.input_handler.frame_preprocess()
+    /// no longer needed: changes to the Speedup key are picked up at the end of every frame. kept so
+    /// that existing frontends keep building. it has no effect on a keypad provider either
+    pub fn input_frame_preprocess(&mut self) {}
+
+    /// cb is called every time the game reads KEYINPUT (0x04000130) and returns the value it reads:
+    /// bit n clear means KeyInput n is pressed, bits 10 and up are ignored. this allows input to
+    /// change within a frame. the provider replaces the normal input path for the GBA keys, so
+    /// process_key, schedule_input and macros no longer change what the game reads, while the
+    /// Speedup and save keys keep working. the call is made on reads of the low byte; a byte read of
+    /// just the high byte returns the value from the previous call
+    pub fn set_keypad_provider(&mut self, cb: KeypadProvider) {
+        self.bus.keypad_provider = Some(cb);
+    }
+
+    /// goes back to the normal input path. the keys held through process_key take effect at the
+    /// end of the current frame
+    pub fn clear_keypad_provider(&mut self) {
+        self.bus.keypad_provider = None;
     }
 
     pub fn process_key(&mut self, key: KeyInput, is_pressed: bool) {
-        self.input_handler.process_key(key, is_pressed);
+        if self.macros[key as usize].is_empty() {
+            self.input_handler.process_key(key, is_pressed);
+        } else if is_pressed {
+            self.start_macro(key);
+        }
+    }
+
+    /// pressing `trigger` plays back `frames` instead of passing the key on: starting with the next
+    /// frame, each mask sets the GBA keys for one frame (bit n set means KeyInput n is pressed, bits
+    /// 10 and up are ignored), after which all GBA keys are released. playback goes through
+    /// schedule_input, so it is frame accurate and recorded inputs replay the same way. pressing a
+    /// trigger while a macro is still playing back does nothing. the keys a macro presses never
+    /// start macros themselves, so a macro can include its own trigger. empty frames remove the macro
+    pub fn define_macro(&mut self, trigger: KeyInput, frames: Vec<u16>) {
+        self.macros[trigger as usize] = frames;
+    }
+
+    fn start_macro(&mut self, trigger: KeyInput) {
+        let start = self.total_frames_passed;
+        if start < self.macro_end_frame {
+            return;
+        }
+        let frames = core::mem::take(&mut self.macros[trigger as usize]);
+        let masks = frames.iter().copied().chain([0]);
+        for (i, mask) in masks.enumerate() {
+            for key in 0..NUM_GBA_KEYS {
+                let is_pressed = (mask >> key) & 1 > 0;
+                self.insert_scheduled_input(
+                    start + i as u64,
+                    key.try_into().unwrap(),
+                    is_pressed,
+                    true,
+                );
+            }
+        }
+        self.macro_end_frame = start + frames.len() as u64;
+        self.macros[trigger as usize] = frames;
+    }
+
+    /// queues a key event to be applied at the start of `frame` (as counted by total_frames_passed),
+    /// as if process_key was called right before that frame. events for the same frame are applied in
+    /// the order they were scheduled
+    pub fn schedule_input(
+        &mut self,
+        frame: u64,
+        key: KeyInput,
+        is_pressed: bool,
+    ) -> Result<(), &'static str> {
+        if frame < self.total_frames_passed {
+            return Err("cannot schedule an input for a frame that has already passed");
+        }
+        self.insert_scheduled_input(frame, key, is_pressed, false);
+        Ok(())
+    }
+
+    fn insert_scheduled_input(
+        &mut self,
+        frame: u64,
+        key: KeyInput,
+        is_pressed: bool,
+        from_macro: bool,
+    ) {
+        let pos = self.scheduled_inputs.partition_point(|x| x.0 <= frame);
+        self.scheduled_inputs
+            .insert(pos, (frame, key, is_pressed, from_macro));
+    }
+
+    fn apply_scheduled_inputs(&mut self) {
+        while let Some(&(frame, key, is_pressed, from_macro)) = self.scheduled_inputs.front() {
+            if frame > self.total_frames_passed {
+                break;
+            }
+            self.scheduled_inputs.pop_front();
+            if from_macro {
+                self.input_handler.process_key(key, is_pressed);
+            } else {
+                self.process_key(key, is_pressed);
+            }
+        }
+    }
+
+    /// requests an interrupt as if `source` had raised it, eg. to test an IRQ handler or to simulate
+    /// pulling out the game pak. like the emulated hardware, the IF bit is always set, whether or not
+    /// the source is enabled in IE. the cpu takes the interrupt once IE, IME and the CPSR allow it
+    pub fn raise_interrupt(&mut self, source: InterruptSource) {
+        self.bus.cpu_interrupt(1 << source as u16);
+    }
+
+    /// overrides the manufacturer and device id that flash backups report, eg. (0x62, 0x13) for a
+    /// Sanyo 128kb chip. by default Macronix ids are used.
+    pub fn set_flash_ids(&mut self, manufacturer: u8, device: u8) {
+        self.bus.flash_ids = Some((manufacturer, device));
+    }
+
+    /// game code from the cartridge header, used to look up its GameConfig
+    pub fn game_code(&self) -> GameCode {
+        core::array::from_fn(|i| {
+            self.bus
+                .read_byte_raw(game_config::GAME_CODE_OFFSET + i, MemoryRegion::Cartridge)
+        })
+    }
+
+    /// applies per-game overrides, eg. from GameConfig::lookup. a cartridge type given by the user
+    /// when constructing the emulator is kept. should be called before the first frame
+    pub fn apply_game_config(&mut self, config: &GameConfig) {
+        if let Some(cartridge_type) = config.cartridge_type {
+            if self.bus.cartridge_type_source != CartridgeTypeSource::Override {
+                self.bus.cartridge_type = cartridge_type;
+                self.bus.cartridge_type_source = CartridgeTypeSource::GameConfig;
+                self.bus.eeprom_size_known = true;
+            }
+        }
+        if let Some((manufacturer, device)) = config.flash_ids {
+            self.set_flash_ids(manufacturer, device);
+        }
+        if let Some(idle_skip) = config.idle_skip {
+            self.set_idle_skip(idle_skip);
+        }
+        let hardware = config.hardware;
+        for (present, name) in [
+            (hardware.rtc, "RTC"),
+            (hardware.solar_sensor, "solar sensor"),
+            (hardware.tilt, "tilt sensor"),
+            (hardware.gyro, "gyro sensor"),
+            (hardware.rumble, "rumble"),
+        ] {
+            if present {
+                warn!(
+                    target: TARGET_BUS,
+                    "game uses a cartridge {}, which is not emulated",
+                    name
+                );
+            }
+        }
+    }
+
+    /// process_frame returns an error if a single call runs for more than `max_cycles` cpu cycles. 0 disables the limit.
+    pub fn set_max_cycles_per_frame(&mut self, max_cycles: u64) {
+        self.max_cycles_per_frame = if max_cycles == 0 {
+            None
+        } else {
+            Some(max_cycles)
+        };
+    }
+
+    /// when enabled, process_frame returns an error once the cpu branches to itself with IRQs masked
+    pub fn set_deadlock_detection(&mut self, enable: bool) {
+        self.deadlock_detection = enable;
+        self.cpu.self_branch_detected = false;
+    }
+
+    /// when enabled, a halted cpu or one spinning in a branch to itself is fast-forwarded to the next
+    /// timer/apu/ppu event instead of sleeping for a fixed number of cycles at a time
+    pub fn set_idle_skip(&mut self, enable: bool) {
+        self.idle_skip = enable;
+        self.cpu.idle_skip = enable;
+        self.cpu.sleep_cycles = config::CPU_HALT_SLEEP_CYCLES;
+    }
+
+    /// experimental: opcode fetches take the wait states of the memory they are read from (WAITCNT
+    /// for the game pak, the internal memory control register for EWRAM), including the game pak
+    /// prefetch buffer. data accesses to work RAM take theirs too: none for the 32 bit IWRAM, and
+    /// those of the 16 bit EWRAM, for each halfword. when disabled (the default), every access
+    /// takes a single cycle
+    pub fn set_memory_timing(&mut self, enable: bool) {
+        self.bus.memory_timing = enable;
+        self.bus.prefetch.flush();
+    }
+
+    /// sets the timing features together. Accuracy::Fast (the default) disables memory timing and idle
+    /// skip. Accuracy::Accurate enables them:
+    /// - set_memory_timing: opcode fetches and work RAM accesses take the wait states of their
+    ///   memory, including the game pak prefetch buffer
+    /// - set_idle_skip: halts and branches to self run until the next timer/apu/ppu event
+    ///
+    /// the individual setters can still be used afterwards
+    pub fn set_accuracy(&mut self, accuracy: Accuracy) {
+        let accurate = accuracy == Accuracy::Accurate;
+        self.set_memory_timing(accurate);
+        self.set_idle_skip(accurate);
+    }
+
+    /// Accuracy::Accurate if all of the features of the profile are enabled, see set_accuracy
+    pub fn accuracy(&self) -> Accuracy {
+        if self.bus.memory_timing && self.idle_skip {
+            Accuracy::Accurate
+        } else {
+            Accuracy::Fast
+        }
+    }
+
+    /// level of the logging from the hot paths: undefined instructions, forbidden memory accesses,
+    /// invalid DMA/sound/video configurations and so on. these are off by default, since a game that
+    /// triggers one usually does so every frame. the ones a game can hit on every access (eg. BIOS
+    /// reads from outside the BIOS) log at debug instead of warn. messages are logged with a target
+    /// per subsystem, see TARGET_CPU and friends. the level is global, not per emulator
+    pub fn set_log_level(&mut self, level: LevelFilter) {
+        trace::set_hot_log_level(level);
+    }
+
+    pub fn log_level(&self) -> LevelFilter {
+        trace::hot_log_level()
+    }
+
+    /// starts counting the reads and writes of the cpu and DMA per memory region, and per 4KiB page
+    /// if `per_page` is set. any previous counts are discarded. a halfword or word access counts
+    /// once, and peeks and pokes are not counted. per page counting is much slower than per region
+    pub fn enable_access_profiler(&mut self, per_page: bool) {
+        self.bus.access_profile = Some(Box::new(AccessProfile::new(per_page)));
+    }
+
+    pub fn disable_access_profiler(&mut self) {
+        self.bus.access_profile = None;
+    }
+
+    /// counts since the profiler was enabled. empty if it is disabled
+    pub fn access_profile(&self) -> AccessProfile {
+        self.bus
+            .access_profile
+            .as_deref()
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// what work RAM, palette, VRAM and OAM hold before the game writes them. applied right away,
+    /// so call it before the first frame. load_rom fills with it again. the default is Zero
+    pub fn set_uninit_fill(&mut self, pattern: UninitFill) {
+        self.bus.uninit_fill = pattern;
+        self.bus.fill_uninit();
+    }
+
+    /// times every process_frame call with clock from now on, see frame_time_stats. in speedup mode
+    /// a call covers several emulated frames
+    pub fn enable_frame_timing(&mut self, clock: FrameClock) {
+        self.frame_timing = Some(FrameTiming::new(clock));
+    }
+
+    pub fn disable_frame_timing(&mut self) {
+        self.frame_timing = None;
+    }
+
+    /// min, median, 95th percentile, max and average wall time of the recent process_frame calls
+    /// that succeeded. all 0 if frame timing is disabled
+    pub fn frame_time_stats(&self) -> FrameTimeStats {
+        self.frame_timing
+            .as_ref()
+            .map(FrameTiming::stats)
+            .unwrap_or_default()
+    }
+
+    /// experimental: affine and bitmap backgrounds are supersampled n*n times per pixel and averaged,
+    /// which smooths rotated and scaled graphics. tiled backgrounds and sprites are unaffected. this
+    /// is an approximation, not hardware accurate, and those layers cost n*n times as much to draw.
+    /// 1 (the default) renders normally; 0 is treated as 1, and n is capped at
+    /// config::MAX_INTERNAL_SCALE (8)
+    pub fn set_internal_scale(&mut self, n: u32) {
+        self.ppu.internal_scale = n.clamp(1, config::MAX_INTERNAL_SCALE);
+    }
+
+    // number of cycles until the next workflow other than the cpu needs to run
+    fn cycles_until_next_event(&self) -> u32 {
+        let cpu_time = self.scheduler.time(Workflow::Cpu);
+        let mut next = u32::MAX;
+        for (time, workflow) in self.scheduler.iter() {
+            match workflow {
+                Workflow::Cpu => continue,
+                Workflow::Timer if !self.bus.is_any_timer_active => continue,
+                _ => next = next.min(time),
+            }
+        }
+        next.saturating_sub(cpu_time).max(1)
+    }
+
+    /// memory region that a cpu read from `addr` would decode to, taking mirroring into account.
+    /// does not access memory.
+    pub fn classify_address(&self, addr: usize) -> MemoryRegion {
+        self.bus.classify_address(addr)
+    }
+
+    // -------- IO register viewing. these read the IO region directly and never trigger side effects.
+    //          addresses can be given either as 0x04000000-based addresses or as offsets into IO.
+    //          note: write-only registers read back the last value written by the game, which
+    //          hardware would not return. see io_register_is_write_only.
+
+    /// halfword at the (halfword aligned) IO address
+    pub fn io_register(&self, addr: u32) -> u16 {
+        self.bus
+            .read_halfword_raw((addr & 0x3fe) as usize, MemoryRegion::IO)
+    }
+
+    /// `len` bytes of IO starting at `start`. stops at the end of the IO region.
+    pub fn dump_io_block(&self, start: u32, len: usize) -> Vec<u8> {
+        let start = (start & 0x3ff) as usize;
+        let end = (start + len).min(0x400);
+        (start..end)
+            .map(|addr| self.bus.read_byte_raw(addr, MemoryRegion::IO))
+            .collect()
+    }
+
+    /// copy of the memory backing `region`, eg. MemoryRegion::BoardWram for all of EWRAM. the save
+    /// memory is MemoryRegion::CartridgeSram. empty for the regions that are not plain memory
+    /// (Illegal, CartridgeUpper and MemoryControl)
+    pub fn dump_region(&self, region: MemoryRegion) -> Vec<u8> {
+        self.bus
+            .region_mem(region)
+            .map(|mem| mem.to_vec())
+            .unwrap_or_default()
+    }
+
+    /// overwrites the memory backing `region` with `data`, which must be as long as the region (see
+    /// dump_region). the bytes are copied as they are, without the side effects of cpu writes, so
+    /// loading IO does not eg. start DMAs or timers. the BIOS and cartridge ROM are read-only
+    pub fn load_region(&mut self, region: MemoryRegion, data: &[u8]) -> Result<(), &'static str> {
+        if region == MemoryRegion::Bios || region == MemoryRegion::Cartridge {
+            return Err("region is read-only");
+        }
+        let mem = self
+            .bus
+            .region_mem_mut(region)
+            .ok_or("region is not backed by memory")?;
+        if mem.len() != data.len() {
+            return Err("data length does not match the region size");
+        }
+        mem.copy_from_slice(data);
+        match region {
+            MemoryRegion::Palette => self.bus.graphics_dirty.palette = true,
+            MemoryRegion::Vram => self.bus.graphics_dirty.vram = true,
+            MemoryRegion::Oam => self.bus.graphics_dirty.oam = true,
+            MemoryRegion::CartridgeSram => self.bus.save_dirty = true,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// true if the game cannot read back the IO register at `addr`
+    pub fn io_register_is_write_only(addr: u32) -> bool {
+        match addr & 0x3fe {
+            0x10..=0x1e => true, // BGxHOFS, BGxVOFS
+            0x20..=0x3e => true, // BG2/BG3 affine parameters and reference points
+            0x40..=0x46 => true, // WIN0H, WIN1H, WIN0V, WIN1V
+            0x4c | 0x4e => true, // MOSAIC
+            0x54 => true,        // BLDY
+            0x56..=0x5e => true, // unused
+            0xa0..=0xa6 => true, // FIFO_A, FIFO_B
+            0xb0..=0xb8 | 0xbc..=0xc4 | 0xc8..=0xd0 | 0xd4..=0xdc => true, // DMA source, dest, count
+            _ => false,
+        }
+    }
+
+    // -------- video state set by the game in DISPCNT. like the IO register viewing above, these
+    //          have no side effects. the game's settings can be changed with poke16(0x04000000, ..)
+
+    /// BG mode, 0-5. modes 0-2 are tiled, 3-5 are bitmap modes
+    pub fn video_mode(&self) -> u8 {
+        (self.io_register(0) & 0b111) as u8
+    }
+
+    /// true while the game has forced blank on, in which case the screen is white
+    pub fn forced_blank(&self) -> bool {
+        (self.io_register(0) >> 7) & 1 > 0
+    }
+
+    /// which of BG0-3 the game has enabled. the current video mode may not use all of them
+    pub fn bg_enabled(&self) -> [bool; 4] {
+        let dispcnt = self.io_register(0);
+        core::array::from_fn(|i| (dispcnt >> (8 + i)) & 1 > 0)
+    }
+
+    /// true if the game has enabled sprites
+    pub fn obj_enabled(&self) -> bool {
+        (self.io_register(0) >> 12) & 1 > 0
+    }
+
+    // -------- memory search for cheats
+
+    /// addresses in `regions` holding `value`, aligned to `size`. starts a new search, which can then
+    /// be narrowed down with refine_search after advancing the game.
+    pub fn search_memory(
+        &mut self,
+        value: u32,
+        size: ChunkSize,
+        regions: &[MemoryRegion],
+    ) -> Vec<usize> {
+        let (search, res) = MemorySearch::new(&self.bus, value, size, regions);
+        self.memory_search = Some(search);
+        res
+    }
+
+    /// addresses in `previous` that satisfy `predicate`, comparing against the values at the last
+    /// search or refine. returns nothing if search_memory has not been called.
+    pub fn refine_search(&mut self, previous: &[usize], predicate: SearchPredicate) -> Vec<usize> {
+        match self.memory_search.as_mut() {
+            Some(search) => search.refine(&self.bus, previous, predicate),
+            None => Vec::new(),
+        }
+    }
+
+    // -------- memory access for scripting and testing. these go through the same path as cpu
+    //          accesses, so IO side effects and the save memory state machines apply.
+    //          unaligned addresses are force-aligned.
+
+    pub fn peek8(&mut self, addr: u32) -> u8 {
+        self.unprofiled(|bus| bus.read_byte(addr as usize))
+    }
+
+    pub fn peek16(&mut self, addr: u32) -> u16 {
+        self.unprofiled(|bus| bus.read_halfword((addr & !0b1) as usize))
+    }
+
+    pub fn peek32(&mut self, addr: u32) -> u32 {
+        self.unprofiled(|bus| bus.read_word((addr & !0b11) as usize))
+    }
+
+    // checked variants of peek; misaligned or unmapped addresses return an error instead of panicking
+
+    pub fn try_read_byte(&mut self, addr: u32) -> Result<u8, BusError> {
+        self.bus.try_read_byte(addr as usize)
+    }
+
+    pub fn try_read_halfword(&mut self, addr: u32) -> Result<u16, BusError> {
+        self.bus.try_read_halfword(addr as usize)
+    }
+
+    pub fn try_read_word(&mut self, addr: u32) -> Result<u32, BusError> {
+        self.bus.try_read_word(addr as usize)
+    }
+
+    pub fn poke8(&mut self, addr: u32, val: u8) {
+        self.unprofiled(|bus| bus.store_byte(addr as usize, val));
+    }
+
+    pub fn poke16(&mut self, addr: u32, val: u16) {
+        self.unprofiled(|bus| bus.store_halfword((addr & !0b1) as usize, val));
+    }
+
+    pub fn poke32(&mut self, addr: u32, val: u32) {
+        self.unprofiled(|bus| bus.store_word((addr & !0b11) as usize, val));
+    }
+
+    // peeks and pokes are not the game's accesses, so they are kept out of the access profile
+    fn unprofiled<T>(&mut self, f: impl FnOnce(&mut Bus) -> T) -> T {
+        let profile = self.bus.access_profile.take();
+        let res = f(&mut self.bus);
+        self.bus.access_profile = profile;
+        res
+    }
+
+    /// for differential fuzzing: executes exactly one instruction from `state` on a fresh cpu, in an
+    /// isolated 32 bit address space that holds only `mem_writes` (other bytes read as 0) and has
+    /// no IO, interrupts or DMA. the instruction itself is fetched from that memory at
+    /// state.r[15]. returns the resulting registers and every byte the instruction wrote, in
+    /// address order. the emulator itself is not involved, so the result only depends on the
+    /// arguments
+    pub fn fuzz_exec_one(state: &CpuState, mem_writes: &[(u32, u8)]) -> (CpuState, Vec<(u32, u8)>) {
+        let mut mem = TestMemory::with_bytes(mem_writes);
+        let (state, _) = Cpu::execute_single_for_test(state, &mut mem);
+        (state, mem.written())
     }
 
     pub fn init(&mut self, current_time: u64) {
@@ -147,43 +960,90 @@ impl GBA {
         self.started = true;
     }
 
+    /// same as init, but uses a fixed baseline of 0 instead of the host clock. to keep runs reproducible,
+    /// pass emulated time (eg. frames passed * frame duration) to process_frame rather than wall time.
+    /// nothing game-visible is seeded from the host clock at the moment (there is no RTC).
+    pub fn init_deterministic(&mut self) {
+        self.init(0);
+    }
+
+    /// non-blocking variant of process_frame for event loop driven frontends: only runs a frame if
+    /// the emulator clock is not ahead of current_time. see also FramePacer
+    pub fn pump_frame(&mut self, current_time: u64) -> Result<FrameResult, &'static str> {
+        if self.last_finished_time > current_time {
+            return Ok(FrameResult::NotDue {
+                micros_until_due: self.last_finished_time - current_time,
+            });
+        }
+        let micros_ahead = self.process_frame(current_time)?;
+        Ok(FrameResult::Ran {
+            micros_ahead,
+            audio_samples: self.last_frame_audio_samples,
+        })
+    }
+
     /// on successful frame, returns the number of microseconds that the emulator clock is ahead of the supposed true GBA clock
+    ///
+    /// while the Speedup key is held, frames run as fast as the host allows: the returned value is
+    /// always 0, only every config::FRAME_RENDER_INTERVAL_SPEEDUP-th frame is rendered (and returned
+    /// from), audio is muted and get_fps reports the actual uncapped rate. the key is checked at the
+    /// end of each frame; on release, pacing restarts from current_time, so there is no catch up
     pub fn process_frame(&mut self, current_time: u64) -> Result<u64, &'static str> {
-        loop {
-            let mut cur_min = 100_000_000;
-            let mut cur_ans = Workflow::Timer;
-            for x in self.workflow_times.iter() {
-                if x.0 < cur_min {
-                    cur_min = x.0;
-                    cur_ans = x.1;
-                }
-            }
+        let start = self.frame_timing.as_mut().map(FrameTiming::now);
+        let result = self.run_frame(current_time);
+        if let (Some(start), Some(timing), Ok(_)) = (start, self.frame_timing.as_mut(), &result) {
+            timing.record(start);
+        }
+        result
+    }
 
-            match cur_ans {
+    fn run_frame(&mut self, current_time: u64) -> Result<u64, &'static str> {
+        self.apply_scheduled_inputs();
+        loop {
+            match self.scheduler.next() {
                 Workflow::Timer => {
                     self.bus.timer_clock();
-                    self.workflow_times[0].0 += config::TIMER_CLOCK_INTERVAL_CLOCKS;
-                }
-                Workflow::DMA => {
-                    // let res = self.bus.dma_clock();
-                    // self.workflow_times[1].0 += res;
-                    self.workflow_times[1].0 += config::DMA_CHECK_INTERVAL_CLOCKS
+                    self.scheduler
+                        .schedule_in(Workflow::Timer, config::TIMER_CLOCK_INTERVAL_CLOCKS);
                 }
                 Workflow::Cpu => {
-                    self.workflow_times[2].0 += self.bus.cpu_clock();
+                    if self.idle_skip && self.cpu.is_idle() {
+                        self.cpu.sleep_cycles = self.cycles_until_next_event();
+                    }
+                    let clocks = self.cpu.clock(&mut self.bus);
+                    self.scheduler.schedule_in(Workflow::Cpu, clocks);
+
+                    self.cycles_this_frame += clocks as u64;
+                    if let Some(max_cycles) = self.max_cycles_per_frame {
+                        if self.cycles_this_frame > max_cycles {
+                            self.cycles_this_frame = 0;
+                            return Err("watchdog tripped: frame exceeded its cycle budget");
+                        }
+                    }
+                    if self.deadlock_detection && self.cpu.self_branch_detected {
+                        self.cpu.self_branch_detected = false;
+                        self.cycles_this_frame = 0;
+                        return Err("watchdog tripped: branch to self with IRQs masked");
+                    }
                 }
                 Workflow::Apu => {
                     self.bus.apu_clock();
-                    self.workflow_times[3].0 += config::AUDIO_SAMPLE_CLOCKS;
+                    self.scheduler
+                        .schedule_in(Workflow::Apu, config::AUDIO_SAMPLE_CLOCKS);
                 }
                 Workflow::Ppu => {
-                    self.workflow_times[4].0 += self.ppu.clock(&mut self.bus);
+                    let clocks = self.ppu.clock(&mut self.bus);
+                    self.scheduler.schedule_in(Workflow::Ppu, clocks);
                     if self.ppu.buffer_ready {
                         self.on_new_buffer(current_time);
+                        self.cycles_this_frame = 0;
+                        self.last_frame_audio_samples = self.bus.apu.take_samples_out();
 
-                        //info!("arm count: {}, thumb count: {}", self.bus.cpu.arm_cnt, self.bus.cpu.thumb_cnt);
+                        //info!("arm count: {}, thumb count: {}", self.cpu.arm_cnt, self.cpu.thumb_cnt);
 
-                        return Ok(if self.last_finished_time > current_time {
+                        return Ok(if self.speedup {
+                            0
+                        } else if self.last_finished_time > current_time {
                             self.last_finished_time - current_time
                         } else {
                             0
@@ -191,7 +1051,7 @@ impl GBA {
                     }
                 }
                 Workflow::Normaliser => {
-                    if !self.input_handler.cur_speedup_state {
+                    if !self.speedup {
                         self.last_finished_time += config::CPU_EXECUTION_INTERVAL_US;
                     }
 
@@ -211,17 +1071,16 @@ impl GBA {
                     }
                     #[cfg(feature = "debug_instr")]
                     {
-                        self.bus.cpu.debug_cnt += 50;
+                        self.cpu.debug_cnt += 50;
                     }
 
                     // roughly every second in real-time, we want to normalize all the values in the array
-                    if self.workflow_times[5].0 >= config::CPU_EXECUTION_INTERVAL_CLOCKS * 60 {
-                        let min = self.workflow_times[5].0;
-                        self.workflow_times.iter_mut().for_each(|x| x.0 -= min);
-                        self.workflow_times[5].0 = config::CPU_EXECUTION_INTERVAL_CLOCKS;
-                    } else {
-                        self.workflow_times[5].0 += config::CPU_EXECUTION_INTERVAL_CLOCKS;
+                    let normaliser_time = self.scheduler.time(Workflow::Normaliser);
+                    if normaliser_time >= config::CPU_EXECUTION_INTERVAL_CLOCKS * 60 {
+                        self.scheduler.rebase(normaliser_time);
                     }
+                    self.scheduler
+                        .schedule_in(Workflow::Normaliser, config::CPU_EXECUTION_INTERVAL_CLOCKS);
                 }
             }
         }
@@ -232,17 +1091,24 @@ impl GBA {
     fn on_new_buffer(&mut self, current_time: u64) {
         // handle input once per frame
         //self.input_handler.process_input(&self.key_receiver, &mut self.bus);
-        self.input_handler.commit(&mut self.bus);
-        if self.input_handler.cur_speedup_state != self.input_handler.prev_speedup_state {
-            self.bus.apu.extern_audio_enabled = self.input_handler.prev_speedup_state;
-            if !self.input_handler.cur_speedup_state {
-                //self.last_finished_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-                self.last_finished_time = current_time;
-                self.ppu.frame_count_render = 1;
+        if self.bus.keypad_provider.is_none() {
+            self.input_handler.commit(&mut self.bus);
+        }
+        if self.input_handler.cur_speedup_state != self.speedup {
+            self.speedup = self.input_handler.cur_speedup_state;
+            self.bus.apu.set_extern_audio_enabled(!self.speedup);
+            //self.last_finished_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            self.last_finished_time = current_time;
+            // start a new fps measurement, so that it doesn't mix both modes
+            self.last_fps_print_time = current_time;
+            self.frame_counter = 0;
+            self.ppu.set_frame_count_render(if self.speedup {
+                config::FRAME_RENDER_INTERVAL_SPEEDUP
             } else {
-                self.ppu.frame_count_render = config::FRAME_RENDER_INTERVAL_SPEEDUP;
-            }
+                1
+            });
         }
+        self.check_save_write();
         for i in 0..config::NUM_SAVE_STATES {
             if self.input_handler.save_requested[i] {
                 self.bus.export_sram(&mut self.save_state[i]);
@@ -252,7 +1118,520 @@ impl GBA {
         }
     }
 
+    /// number of stereo samples that the last frame from process_frame added to the sound buffer,
+    /// at the output sample rate. audio is resampled in fixed size chunks, so this is a multiple of
+    /// the chunk size and varies from frame to frame (eg. 0 or 1125 samples at 48kHz), even though
+    /// it averages out to sample_rate / 59.73 per frame. 0 while in speedup mode
+    pub fn last_frame_audio_samples(&self) -> usize {
+        self.last_frame_audio_samples
+    }
+
     pub fn total_frames_passed(&self) -> u64 {
         self.total_frames_passed
     }
+
+    /// runs frames until total_frames_passed reaches `target`. emulated time is used as the clock, so
+    /// this is deterministic. note: in speedup mode only every few frames are rendered, so the count
+    /// may overshoot the target.
+    pub fn run_until_frame(&mut self, target: u64) -> Result<(), &'static str> {
+        if self.total_frames_passed > target {
+            return Err("target frame has already passed");
+        }
+        while self.total_frames_passed < target {
+            let current_time = self.last_finished_time;
+            self.process_frame(current_time)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{config, InterruptSource, GBA};
+    use crate::bus::{CartridgeType, CartridgeTypeSource, GraphicsDirty};
+    use crate::error::InitError;
+    use crate::input_handler::KeyInput;
+    use crate::test_util::{bios, gba_with_bios, run_frames};
+    use crate::uninit_fill::UninitFill;
+    use alloc::{vec, vec::Vec};
+
+    // the cpu is clocked with a plain borrow of the bus, which `cargo miri test` checks. a few
+    // frames are enough to go through the cpu, bus, dma, ppu and apu paths while staying fast
+    // enough for miri
+    #[test]
+    fn runs_frames() {
+        // r6 counts loop iterations in IWRAM
+        let mut gba = gba_with_bios(&[(
+            0,
+            &[
+                0xe3a05403, // mov r5, #0x3000000
+                0xe5956000, // ldr r6, [r5]
+                0xe2866001, // add r6, r6, #1
+                0xe5856000, // str r6, [r5]
+                0xeafffffb, // b 0x4
+            ],
+        )]);
+        run_frames(&mut gba, 2);
+        assert!(gba.peek32(0x3000000) > 1000);
+        assert_eq!(gba.total_frames_passed(), 2);
+    }
+
+    #[test]
+    fn watchdog_trips_on_branch_to_self_with_irqs_masked() {
+        let mut gba = gba_with_bios(&[(
+            0,
+            &[
+                0xe321f0d3, // msr cpsr_c, #0xd3 (svc, IRQs masked)
+                0xeafffffe, // b .
+            ],
+        )]);
+        gba.set_deadlock_detection(true);
+        assert_eq!(
+            gba.process_frame(0),
+            Err("watchdog tripped: branch to self with IRQs masked")
+        );
+    }
+
+    #[test]
+    fn watchdog_trips_on_cycle_budget() {
+        let mut gba = gba_with_bios(&[(0, &[0xeafffffe])]); // b .
+        gba.set_max_cycles_per_frame(10000);
+        assert_eq!(
+            gba.process_frame(0),
+            Err("watchdog tripped: frame exceeded its cycle budget")
+        );
+
+        // a frame takes 280896 cycles
+        gba.set_max_cycles_per_frame(300000);
+        assert!(gba.process_frame(0).is_ok());
+    }
+
+    #[test]
+    fn running_program_reads_poked_value() {
+        let mut gba = gba_with_bios(&[(
+            0,
+            &[
+                0xe3a05403, // mov r5, #0x3000000
+                0xe5956000, // ldr r6, [r5]
+                0xe5856004, // str r6, [r5, #4]
+                0xeafffffc, // b 0x4
+            ],
+        )]);
+        gba.poke32(0x3000000, 0x12345678);
+        run_frames(&mut gba, 1);
+        assert_eq!(gba.peek32(0x3000004), 0x12345678);
+        assert_eq!(gba.peek16(0x3000006), 0x1234);
+        assert_eq!(gba.peek8(0x3000004), 0x78);
+    }
+
+    #[test]
+    fn poking_dma_control_starts_transfer() {
+        let mut gba = gba_with_bios(&[(0, &[0xeafffffe])]); // b .
+        for i in 0..4 {
+            gba.poke32(0x3000100 + i * 4, 0x11111111 * (i + 1));
+        }
+        gba.poke32(0x40000d4, 0x3000100); // DMA3SAD
+        gba.poke32(0x40000d8, 0x3000200); // DMA3DAD
+        gba.poke32(0x40000dc, 0x84000004); // 4 words, immediately
+        run_frames(&mut gba, 1);
+        for i in 0..4 {
+            assert_eq!(gba.peek32(0x3000200 + i * 4), 0x11111111 * (i + 1));
+        }
+        // the channel disables itself once done
+        assert_eq!(gba.peek16(0x40000de) >> 15, 0);
+    }
+
+    #[test]
+    fn flags_graphics_written_since_last_query() {
+        let mut gba = gba_with_bios(&[(
+            0,
+            &[
+                0xe3a00405, // mov r0, #0x5000000
+                0xe1c000b0, // strh r0, [r0]
+                0xeafffffe, // b .
+            ],
+        )]);
+        run_frames(&mut gba, 1);
+        assert_eq!(
+            gba.graphics_dirty(),
+            GraphicsDirty {
+                palette: true,
+                ..Default::default()
+            }
+        );
+        // the query clears the flags
+        assert_eq!(gba.graphics_dirty(), GraphicsDirty::default());
+        run_frames(&mut gba, 1);
+        assert_eq!(gba.graphics_dirty(), GraphicsDirty::default());
+    }
+
+    // copies KEYINPUT to IWRAM
+    fn keyinput_gba() -> GBA {
+        gba_with_bios(&[(
+            0,
+            &[
+                0xe3a00301, // mov r0, #0x4000000
+                0xe2800c01, // add r0, r0, #0x100
+                0xe3a01403, // mov r1, #0x3000000
+                0xe1d023b0, // ldrh r2, [r0, #0x30]
+                0xe1c120b0, // strh r2, [r1]
+                0xeafffffc, // b 0xc
+            ],
+        )])
+    }
+
+    // runs n frames and returns the GBA keys the game saw pressed in each, as masks
+    fn keys_seen(gba: &mut GBA, n: usize) -> Vec<u16> {
+        (0..n)
+            .map(|_| {
+                run_frames(gba, 1);
+                !gba.peek16(0x3000000) & 0x3ff
+            })
+            .collect()
+    }
+
+    #[test]
+    fn applies_scheduled_input_at_its_frame() {
+        let mut gba = keyinput_gba();
+        gba.schedule_input(2, KeyInput::A, true).unwrap();
+        gba.schedule_input(3, KeyInput::A, false).unwrap();
+        // keys are committed to KEYINPUT at the end of a frame, so the game sees an input applied at
+        // the start of frame n during frame n + 1
+        run_frames(&mut gba, 1);
+        let mut a_pressed = Vec::new();
+        for _ in 1..5 {
+            run_frames(&mut gba, 1);
+            a_pressed.push(gba.peek16(0x3000000) & 1 == 0);
+        }
+        assert_eq!(a_pressed, [false, false, true, false]);
+
+        assert!(gba.schedule_input(4, KeyInput::A, true).is_err());
+        assert!(gba.schedule_input(5, KeyInput::A, true).is_ok());
+    }
+
+    #[test]
+    fn macro_can_press_its_own_trigger() {
+        let mut gba = keyinput_gba();
+        gba.define_macro(KeyInput::A, vec![1, 1, 1]);
+        run_frames(&mut gba, 1);
+        gba.process_key(KeyInput::A, true);
+        gba.process_key(KeyInput::A, false);
+        // the game sees each frame's keys one frame later
+        assert_eq!(keys_seen(&mut gba, 5), [0, 1, 1, 1, 0]);
+    }
+
+    #[test]
+    fn macro_presses_another_trigger_as_a_plain_key() {
+        let mut gba = keyinput_gba();
+        gba.define_macro(KeyInput::L, vec![1, 1]);
+        gba.define_macro(KeyInput::A, vec![1 << KeyInput::B as u16]);
+        run_frames(&mut gba, 1);
+        gba.process_key(KeyInput::L, true);
+        assert_eq!(keys_seen(&mut gba, 4), [0, 1, 1, 0]);
+    }
+
+    #[test]
+    fn trigger_during_playback_is_ignored() {
+        let mut gba = keyinput_gba();
+        let (a, b) = (1 << KeyInput::A as u16, 1 << KeyInput::B as u16);
+        gba.define_macro(KeyInput::L, vec![a, a]);
+        gba.define_macro(KeyInput::R, vec![b]);
+        run_frames(&mut gba, 1);
+        gba.process_key(KeyInput::L, true);
+        run_frames(&mut gba, 1);
+        gba.process_key(KeyInput::R, true);
+        assert_eq!(keys_seen(&mut gba, 3), [a, a, 0]);
+
+        // once the first macro is done, the other one plays
+        gba.process_key(KeyInput::R, true);
+        assert_eq!(keys_seen(&mut gba, 3), [0, b, 0]);
+    }
+
+    #[test]
+    fn sample_rate_sets_samples_per_frame() {
+        let mut gba = gba_with_bios(&[(0, &[0xeafffffe])]); // b .
+        let samples_in_20_frames = |gba: &mut GBA| {
+            (0..20)
+                .map(|_| {
+                    run_frames(gba, 1);
+                    gba.reset_sound_buffer();
+                    gba.last_frame_audio_samples()
+                })
+                .sum::<usize>()
+        };
+        // a third of a second of audio, give or take a chunk
+        for sample_rate in [48000, 24000, 32768] {
+            gba.set_sample_rate(sample_rate).unwrap();
+            let samples = samples_in_20_frames(&mut gba);
+            let expected = sample_rate * 20 * 100 / 5973;
+            assert!(samples.abs_diff(expected) < sample_rate / 40, "{}", samples);
+        }
+
+        assert_eq!(gba.set_sample_rate(0), Err(InitError::InvalidSampleRate));
+    }
+
+    #[test]
+    fn load_rom_swaps_game_and_returns_previous_save() {
+        let rom = |game_code: &[u8], save_id: &[u8]| {
+            let mut rom = vec![0; 0x200];
+            rom[0xac..0xb0].copy_from_slice(game_code);
+            rom[0x100..0x100 + save_id.len()].copy_from_slice(save_id);
+            rom
+        };
+        let mut gba = GBA::try_new(
+            &bios(&[(0, &[0xeafffffe])]), // b .
+            &rom(b"AAAA", b"SRAM_V"),
+            None,
+            None,
+            None,
+            48000,
+        )
+        .unwrap();
+        gba.poke8(0xe000000, 0x42);
+        run_frames(&mut gba, 1);
+        assert_eq!(&gba.game_code(), b"AAAA");
+
+        let previous_save = gba
+            .load_rom(&rom(b"BBBB", b"FLASH1M_V"), None, None, None)
+            .unwrap();
+        assert_eq!(previous_save.unwrap()[0][0], 0x42);
+        assert_eq!(&gba.game_code(), b"BBBB");
+        assert_eq!(
+            gba.cartridge_type(),
+            (CartridgeType::Flash128, CartridgeTypeSource::Detected)
+        );
+        assert_eq!(gba.peek8(0xe000000), 0);
+    }
+
+    #[test]
+    fn speedup_runs_unpaced_frames_without_audio() {
+        let mut gba = gba_with_bios(&[(0, &[0xeafffffe])]); // b .
+
+        // runs until the next rendered frame, returning the sleep hint and the frames that passed
+        let frame = |gba: &mut GBA| {
+            let frames = gba.total_frames_passed();
+            let sleep = gba.process_frame(0).unwrap();
+            assert!(gba.get_screen_buffer().is_some());
+            (sleep, gba.total_frames_passed() - frames)
+        };
+        frame(&mut gba);
+        // the host clock stands still, so the emulator gets ahead of it
+        assert!(frame(&mut gba).0 > 0);
+
+        // the key is picked up at the end of the next frame, and the frame after a toggle is always
+        // rendered
+        gba.process_key(KeyInput::Speedup, true);
+        frame(&mut gba);
+        assert_eq!(frame(&mut gba), (0, 1));
+        for _ in 0..3 {
+            let interval = config::FRAME_RENDER_INTERVAL_SPEEDUP as u64;
+            assert_eq!(frame(&mut gba), (0, interval));
+            assert_eq!(gba.last_frame_audio_samples(), 0);
+        }
+
+        gba.process_key(KeyInput::Speedup, false);
+        frame(&mut gba);
+        let (sleep, frames) = frame(&mut gba);
+        assert!(sleep > 0);
+        assert_eq!(frames, 1);
+    }
+
+    #[test]
+    fn raised_timer_interrupt_enters_irq_vector() {
+        let mut gba = gba_with_bios(&[
+            (0, &[0xea00003e]), // b 0x100
+            (
+                0x18,
+                &[
+                    0xe10f0000, // mrs r0, cpsr
+                    0xe3a01403, // mov r1, #0x3000000
+                    0xe5810000, // str r0, [r1]
+                    0xe581e004, // str lr, [r1, #4]
+                    0xeafffffe, // b .
+                ],
+            ),
+            (
+                0x100,
+                &[
+                    0xe3a00301, // mov r0, #0x4000000
+                    0xe2802c02, // add r2, r0, #0x200
+                    0xe3a01008, // mov r1, #8
+                    0xe1c210b0, // strh r1, [r2] (IE: timer 0)
+                    0xe3a01001, // mov r1, #1
+                    0xe5821008, // str r1, [r2, #8] (IME)
+                    0xeafffffe, // b .
+                ],
+            ),
+        ]);
+        run_frames(&mut gba, 1);
+        assert_eq!(gba.peek32(0x3000000), 0);
+
+        gba.raise_interrupt(InterruptSource::Timer0);
+        assert_eq!(gba.peek16(0x4000202), 1 << 3);
+        run_frames(&mut gba, 1);
+        // IRQ mode, ARM state, IRQs masked, returning after the b . at 0x118
+        assert_eq!(gba.peek32(0x3000000) & 0xff, 0x92);
+        assert_eq!(gba.peek32(0x3000004), 0x11c);
+    }
+
+    #[test]
+    fn enabling_ime_in_the_handler_does_not_reenter_it() {
+        let mut gba = gba_with_bios(&[
+            (0, &[0xea00003e]), // b 0x100
+            (
+                0x18,
+                &[
+                    0xe5934000, // ldr r4, [r3] (count the calls)
+                    0xe2844001, // add r4, r4, #1
+                    0xe5834000, // str r4, [r3]
+                    0xe5821008, // str r1, [r2, #8] (IME, with IF still set)
+                    0xe3a05008, // mov r5, #8
+                    0xe1c250b2, // strh r5, [r2, #2] (IF: acknowledge timer 0)
+                    0xe25ef004, // subs pc, lr, #4
+                ],
+            ),
+            (
+                0x100,
+                &[
+                    0xe3a00301, // mov r0, #0x4000000
+                    0xe2802c02, // add r2, r0, #0x200
+                    0xe3a03403, // mov r3, #0x3000000
+                    0xe3a01008, // mov r1, #8
+                    0xe1c210b0, // strh r1, [r2] (IE: timer 0)
+                    0xe3a01001, // mov r1, #1
+                    0xe5821008, // str r1, [r2, #8] (IME)
+                    0xeafffffe, // b .
+                ],
+            ),
+        ]);
+        run_frames(&mut gba, 1);
+        gba.raise_interrupt(InterruptSource::Timer0);
+        run_frames(&mut gba, 2);
+        // the cpu masks IRQs until the handler returns, and by then IF is clear
+        assert_eq!(gba.peek32(0x3000000), 1);
+        assert_eq!(gba.peek16(0x4000202), 0);
+        assert_eq!(gba.peek16(0x4000208), 1);
+    }
+
+    #[test]
+    fn halt_ends_on_an_enabled_request_with_ime_off() {
+        let mut gba = gba_with_bios(&[
+            (0, &[0xea00003e]), // b 0x100
+            (
+                0x18,
+                &[
+                    0xe3a03403, // mov r3, #0x3000000
+                    0xe5833004, // str r3, [r3, #4]
+                    0xeafffffe, // b .
+                ],
+            ),
+            (
+                0x100,
+                &[
+                    0xe3a00301, // mov r0, #0x4000000
+                    0xe2802c02, // add r2, r0, #0x200
+                    0xe3a01001, // mov r1, #1
+                    0xe1c210b0, // strh r1, [r2] (IE: vblank)
+                    0xe3a07008, // mov r7, #8
+                    0xe1c070b4, // strh r7, [r0, #4] (DISPSTAT: vblank irq)
+                    0xe3a06000, // mov r6, #0
+                    0xe5c06301, // strb r6, [r0, #0x301] (HALTCNT)
+                    0xe3a03403, // mov r3, #0x3000000
+                    0xe5831000, // str r1, [r3]
+                    0xeafffffe, // b .
+                ],
+            ),
+        ]);
+        run_frames(&mut gba, 2);
+        // woken up by the vblank request, which is not taken since IME is off
+        assert_eq!(gba.peek32(0x3000000), 1);
+        assert_eq!(gba.peek32(0x3000004), 0);
+    }
+
+    #[test]
+    fn unknown_cartridge_type_is_an_error() {
+        let res = GBA::try_new(&bios(&[]), &[0; 0x200], None, None, Some("FLASH2M"), 48000);
+        assert_eq!(
+            res.err(),
+            Some(InitError::UnknownCartridgeType("FLASH2M".into()))
+        );
+    }
+
+    #[test]
+    fn reports_cartridge_type_source() {
+        let new = |rom: &[u8], cartridge_type_str| {
+            GBA::try_new(&bios(&[]), rom, None, None, cartridge_type_str, 48000).unwrap()
+        };
+        let mut rom = vec![0; 0x200];
+        assert_eq!(
+            new(&rom, None).cartridge_type().1,
+            CartridgeTypeSource::Default
+        );
+        assert_eq!(
+            new(&rom, Some("SRAM")).cartridge_type(),
+            (CartridgeType::Sram, CartridgeTypeSource::Override)
+        );
+        rom[0x100..0x109].copy_from_slice(b"FLASH1M_V");
+        assert_eq!(
+            new(&rom, None).cartridge_type(),
+            (CartridgeType::Flash128, CartridgeTypeSource::Detected)
+        );
+    }
+
+    #[test]
+    fn random_uninit_fill_is_reproducible() {
+        let ewram = |fill| {
+            let mut gba = gba_with_bios(&[]);
+            gba.set_uninit_fill(fill);
+            (0x2000000..0x2040000)
+                .step_by(4)
+                .map(|addr| gba.peek32(addr))
+                .collect::<Vec<_>>()
+        };
+        let random = ewram(UninitFill::Random(42));
+        assert!(random.iter().any(|&word| word != 0));
+        assert_eq!(random, ewram(UninitFill::Random(42)));
+        assert_ne!(random, ewram(UninitFill::Random(43)));
+        assert!(ewram(UninitFill::Ones).iter().all(|&word| word == !0));
+    }
+
+    #[test]
+    fn set_bios_swaps_what_bios_code_reads() {
+        // copies the BIOS word at 0x40 to IWRAM. the first BIOS then spins, the second one loops
+        // back to the copy
+        let program = |loop_instr, word| {
+            bios(&[
+                (
+                    0,
+                    &[
+                        0xe3a00403, // mov r0, #0x3000000
+                        0xe3a02040, // mov r2, #0x40
+                        0xe5921000, // ldr r1, [r2]
+                        0xe5801000, // str r1, [r0]
+                        loop_instr,
+                    ],
+                ),
+                (0x40, &[word]),
+            ])
+        };
+        let mut gba = GBA::try_new(
+            &program(0xeafffffe, 0x11111111), // b .
+            &[0; 0x200],
+            None,
+            None,
+            None,
+            48000,
+        )
+        .unwrap();
+        run_frames(&mut gba, 1);
+        assert_eq!(gba.peek32(0x3000000), 0x11111111);
+
+        assert_eq!(
+            gba.set_bios(&[0; 0x100]),
+            Err(InitError::BiosWrongSize { size: 0x100 })
+        );
+        gba.set_bios(&program(0xeafffffa, 0x22222222)).unwrap(); // b 0
+        run_frames(&mut gba, 1);
+        assert_eq!(gba.peek32(0x3000000), 0x22222222);
+    }
 }
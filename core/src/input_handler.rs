@@ -1,6 +1,11 @@
-use crate::{bus::Bus, config};
+use alloc::boxed::Box;
 
-#[derive(Clone, Copy)]
+use crate::{
+    bus::{Bus, MemoryRegion},
+    config,
+};
+
+#[derive(Clone, Copy, Debug)]
 pub enum KeyInput {
     // GBA official keys
     A = 0,
@@ -23,6 +28,10 @@ pub enum KeyInput {
     Save4 = 15,
 }
 
+// number of KeyInput variants, and of those that are GBA keys (A to L)
+pub const NUM_KEY_INPUTS: usize = 16;
+pub const NUM_GBA_KEYS: u8 = 10;
+
 impl TryFrom<u8> for KeyInput {
     type Error = ();
 
@@ -55,6 +64,9 @@ impl Into<u8> for KeyInput {
     }
 }
 
+// returns the value of KEYINPUT: bit n clear means KeyInput n is pressed. see GBA::set_keypad_provider
+pub type KeypadProvider = Box<dyn FnMut() -> u16 + Send>;
+
 struct KeyBuffer(u16);
 
 impl KeyBuffer {
@@ -76,8 +88,7 @@ impl KeyBuffer {
 pub struct InputHandler {
     keybuf: KeyBuffer,
 
-    // speedup state: true means emulator is in speedup mode
-    pub prev_speedup_state: bool,
+    // whether the Speedup key is held. the GBA applies changes once per frame
     pub cur_speedup_state: bool,
 
     pub save_requested: [bool; config::NUM_SAVE_STATES],
@@ -87,7 +98,6 @@ impl InputHandler {
     pub fn new() -> InputHandler {
         InputHandler {
             keybuf: KeyBuffer::new(),
-            prev_speedup_state: false,
             cur_speedup_state: false,
             save_requested: [false; config::NUM_SAVE_STATES],
         }
@@ -127,15 +137,10 @@ impl InputHandler {
         self.save_requested[index] = true
     }
 
-    // must be called before processing keys for each frame
-    #[inline(always)]
-    pub fn frame_preprocess(&mut self) {
-        self.prev_speedup_state = self.cur_speedup_state;
-    }
-
     #[inline(always)]
     pub fn commit(&self, bus: &mut Bus) {
-        bus.store_halfword(0x04000130, self.keybuf.0);
+        // KEYINPUT is read only for the cpu
+        bus.store_halfword_raw(0x130, MemoryRegion::IO, self.keybuf.0);
     }
 
     /*pub fn process_input(&mut self, key_receiver: &Receiver<(KeyInput, bool)>, bus: &mut Bus) {
@@ -1,18 +1,63 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+// first, so the hot path logging macros are available to the other modules
+#[macro_use]
+mod trace;
 mod algorithm;
 mod apu;
 mod bus;
 mod config;
 mod cpu;
 mod dma_channel;
+mod eeprom;
+mod error;
+mod frame_timing;
+mod game_config;
 mod gba;
 mod input_handler;
+#[cfg(feature = "std")]
+mod loader;
+mod memory;
+mod pacer;
 mod ppu;
+mod prefetch;
+mod profiler;
+#[cfg(not(feature = "std"))]
+mod resampler;
+pub mod rom;
+mod save_backend;
+mod scheduler;
+mod search;
+mod snapshot;
+#[cfg(test)]
+mod test_util;
 mod timer;
+mod uninit_fill;
 mod util;
-pub use apu::SoundBufferIt;
+pub use apu::{AudioReadyCallback, SoundBufferIt, SoundChannel, NUM_SOUND_CHANNELS};
+pub use bus::{CartridgeType, CartridgeTypeSource, ChunkSize, GraphicsDirty, MemoryRegion};
+pub use config::CPU_EXECUTION_INTERVAL_CLOCKS;
 pub use config::NUM_SAVE_STATES;
 pub use config::SAVE_STATE_SIZE;
-pub use gba::GBA;
-pub use input_handler::KeyInput;
-pub use ppu::{Pixel, ScreenBuffer};
+pub use cpu::{Cpu, CpuState};
+pub use dma_channel::{DmaInfo, TimingMode};
+pub use error::{BusError, GbaError, InitError};
+pub use frame_timing::{percentile_index, FrameClock, FrameTimeStats};
+pub use game_config::{game_code, CartridgeHardware, GameCode, GameConfig};
+pub use gba::{Accuracy, FrameResult, InterruptSource, SaveWriteCallback, GBA};
+pub use input_handler::{KeyInput, KeypadProvider};
+#[cfg(feature = "std")]
+pub use loader::{default_save_path, load_from_paths, load_with_rom};
+pub use memory::{CpuBusView, MemoryInterface, TestMemory};
+pub use pacer::FramePacer;
+pub use ppu::{BorderedScreenBuffer, ColourCorrection, Pixel, PixelFormat, ScreenBuffer};
+pub use profiler::AccessProfile;
+#[cfg(feature = "std")]
+pub use save_backend::FileSaveBackend;
+pub use save_backend::{InMemorySaveBackend, SaveBackend};
+pub use search::SearchPredicate;
+pub use trace::{TARGET_APU, TARGET_BUS, TARGET_CPU, TARGET_DMA, TARGET_PPU};
+pub use uninit_fill::UninitFill;
 pub use util::marshall_save_state;
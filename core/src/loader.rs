@@ -0,0 +1,97 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use log::info;
+
+use crate::{
+    config,
+    error::GbaError,
+    save_backend::{FileSaveBackend, SaveBackend},
+    util::marshall_save_state,
+    GBA,
+};
+
+fn read_file(path: &Path) -> Result<Vec<u8>, GbaError> {
+    fs::read(path).map_err(|why| GbaError::Io {
+        path: path.display().to_string(),
+        reason: why.to_string(),
+    })
+}
+
+/// save file used for a ROM when no save path is given, eg. rustsav/game.rustsav next to game.gba
+pub fn default_save_path(rom: &Path) -> PathBuf {
+    let file_stem = rom.file_stem().unwrap_or(rom.as_os_str());
+    let mut file_name = file_stem.to_os_string();
+    file_name.push(".");
+    file_name.push(config::SAVE_FILE_EXT);
+    rom.parent()
+        .unwrap_or_else(|| Path::new(""))
+        .join(config::SAVE_FILE_DIR)
+        .join(file_name)
+}
+
+/// reads the BIOS, ROM and save file from disk and constructs the emulator. without a save path,
+/// default_save_path is used and its directory is created. a missing save file leaves the
+/// cartridge blank. the returned backend points at the save file that was read, for writing it back
+pub fn load_from_paths(
+    rom: &Path,
+    bios: &Path,
+    save: Option<&Path>,
+    save_state_bank: Option<usize>,
+    cartridge_type_str: Option<&str>,
+    audio_sample_rate: usize,
+) -> Result<(GBA, FileSaveBackend), GbaError> {
+    let rom_bin = read_file(rom)?;
+    load_with_rom(
+        &rom_bin,
+        rom,
+        bios,
+        save,
+        save_state_bank,
+        cartridge_type_str,
+        audio_sample_rate,
+    )
+}
+
+/// same as load_from_paths, for a ROM that was already read by the frontend (eg. from an archive).
+/// the ROM path is only used for the default save path
+pub fn load_with_rom(
+    rom_bin: &[u8],
+    rom: &Path,
+    bios: &Path,
+    save: Option<&Path>,
+    save_state_bank: Option<usize>,
+    cartridge_type_str: Option<&str>,
+    audio_sample_rate: usize,
+) -> Result<(GBA, FileSaveBackend), GbaError> {
+    let bios_bin = read_file(bios)?;
+
+    let save_path = match save {
+        Some(path) => path.to_path_buf(),
+        None => {
+            let path = default_save_path(rom);
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir).map_err(|why| GbaError::Io {
+                    path: dir.display().to_string(),
+                    reason: why.to_string(),
+                })?;
+            }
+            path
+        }
+    };
+    info!("rom save path: {}", save_path.display());
+    let mut save_backend = FileSaveBackend::new(save_path);
+    let save_state = save_backend.load().map(|bin| marshall_save_state(&bin));
+
+    let gba = GBA::try_new(
+        &bios_bin,
+        rom_bin,
+        save_state,
+        save_state_bank,
+        cartridge_type_str,
+        audio_sample_rate,
+    )?;
+    Ok((gba, save_backend))
+}
@@ -0,0 +1,254 @@
+// memory interface used by the cpu.
+//
+// Bus is the real implementation. Cpu::clock and the instruction execution paths are generic over
+// this trait (not dyn), so with Bus they compile to the same direct calls as before. TestMemory is
+// a sparse, little endian block of memory with no io or cartridge behaviour, so single instructions
+// can be executed without a BIOS or ROM (e.g. from a fuzzer or a file of test vectors, see
+// Cpu::execute_single_for_test).
+
+use alloc::{collections::BTreeMap, vec::Vec};
+
+use crate::bus::{Bus, MemoryRegion};
+
+// the cpu state that bus accesses depend on. the cpu is not part of the bus, so it publishes this
+// whenever it may have changed, see Cpu::update_bus_view
+#[derive(Clone, Copy, Default)]
+pub struct CpuBusView {
+    // the BIOS can only be read while the cpu executes from it
+    pub pc: u32,
+    // returned by reads of unmapped memory: the prefetched opcode
+    pub open_bus: u32,
+    // returned by reads of the BIOS from outside of it
+    pub last_fetched_bios_instr: u32,
+}
+
+pub trait MemoryInterface {
+    fn read_byte(&mut self, addr: usize) -> u8;
+    fn read_halfword(&mut self, addr: usize) -> u16;
+    fn read_word(&mut self, addr: usize) -> u32;
+    fn store_byte(&mut self, addr: usize, val: u8);
+    fn store_halfword(&mut self, addr: usize, val: u16);
+    fn store_word(&mut self, addr: usize, val: u32);
+
+    // word of the BIOS at addr, without going through the BIOS read protection. used for the value
+    // returned by protected BIOS reads
+    fn read_bios_word(&self, addr: usize) -> u32;
+
+    // dma. memories without a dma controller never have a transfer pending
+    fn dma_pending(&self) -> bool {
+        false
+    }
+
+    // executes the pending dma transfers, returns number of clock cycles
+    fn run_dma(&mut self) -> u32 {
+        0
+    }
+
+    // wait states of an opcode fetch, see Bus::opcode_fetch_waits. memories without wait states
+    // take none
+    fn opcode_fetch_waits(&mut self, _addr: usize, _size: usize) -> u32 {
+        0
+    }
+
+    // wait states of the data accesses made since the last call, see Bus::data_access_waits
+    fn data_access_waits(&mut self) -> u32 {
+        0
+    }
+
+    // called after every instruction with the cycles it took
+    fn cpu_cycles_passed(&mut self, _cycles: u32) {}
+
+    // the cpu state the next accesses see, see CpuBusView
+    fn set_cpu_view(&mut self, _view: CpuBusView) {}
+
+    // IE & IF & IME: the cpu takes an interrupt unless the I flag masks it
+    fn interrupt_requested(&self) -> bool {
+        false
+    }
+
+    // IE & IF, which ends a halt even while IME or the I flag keep the interrupt from being taken
+    fn interrupt_pending(&self) -> bool {
+        false
+    }
+
+    // true once after each write to HALTCNT that halts the cpu
+    fn take_halt_request(&mut self) -> bool {
+        false
+    }
+}
+
+impl MemoryInterface for Bus {
+    #[inline(always)]
+    fn read_byte(&mut self, addr: usize) -> u8 {
+        Bus::read_byte(self, addr)
+    }
+
+    #[inline(always)]
+    fn read_halfword(&mut self, addr: usize) -> u16 {
+        Bus::read_halfword(self, addr)
+    }
+
+    #[inline(always)]
+    fn read_word(&mut self, addr: usize) -> u32 {
+        Bus::read_word(self, addr)
+    }
+
+    #[inline(always)]
+    fn store_byte(&mut self, addr: usize, val: u8) {
+        Bus::store_byte(self, addr, val)
+    }
+
+    #[inline(always)]
+    fn store_halfword(&mut self, addr: usize, val: u16) {
+        Bus::store_halfword(self, addr, val)
+    }
+
+    #[inline(always)]
+    fn store_word(&mut self, addr: usize, val: u32) {
+        Bus::store_word(self, addr, val)
+    }
+
+    #[inline(always)]
+    fn read_bios_word(&self, addr: usize) -> u32 {
+        self.read_word_raw(addr, MemoryRegion::Bios)
+    }
+
+    #[inline(always)]
+    fn dma_pending(&self) -> bool {
+        self.check_dma()
+    }
+
+    #[inline(always)]
+    fn run_dma(&mut self) -> u32 {
+        Bus::run_dma(self)
+    }
+
+    #[inline(always)]
+    fn opcode_fetch_waits(&mut self, addr: usize, size: usize) -> u32 {
+        Bus::opcode_fetch_waits(self, addr, size)
+    }
+
+    #[inline(always)]
+    fn data_access_waits(&mut self) -> u32 {
+        Bus::data_access_waits(self)
+    }
+
+    #[inline(always)]
+    fn cpu_cycles_passed(&mut self, cycles: u32) {
+        Bus::cpu_cycles_passed(self, cycles)
+    }
+
+    #[inline(always)]
+    fn set_cpu_view(&mut self, view: CpuBusView) {
+        self.cpu_view = view;
+    }
+
+    #[inline(always)]
+    fn interrupt_requested(&self) -> bool {
+        self.interrupt_requested
+    }
+
+    #[inline(always)]
+    fn interrupt_pending(&self) -> bool {
+        self.interrupt_pending
+    }
+
+    #[inline(always)]
+    fn take_halt_request(&mut self) -> bool {
+        core::mem::take(&mut self.halt_requested)
+    }
+}
+
+// sparse memory for executing single instructions: bytes that were never stored read as 0 and every
+// byte written is recorded. addresses wrap around the size of the memory, which is either a power of
+// two (new) or the whole 32 bit address space (with_bytes, see GBA::fuzz_exec_one)
+pub struct TestMemory {
+    mem: BTreeMap<usize, u8>,
+    written: BTreeMap<usize, u8>,
+    mask: usize,
+}
+
+impl TestMemory {
+    pub fn new(size: usize) -> TestMemory {
+        assert!(size.is_power_of_two());
+        TestMemory {
+            mem: BTreeMap::new(),
+            written: BTreeMap::new(),
+            mask: size - 1,
+        }
+    }
+
+    pub fn with_bytes(bytes: &[(u32, u8)]) -> TestMemory {
+        TestMemory {
+            mem: bytes
+                .iter()
+                .map(|&(addr, val)| (addr as usize, val))
+                .collect(),
+            written: BTreeMap::new(),
+            mask: u32::MAX as usize,
+        }
+    }
+
+    // places an ARM or THUMB instruction at addr without recording it as written
+    pub fn store_instr(&mut self, addr: u32, instr: u32, thumb: bool) {
+        let (addr, len) = if thumb {
+            (addr as usize & !0b1, 2)
+        } else {
+            (addr as usize & !0b11, 4)
+        };
+        for i in 0..len {
+            let addr = self.index(addr + i);
+            self.mem.insert(addr, (instr >> (i * 8)) as u8);
+        }
+    }
+
+    // (address, value) of every byte written, in address order
+    pub fn written(&self) -> Vec<(u32, u8)> {
+        self.written
+            .iter()
+            .map(|(&addr, &val)| (addr as u32, val))
+            .collect()
+    }
+
+    #[inline(always)]
+    fn index(&self, addr: usize) -> usize {
+        addr & self.mask
+    }
+}
+
+impl MemoryInterface for TestMemory {
+    fn read_byte(&mut self, addr: usize) -> u8 {
+        self.mem.get(&self.index(addr)).copied().unwrap_or(0)
+    }
+
+    fn read_halfword(&mut self, addr: usize) -> u16 {
+        self.read_byte(addr) as u16 + ((self.read_byte(addr + 1) as u16) << 8)
+    }
+
+    fn read_word(&mut self, addr: usize) -> u32 {
+        self.read_halfword(addr) as u32 + ((self.read_halfword(addr + 2) as u32) << 16)
+    }
+
+    fn store_byte(&mut self, addr: usize, val: u8) {
+        let addr = self.index(addr);
+        self.mem.insert(addr, val);
+        self.written.insert(addr, val);
+    }
+
+    fn store_halfword(&mut self, addr: usize, val: u16) {
+        self.store_byte(addr, (val & 0b11111111) as u8);
+        self.store_byte(addr + 1, (val >> 8) as u8);
+    }
+
+    fn store_word(&mut self, addr: usize, val: u32) {
+        self.store_halfword(addr, (val & 0xffff) as u16);
+        self.store_halfword(addr + 2, (val >> 16) as u16);
+    }
+
+    fn read_bios_word(&self, addr: usize) -> u32 {
+        (0..4).fold(0, |res, i| {
+            let byte = self.mem.get(&self.index(addr + i)).copied();
+            res | ((byte.unwrap_or(0) as u32) << (i * 8))
+        })
+    }
+}
@@ -0,0 +1,43 @@
+// frame pacing for event loop driven frontends (eg. winit/egui) that can't block a thread on the
+// emulator.
+//
+// integration: create a FramePacer when the emulator is initialised. in the redraw handler, call
+// frame_due with the current time; if it returns true run one frame with GBA::pump_frame (or
+// process_frame), then call frame_done. afterwards, request the next redraw micros_until_due
+// microseconds from now.
+
+use crate::config;
+
+pub struct FramePacer {
+    frame_micros: u64,
+    next_due: u64, // microseconds
+}
+
+// if the frontend falls further behind than this, frames are dropped instead of being run back to
+// back to catch up
+const MAX_FRAMES_BEHIND: u64 = 4;
+
+impl FramePacer {
+    pub fn new(current_time: u64) -> FramePacer {
+        FramePacer {
+            frame_micros: config::CPU_EXECUTION_INTERVAL_US,
+            next_due: current_time,
+        }
+    }
+
+    pub fn frame_due(&self, current_time: u64) -> bool {
+        current_time >= self.next_due
+    }
+
+    // schedules the next frame one frame duration after the previous one
+    pub fn frame_done(&mut self, current_time: u64) {
+        self.next_due += self.frame_micros;
+        if current_time > self.next_due + MAX_FRAMES_BEHIND * self.frame_micros {
+            self.next_due = current_time;
+        }
+    }
+
+    pub fn micros_until_due(&self, current_time: u64) -> u64 {
+        self.next_due.saturating_sub(current_time)
+    }
+}
@@ -1,10 +1,11 @@
 #![allow(non_camel_case_types)]
 
-use log::warn;
-
 use crate::bus::{Bus, MemoryRegion};
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::trace::TARGET_PPU;
 
-use std::num::Wrapping;
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::num::Wrapping;
 
 #[derive(Clone, Copy)]
 pub struct Pixel(u8, u8, u8);
@@ -14,16 +15,38 @@ impl Pixel {
         Pixel(r.min(31), g.min(31), b.min(31))
     }
 
-    pub fn to_float(&self) -> (f32, f32, f32) {
-        (
-            self.0 as f32 / 31.,
-            self.1 as f32 / 31.,
-            self.2 as f32 / 31.,
-        )
+    // same as to_u8, scaled to [0, 1]
+    pub fn to_float(
+        &self,
+        colour_correction: ColourCorrection,
+        row: usize,
+        col: usize,
+    ) -> (f32, f32, f32) {
+        let (r, g, b) = self.to_u8(colour_correction, row, col);
+        (r as f32 / 255., g as f32 / 255., b as f32 / 255.)
     }
 
-    pub fn to_u8(&self) -> (u8, u8, u8) {
-        (self.0 << 3, self.1 << 3, self.2 << 3)
+    // 24 bit colour of the pixel at (row, col) of the screen, see ColourCorrection::apply
+    pub fn to_u8(
+        &self,
+        colour_correction: ColourCorrection,
+        row: usize,
+        col: usize,
+    ) -> (u8, u8, u8) {
+        colour_correction.apply(*self, row, col)
+    }
+
+    // native 15 bit colour of the GBA: 0bbbbbgggggrrrrr
+    pub fn to_bgr555(&self) -> u16 {
+        self.0 as u16 | ((self.1 as u16) << 5) | ((self.2 as u16) << 10)
+    }
+
+    pub fn from_bgr555(val: u16) -> Pixel {
+        Pixel(
+            (val & 0b11111) as u8,
+            ((val >> 5) & 0b11111) as u8,
+            ((val >> 10) & 0b11111) as u8,
+        )
     }
 
     pub fn blend(pixel_front: Pixel, pixel_back: Pixel, a: u16, b: u16) -> Pixel {
@@ -39,9 +62,99 @@ pub trait RenderOutput {
     fn set(&mut self, y: usize, scanline: &[Pixel; 240]);
 }
 
+// output pixel formats for ScreenBuffer::convert_into. 16 bit formats are stored little endian
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PixelFormat {
+    Rgba8888,
+    Rgb888,
+    Rgb565,
+    Rgba4444,
+    Bgr555, // native format of the GBA
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelFormat::Rgba8888 => 4,
+            PixelFormat::Rgb888 => 3,
+            PixelFormat::Rgb565 | PixelFormat::Rgba4444 | PixelFormat::Bgr555 => 2,
+        }
+    }
+}
+
+// how the 15 bit colours of the GBA are converted to 24 bit colours
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ColourCorrection {
+    None,
+    // the gamma and colour mixing curve of the GBA LCD (as used by higan), which makes colours look
+    // darker and less saturated, like on a real GBA screen
+    GbaLcd,
+    // ordered dithering of the 24 bit colours, to hide the banding of 15 bit gradients
+    Dither,
+}
+
+// (i / 31) ^ 4 * 65535: the response of the LCD
+const LCD_GAMMA: [u16; 32] = [
+    0, 0, 1, 6, 18, 44, 92, 170, 291, 466, 710, 1039, 1471, 2027, 2726, 3592, 4651, 5927, 7449,
+    9248, 11354, 13801, 16623, 19858, 23544, 27720, 32428, 37712, 43617, 50190, 57479, 65535,
+];
+
+// (i / 255) ^ (1 / 2.2) * 255 * 255 / 280: the gamma of the output display. indexed by the top 8
+// bits of a mixed LCD_GAMMA value
+const OUT_GAMMA: [u8; 256] = [
+    0, 19, 26, 31, 35, 39, 42, 45, 48, 51, 53, 56, 58, 60, 62, 64, 66, 68, 70, 71, 73, 75, 76, 78,
+    79, 81, 82, 84, 85, 86, 88, 89, 90, 92, 93, 94, 95, 97, 98, 99, 100, 101, 102, 103, 104, 106,
+    107, 108, 109, 110, 111, 112, 113, 114, 115, 116, 117, 118, 118, 119, 120, 121, 122, 123, 124,
+    125, 126, 126, 127, 128, 129, 130, 131, 132, 132, 133, 134, 135, 136, 136, 137, 138, 139, 139,
+    140, 141, 142, 142, 143, 144, 145, 145, 146, 147, 148, 148, 149, 150, 150, 151, 152, 152, 153,
+    154, 154, 155, 156, 156, 157, 158, 158, 159, 160, 160, 161, 162, 162, 163, 164, 164, 165, 165,
+    166, 167, 167, 168, 169, 169, 170, 170, 171, 172, 172, 173, 173, 174, 175, 175, 176, 176, 177,
+    177, 178, 179, 179, 180, 180, 181, 181, 182, 182, 183, 184, 184, 185, 185, 186, 186, 187, 187,
+    188, 188, 189, 189, 190, 191, 191, 192, 192, 193, 193, 194, 194, 195, 195, 196, 196, 197, 197,
+    198, 198, 199, 199, 200, 200, 201, 201, 202, 202, 203, 203, 204, 204, 205, 205, 206, 206, 207,
+    207, 207, 208, 208, 209, 209, 210, 210, 211, 211, 212, 212, 213, 213, 214, 214, 214, 215, 215,
+    216, 216, 217, 217, 218, 218, 219, 219, 219, 220, 220, 221, 221, 222, 222, 222, 223, 223, 224,
+    224, 225, 225, 225, 226, 226, 227, 227, 228, 228, 228, 229, 229, 230, 230, 231, 231, 231, 232,
+    232,
+];
+
+const BAYER_4X4: [u8; 16] = [0, 8, 2, 10, 12, 4, 14, 6, 3, 11, 1, 9, 15, 7, 13, 5];
+
+impl ColourCorrection {
+    // 24 bit colour of the pixel at (row, col)
+    #[inline(always)]
+    pub fn apply(&self, pixel: Pixel, row: usize, col: usize) -> (u8, u8, u8) {
+        let Pixel(r, g, b) = pixel;
+        let expand = |c: u8| c << 3 | c >> 2;
+        match self {
+            ColourCorrection::None => (expand(r), expand(g), expand(b)),
+            ColourCorrection::GbaLcd => {
+                let (r, g, b) = (
+                    LCD_GAMMA[r as usize] as u32,
+                    LCD_GAMMA[g as usize] as u32,
+                    LCD_GAMMA[b as usize] as u32,
+                );
+                let mix = |wr: u32, wg: u32, wb: u32| {
+                    let lin = ((wr * r + wg * g + wb * b) / 255).min(0xffff);
+                    OUT_GAMMA[lin as usize >> 8]
+                };
+                (mix(255, 50, 0), mix(10, 230, 30), mix(50, 10, 220))
+            }
+            ColourCorrection::Dither => {
+                // offset in [-4, 3], half of a 5 bit step either way
+                let offset = (BAYER_4X4[(row & 3) * 4 + (col & 3)] >> 1) as i16 - 4;
+                let dither = |c: u8| (expand(c) as i16 + offset).clamp(0, 255) as u8;
+                (dither(r), dither(g), dither(b))
+            }
+        }
+    }
+}
+
+// pixels are stored as their native 15 bit colours, row major
 #[derive(Clone)]
 pub struct ScreenBuffer {
-    buffer: Box<[[Pixel; 240]; 160]>,
+    buffer: Box<[u16; 240 * 160]>,
+    pub colour_correction: ColourCorrection,
 }
 
 impl Default for ScreenBuffer {
@@ -53,14 +166,131 @@ impl Default for ScreenBuffer {
 impl ScreenBuffer {
     pub fn new() -> ScreenBuffer {
         ScreenBuffer {
-            buffer: Box::new([[Pixel::new(0, 0, 0); 240]; 160]),
+            buffer: Box::new([0; 240 * 160]),
+            colour_correction: ColourCorrection::None,
         }
     }
     pub fn write_pixel(&mut self, row: usize, col: usize, pixel: Pixel) {
-        self.buffer[row][col] = pixel;
+        self.buffer[row * 240 + col] = pixel.to_bgr555();
     }
     pub fn read_pixel(&self, row: usize, col: usize) -> Pixel {
-        self.buffer[row][col]
+        Pixel::from_bgr555(self.buffer[row * 240 + col])
+    }
+    /// stored BGR555 colour of the pixel, for frontends doing their own colour conversion
+    pub fn read_pixel_raw(&self, row: usize, col: usize) -> u16 {
+        self.buffer[row * 240 + col]
+    }
+    /// every pixel as BGR555, row major (240 pixels per row, 160 rows)
+    pub fn as_u16_slice(&self) -> &[u16] {
+        &self.buffer[..]
+    }
+    /// 24 bit colour of the pixel, after colour_correction
+    pub fn read_pixel_u8(&self, row: usize, col: usize) -> (u8, u8, u8) {
+        self.read_pixel(row, col)
+            .to_u8(self.colour_correction, row, col)
+    }
+    /// colour of the pixel scaled to [0, 1], after colour_correction
+    pub fn read_pixel_float(&self, row: usize, col: usize) -> (f32, f32, f32) {
+        self.read_pixel(row, col)
+            .to_float(self.colour_correction, row, col)
+    }
+
+    /// writes every pixel, row major, in the given format. out must be at least
+    /// 240 * 160 * format.bytes_per_pixel() bytes long. colour_correction is applied to every
+    /// format except the native Bgr555
+    pub fn convert_into(&self, out: &mut [u8], format: PixelFormat) {
+        let bpp = format.bytes_per_pixel();
+        assert!(out.len() >= 240 * 160 * bpp);
+        for (i, (raw, out)) in self
+            .buffer
+            .iter()
+            .zip(out.chunks_exact_mut(bpp))
+            .enumerate()
+        {
+            if format == PixelFormat::Bgr555 {
+                out.copy_from_slice(&raw.to_le_bytes());
+                continue;
+            }
+            let (r, g, b) = self.read_pixel_u8(i / 240, i % 240);
+            match format {
+                PixelFormat::Rgba8888 => out.copy_from_slice(&[r, g, b, 255]),
+                PixelFormat::Rgb888 => out.copy_from_slice(&[r, g, b]),
+                PixelFormat::Rgb565 => {
+                    let val = ((r >> 3) as u16) << 11 | ((g >> 2) as u16) << 5 | (b >> 3) as u16;
+                    out.copy_from_slice(&val.to_le_bytes());
+                }
+                PixelFormat::Rgba4444 => {
+                    let val = ((r >> 4) as u16) << 12
+                        | ((g >> 4) as u16) << 8
+                        | ((b >> 4) as u16) << 4
+                        | 0xf;
+                    out.copy_from_slice(&val.to_le_bytes());
+                }
+                PixelFormat::Bgr555 => unreachable!(),
+            }
+        }
+    }
+    /// intended display aspect ratio (width, height) of the image
+    pub fn aspect_ratio(&self) -> (u32, u32) {
+        (3, 2)
+    }
+    /// copy of the screen surrounded by px pixels of the given colour on every side
+    pub fn with_border(&self, colour: Pixel, px: usize) -> BorderedScreenBuffer {
+        let (width, height) = (240 + 2 * px, 160 + 2 * px);
+        let mut buffer = vec![colour.to_bgr555(); width * height];
+        for row in 0..160 {
+            let start = (row + px) * width + px;
+            buffer[start..start + 240].copy_from_slice(&self.buffer[row * 240..(row + 1) * 240]);
+        }
+        BorderedScreenBuffer {
+            width,
+            height,
+            buffer,
+        }
+    }
+    /// 120x80 RGB image (3 bytes per pixel, row major) of the screen, each pixel being the average
+    /// of a 2x2 block after colour_correction. meant as a preview image for save menus
+    pub fn thumbnail(&self) -> Vec<u8> {
+        let mut res = Vec::with_capacity(120 * 80 * 3);
+        for row in 0..80 {
+            for col in 0..120 {
+                let mut sum = (0u16, 0u16, 0u16);
+                for (y, x) in [(0, 0), (0, 1), (1, 0), (1, 1)] {
+                    let pixel = self.read_pixel_u8(row * 2 + y, col * 2 + x);
+                    sum.0 += pixel.0 as u16;
+                    sum.1 += pixel.1 as u16;
+                    sum.2 += pixel.2 as u16;
+                }
+                res.push((sum.0 >> 2) as u8);
+                res.push((sum.1 >> 2) as u8);
+                res.push((sum.2 >> 2) as u8);
+            }
+        }
+        res
+    }
+}
+
+// screen with a border, see ScreenBuffer::with_border. the aspect ratio of the GBA image within it
+// is still that of ScreenBuffer::aspect_ratio
+#[derive(Clone)]
+pub struct BorderedScreenBuffer {
+    width: usize,
+    height: usize,
+    buffer: Vec<u16>,
+}
+
+impl BorderedScreenBuffer {
+    pub fn width(&self) -> usize {
+        self.width
+    }
+    pub fn height(&self) -> usize {
+        self.height
+    }
+    pub fn read_pixel(&self, row: usize, col: usize) -> Pixel {
+        Pixel::from_bgr555(self.buffer[row * self.width + col])
+    }
+    pub fn as_u16_slice(&self) -> &[u16] {
+        &self.buffer
     }
 }
 
@@ -94,6 +324,9 @@ pub struct Ppu {
     cur_scanline: Vec<Pixel>,
     cur_scanline_front: Vec<(Pixel, PixelType, WindowType)>,
     cur_scanline_back: Vec<(Pixel, PixelType, WindowType)>,
+    // topmost sprite pixel for each column: (pixel, priority, is_blend). the sprite with the lowest
+    // OAM index wins regardless of priority, and is then drawn at its own priority
+    obj_scanline: Vec<Option<(Pixel, u8, bool)>>,
 
     window_scanlines: [Vec<bool>; 4],
     active_windows: [bool; 4],
@@ -103,13 +336,16 @@ pub struct Ppu {
 
     cur_priority: u8,
 
+    // supersampling factor of affine and bitmap layers, see process_affine_layer
+    pub internal_scale: u32,
+
     disp_cnt: u16,
     disp_stat: u16,
 
     cpu_interrupt: u16,
 
     frame_count: u32,
-    pub frame_count_render: u32,
+    frame_count_render: u32,
 }
 
 impl Ppu {
@@ -130,8 +366,9 @@ impl Ppu {
                 (Pixel::new(0, 0, 0), PixelType::Backdrop, WindowType::W_full);
                 240
             ],
+            obj_scanline: vec![None; 240],
 
-            window_scanlines: std::array::from_fn(|_| vec![true; 240]),
+            window_scanlines: core::array::from_fn(|_| vec![true; 240]),
             active_windows: [false; 4],
             window_flags: [0; 4],
             is_windowing_active: false,
@@ -139,6 +376,8 @@ impl Ppu {
 
             cur_priority: 0,
 
+            internal_scale: 1,
+
             disp_cnt: 0,
             disp_stat: 0,
 
@@ -149,6 +388,30 @@ impl Ppu {
         }
     }
 
+    // power on state. the rendering settings are kept
+    pub fn reset(&mut self) {
+        let mut ppu = Ppu::new();
+        ppu.internal_scale = self.internal_scale;
+        ppu.frame_count_render = self.frame_count_render;
+        ppu.buffer.colour_correction = self.buffer.colour_correction;
+        *self = ppu;
+    }
+
+    // render every n-th frame, starting with the next one
+    pub fn set_frame_count_render(&mut self, n: u32) {
+        self.frame_count_render = n;
+        self.frame_count = 0;
+    }
+
+    pub fn set_colour_correction(&mut self, colour_correction: ColourCorrection) {
+        self.buffer.colour_correction = colour_correction;
+    }
+
+    // the last rendered frame, whether or not it was collected
+    pub fn screen_buffer(&self) -> &ScreenBuffer {
+        &self.buffer
+    }
+
     pub fn get_screen_buffer(&mut self) -> Option<&ScreenBuffer> {
         if self.buffer_ready {
             self.buffer_ready = false;
@@ -166,17 +429,10 @@ impl Ppu {
         self.disp_cnt = bus.read_halfword_raw(0x0, MemoryRegion::IO);
         self.disp_stat = bus.read_halfword_raw(0x4, MemoryRegion::IO);
 
-        let res = if self.cur_line >= 160 {
-            self.cur_line += 1;
-            if self.cur_line == 228 {
-                self.is_hblank = false;
-                self.cur_line = 0;
-                960
-            } else {
-                1232
-            }
-        } else if !self.is_hblank {
-            if self.frame_count == 0 {
+        // every line, including the vblank lines, is 960 cycles of drawing followed by 272 cycles
+        // of hblank
+        let res = if !self.is_hblank {
+            if self.cur_line < 160 && self.frame_count == 0 {
                 self.process_scanline(bus);
                 for j in 0..240 {
                     self.buffer
@@ -187,16 +443,28 @@ impl Ppu {
 
             self.is_hblank = true;
 
-            // set hblank interrupt, dma
+            // set hblank interrupt, dma. the interrupt is also requested during vblank, the dma is not
             if (self.disp_stat >> 4) & 1 > 0 {
                 self.cpu_interrupt |= 0b10;
             }
-            bus.hblank_dma = true;
+            bus.hblank_dma = self.cur_line < 160;
+            bus.video_capture_dma = (2..162).contains(&self.cur_line);
 
             272
         } else {
             self.is_hblank = false;
             self.cur_line += 1;
+            if self.cur_line == 228 {
+                self.cur_line = 0;
+            }
+            // hblank dma that didn't start during this hblank must wait for the next one
+            bus.hblank_dma = false;
+            bus.video_capture_dma = false;
+            // vblank dma may only start on the first line of vblank
+            bus.vblank_dma = false;
+            if self.cur_line == 162 {
+                bus.stop_video_capture_dma();
+            }
 
             if self.cur_line == 160 {
                 if self.frame_count == 0 {
@@ -206,18 +474,19 @@ impl Ppu {
                 if self.frame_count >= self.frame_count_render {
                     self.frame_count = 0;
                 }
-                1232
-            } else {
-                960
             }
+            960
         };
         // store VCOUNT
         bus.store_byte_raw(0x6, MemoryRegion::IO, self.cur_line);
 
+        // interrupts are requested once, when the line starts. the flags are set for as long as the
+        // condition holds
         self.disp_stat &= !0b111;
-        if self.cur_line >= 160 {
-            // set vblank interrupt
-            if self.cur_line == 160 {
+        // the vblank flag is clear on the last line
+        if (160..227).contains(&self.cur_line) {
+            if self.cur_line == 160 && !self.is_hblank {
+                // set vblank interrupt
                 if (self.disp_stat >> 3) & 1 > 0 {
                     self.cpu_interrupt |= 1;
                 }
@@ -228,9 +497,9 @@ impl Ppu {
         if self.is_hblank {
             self.disp_stat |= 0b010;
         }
-        // vcount interrupt request
-        if !self.is_hblank && self.cur_line as u16 == (self.disp_stat >> 8) {
-            if (self.disp_stat >> 5) & 1 > 0 {
+        // vcount match
+        if self.cur_line as u16 == (self.disp_stat >> 8) {
+            if !self.is_hblank && (self.disp_stat >> 5) & 1 > 0 {
                 self.cpu_interrupt |= 0b100;
                 //info!("vcount irq requested: {}, frame: {}", self.disp_stat >> 8, self.frame_count);
             }
@@ -248,6 +517,18 @@ impl Ppu {
 
     fn process_scanline(&mut self, bus: &Bus) {
         let backdrop_colour = bus.read_halfword_raw(0x0, MemoryRegion::Palette);
+
+        // fast paths that skip windows, sprites and blending: forced blank shows white, and without
+        // any enabled layer only the backdrop is left
+        if (self.disp_cnt >> 7) & 1 > 0 {
+            self.cur_scanline.fill(Pixel::new(31, 31, 31));
+            return;
+        }
+        if self.disp_cnt & 0x1f00 == 0 {
+            self.cur_scanline
+                .fill(Ppu::process_15bit_colour(backdrop_colour));
+            return;
+        }
         //self.cur_scanline.iter_mut().for_each(|x| *x = PPU::process_15bit_colour(backdrop_colour));
         self.cur_scanline_back.iter_mut().for_each(|x| {
             *x = (
@@ -266,6 +547,9 @@ impl Ppu {
 
         self.init_window_scanline(bus);
 
+        self.obj_scanline.iter_mut().for_each(|x| *x = None);
+        self.process_sprites(false, bus);
+
         //info!("bldcnt: {:#018b}, bldalpha: {:#018b}", bus.read_halfword_raw(0x04000050), bus.read_halfword_raw(0x04000052));
         //info!("bg0cnt: {:#018b}, bg2cnt: {:#018b}", bus.read_halfword_raw(0x04000008), bus.read_halfword_raw(0x04000012));
 
@@ -303,14 +587,13 @@ impl Ppu {
                     }
                     3 => self.process_bg_mode_3(bus),
                     4 => self.process_bg_mode_4(bus),
-                    5 => warn!("current bg mode 5, not implemented yet"),
+                    5 => self.process_bg_mode_5(bus),
                     _ => {}
                 }
 
-                // process sprites
-                //if self.cur_window != WindowType::W_obj{
-                self.process_sprites(false, bus);
-                //}
+                // process sprites. drawn after the backgrounds, so they win against backgrounds of
+                // the same priority
+                self.draw_obj_scanline();
             }
         }
 
@@ -370,55 +653,135 @@ impl Ppu {
 
     // -------- background processing methods
 
+    // bitmap modes draw a single background on BG2, which uses the priority in BG2CNT
+    fn check_bitmap_bg(&self, bus: &Bus) -> bool {
+        let bg_cnt = bus.read_halfword_raw(0xc, MemoryRegion::IO);
+        self.cur_priority == bg_cnt as u8 & 0b11
+            && (self.disp_cnt >> 10) & 1 > 0
+            && self.check_window_bg(PixelType::BG_2)
+    }
+
+    // 240x160, 15 bit colour
     fn process_bg_mode_3(&mut self, bus: &Bus) {
-        // assume that one background of priority 3 is drawn
-        if !self.check_window_bg(PixelType::BG_0) || self.cur_priority < 3 {
+        if !self.check_bitmap_bg(bus) {
             return;
         }
-        let addr = self.cur_line as usize * 240 * 2;
-
-        for i in 0..240 {
-            self.update_cur_scanline_bg(
-                i,
-                Some(Ppu::process_15bit_colour(
-                    bus.read_halfword_raw(addr + i * 2, MemoryRegion::Vram),
-                )),
-                PixelType::BG_0,
-            );
-        }
+        self.process_bitmap_bg(bus, 240, 160, |x, y| {
+            Some(Ppu::process_15bit_colour(
+                bus.read_halfword_raw((y * 240 + x) * 2, MemoryRegion::Vram),
+            ))
+        });
     }
 
+    // 240x160, 8 bit palette index, two pages
     fn process_bg_mode_4(&mut self, bus: &Bus) {
-        // assume that one background of priority 3 is drawn
-        if self.cur_priority < 3 {
+        if !self.check_bitmap_bg(bus) {
             return;
         }
-        let mut addr = self.cur_line as usize * 240;
+        let base_addr = self.bitmap_page_addr();
+        self.process_bitmap_bg(bus, 240, 160, |x, y| {
+            Ppu::process_palette_colour(
+                bus.read_byte_raw(base_addr + y * 240 + x, MemoryRegion::Vram),
+                false,
+                false,
+                bus,
+            )
+        });
+    }
 
-        let pixel_type;
+    // 160x128, 15 bit colour, two pages. the area outside of the bitmap shows the backdrop. games
+    // usually scale it up to fill the screen through the BG2 affine parameters
+    fn process_bg_mode_5(&mut self, bus: &Bus) {
+        if !self.check_bitmap_bg(bus) {
+            return;
+        }
+        let base_addr = self.bitmap_page_addr();
+        self.process_bitmap_bg(bus, 160, 128, |x, y| {
+            Some(Ppu::process_15bit_colour(bus.read_halfword_raw(
+                base_addr + (y * 160 + x) * 2,
+                MemoryRegion::Vram,
+            )))
+        });
+    }
 
-        // frame number
+    // offset of the displayed page in modes 4 and 5, selected by the frame bit of DISPCNT
+    fn bitmap_page_addr(&self) -> usize {
         if (self.disp_cnt >> 4) & 1 > 0 {
-            pixel_type = PixelType::BG_1;
-            addr += 0x9600;
+            0xa000
         } else {
-            pixel_type = PixelType::BG_0;
-        }
-        if !self.check_window_bg(pixel_type) {
-            return;
+            0
         }
+    }
 
-        for i in 0..240 {
-            self.update_cur_scanline_bg(
-                i as usize,
-                Ppu::process_palette_colour(
-                    bus.read_byte_raw(addr + i, MemoryRegion::Vram),
-                    false,
-                    false,
-                    bus,
-                ),
-                pixel_type,
-            );
+    // bitmaps are drawn through the BG2 affine transform, without wrapping. pixel_at gets the
+    // coordinates within the bitmap
+    fn process_bitmap_bg<F: Fn(usize, usize) -> Option<Pixel>>(
+        &mut self,
+        bus: &Bus,
+        w: i32,
+        h: i32,
+        pixel_at: F,
+    ) {
+        self.process_affine_layer(bus, PixelType::BG_2, |ox, oy| {
+            if ox < 0 || oy < 0 || ox >= w || oy >= h {
+                return None;
+            }
+            pixel_at(ox as usize, oy as usize)
+        });
+    }
+
+    // draws a layer through the affine parameters of BG2 or BG3. sample_at gets the (unbounded)
+    // coordinates within the layer.
+    //
+    // with an internal scale of n, each screen pixel is the average of n*n samples of the layer (and
+    // is transparent if most of them are). this smooths rotated and scaled graphics, but is not what
+    // the hardware does, and costs n*n samples per pixel.
+    fn process_affine_layer<F: Fn(i32, i32) -> Option<Pixel>>(
+        &mut self,
+        bus: &Bus,
+        pixel_type: PixelType,
+        sample_at: F,
+    ) {
+        let base_p_addr = 0x20 + 0x10 * (pixel_type as usize - 2);
+        let pa = bus.read_halfword_raw(base_p_addr, MemoryRegion::IO) as i16 as i32;
+        let pb = bus.read_halfword_raw(base_p_addr + 2, MemoryRegion::IO) as i16 as i32;
+        let pc = bus.read_halfword_raw(base_p_addr + 4, MemoryRegion::IO) as i16 as i32;
+        let pd = bus.read_halfword_raw(base_p_addr + 6, MemoryRegion::IO) as i16 as i32;
+
+        let dx = bus.read_word_raw(base_p_addr + 8, MemoryRegion::IO) as i32;
+        let dy = bus.read_word_raw(base_p_addr + 12, MemoryRegion::IO) as i32;
+
+        let n = self.internal_scale as i32;
+        let cy = self.cur_line as i32;
+        for j in 0..240 {
+            let cx = j as i32;
+            if n == 1 {
+                let ox = (dx + pa * cx + pb * cy) >> 8;
+                let oy = (dy + pc * cx + pd * cy) >> 8;
+                if let Some(pixel) = sample_at(ox, oy) {
+                    self.update_cur_scanline_bg(j, Some(pixel), pixel_type);
+                }
+                continue;
+            }
+            let (mut r, mut g, mut b, mut cnt) = (0u32, 0u32, 0u32, 0);
+            for sy in 0..n {
+                for sx in 0..n {
+                    let (scx, scy) = (cx * n + sx, cy * n + sy);
+                    let ox = (dx + (pa * scx + pb * scy) / n) >> 8;
+                    let oy = (dy + (pc * scx + pd * scy) / n) >> 8;
+                    if let Some(pixel) = sample_at(ox, oy) {
+                        r += pixel.0 as u32;
+                        g += pixel.1 as u32;
+                        b += pixel.2 as u32;
+                        cnt += 1;
+                    }
+                }
+            }
+            if cnt * 2 >= n * n {
+                let cnt = cnt as u32;
+                let pixel = Pixel::new((r / cnt) as u8, (g / cnt) as u8, (b / cnt) as u8);
+                self.update_cur_scanline_bg(j, Some(pixel), pixel_type);
+            }
         }
     }
 
@@ -439,97 +802,69 @@ impl Ppu {
         let base_screenblock_addr = ((bg_cnt as usize >> 8) & 0b11111) * 2048;
         let base_charblock_addr = ((bg_cnt as usize >> 2) & 0b11) * 0x4000;
 
-        let x = 0 - bus.read_halfword_raw(0x10 + 4 * bg_num, MemoryRegion::IO);
-        let y = 0 - bus.read_halfword_raw(0x12 + 4 * bg_num, MemoryRegion::IO);
-
-        let i_rel = self.cur_line as u16 - y;
-
-        let base_p_addr = 0x20 + 0x10 * (bg_num - 2);
-        let pa = bus.read_halfword_raw(base_p_addr, MemoryRegion::IO) as i16 as i32;
-        let pb = bus.read_halfword_raw(base_p_addr + 2, MemoryRegion::IO) as i16 as i32;
-        let pc = bus.read_halfword_raw(base_p_addr + 4, MemoryRegion::IO) as i16 as i32;
-        let pd = bus.read_halfword_raw(base_p_addr + 6, MemoryRegion::IO) as i16 as i32;
-
-        let dx = bus.read_word_raw(0x28 + 0x10 * (bg_num - 2), MemoryRegion::IO) as i32;
-        let dy = bus.read_word_raw(0x2c + 0x10 * (bg_num - 2), MemoryRegion::IO) as i32;
-
-        for j in 0..240 {
-            let j_rel = j - x;
-
-            let mut ox = j_rel;
-            let mut oy = i_rel;
-
-            let (mut px, mut py, tile_addr);
-            let mut pal_bank = 0; // NOTE: pal_bank is unused for affine backgrounds
-
-            if is_affine {
-                let cy = self.cur_line as i32;
-                let cx = j as i32;
-
-                ox = ((dx + pa * cx + pb * cy) >> 8) as u16;
-                oy = ((dy + pc * cx + pd * cy) >> 8) as u16;
-
-                if !wrapping && (ox >= w || oy >= h) {
+        if is_affine {
+            let (w, h) = (w as i32, h as i32);
+            self.process_affine_layer(bus, pixel_type, |ox, oy| {
+                if !wrapping && (ox < 0 || oy < 0 || ox >= w || oy >= h) {
                     // no wrapping, so pixel is out of bounds. do nothing
-                    continue;
-                } else {
-                    ox %= w;
-                    oy %= h;
+                    return None;
                 }
-
-                let offset_screen_entry = (oy >> 3) * (w >> 3) + (ox >> 3);
+                let (ox, oy) = (ox.rem_euclid(w) as usize, oy.rem_euclid(h) as usize);
+                let offset_screen_entry = (oy >> 3) * (w as usize >> 3) + (ox >> 3);
                 let screen_entry = bus.read_byte_raw(
-                    base_screenblock_addr + offset_screen_entry as usize,
+                    base_screenblock_addr + offset_screen_entry,
                     MemoryRegion::Vram,
                 );
-                px = ox & 0b111;
-                py = oy & 0b111;
-
-                tile_addr = base_charblock_addr + ((screen_entry as usize) << 6);
-            } else {
-                // get pixel data. assumes ox and oy are relative to the background.
-
-                ox %= w;
-                oy %= h;
-
-                let mut cur_screenblock_addr = base_screenblock_addr;
-                let mut ox_rel = ox;
-                let mut oy_rel = oy;
-                if !is_affine {
-                    cur_screenblock_addr +=
-                        ((oy as usize / 256) * w as usize / 256 + ox as usize / 256) * 2048;
-                    // relative to current screenblock
-                    ox_rel = ox % 256;
-                    oy_rel = oy % 256;
-                }
-
-                //let offset_screen_entry = (oy_rel as usize >> 3) * 32 + (ox_rel as usize >> 3) * 64 + ((oy_rel as usize & 0b111) * 8 + (ox_rel as usize & 0b111));
-                let offset_screen_entry = if !is_affine {
-                    (oy_rel >> 3) * 32 + (ox_rel >> 3)
-                } else {
-                    (oy_rel >> 3) * (w >> 3) + (ox_rel >> 3)
-                };
-                let screen_entry = bus.read_halfword_raw(
-                    cur_screenblock_addr + ((offset_screen_entry as usize) << 1),
+                let tile_addr = base_charblock_addr + ((screen_entry as usize) << 6);
+                let pal = bus.read_byte_raw(
+                    tile_addr + (oy & 0b111) * 8 + (ox & 0b111),
                     MemoryRegion::Vram,
                 );
+                Ppu::process_palette_colour(pal, false, false, bus)
+            });
+            return;
+        }
 
-                // relative to current tile
-                px = ox_rel & 0b111;
-                py = oy_rel & 0b111;
+        let x = 0 - bus.read_halfword_raw(0x10 + 4 * bg_num, MemoryRegion::IO);
+        let y = 0 - bus.read_halfword_raw(0x12 + 4 * bg_num, MemoryRegion::IO);
 
-                if (screen_entry >> 10) & 1 > 0 {
-                    px = 8 - px - 1;
-                }
-                if (screen_entry >> 11) & 1 > 0 {
-                    py = 8 - py - 1;
-                }
+        let i_rel = self.cur_line as u16 - y;
 
-                pal_bank = ((screen_entry >> 12) << 4) as u8;
+        for j in 0..240 {
+            let j_rel = j - x;
+
+            // get pixel data. assumes ox and oy are relative to the background.
+            let ox = j_rel % w;
+            let oy = i_rel % h;
+
+            let cur_screenblock_addr = base_screenblock_addr
+                + ((oy as usize / 256) * w as usize / 256 + ox as usize / 256) * 2048;
+            // relative to current screenblock
+            let ox_rel = ox % 256;
+            let oy_rel = oy % 256;
+
+            //let offset_screen_entry = (oy_rel as usize >> 3) * 32 + (ox_rel as usize >> 3) * 64 + ((oy_rel as usize & 0b111) * 8 + (ox_rel as usize & 0b111));
+            let offset_screen_entry = (oy_rel >> 3) * 32 + (ox_rel >> 3);
+            let screen_entry = bus.read_halfword_raw(
+                cur_screenblock_addr + ((offset_screen_entry as usize) << 1),
+                MemoryRegion::Vram,
+            );
+
+            // relative to current tile
+            let mut px = ox_rel & 0b111;
+            let mut py = oy_rel & 0b111;
 
-                tile_addr = base_charblock_addr
-                    + (screen_entry as usize & 0b1111111111) * if density { 64 } else { 32 };
+            if (screen_entry >> 10) & 1 > 0 {
+                px = 8 - px - 1;
             }
+            if (screen_entry >> 11) & 1 > 0 {
+                py = 8 - py - 1;
+            }
+
+            let pal_bank = ((screen_entry >> 12) << 4) as u8;
+
+            let tile_addr = base_charblock_addr
+                + (screen_entry as usize & 0b1111111111) * if density { 64 } else { 32 };
             let offset_pixels = (py << 3) as usize + px as usize;
 
             let pal = if !density {
@@ -565,9 +900,11 @@ impl Ppu {
             (0b10, true) => (512, 512),
             (0b11, true) => (1024, 1024),
             _ => {
-                warn!(
+                hot_warn!(
+                    target: TARGET_PPU,
                     "invalid sz_flag for tiled bg dimensions: {}, {}",
-                    sz_flag, is_affine
+                    sz_flag,
+                    is_affine
                 );
                 (256, 256)
             }
@@ -576,9 +913,9 @@ impl Ppu {
 
     // -------- sprite processing
 
-    // process_win_obj: if set true, no sprites are drawn. instead, updates windows.
+    // process_win_obj: if set true, updates windows. otherwise, fills obj_scanline
     fn process_sprites(&mut self, process_win_obj: bool, bus: &Bus) {
-        if !self.check_window_sprite(process_win_obj) || (self.disp_cnt >> 12) & 1 == 0 {
+        if (self.disp_cnt >> 12) & 1 == 0 {
             return;
         }
 
@@ -595,9 +932,6 @@ impl Ppu {
             }
             let attr2 = bus.read_halfword_raw(k * 8 + 4, MemoryRegion::Oam);
             let cur_p = ((attr2 >> 10) & 0b11) as u8;
-            if !process_win_obj && cur_p != self.cur_priority {
-                continue;
-            }
 
             let gfx = (attr0 >> 10) & 0b11;
             if process_win_obj && (gfx != 0b10) {
@@ -661,16 +995,22 @@ impl Ppu {
                     read_pixel = ox < w && oy < h;
                 };
                 if read_pixel {
-                    // byte offset for each pixel
-                    let offset_pixels = (oy as usize >> 3) * (w as usize >> 3) * 64
-                        + (ox as usize >> 3) * 64
-                        + ((oy as usize & 0b111) * 8 + (ox as usize & 0b111));
+                    // 1D mapping stores the tile rows of the sprite one after another. 2D mapping
+                    // treats OBJ vram as a 32x32 grid of 4bpp tiles, so rows are 32 tiles apart
+                    let (ox, oy) = (ox as usize, oy as usize);
+                    let tile_size = if density { 64 } else { 32 };
+                    let row_stride = if map_mode {
+                        (w as usize >> 3) * tile_size
+                    } else {
+                        32 * 32
+                    };
+                    let offset_tile = (oy >> 3) * row_stride + (ox >> 3) * tile_size;
+                    // pixel offset within the tile
+                    let offset_pixels = (oy & 0b111) * 8 + (ox & 0b111);
                     let pal = if !density {
                         // 4 bits per pixel
-                        let mut cur_addr = base_tile_index as usize * 32 + (offset_pixels >> 1);
-                        if !map_mode {
-                            cur_addr += ((oy as usize >> 3) * (128 - (w as usize >> 1))) << 3;
-                        }
+                        let cur_addr =
+                            base_tile_index as usize * 32 + offset_tile + (offset_pixels >> 1);
                         let cur_addr = 0x10000 + (cur_addr % 32768);
                         if offset_pixels & 1 > 0 {
                             (bus.read_byte_raw(cur_addr, MemoryRegion::Vram) >> 4) + pal_bank
@@ -679,10 +1019,7 @@ impl Ppu {
                         }
                     } else {
                         // 8 bits per pixel
-                        let mut cur_addr = base_tile_index as usize * 32 + offset_pixels;
-                        if !map_mode {
-                            cur_addr += ((oy as usize >> 3) * (128 - w as usize)) << 3;
-                        }
+                        let cur_addr = base_tile_index as usize * 32 + offset_tile + offset_pixels;
                         let cur_addr = 0x10000 + (cur_addr % 32768);
                         bus.read_byte_raw(cur_addr, MemoryRegion::Vram)
                     };
@@ -697,10 +1034,13 @@ impl Ppu {
                         if gfx == 0b10 && !process_win_obj {
                             continue;
                         }
-                        if !process_win_obj {
-                            self.update_cur_scanline_sprite(tx, pixel, gfx == 1);
-                        } else if pixel.is_some() {
-                            self.set_window_scanline(WindowType::W_obj, tx);
+                        if let Some(pixel) = pixel {
+                            if !process_win_obj {
+                                // sprites are processed from the highest OAM index down
+                                self.obj_scanline[tx] = Some((pixel, cur_p, gfx == 1));
+                            } else {
+                                self.set_window_scanline(WindowType::W_obj, tx);
+                            }
                         }
                     }
                 }
@@ -708,6 +1048,20 @@ impl Ppu {
         }
     }
 
+    // draws the sprite pixels of the current priority
+    fn draw_obj_scanline(&mut self) {
+        if !self.check_window_sprite(false) {
+            return;
+        }
+        for i in 0..240 {
+            if let Some((pixel, priority, is_blend)) = self.obj_scanline[i] {
+                if priority == self.cur_priority {
+                    self.update_cur_scanline_sprite(i, Some(pixel), is_blend);
+                }
+            }
+        }
+    }
+
     // returns width, height in terms of pixels
     fn get_sprite_dimensions(&self, shape: u8, size: u8) -> (u16, u16) {
         match (shape, size) {
@@ -724,7 +1078,7 @@ impl Ppu {
             (0b10, 0b10) => (16, 32),
             (0b10, 0b11) => (32, 64),
             _ => {
-                warn!("invalid sprite shape and/or size");
+                hot_warn!(target: TARGET_PPU, "invalid sprite shape and/or size");
                 (8, 8)
             }
         }
@@ -1117,4 +1471,380 @@ impl Ppu {
         }
     }
     */
+
+    // everything else is either read from IO every clock or rebuilt for every scanline
+    pub fn write_snapshot(&self, w: &mut SnapshotWriter) {
+        w.bool(self.is_hblank);
+        w.u8(self.cur_line);
+        w.u32(self.frame_count);
+    }
+
+    pub fn read_snapshot(&mut self, r: &mut SnapshotReader) -> Result<(), &'static str> {
+        self.is_hblank = r.bool()?;
+        self.cur_line = r.u8()?;
+        self.frame_count = r.u32()?;
+        if self.cur_line >= 228 {
+            return Err("invalid scanline in snapshot");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ColourCorrection, Pixel, PixelFormat, ScreenBuffer};
+    use crate::test_util::{gba_with_bios, render_frame};
+    use crate::GBA;
+    use alloc::vec;
+
+    // a gradient of 15 bit colours: red follows the column, green the row
+    fn gradient(x: usize, y: usize) -> u16 {
+        ((x % 32) | ((y % 32) << 5)) as u16
+    }
+
+    // BG2 in the given bitmap mode, drawn 1:1
+    fn bitmap_gba(mode: u16) -> GBA {
+        let mut gba = gba_with_bios(&[(0, &[0xeafffffe])]); // b .
+        gba.poke16(0x4000000, mode | 1 << 10);
+        gba.poke16(0x4000020, 0x100); // BG2PA
+        gba.poke16(0x4000026, 0x100); // BG2PD
+        gba
+    }
+
+    #[test]
+    fn draws_mode_3() {
+        let mut gba = bitmap_gba(3);
+        for y in 0..160 {
+            for x in 0..240 {
+                gba.poke16(0x6000000 + ((y * 240 + x) * 2) as u32, gradient(x, y));
+            }
+        }
+        let screen = render_frame(&mut gba);
+        for (y, x) in [(0, 0), (0, 239), (17, 100), (159, 0), (159, 239)] {
+            assert_eq!(screen.read_pixel_raw(y, x), gradient(x, y));
+        }
+    }
+
+    #[test]
+    fn draws_mode_4() {
+        let mut gba = bitmap_gba(4);
+        for i in 1..256 {
+            gba.poke16(0x5000000 + i * 2, gradient(i as usize, 0));
+        }
+        for y in 0..160 {
+            for x in (0..240).step_by(2) {
+                let index = |x: usize| ((y + x) % 255 + 1) as u16;
+                let addr = 0x6000000 + (y * 240 + x) as u32;
+                gba.poke16(addr, index(x) | (index(x + 1) << 8));
+            }
+        }
+        let screen = render_frame(&mut gba);
+        for (y, x) in [(0, 0), (0, 239), (17, 101), (159, 0), (159, 239)] {
+            assert_eq!(screen.read_pixel_raw(y, x), gradient((y + x) % 255 + 1, 0));
+        }
+    }
+
+    #[test]
+    fn draws_mode_5() {
+        let mut gba = bitmap_gba(5);
+        gba.poke16(0x5000000, 0x7c00); // backdrop
+        for y in 0..128 {
+            for x in 0..160 {
+                gba.poke16(0x6000000 + ((y * 160 + x) * 2) as u32, gradient(x, y));
+            }
+        }
+        let screen = render_frame(&mut gba);
+        for (y, x) in [(0, 0), (0, 159), (17, 100), (127, 0), (127, 159)] {
+            assert_eq!(screen.read_pixel_raw(y, x), gradient(x, y));
+        }
+        // outside of the 160x128 bitmap
+        assert_eq!(screen.read_pixel_raw(0, 160), 0x7c00);
+        assert_eq!(screen.read_pixel_raw(128, 0), 0x7c00);
+    }
+
+    #[test]
+    fn blank_screens_skip_the_layers() {
+        let mut gba = bitmap_gba(3);
+        gba.poke16(0x5000000, 0x7c00); // backdrop
+        for i in (0..240 * 160 * 2).step_by(2) {
+            gba.poke16(0x6000000 + i, 0x001f);
+        }
+        // forced blank
+        gba.poke16(0x4000000, 3 | 1 << 7 | 1 << 10);
+        let screen = render_frame(&mut gba);
+        assert!(screen.as_u16_slice().iter().all(|&x| x == 0x7fff));
+
+        // no layers enabled
+        gba.poke16(0x4000000, 3);
+        let screen = render_frame(&mut gba);
+        assert!(screen.as_u16_slice().iter().all(|&x| x == 0x7c00));
+    }
+
+    #[test]
+    fn flips_mode_4_page() {
+        let mut gba = bitmap_gba(4);
+        gba.poke16(0x5000002, 0x001f);
+        gba.poke16(0x5000004, 0x03e0);
+        for i in (0..240 * 160).step_by(2) {
+            gba.poke16(0x6000000 + i, 0x0101);
+            gba.poke16(0x600a000 + i, 0x0202);
+        }
+        let screen = render_frame(&mut gba);
+        assert_eq!(screen.read_pixel_raw(80, 120), 0x001f);
+
+        gba.poke16(0x4000000, 4 | 1 << 4 | 1 << 10);
+        let screen = render_frame(&mut gba);
+        assert_eq!(screen.read_pixel_raw(0, 0), 0x03e0);
+        assert_eq!(screen.read_pixel_raw(159, 239), 0x03e0);
+    }
+
+    #[test]
+    fn scales_mode_5_to_the_screen() {
+        let mut gba = bitmap_gba(5);
+        gba.poke16(0x5000000, 0x7c00); // backdrop
+        for y in 0..128 {
+            for x in 0..160 {
+                gba.poke16(0x6000000 + ((y * 160 + x) * 2) as u32, gradient(x, y));
+            }
+        }
+        // 160x128 stretched to 240x160
+        gba.poke16(0x4000020, 160 * 256 / 240 + 1);
+        gba.poke16(0x4000026, 128 * 256 / 160 + 1);
+        let screen = render_frame(&mut gba);
+        for (y, x) in [(0, 0), (80, 120), (159, 239)] {
+            let expected = gradient(x * 160 / 240, y * 128 / 160);
+            assert_eq!(screen.read_pixel_raw(y, x), expected, "({}, {})", y, x);
+        }
+        // no backdrop is left
+        assert!(screen.as_u16_slice().iter().all(|&x| x != 0x7c00));
+    }
+
+    // mode 0 with BG0 filled with red (palette entry 1) at the given priority, and sprites enabled
+    // but all hidden. OBJ tile 0 is filled with colour 1 and OBJ tile 1 with colour 2
+    fn bg_and_obj_gba(bg_priority: u16) -> GBA {
+        let mut gba = gba_with_bios(&[(0, &[0xeafffffe])]); // b .
+        gba.poke16(0x4000000, 1 << 6 | 1 << 8 | 1 << 12); // 1D OBJ mapping, BG0, OBJ
+        gba.poke16(0x4000008, bg_priority | 31 << 8); // screen base block 31
+        gba.poke16(0x5000002, 0x001f);
+        gba.poke16(0x5000202, 0x03e0);
+        gba.poke16(0x5000204, 0x7c00);
+        for i in (0..32).step_by(2) {
+            gba.poke16(0x6000020 + i, 0x1111); // BG tile 1
+            gba.poke16(0x6010000 + i, 0x1111); // OBJ tile 0
+            gba.poke16(0x6010020 + i, 0x2222); // OBJ tile 1
+        }
+        for i in (0..0x800).step_by(2) {
+            gba.poke16(0x600f800 + i, 1);
+        }
+        for i in 0..128 {
+            gba.poke16(0x7000000 + i * 8, 1 << 9); // hidden
+        }
+        gba
+    }
+
+    // an 8x8 sprite at the top left corner
+    fn set_obj(gba: &mut GBA, index: u32, tile: u16, priority: u16) {
+        gba.poke16(0x7000000 + index * 8, 0);
+        gba.poke16(0x7000002 + index * 8, 0);
+        gba.poke16(0x7000004 + index * 8, tile | priority << 10);
+    }
+
+    #[test]
+    fn resolves_bg_and_obj_priority() {
+        // the higher priority background covers the sprite
+        let mut gba = bg_and_obj_gba(0);
+        set_obj(&mut gba, 0, 0, 3);
+        let screen = render_frame(&mut gba);
+        assert_eq!(screen.read_pixel_raw(0, 0), 0x001f);
+        assert_eq!(screen.read_pixel_raw(0, 8), 0x001f);
+
+        // the sprite wins against a background of the same priority
+        let mut gba = bg_and_obj_gba(3);
+        set_obj(&mut gba, 0, 0, 3);
+        let screen = render_frame(&mut gba);
+        assert_eq!(screen.read_pixel_raw(0, 0), 0x03e0);
+        assert_eq!(screen.read_pixel_raw(0, 8), 0x001f);
+    }
+
+    #[test]
+    fn lower_oam_index_covers_other_sprites_regardless_of_priority() {
+        // sprite 0 is on top of sprite 1, and is then drawn behind the background
+        let mut gba = bg_and_obj_gba(1);
+        set_obj(&mut gba, 0, 0, 2);
+        set_obj(&mut gba, 1, 1, 0);
+        let screen = render_frame(&mut gba);
+        assert_eq!(screen.read_pixel_raw(0, 0), 0x001f);
+
+        let mut gba = bg_and_obj_gba(3);
+        set_obj(&mut gba, 0, 0, 2);
+        set_obj(&mut gba, 1, 1, 0);
+        let screen = render_frame(&mut gba);
+        assert_eq!(screen.read_pixel_raw(0, 0), 0x03e0);
+    }
+
+    // a 16x16 sprite (2x2 tiles) starting at tile 0, in the given OBJ mapping mode
+    fn draw_2x2_sprite(one_dimensional: bool) -> [[u16; 2]; 2] {
+        let mut gba = gba_with_bios(&[(0, &[0xeafffffe])]); // b .
+        gba.poke16(0x4000000, (one_dimensional as u16) << 6 | 1 << 12);
+        // each tile is filled with its own colour of the OBJ palette, whose value is 2 * tile + 1
+        for (colour, tile) in [(1, 0), (2, 1), (3, 2), (4, 3), (5, 32), (6, 33)] {
+            gba.poke16(0x5000200 + colour * 2, 2 * tile as u16 + 1);
+            for i in (0..32).step_by(2) {
+                gba.poke16(0x6010000 + tile * 32 + i, 0x1111 * colour as u16);
+            }
+        }
+        for i in 1..128 {
+            gba.poke16(0x7000000 + i * 8, 1 << 9); // hidden
+        }
+        gba.poke16(0x7000000, 0); // square
+        gba.poke16(0x7000002, 1 << 14); // 16x16
+        gba.poke16(0x7000004, 0); // tile 0
+        let screen = render_frame(&mut gba);
+        [
+            [screen.read_pixel_raw(0, 0), screen.read_pixel_raw(0, 8)],
+            [screen.read_pixel_raw(8, 0), screen.read_pixel_raw(8, 8)],
+        ]
+    }
+
+    #[test]
+    fn maps_obj_tiles_in_1d_and_2d() {
+        // 1D: the tiles of the sprite follow each other
+        assert_eq!(draw_2x2_sprite(true), [[1, 3], [5, 7]]);
+        // 2D: the rows of the sprite are 32 tiles apart
+        assert_eq!(draw_2x2_sprite(false), [[1, 3], [65, 67]]);
+    }
+
+    #[test]
+    fn surrounds_screen_with_border() {
+        let mut screen = ScreenBuffer::new();
+        screen.write_pixel(0, 0, Pixel::new(1, 2, 3));
+        screen.write_pixel(159, 239, Pixel::new(4, 5, 6));
+        let bordered = screen.with_border(Pixel::new(31, 0, 0), 4);
+        assert_eq!((bordered.width(), bordered.height()), (248, 168));
+        assert_eq!(bordered.as_u16_slice().len(), 248 * 168);
+        assert_eq!(screen.aspect_ratio(), (3, 2));
+
+        let raw = |row, col| bordered.read_pixel(row, col).to_bgr555();
+        for (row, col) in [
+            (0, 0),
+            (3, 3),
+            (0, 247),
+            (167, 0),
+            (167, 247),
+            (4, 3),
+            (80, 244),
+        ] {
+            assert_eq!(raw(row, col), 0x001f, "({}, {})", row, col);
+        }
+        assert_eq!(raw(4, 4), Pixel::new(1, 2, 3).to_bgr555());
+        assert_eq!(raw(163, 243), Pixel::new(4, 5, 6).to_bgr555());
+        assert_eq!(raw(80, 100), 0);
+    }
+
+    // enables the vcount match interrupt for the given line. the handler waits for the hblank of
+    // the line it was interrupted on, then stores VCOUNT and DISPSTAT to IWRAM
+    fn vcount_irq_gba(line: u32) -> GBA {
+        gba_with_bios(&[
+            (0, &[0xea00003e]), // b 0x100
+            (
+                0x18,
+                &[
+                    0xe3a00301, // mov r0, #0x4000000
+                    0xe1d020b4, // ldrh r2, [r0, #4]
+                    0xe3120002, // tst r2, #2
+                    0x0afffffc, // beq 0x1c
+                    0xe1d010b6, // ldrh r1, [r0, #6]
+                    0xe3a03403, // mov r3, #0x3000000
+                    0xe5831000, // str r1, [r3]
+                    0xe5832004, // str r2, [r3, #4]
+                    0xeafffffe, // b .
+                ],
+            ),
+            (
+                0x100,
+                &[
+                    0xe3a00301,        // mov r0, #0x4000000
+                    0xe3a01c00 | line, // mov r1, #(line << 8)
+                    0xe3811020,        // orr r1, r1, #0x20
+                    0xe1c010b4,        // strh r1, [r0, #4] (DISPSTAT: match line, irq)
+                    0xe2802c02,        // add r2, r0, #0x200
+                    0xe3a01004,        // mov r1, #4
+                    0xe1c210b0,        // strh r1, [r2] (IE: vcount)
+                    0xe3a01001,        // mov r1, #1
+                    0xe5821008,        // str r1, [r2, #8] (IME)
+                    0xeafffffe,        // b .
+                ],
+            ),
+        ])
+    }
+
+    #[test]
+    fn vcount_match_interrupts_and_flags_the_whole_line() {
+        let mut gba = vcount_irq_gba(80);
+        render_frame(&mut gba);
+        assert_eq!(gba.peek16(0x3000000), 80);
+        assert_eq!(gba.peek16(0x3000004) & 0b110, 0b110);
+    }
+
+    #[test]
+    fn runs_hblank_dma_once_per_visible_line() {
+        let mut gba = gba_with_bios(&[(0, &[0xeafffffe])]); // b .
+        gba.poke32(0x40000b0, 0x3000000); // DMA0SAD
+        gba.poke32(0x40000b4, 0x3001000); // DMA0DAD
+        gba.poke32(0x40000b8, 0xa6400001); // 1 word per hblank, repeating, fixed dest
+        for frames in 1..=2 {
+            render_frame(&mut gba);
+            assert_eq!(gba.dma_channels()[0].src_addr, 0x3000000 + frames * 160 * 4);
+        }
+    }
+
+    #[test]
+    fn video_capture_dma_runs_on_lines_2_to_161_then_stops() {
+        let mut gba = gba_with_bios(&[(0, &[0xeafffffe])]); // b .
+        gba.poke32(0x40000d4, 0x3000000); // DMA3SAD
+        gba.poke32(0x40000d8, 0x3001000); // DMA3DAD
+        gba.poke32(0x40000dc, 0xb6400001); // 1 word per line, video capture, repeating
+
+        // a frame ends when vblank starts, so lines 160 and 161 transfer in the next one
+        render_frame(&mut gba);
+        let dma = gba.dma_channels()[3];
+        assert_eq!(dma.src_addr, 0x3000000 + 158 * 4);
+        assert!(dma.is_enabled);
+        for _ in 0..2 {
+            render_frame(&mut gba);
+            let dma = gba.dma_channels()[3];
+            assert_eq!(dma.src_addr, 0x3000000 + 160 * 4);
+            assert!(!dma.is_enabled);
+            assert_eq!(gba.peek16(0x40000de) >> 15, 0);
+        }
+    }
+
+    #[test]
+    fn corrects_pure_red_for_the_gba_lcd() {
+        let red = Pixel::new(31, 0, 0);
+        assert_eq!(ColourCorrection::None.apply(red, 0, 0), (255, 0, 0));
+        assert_eq!(ColourCorrection::GbaLcd.apply(red, 0, 0), (232, 53, 111));
+
+        // the screen buffer conversions go through the same correction
+        let mut screen = ScreenBuffer::new();
+        screen.write_pixel(0, 0, red);
+        screen.colour_correction = ColourCorrection::GbaLcd;
+        assert_eq!(screen.read_pixel_u8(0, 0), (232, 53, 111));
+        let mut out = vec![0; 240 * 160 * 3];
+        screen.convert_into(&mut out, PixelFormat::Rgb888);
+        assert_eq!(out[..3], [232, 53, 111]);
+    }
+
+    #[test]
+    fn vcount_match_interrupts_on_vblank_lines() {
+        // a frame ends when vblank starts, so the vblank lines are in the second frame
+        for (line, vblank) in [(200, 1), (227, 0)] {
+            let mut gba = vcount_irq_gba(line);
+            render_frame(&mut gba);
+            render_frame(&mut gba);
+            assert_eq!(gba.peek16(0x3000000), line as u16);
+            // hblank also runs on vblank lines. the vblank flag is clear on the last line
+            assert_eq!(gba.peek16(0x3000004) & 0b111, 0b110 | vblank);
+        }
+    }
 }
@@ -0,0 +1,137 @@
+// game pak prefetch buffer (WAITCNT bit 14).
+//
+// while the cpu isn't using the game pak bus, the buffer keeps reading up to 8 halfwords ahead of
+// the last opcode fetched from the game pak. sequential opcode fetches served from the buffer take
+// a single cycle instead of the game pak wait states. non-sequential fetches and any other game pak
+// access empty the buffer. only used when memory timing is enabled, see Bus::opcode_fetch_waits.
+
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+
+const PREFETCH_HALFWORDS: u32 = 8;
+
+#[derive(Default)]
+pub struct Prefetch {
+    // address the next sequential opcode fetch would be at. the buffer holds the halfwords from here
+    pub next_addr: usize,
+    // halfwords in the buffer
+    count: u32,
+    // cycles spent towards reading the next halfword
+    progress: u32,
+    // reads from the game pak, counted by the bus. an opcode fetch is one read, so a difference of
+    // more than one between fetches means there was a data access in between
+    gamepak_reads: u32,
+    last_gamepak_reads: u32,
+}
+
+impl Prefetch {
+    #[inline(always)]
+    pub fn note_gamepak_read(&mut self) {
+        self.gamepak_reads = self.gamepak_reads.wrapping_add(1);
+    }
+
+    // called for every opcode fetch from the game pak. returns whether the fetch is sequential:
+    // right after the previous one, without other game pak accesses in between
+    pub fn start_fetch(&mut self, addr: usize, size: usize) -> bool {
+        let sequential =
+            addr == self.next_addr && self.gamepak_reads.wrapping_sub(self.last_gamepak_reads) == 1;
+        self.next_addr = addr + size;
+        self.last_gamepak_reads = self.gamepak_reads;
+        sequential
+    }
+
+    // cycles taken by a sequential fetch of size bytes. seq_cycles: cycles of a sequential halfword
+    // read from the game pak
+    pub fn take(&mut self, size: usize, seq_cycles: u32) -> u32 {
+        let halfwords = size as u32 / 2;
+        if self.count >= halfwords {
+            self.count -= halfwords;
+            1
+        } else {
+            // wait for the rest of the opcode to be read
+            let cycles = ((halfwords - self.count) * seq_cycles)
+                .saturating_sub(self.progress)
+                .max(1);
+            self.count = 0;
+            self.progress = 0;
+            cycles
+        }
+    }
+
+    pub fn flush(&mut self) {
+        self.count = 0;
+        self.progress = 0;
+    }
+
+    // the game pak bus was free for `cycles`
+    pub fn run(&mut self, cycles: u32, seq_cycles: u32) {
+        if self.count < PREFETCH_HALFWORDS {
+            self.progress += cycles;
+            let read = (self.progress / seq_cycles).min(PREFETCH_HALFWORDS - self.count);
+            self.count += read;
+            self.progress = if self.count == PREFETCH_HALFWORDS {
+                0
+            } else {
+                self.progress - read * seq_cycles
+            };
+        }
+    }
+
+    pub fn write_snapshot(&self, w: &mut SnapshotWriter) {
+        w.usize(self.next_addr);
+        w.u32(self.count);
+        w.u32(self.progress);
+        w.u32(self.gamepak_reads);
+        w.u32(self.last_gamepak_reads);
+    }
+
+    pub fn read_snapshot(&mut self, r: &mut SnapshotReader) -> Result<(), &'static str> {
+        self.next_addr = r.usize()?;
+        self.count = r.u32()?;
+        self.progress = r.u32()?;
+        self.gamepak_reads = r.u32()?;
+        self.last_gamepak_reads = r.u32()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Prefetch;
+    use crate::test_util::gba_with_rom;
+
+    #[test]
+    fn serves_buffered_fetches_in_one_cycle() {
+        let mut prefetch = Prefetch::default();
+        // 3 cycles per halfword: 7 free cycles read 2 halfwords, with 1 cycle towards the third
+        prefetch.run(7, 3);
+        assert_eq!(prefetch.take(4, 3), 1);
+        assert_eq!(prefetch.take(2, 3), 2);
+        prefetch.run(100, 3);
+        assert_eq!(prefetch.count, 8);
+        prefetch.flush();
+        assert_eq!(prefetch.take(2, 3), 3);
+    }
+
+    #[test]
+    fn prefetch_speeds_up_game_pak_code() {
+        // counts iterations of a loop in the game pak during one frame. the multiply and the IWRAM
+        // store leave the game pak bus free for the prefetch buffer
+        let code = [
+            0xe3a04403, // mov r4, #0x3000000
+            0xe3a06000, // mov r6, #0
+            0xe2866001, // add r6, r6, #1
+            0xe5846000, // str r6, [r4]
+            0xe0070696, // mul r7, r6, r6
+            0xeafffffb, // b 0x8
+        ];
+        let iterations = |waitcnt: u16| {
+            let mut gba = gba_with_rom(&code);
+            gba.set_memory_timing(true);
+            gba.poke16(0x4000204, waitcnt);
+            gba.process_frame(0).unwrap();
+            gba.peek32(0x3000000)
+        };
+        let (off, on) = (iterations(0), iterations(1 << 14));
+        assert!(on > off, "{} {}", on, off);
+    }
+}
@@ -0,0 +1,93 @@
+// memory access profiler, for finding the memory a game spends its time in.
+//
+// counts the reads and writes of the cpu and DMA per memory region, and optionally per 4KiB page.
+// a halfword or word access counts once. accesses from tooling (peek, try_read) are not counted.
+// the bus only calls into the profiler while it is enabled, see GBA::enable_access_profiler.
+
+use alloc::collections::BTreeMap;
+
+use crate::bus::MemoryRegion;
+
+const NUM_MEMORY_REGIONS: usize = 12;
+const PAGE_SHIFT: usize = 12;
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct AccessProfile {
+    // indexed by MemoryRegion
+    pub reads: [u64; NUM_MEMORY_REGIONS],
+    pub writes: [u64; NUM_MEMORY_REGIONS],
+    // cpu address of the page -> (reads, writes). None unless per page counting was enabled. only
+    // regions with a cpu_range are counted per page
+    pub pages: Option<BTreeMap<u32, (u64, u64)>>,
+}
+
+impl AccessProfile {
+    pub fn new(per_page: bool) -> AccessProfile {
+        AccessProfile {
+            pages: per_page.then(BTreeMap::new),
+            ..Default::default()
+        }
+    }
+
+    pub fn region_reads(&self, region: MemoryRegion) -> u64 {
+        self.reads[region as usize]
+    }
+
+    pub fn region_writes(&self, region: MemoryRegion) -> u64 {
+        self.writes[region as usize]
+    }
+
+    // addr is the offset into the region, as given to Bus::internal_read_byte
+    #[inline(always)]
+    pub fn note_access(&mut self, addr: usize, region: MemoryRegion, is_write: bool) {
+        if is_write {
+            self.writes[region as usize] += 1;
+        } else {
+            self.reads[region as usize] += 1;
+        }
+        if let (Some(pages), Some((base, _))) = (&mut self.pages, region.cpu_range()) {
+            let page = (base + ((addr >> PAGE_SHIFT) << PAGE_SHIFT)) as u32;
+            let (reads, writes) = pages.entry(page).or_default();
+            if is_write {
+                *writes += 1;
+            } else {
+                *reads += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bus::MemoryRegion;
+    use crate::test_util::gba_with_rom;
+
+    #[test]
+    fn counts_game_pak_code_and_iwram_stores() {
+        let mut gba = gba_with_rom(&[
+            0xe3a04403, // mov r4, #0x3000000
+            0xe5844010, // str r4, [r4, #0x10]
+            0xeafffffd, // b 0x4
+        ]);
+        gba.enable_access_profiler(true);
+        gba.process_frame(0).unwrap();
+        // peeks and pokes are not counted
+        gba.peek32(0x3000010);
+        gba.poke32(0x3000010, 0);
+
+        let profile = gba.access_profile();
+        assert!(profile.region_reads(MemoryRegion::Cartridge) > 0);
+        assert_eq!(profile.region_writes(MemoryRegion::Cartridge), 0);
+        assert!(profile.region_writes(MemoryRegion::ChipWram) > 0);
+        assert_eq!(profile.region_reads(MemoryRegion::ChipWram), 0);
+        let pages = profile.pages.as_ref().unwrap();
+        assert_eq!(
+            pages[&0x3000000],
+            (0, profile.region_writes(MemoryRegion::ChipWram))
+        );
+        assert!(pages[&0x8000000].0 > 0);
+
+        gba.disable_access_profiler();
+        assert_eq!(gba.access_profile(), Default::default());
+    }
+}
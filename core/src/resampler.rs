@@ -0,0 +1,83 @@
+// linear interpolation resampler used instead of rubato when building without std. it has the
+// subset of the rubato interface that the apu uses: a fixed number of input frames per chunk, and a
+// number of output frames that varies by one depending on the current phase.
+
+use alloc::{vec, vec::Vec};
+
+pub struct LinearResampler {
+    chunk_size_in: usize,
+    // input frames per output frame
+    step: f32,
+    // position of the next output frame, where 0 is the last input frame of the previous chunk
+    pos: f32,
+    last_frame: Vec<f32>,
+}
+
+impl LinearResampler {
+    pub fn new(
+        sample_rate_input: usize,
+        sample_rate_output: usize,
+        chunk_size_in: usize,
+        nbr_channels: usize,
+    ) -> Result<LinearResampler, ()> {
+        if sample_rate_input == 0 || sample_rate_output == 0 || chunk_size_in == 0 {
+            return Err(());
+        }
+        Ok(LinearResampler {
+            chunk_size_in,
+            step: sample_rate_input as f32 / sample_rate_output as f32,
+            pos: 1.,
+            last_frame: vec![0.; nbr_channels],
+        })
+    }
+
+    pub fn input_frames_next(&self) -> usize {
+        self.chunk_size_in
+    }
+
+    pub fn input_buffer_allocate(&self) -> Vec<Vec<f32>> {
+        vec![Vec::with_capacity(self.chunk_size_in); self.last_frame.len()]
+    }
+
+    pub fn output_buffer_allocate(&self) -> Vec<Vec<f32>> {
+        let frames = (self.chunk_size_in as f32 / self.step) as usize + 2;
+        vec![Vec::with_capacity(frames); self.last_frame.len()]
+    }
+
+    pub fn process_into_buffer(
+        &mut self,
+        wave_in: &[Vec<f32>],
+        wave_out: &mut [Vec<f32>],
+        _active_channels_mask: Option<&[bool]>,
+    ) -> Result<(), ()> {
+        let frames = self.chunk_size_in;
+        if wave_in.len() != self.last_frame.len() || wave_in.iter().any(|x| x.len() != frames) {
+            return Err(());
+        }
+        let mut end_pos = self.pos;
+        for chan in 0..self.last_frame.len() {
+            let frame_at = |i: usize| {
+                if i == 0 {
+                    self.last_frame[chan]
+                } else {
+                    wave_in[chan][i - 1]
+                }
+            };
+            let out = &mut wave_out[chan];
+            out.clear();
+            let mut pos = self.pos;
+            while pos < frames as f32 {
+                let i = pos as usize;
+                let frac = pos - i as f32;
+                out.push(frame_at(i) * (1. - frac) + frame_at(i + 1) * frac);
+                pos += self.step;
+            }
+            end_pos = pos;
+        }
+        self.pos = end_pos - frames as f32;
+        for (last_frame, wave) in self.last_frame.iter_mut().zip(wave_in) {
+            *last_frame = wave[frames - 1];
+        }
+        Ok(())
+    }
+}
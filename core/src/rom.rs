@@ -0,0 +1,279 @@
+// checksums and header fields of a ROM dump, eg. for matching it against a no-intro DAT.
+//
+// the hashes are implemented here since they are small and gba-core has to build without std.
+
+use alloc::string::String;
+
+use crate::game_config::{self, GameCode};
+
+// the cartridge address space is 32MiB, larger ROMs can't be loaded
+pub const MAX_ROM_SIZE: usize = 32 * 1024 * 1024;
+
+// offsets in the cartridge header
+const TITLE_OFFSET: usize = 0xa0;
+const TITLE_LEN: usize = 12;
+const MAKER_CODE_OFFSET: usize = 0xb0;
+const VERSION_OFFSET: usize = 0xbc;
+const HEADER_CHECKSUM_OFFSET: usize = 0xbd;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RomHashes {
+    pub crc32: u32,
+    pub md5: [u8; 16],
+    pub sha1: [u8; 20],
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RomHeader {
+    // upper case ascii, padded with zeroes in the ROM. the padding is stripped here
+    pub title: String,
+    pub game_code: GameCode,
+    pub maker_code: [u8; 2],
+    pub version: u8,
+    pub header_checksum: u8,
+    // whether header_checksum matches the header. the BIOS refuses to boot the ROM otherwise
+    pub header_checksum_valid: bool,
+}
+
+pub fn hashes(rom: &[u8]) -> RomHashes {
+    RomHashes {
+        crc32: crc32(rom),
+        md5: md5(rom),
+        sha1: sha1(rom),
+    }
+}
+
+// None if the ROM is too short to have a header
+pub fn header(rom: &[u8]) -> Option<RomHeader> {
+    let game_code = game_config::game_code(rom)?;
+    if rom.len() <= HEADER_CHECKSUM_OFFSET {
+        return None;
+    }
+    let title = rom[TITLE_OFFSET..TITLE_OFFSET + TITLE_LEN]
+        .iter()
+        .take_while(|x| **x != 0)
+        .map(|x| *x as char)
+        .collect();
+    let checksum = rom[TITLE_OFFSET..HEADER_CHECKSUM_OFFSET]
+        .iter()
+        .fold(0u8, |sum, x| sum.wrapping_sub(*x))
+        .wrapping_sub(0x19);
+    Some(RomHeader {
+        title,
+        game_code,
+        maker_code: [rom[MAKER_CODE_OFFSET], rom[MAKER_CODE_OFFSET + 1]],
+        version: rom[VERSION_OFFSET],
+        header_checksum: rom[HEADER_CHECKSUM_OFFSET],
+        header_checksum_valid: checksum == rom[HEADER_CHECKSUM_OFFSET],
+    })
+}
+
+// ---------- crc32 (the one used by zip and the DATs, reflected polynomial 0xedb88320)
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 > 0 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, x| {
+        CRC32_TABLE[((crc ^ *x as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+// ---------- md5 and sha1
+
+// calls f with every 64 byte block of data, padded with 0x80, zeroes and the length in bits
+fn for_each_block(data: &[u8], big_endian_len: bool, mut f: impl FnMut(&[u8; 64])) {
+    let mut blocks = data.chunks_exact(64);
+    for block in &mut blocks {
+        f(block.try_into().unwrap());
+    }
+    let rem = blocks.remainder();
+    let mut tail = [0u8; 128];
+    tail[..rem.len()].copy_from_slice(rem);
+    tail[rem.len()] = 0x80;
+    let tail_len = if rem.len() < 56 { 64 } else { 128 };
+    let bit_len = (data.len() as u64).wrapping_mul(8);
+    tail[tail_len - 8..tail_len].copy_from_slice(&if big_endian_len {
+        bit_len.to_be_bytes()
+    } else {
+        bit_len.to_le_bytes()
+    });
+    for block in tail[..tail_len].chunks_exact(64) {
+        f(block.try_into().unwrap());
+    }
+}
+
+const MD5_SHIFTS: [u32; 64] = [
+    7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 5, 9, 14, 20, 5, 9, 14, 20, 5, 9,
+    14, 20, 5, 9, 14, 20, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 6, 10, 15,
+    21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21,
+];
+
+// floor(abs(sin(i + 1)) * 2^32)
+const MD5_CONSTANTS: [u32; 64] = [
+    0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee, 0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+    0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be, 0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+    0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa, 0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+    0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed, 0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+    0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c, 0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+    0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05, 0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+    0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039, 0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+    0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1, 0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391,
+];
+
+fn md5(data: &[u8]) -> [u8; 16] {
+    let mut state: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    for_each_block(data, false, |block| {
+        let m: [u32; 16] = core::array::from_fn(|i| {
+            u32::from_le_bytes(block[i * 4..i * 4 + 4].try_into().unwrap())
+        });
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let f = f
+                .wrapping_add(a)
+                .wrapping_add(MD5_CONSTANTS[i])
+                .wrapping_add(m[g]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(f.rotate_left(MD5_SHIFTS[i]));
+        }
+        for (x, y) in state.iter_mut().zip([a, b, c, d]) {
+            *x = x.wrapping_add(y);
+        }
+    });
+    let mut res = [0; 16];
+    for (i, x) in state.iter().enumerate() {
+        res[i * 4..i * 4 + 4].copy_from_slice(&x.to_le_bytes());
+    }
+    res
+}
+
+// also used on its own to identify a ROM in recordings, without paying for the other hashes
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    for_each_block(data, true, |block| {
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes(block[i * 4..i * 4 + 4].try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (i, x) in w.iter().enumerate() {
+            let (f, k) = match i / 20 {
+                0 => ((b & c) | (!b & d), 0x5a827999),
+                1 => (b ^ c ^ d, 0x6ed9eba1),
+                2 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*x);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (x, y) in state.iter_mut().zip([a, b, c, d, e]) {
+            *x = x.wrapping_add(y);
+        }
+    });
+    let mut res = [0; 20];
+    for (i, x) in state.iter().enumerate() {
+        res[i * 4..i * 4 + 4].copy_from_slice(&x.to_be_bytes());
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{format, vec::Vec};
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|x| format!("{:02x}", x)).collect()
+    }
+
+    // reference values from python's zlib and hashlib. 55 and 64 bytes are the edges of the padding
+    // of the last block
+    #[test]
+    fn hashes_known_vectors() {
+        let pattern: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        let a_55 = [b'a'; 55];
+        let a_64 = [b'a'; 64];
+        for (data, crc32, md5, sha1) in [
+            (
+                &b""[..],
+                0x00000000,
+                "d41d8cd98f00b204e9800998ecf8427e",
+                "da39a3ee5e6b4b0d3255bfef95601890afd80709",
+            ),
+            (
+                b"abc",
+                0x352441c2,
+                "900150983cd24fb0d6963f7d28e17f72",
+                "a9993e364706816aba3e25717850c26c9cd0d89d",
+            ),
+            (
+                b"The quick brown fox jumps over the lazy dog",
+                0x414fa339,
+                "9e107d9d372bb6826bd81d3542a419d6",
+                "2fd4e1c67a2d28fced849ee1bb76e7391b93eb12",
+            ),
+            (
+                &a_55,
+                0xaadfe34e,
+                "ef1772b6dff9a122358552954ad0df65",
+                "c1c8bbdc22796e28c0e15163d20899b65621d65a",
+            ),
+            (
+                &a_64,
+                0x89b46555,
+                "014842d480b571495a4a0363793f7367",
+                "0098ba824b5c16427bd7a1122a5a442a25ec644d",
+            ),
+            (
+                &pattern,
+                0x721746a6,
+                "a24f1e3ef66950e1327f210e3997ba2c",
+                "c9c960a0b925474fab83942cc27d504fc24ac37b",
+            ),
+        ] {
+            let hashes = hashes(data);
+            assert_eq!(hashes.crc32, crc32, "{}", data.len());
+            assert_eq!(hex(&hashes.md5), md5, "{}", data.len());
+            assert_eq!(hex(&hashes.sha1), sha1, "{}", data.len());
+        }
+    }
+}
@@ -0,0 +1,70 @@
+// where the cartridge saves (all save state banks, concatenated like GBA::get_save_state) are kept
+// between runs. the core itself never touches storage: frontends pick a backend, load from it
+// before constructing the emulator and store to it when GBA::get_updated_save_state returns Some.
+
+use alloc::vec::Vec;
+
+use crate::error::GbaError;
+
+pub trait SaveBackend {
+    // the last stored save, None if there is none yet
+    fn load(&mut self) -> Option<Vec<u8>>;
+
+    fn store(&mut self, bin: &[u8]) -> Result<(), GbaError>;
+}
+
+// keeps the save in memory only, eg. for tests and runs that should not persist anything
+#[derive(Clone, Default, Debug)]
+pub struct InMemorySaveBackend {
+    bin: Option<Vec<u8>>,
+}
+
+impl InMemorySaveBackend {
+    pub fn new(bin: Option<Vec<u8>>) -> InMemorySaveBackend {
+        InMemorySaveBackend { bin }
+    }
+}
+
+impl SaveBackend for InMemorySaveBackend {
+    fn load(&mut self) -> Option<Vec<u8>> {
+        self.bin.clone()
+    }
+
+    fn store(&mut self, bin: &[u8]) -> Result<(), GbaError> {
+        self.bin = Some(bin.to_vec());
+        Ok(())
+    }
+}
+
+// a .rustsav file on disk, see default_save_path
+#[cfg(feature = "std")]
+#[derive(Clone, Debug)]
+pub struct FileSaveBackend {
+    path: std::path::PathBuf,
+}
+
+#[cfg(feature = "std")]
+impl FileSaveBackend {
+    pub fn new(path: impl Into<std::path::PathBuf>) -> FileSaveBackend {
+        FileSaveBackend { path: path.into() }
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+}
+
+#[cfg(feature = "std")]
+impl SaveBackend for FileSaveBackend {
+    // a missing or unreadable file is treated as no save, the cartridge then starts out blank
+    fn load(&mut self) -> Option<Vec<u8>> {
+        std::fs::read(&self.path).ok()
+    }
+
+    fn store(&mut self, bin: &[u8]) -> Result<(), GbaError> {
+        std::fs::write(&self.path, bin).map_err(|why| GbaError::Io {
+            path: self.path.display().to_string(),
+            reason: why.to_string(),
+        })
+    }
+}
@@ -0,0 +1,199 @@
+// Event scheduler for the components of the system.
+//
+// Every event source (a "workflow") other than the cpu sits in a min-heap keyed by the cycle at which
+// it next needs to run. The main loop in GBA::process_frame runs the cpu until the nearest of them is
+// due, then pops and runs only that event and pushes it back with the number of cycles until it is
+// next due. Components that don't need to run every cycle (timers, apu, ppu) are therefore only
+// visited when they are due, and the per instruction cost is a single comparison against the top
+// of the heap.
+//
+// DMA is not an event: transfers are triggered by register writes, HBlank/VBlank and the timers, and
+// are run by the cpu itself (see Bus::run_dma).
+//
+// Adding an event source:
+//   1. add a variant to Workflow. the discriminant is its priority when two workflows are due on the
+//      same cycle, and its position in snapshots.
+//   2. bump NUM_WORKFLOWS and add it to WORKFLOWS
+//   3. handle the variant in GBA::process_frame, calling schedule_in with the cycles until it is next due.
+
+use alloc::collections::BinaryHeap;
+use core::cmp::Reverse;
+
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+
+// smaller values have priority.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Workflow {
+    Timer = 0,
+    Cpu = 1,
+    Apu = 2,
+    Ppu = 3,
+    Normaliser = 4,
+}
+
+pub const NUM_WORKFLOWS: usize = 5;
+
+const WORKFLOWS: [Workflow; NUM_WORKFLOWS] = [
+    Workflow::Timer,
+    Workflow::Cpu,
+    Workflow::Apu,
+    Workflow::Ppu,
+    Workflow::Normaliser,
+];
+
+pub struct Scheduler {
+    cpu_time: u32,
+    // every workflow except the cpu and the one being run
+    events: BinaryHeap<Reverse<(u32, Workflow)>>,
+    // the event last returned by next, until it is rescheduled
+    running: Option<(u32, Workflow)>,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler::with_times([0; NUM_WORKFLOWS])
+    }
+
+    fn with_times(times: [u32; NUM_WORKFLOWS]) -> Scheduler {
+        let mut events = BinaryHeap::with_capacity(NUM_WORKFLOWS);
+        for (time, workflow) in times.into_iter().zip(WORKFLOWS) {
+            if workflow != Workflow::Cpu {
+                events.push(Reverse((time, workflow)));
+            }
+        }
+        Scheduler {
+            cpu_time: times[Workflow::Cpu as usize],
+            events,
+            running: None,
+        }
+    }
+
+    // the workflow that is due first. ties are broken by priority. an event other than the cpu is
+    // taken off the queue, and must be put back with schedule_in before next is called again
+    #[inline(always)]
+    pub fn next(&mut self) -> Workflow {
+        debug_assert!(self.running.is_none(), "event was not rescheduled");
+        match self.events.peek() {
+            Some(&Reverse(event)) if event < (self.cpu_time, Workflow::Cpu) => {
+                self.events.pop();
+                self.running = Some(event);
+                event.1
+            }
+            _ => Workflow::Cpu,
+        }
+    }
+
+    // cycle at which the workflow is (or, while it is running, was) due
+    pub fn time(&self, workflow: Workflow) -> u32 {
+        if workflow == Workflow::Cpu {
+            return self.cpu_time;
+        }
+        self.iter()
+            .find(|x| x.1 == workflow)
+            .map(|x| x.0)
+            .expect("every workflow is scheduled")
+    }
+
+    // reschedules the cpu, or the event just returned by next, `clocks` cycles after it was due
+    #[inline(always)]
+    pub fn schedule_in(&mut self, workflow: Workflow, clocks: u32) {
+        if workflow == Workflow::Cpu {
+            self.cpu_time += clocks;
+            return;
+        }
+        match self.running.take() {
+            Some((time, running)) if running == workflow => {
+                self.events.push(Reverse((time + clocks, workflow)))
+            }
+            _ => panic!("{:?} is not the running event", workflow),
+        }
+    }
+
+    // (time, workflow) of every workflow, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (u32, Workflow)> + '_ {
+        self.events
+            .iter()
+            .map(|x| x.0)
+            .chain(self.running)
+            .chain(core::iter::once((self.cpu_time, Workflow::Cpu)))
+    }
+
+    fn times(&self) -> [u32; NUM_WORKFLOWS] {
+        let mut times = [0; NUM_WORKFLOWS];
+        self.iter().for_each(|x| times[x.1 as usize] = x.0);
+        times
+    }
+
+    // subtract `clocks` from every workflow so the times don't overflow. clocks must not be larger
+    // than the earliest time
+    pub fn rebase(&mut self, clocks: u32) {
+        self.cpu_time -= clocks;
+        self.events = core::mem::take(&mut self.events)
+            .into_iter()
+            .map(|Reverse((time, workflow))| Reverse((time - clocks, workflow)))
+            .collect();
+        if let Some(running) = self.running.as_mut() {
+            running.0 -= clocks;
+        }
+    }
+
+    pub fn write_snapshot(&self, w: &mut SnapshotWriter) {
+        self.times().iter().for_each(|&x| w.u32(x));
+    }
+
+    pub fn read_snapshot(&mut self, r: &mut SnapshotReader) -> Result<(), &'static str> {
+        let mut times = [0; NUM_WORKFLOWS];
+        for x in times.iter_mut() {
+            *x = r.u32()?;
+        }
+        *self = Scheduler::with_times(times);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    // pops events in (time, priority) order and runs the cpu until the nearest one
+    #[test]
+    fn runs_due_events_in_order() {
+        let mut scheduler = Scheduler::new();
+        let mut order = Vec::new();
+        for _ in 0..12 {
+            let workflow = scheduler.next();
+            order.push((scheduler.time(workflow), workflow));
+            let clocks = match workflow {
+                Workflow::Timer => 4,
+                Workflow::Cpu => 3,
+                Workflow::Apu => 6,
+                Workflow::Ppu => 100,
+                Workflow::Normaliser => 1000,
+            };
+            scheduler.schedule_in(workflow, clocks);
+        }
+        assert_eq!(
+            order,
+            [
+                (0, Workflow::Timer),
+                (0, Workflow::Cpu),
+                (0, Workflow::Apu),
+                (0, Workflow::Ppu),
+                (0, Workflow::Normaliser),
+                (3, Workflow::Cpu),
+                (4, Workflow::Timer),
+                (6, Workflow::Cpu),
+                (6, Workflow::Apu),
+                (8, Workflow::Timer),
+                (9, Workflow::Cpu),
+                (12, Workflow::Timer),
+            ]
+        );
+
+        scheduler.rebase(10);
+        assert_eq!(scheduler.time(Workflow::Cpu), 2);
+        assert_eq!(scheduler.time(Workflow::Timer), 6);
+        assert_eq!(scheduler.time(Workflow::Normaliser), 990);
+    }
+}
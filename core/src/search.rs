@@ -0,0 +1,127 @@
+// memory search, for finding the addresses of values when making cheats.
+//
+// a search first collects every address in the given regions that holds a value, and keeps a
+// snapshot of those regions. after the game has been advanced, refine compares each remaining
+// address against the snapshot and keeps those that match the predicate.
+
+use alloc::vec::Vec;
+
+use crate::bus::{Bus, ChunkSize, MemoryRegion};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SearchPredicate {
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+    Equal(u32),
+}
+
+pub struct MemorySearch {
+    chunk_size: ChunkSize,
+    snapshot: Vec<(MemoryRegion, usize, Vec<u8>)>, // (region, cpu address of region, memory)
+}
+
+impl MemorySearch {
+    pub fn new(
+        bus: &Bus,
+        value: u32,
+        chunk_size: ChunkSize,
+        regions: &[MemoryRegion],
+    ) -> (MemorySearch, Vec<usize>) {
+        let mut res = MemorySearch {
+            chunk_size,
+            snapshot: Vec::new(),
+        };
+        let mut matches = Vec::new();
+        for &region in regions {
+            if let (Some((base, len)), Some(mem)) = (region.cpu_range(), bus.region_mem(region)) {
+                let mem = &mem[..len];
+                for offset in (0..mem.len()).step_by(chunk_size as usize) {
+                    if read_value(mem, offset, chunk_size) == value {
+                        matches.push(base + offset);
+                    }
+                }
+                res.snapshot.push((region, base, mem.to_vec()));
+            }
+        }
+        (res, matches)
+    }
+
+    // keeps the addresses in previous whose value satisfies predicate relative to the snapshot, then
+    // takes a new snapshot
+    pub fn refine(
+        &mut self,
+        bus: &Bus,
+        previous: &[usize],
+        predicate: SearchPredicate,
+    ) -> Vec<usize> {
+        let chunk_size = self.chunk_size;
+        let mut matches = Vec::new();
+        for &addr in previous {
+            let entry = self.snapshot.iter().find(|(_, base, mem)| {
+                addr >= *base && addr + chunk_size as usize <= *base + mem.len()
+            });
+            let (region, base, old_mem) = match entry {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let mem = bus.region_mem(*region).unwrap();
+            let old = read_value(old_mem, addr - base, chunk_size);
+            let new = read_value(mem, addr - base, chunk_size);
+            let keep = match predicate {
+                SearchPredicate::Changed => new != old,
+                SearchPredicate::Unchanged => new == old,
+                SearchPredicate::Increased => new > old,
+                SearchPredicate::Decreased => new < old,
+                SearchPredicate::Equal(val) => new == val,
+            };
+            if keep {
+                matches.push(addr);
+            }
+        }
+        for (region, _, mem) in self.snapshot.iter_mut() {
+            let len = mem.len();
+            mem.copy_from_slice(&bus.region_mem(*region).unwrap()[..len]);
+        }
+        matches
+    }
+}
+
+// little endian value of the given size at offset
+fn read_value(mem: &[u8], offset: usize, chunk_size: ChunkSize) -> u32 {
+    let mut res = 0;
+    for i in 0..chunk_size as usize {
+        res |= (mem[offset + i] as u32) << (i * 8);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::gba_with_bios;
+
+    #[test]
+    fn refines_to_the_changed_address() {
+        let mut gba = gba_with_bios(&[(0, &[0xeafffffe])]); // b .
+        gba.poke32(0x2000100, 0xdeadbeef);
+        gba.poke32(0x2000200, 0xdeadbeef);
+        let found = gba.search_memory(
+            0xdeadbeef,
+            ChunkSize::Word,
+            &[MemoryRegion::BoardWram, MemoryRegion::ChipWram],
+        );
+        assert_eq!(found, [0x2000100, 0x2000200]);
+
+        gba.poke32(0x2000100, 0xdeadbef0);
+        let found = gba.refine_search(&found, SearchPredicate::Increased);
+        assert_eq!(found, [0x2000100]);
+        // compares against the values at the last refine
+        assert_eq!(gba.refine_search(&found, SearchPredicate::Changed), []);
+        assert_eq!(
+            gba.refine_search(&found, SearchPredicate::Equal(0xdeadbef0)),
+            [0x2000100]
+        );
+    }
+}
@@ -0,0 +1,154 @@
+// full machine snapshots (save states), see GBA::snapshot.
+//
+// every component writes its state with a SnapshotWriter and reads it back with a SnapshotReader in
+// the same order, so the format is just the fields one after another in little endian. the BIOS
+// and ROM are not part of a snapshot, and neither is state that is rebuilt every scanline or frame
+// (scanline buffers, the screen buffer, resampled audio).
+//
+// the header after the version holds an optional thumbnail of the screen (see
+// ScreenBuffer::thumbnail), so save menus can show it with read_thumbnail without restoring the
+// snapshot.
+
+use alloc::vec::Vec;
+
+const MAGIC: &[u8; 4] = b"GBAS";
+// must be bumped whenever the order or meaning of the fields changes
+const VERSION: u32 = 8;
+// 120x80 RGB, see ScreenBuffer::thumbnail
+pub const THUMBNAIL_LEN: usize = 120 * 80 * 3;
+
+pub struct SnapshotWriter {
+    buf: Vec<u8>,
+}
+
+impl SnapshotWriter {
+    pub fn new() -> SnapshotWriter {
+        let mut res = SnapshotWriter { buf: Vec::new() };
+        res.bytes(MAGIC);
+        res.u32(VERSION);
+        res
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+
+    // must be written right after new, thumbnail is THUMBNAIL_LEN bytes long
+    pub fn thumbnail(&mut self, thumbnail: Option<&[u8]>) {
+        debug_assert!(thumbnail.is_none_or(|x| x.len() == THUMBNAIL_LEN));
+        self.bool(thumbnail.is_some());
+        if let Some(thumbnail) = thumbnail {
+            self.bytes(thumbnail);
+        }
+    }
+
+    pub fn bytes(&mut self, val: &[u8]) {
+        self.buf.extend_from_slice(val);
+    }
+
+    pub fn u8(&mut self, val: u8) {
+        self.buf.push(val);
+    }
+
+    pub fn bool(&mut self, val: bool) {
+        self.u8(val as u8);
+    }
+
+    pub fn u16(&mut self, val: u16) {
+        self.bytes(&val.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, val: u32) {
+        self.bytes(&val.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, val: u64) {
+        self.bytes(&val.to_le_bytes());
+    }
+
+    pub fn usize(&mut self, val: usize) {
+        self.u64(val as u64);
+    }
+}
+
+pub struct SnapshotReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> SnapshotReader<'a> {
+    pub fn new(buf: &'a [u8]) -> Result<SnapshotReader<'a>, &'static str> {
+        let mut res = SnapshotReader { buf };
+        let mut magic = [0; 4];
+        res.bytes(&mut magic)?;
+        if &magic != MAGIC {
+            return Err("not a snapshot");
+        }
+        if res.u32()? != VERSION {
+            return Err("snapshot was made by an incompatible version");
+        }
+        Ok(res)
+    }
+
+    // errors if there is data left over, which means the snapshot doesn't match this version
+    pub fn finish(self) -> Result<(), &'static str> {
+        if self.buf.is_empty() {
+            Ok(())
+        } else {
+            Err("snapshot is too long")
+        }
+    }
+
+    // must be read right after new
+    pub fn thumbnail(&mut self) -> Result<Option<&'a [u8]>, &'static str> {
+        if !self.bool()? {
+            return Ok(None);
+        }
+        Ok(Some(self.slice(THUMBNAIL_LEN)?))
+    }
+
+    fn slice(&mut self, len: usize) -> Result<&'a [u8], &'static str> {
+        if self.buf.len() < len {
+            return Err("snapshot is truncated");
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    pub fn bytes(&mut self, dest: &mut [u8]) -> Result<(), &'static str> {
+        dest.copy_from_slice(self.slice(dest.len())?);
+        Ok(())
+    }
+
+    pub fn u8(&mut self) -> Result<u8, &'static str> {
+        let mut res = [0; 1];
+        self.bytes(&mut res)?;
+        Ok(res[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, &'static str> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, &'static str> {
+        let mut res = [0; 2];
+        self.bytes(&mut res)?;
+        Ok(u16::from_le_bytes(res))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, &'static str> {
+        let mut res = [0; 4];
+        self.bytes(&mut res)?;
+        Ok(u32::from_le_bytes(res))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, &'static str> {
+        let mut res = [0; 8];
+        self.bytes(&mut res)?;
+        Ok(u64::from_le_bytes(res))
+    }
+
+    pub fn usize(&mut self) -> Result<usize, &'static str> {
+        Ok(self.u64()? as usize)
+    }
+}
@@ -0,0 +1,58 @@
+// helpers for the unit tests. there are no BIOS or ROM dumps to test with, so the tests run small
+// hand-assembled programs from the BIOS region, where the cpu starts in ARM mode with IRQs enabled
+
+use alloc::{vec, vec::Vec};
+
+use crate::{ScreenBuffer, GBA};
+
+pub const BIOS_SIZE: usize = 0x4000;
+
+// a BIOS with the given ARM instructions at the given addresses and zeros elsewhere
+pub fn bios(code: &[(usize, &[u32])]) -> Vec<u8> {
+    let mut res = vec![0; BIOS_SIZE];
+    for (addr, instrs) in code {
+        for (i, instr) in instrs.iter().enumerate() {
+            let at = addr + i * 4;
+            res[at..at + 4].copy_from_slice(&instr.to_le_bytes());
+        }
+    }
+    res
+}
+
+// an emulator running the given BIOS with a blank ROM
+pub fn gba_with_bios(code: &[(usize, &[u32])]) -> GBA {
+    let mut gba = GBA::try_new(&bios(code), &[0; 0x200], None, None, None, 48000).unwrap();
+    gba.init_deterministic();
+    gba
+}
+
+// a ROM with the given ARM instructions at its start and zeros after them
+pub fn rom(code: &[u32]) -> Vec<u8> {
+    let mut res = vec![0; 0x200];
+    for (i, instr) in code.iter().enumerate() {
+        res[i * 4..i * 4 + 4].copy_from_slice(&instr.to_le_bytes());
+    }
+    res
+}
+
+// an emulator running the given ROM code, with a BIOS that jumps straight to it
+pub fn gba_with_rom(code: &[u32]) -> GBA {
+    let bios = bios(&[(0, &[0xe3a0f302])]); // mov pc, #0x8000000
+    let mut gba = GBA::try_new(&bios, &rom(code), None, None, None, 48000).unwrap();
+    gba.init_deterministic();
+    gba
+}
+
+// runs n frames, collecting the screen buffer after each one so that the next frame renders
+pub fn run_frames(gba: &mut GBA, n: usize) {
+    for _ in 0..n {
+        gba.process_frame(0).unwrap();
+        let _ = gba.get_screen_buffer();
+    }
+}
+
+// runs a frame and returns what it rendered
+pub fn render_frame(gba: &mut GBA) -> ScreenBuffer {
+    gba.process_frame(0).unwrap();
+    gba.get_screen_buffer().unwrap().clone()
+}
@@ -1,6 +1,6 @@
 use crate::{
-    bus::{Bus, MemoryRegion},
     config,
+    snapshot::{SnapshotReader, SnapshotWriter},
 };
 
 pub struct Timer {
@@ -32,7 +32,9 @@ impl Timer {
         }
     }
 
-    // bits must be: [0, 4)
+    // bits must be: [0, 4). the count is kept, only the rate changes. the prescaler keeps its
+    // progress into the current tick, less any whole ticks at the new rate, which were not due at
+    // the old one
     #[inline(always)]
     pub fn set_period(&mut self, bits: u8) {
         self.period_pow = match bits {
@@ -43,29 +45,33 @@ impl Timer {
             _ => unreachable!("timer invalid period"),
         };
         self.period = 1 << self.period_pow;
+        self.cur_cycle &= self.period - 1;
         //info!("timer: {}, period: {}", self.timer_no, self.period);
     }
 
-    pub fn sync_registers_to_bus(&self, bus: &mut Bus) {
+    // io is the IO region. the count register reads back the count, so it is kept in sync here
+    pub fn sync_registers(&self, io: &mut [u8]) {
         let addr = 0x100 + ((self.timer_no as usize) << 2);
-        bus.store_byte_raw(addr, MemoryRegion::IO, self.timer_count as u8);
-        bus.store_byte_raw(addr + 1, MemoryRegion::IO, (self.timer_count >> 8) as u8);
+        io[addr..addr + 2].copy_from_slice(&self.timer_count.to_le_bytes());
     }
 
+    // the count is reloaded and the prescaler restarted only when a disabled timer is enabled, not
+    // on every control write
     #[inline(always)]
-    pub fn set_is_enabled(&mut self, bus: &mut Bus, enable: bool) {
+    pub fn set_is_enabled(&mut self, io: &mut [u8], enable: bool) {
         //info!("timer_no: {}, enabled: {}", self.timer_no, enable);
         if enable && !self.is_enabled {
             self.timer_count = self.reload_val;
-            self.sync_registers_to_bus(bus);
-        } else if !enable && self.is_enabled {
+            self.cur_cycle = 0;
+            self.sync_registers(io);
         }
         self.is_enabled = enable;
     }
 
-    // returns true if overflow happened
+    // returns true if overflow happened. the caller handles the effects of the overflow on the rest
+    // of the system (Direct Sound, interrupts, cascading), see Bus::timer_clock
     #[inline(always)]
-    pub fn clock(&mut self, bus: &mut Bus) -> bool {
+    pub fn clock(&mut self, io: &mut [u8]) -> bool {
         if !self.is_cascading {
             self.cur_cycle += config::TIMER_CLOCK_INTERVAL_CLOCKS as u16;
         }
@@ -74,46 +80,15 @@ impl Timer {
             let timer_count_old = self.timer_count;
             self.timer_count += self.cur_cycle >> self.period_pow;
             self.cur_cycle &= self.period - 1;
-            self.sync_registers_to_bus(bus);
 
             // overflow
-            if self.timer_count < timer_count_old {
+            let overflow = self.timer_count < timer_count_old;
+            if overflow {
                 //info!("timer_no: {}, reload_val: {}, period: {}", self.timer_no, self.reload_val, self.period);
-                // increment the position of next Direct Sound sample played
-                //let snd_ds_cnt = bus.read_halfword_raw(0x04000082);
-                for i in 0..2 {
-                    /*let enable_right_left = [(snd_ds_cnt >> (8 + 4 * i)) & 1 > 0, (snd_ds_cnt >> (9 + 4 * i)) & 1 > 0];
-                    if !enable_right_left[0] && !enable_right_left[1] {
-                        continue;
-                    }*/
-                    if let Some(timer_no) = bus.apu.direct_sound_timer[i] {
-                        if timer_no == self.timer_no as usize {
-                            //bus.apu.direct_sound_fifo_cur[0] = *bus.apu.direct_sound_fifo[0].front().unwrap();
-                            if let Some(val) = bus.apu.direct_sound_fifo[i].pop_front() {
-                                bus.apu.direct_sound_fifo_cur[i] = val;
-                            } else {
-                                //warn!("timer overflow; attempted read from empty fifo")
-                            }
-                        }
-                    }
-                }
-
-                /*if let Some(timer_no) = bus.apu.direct_sound_timer[1] {
-                    if timer_no == self.timer_no{
-                        if let Some(val) = bus.apu.direct_sound_fifo[1].pop_front(){
-                            bus.apu.direct_sound_fifo_cur[1] = val;
-                        }
-                    }
-                }*/
                 self.timer_count += self.reload_val;
-                self.sync_registers_to_bus(bus);
-                if self.raise_interrupt {
-                    bus.cpu_interrupt(1 << (3 + self.timer_no));
-                }
-                true
-            } else {
-                false
             }
+            self.sync_registers(io);
+            overflow
         } else {
             false
         }
@@ -121,7 +96,90 @@ impl Timer {
 
     #[inline(always)]
     pub fn cascade(&mut self) {
-        assert!(self.is_cascading);
+        hot_assert!(self.is_cascading);
         self.cur_cycle += 1;
     }
+
+    pub fn write_snapshot(&self, w: &mut SnapshotWriter) {
+        w.u16(self.timer_count);
+        w.u16(self.cur_cycle);
+        w.u16(self.period);
+        w.u16(self.period_pow);
+        w.u16(self.reload_val);
+        w.bool(self.raise_interrupt);
+        w.bool(self.is_cascading);
+        w.bool(self.is_enabled);
+    }
+
+    pub fn read_snapshot(&mut self, r: &mut SnapshotReader) -> Result<(), &'static str> {
+        self.timer_count = r.u16()?;
+        self.cur_cycle = r.u16()?;
+        self.period = r.u16()?;
+        self.period_pow = r.u16()?;
+        self.reload_val = r.u16()?;
+        self.raise_interrupt = r.bool()?;
+        self.is_cascading = r.bool()?;
+        self.is_enabled = r.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Timer;
+    use crate::config::TIMER_CLOCK_INTERVAL_CLOCKS;
+    use crate::test_util::gba_with_bios;
+
+    #[test]
+    fn reloads_only_when_enabled() {
+        let mut io = [0; 0x400];
+        let mut timer = Timer::new(0);
+        timer.reload_val = 0x100;
+        timer.set_is_enabled(&mut io, true);
+        assert_eq!(timer.timer_count, 0x100);
+        assert_eq!(io[0x100..0x102], [0x00, 0x01]);
+
+        timer.clock(&mut io);
+        let count = 0x100 + TIMER_CLOCK_INTERVAL_CLOCKS as u16;
+        assert_eq!(timer.timer_count, count);
+        // a control write to a running timer doesn't reload it
+        timer.set_is_enabled(&mut io, true);
+        assert_eq!(timer.timer_count, count);
+
+        timer.set_is_enabled(&mut io, false);
+        timer.set_is_enabled(&mut io, true);
+        assert_eq!(timer.timer_count, 0x100);
+    }
+
+    #[test]
+    fn prescaler_change_keeps_the_count() {
+        let mut io = [0; 0x400];
+        let mut timer = Timer::new(0);
+        timer.set_period(0b11); // 1024 cycles per tick
+        timer.set_is_enabled(&mut io, true);
+        let clocks_per_tick = 1024 / TIMER_CLOCK_INTERVAL_CLOCKS;
+        for _ in 0..clocks_per_tick * 3 + 5 {
+            timer.clock(&mut io);
+        }
+        assert_eq!(timer.timer_count, 3);
+
+        // 256 cycles per tick. the progress into the current tick is more than 2 whole ticks at the
+        // new rate, which are not counted
+        timer.set_period(0b10);
+        assert_eq!(timer.timer_count, 3);
+        let clocks_per_tick = 256 / TIMER_CLOCK_INTERVAL_CLOCKS;
+        for _ in 0..clocks_per_tick {
+            timer.clock(&mut io);
+        }
+        assert_eq!(timer.timer_count, 4);
+    }
+
+    #[test]
+    fn count_register_reads_back_the_count() {
+        let mut gba = gba_with_bios(&[(0, &[0xeafffffe])]); // b .
+        gba.poke16(0x4000100, 0xff00); // TM0CNT_L: reload value
+        assert_eq!(gba.peek16(0x4000100), 0);
+        gba.poke16(0x4000102, 0x80); // TM0CNT_H: enable
+        assert_eq!(gba.peek16(0x4000100), 0xff00);
+    }
 }
@@ -0,0 +1,82 @@
+// logging from the hot paths: instruction decode, memory accesses, DMA, sound and rendering.
+//
+// a game that hits one of these usually does so every frame, so they are gated behind their own
+// level, which is off by default. the check is a relaxed atomic load, so the hot paths don't pay
+// for formatting or the logger unless a frontend enables them with GBA::set_log_level. the level
+// is global, like the logger itself.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use log::LevelFilter;
+
+// log targets of the subsystems, so that a frontend's logger can filter them separately, eg. hide
+// the DMA warnings but keep the cpu ones. messages from elsewhere keep their module path as target
+pub const TARGET_CPU: &str = "gba_core::cpu";
+pub const TARGET_DMA: &str = "gba_core::dma";
+pub const TARGET_APU: &str = "gba_core::apu";
+pub const TARGET_BUS: &str = "gba_core::bus";
+pub const TARGET_PPU: &str = "gba_core::ppu";
+
+static HOT_LOG_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Off as usize);
+
+pub fn set_hot_log_level(level: LevelFilter) {
+    HOT_LOG_LEVEL.store(level as usize, Ordering::Relaxed);
+}
+
+pub fn hot_log_level() -> LevelFilter {
+    match HOT_LOG_LEVEL.load(Ordering::Relaxed) {
+        0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+#[inline(always)]
+pub fn hot_log_enabled(level: log::Level) -> bool {
+    level as usize <= HOT_LOG_LEVEL.load(Ordering::Relaxed)
+}
+
+// warn! for the hot paths, only formatted when enabled with GBA::set_log_level
+macro_rules! hot_warn {
+    ($($arg:tt)+) => {
+        if $crate::trace::hot_log_enabled(log::Level::Warn) {
+            log::warn!($($arg)+);
+        }
+    };
+}
+
+// debug! for the hot paths, for messages that a game can trigger on every access
+macro_rules! hot_debug {
+    ($($arg:tt)+) => {
+        if $crate::trace::hot_log_enabled(log::Level::Debug) {
+            log::debug!($($arg)+);
+        }
+    };
+}
+
+// assert! for invariants that a misbehaving game can break. with the strict feature these panic
+// like assert!, otherwise they are debug_assert!s and the code after them degrades gracefully, so
+// that a release build (eg. the wasm frontend, where a panic kills the page) keeps running. the
+// downgraded invariants and what happens instead:
+// - aligned halfword and word bus accesses: the address is aligned
+// - run_dma only called with an active channel, and every cpu step taking at least 1 clock: the
+//   step takes 1 clock
+// - sound FIFO DMA set up with a FIFO destination: the channel is disabled
+// - a thumb LDMIA/STMIA base register not in the register list: the transfer goes ahead
+// - timer cascade only for cascading timers: the timer counts anyway
+#[cfg(feature = "strict")]
+macro_rules! hot_assert {
+    ($($arg:tt)+) => {
+        assert!($($arg)+)
+    };
+}
+
+#[cfg(not(feature = "strict"))]
+macro_rules! hot_assert {
+    ($($arg:tt)+) => {
+        debug_assert!($($arg)+)
+    };
+}
@@ -0,0 +1,35 @@
+// what the volatile memories (work RAM, palette, VRAM and OAM) hold before a game writes to them.
+//
+// on hardware their contents at power on are unspecified, and games that read memory they never
+// initialised behave differently across consoles. Zero matches what the emulator always did;
+// the other patterns help to shake out such bugs, and are deterministic so a run can be replayed.
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum UninitFill {
+    #[default]
+    Zero,
+    Ones,
+    // pseudo random bytes from the seed, the same seed always gives the same contents
+    Random(u64),
+}
+
+impl UninitFill {
+    pub fn fill(&self, mem: &mut [u8]) {
+        match *self {
+            UninitFill::Zero => mem.fill(0),
+            UninitFill::Ones => mem.fill(0xff),
+            UninitFill::Random(seed) => {
+                // splitmix64, which also works for a seed of 0
+                let mut state = seed;
+                for chunk in mem.chunks_mut(8) {
+                    state = state.wrapping_add(0x9e3779b97f4a7c15);
+                    let mut z = state;
+                    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+                    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+                    z ^= z >> 31;
+                    chunk.copy_from_slice(&z.to_le_bytes()[..chunk.len()]);
+                }
+            }
+        }
+    }
+}
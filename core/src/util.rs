@@ -1,3 +1,5 @@
+use alloc::vec::Vec;
+
 use crate::config;
 
 pub fn marshall_save_state(bin: &[u8]) -> Vec<Vec<u8>> {
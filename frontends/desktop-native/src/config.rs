@@ -1,2 +0,0 @@
-pub const SAVE_FILE_DIR: &str = "/rustsav";
-pub const SAVE_FILE_SUF: &str = ".rustsav";
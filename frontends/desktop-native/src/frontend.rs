@@ -1,10 +1,11 @@
 use std::collections::HashMap;
 use std::sync::mpsc::{Receiver, Sender};
+use std::time::{Duration, Instant};
 
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use cpal::Device;
 use glutin_window::GlutinWindow as Window;
-use graphics::{clear, rectangle, Transformed};
+use graphics::{clear, math::Matrix2d, rectangle, Transformed};
 use log::{info, warn};
 use opengl_graphics::{GlGraphics, OpenGL};
 use piston::event_loop::{EventSettings, Events};
@@ -12,7 +13,9 @@ use piston::input::RenderEvent;
 use piston::window::WindowSettings;
 use piston::{Button, Key, PressEvent, ReleaseEvent};
 
-use gba_core::{KeyInput, ScreenBuffer};
+use gba_core::{KeyInput, ScreenBuffer, NUM_SAVE_STATES};
+
+use crate::overlay;
 
 pub struct Frontend {
     gl: Option<GlGraphics>,
@@ -26,12 +29,133 @@ pub struct Frontend {
     key_map: HashMap<Key, KeyInput>,
     key_sender: Sender<(KeyInput, bool)>,
 
+    // frontend level fast-forward, see --ff-speed
+    fast_forward_key: Key,
+    fast_forward_sender: Sender<bool>,
+
+    // number keys save a snapshot to that slot, shift + number key restores it
+    snapshot_keys: [Key; NUM_SNAPSHOT_SLOTS],
+    // the same for the function keys, for slots 1 to 8
+    snapshot_fn_keys: [Key; 8],
+    snapshot_sender: Sender<SnapshotCommand>,
+    shift_held: bool,
+
+    // ctrl + key writes the cartridge save to that save state bank, see KeyInput::Save0
+    save_bank_keys: [(Key, KeyInput); NUM_SAVE_STATES],
+    ctrl_held: bool,
+
     audio_output_device: Device,
     audio_receiver: Option<Receiver<(f32, f32)>>,
 
     fps_receiver: Receiver<f64>,
     cur_fps: f64,
     avg_fps: f64,
+    // fps and speed in the top left corner of the screen, toggled with fps_overlay_key
+    show_fps: bool,
+    fps_overlay_key: Key,
+
+    // short messages shown at the bottom of the screen, e.g. when a save is written
+    toast_receiver: Receiver<String>,
+    toast: Option<(String, Instant)>,
+}
+
+const TOAST_DURATION: Duration = Duration::from_secs(2);
+
+pub const NUM_SNAPSHOT_SLOTS: usize = 10;
+
+// sent to the emulation thread, which does the disk IO. the value is the slot
+pub enum SnapshotCommand {
+    Save(usize),
+    Restore(usize),
+}
+
+// draws text in the overlay font on a translucent box, with the top left corner of the box at
+// (x, y) in GBA pixels. text that doesn't fit on the screen is cut off
+fn draw_text(text: &str, x: usize, y: usize, transform: Matrix2d, gl: &mut GlGraphics) {
+    let max_len = (240 - x - 1) / (overlay::GLYPH_WIDTH + 1);
+    let text: String = text.chars().take(max_len).collect();
+    let width = text.chars().count() * (overlay::GLYPH_WIDTH + 1) + 1;
+    let height = overlay::GLYPH_HEIGHT + 2;
+    rectangle(
+        [0., 0., 0., 0.6],
+        [
+            x as f64 * 2.,
+            y as f64 * 2.,
+            width as f64 * 2.,
+            height as f64 * 2.,
+        ],
+        transform,
+        gl,
+    );
+    let square = rectangle::square(0.0, 0.0, 2.);
+    for (i, j) in overlay::text_pixels(&text) {
+        let transform = transform.trans((x + i + 1) as f64 * 2., (y + j + 1) as f64 * 2.);
+        rectangle([1., 1., 1., 1.], square, transform, gl);
+    }
+}
+
+fn is_stereo(device: &Device) -> bool {
+    matches!(device.default_output_config(), Ok(config) if config.channels() == 2)
+}
+
+// output devices that support stereo audio
+fn stereo_output_devices() -> Vec<Device> {
+    cpal::default_host()
+        .devices()
+        .map(|devices| devices.filter(is_stereo).collect())
+        .unwrap_or_default()
+}
+
+/// names of the audio devices that can be passed as the preferred audio device
+pub fn list_audio_devices() -> Vec<String> {
+    stereo_output_devices()
+        .iter()
+        .filter_map(|x| x.name().ok())
+        .collect()
+}
+
+// picks the device whose name contains the preferred name (case insensitive), preferring an exact
+// match. falls back to the default device if no device matches
+fn select_audio_device(audio_device_name: Option<&str>) -> Result<Device, String> {
+    let devices = stereo_output_devices();
+    if let Some(preferred_name) = audio_device_name {
+        let preferred_name = preferred_name.to_lowercase();
+        let mut matches: Vec<(Device, String)> = devices
+            .into_iter()
+            .filter_map(|x| {
+                let name = x.name().ok()?;
+                name.to_lowercase()
+                    .contains(&preferred_name)
+                    .then_some((x, name))
+            })
+            .collect();
+        if let Some(pos) = matches
+            .iter()
+            .position(|(_, name)| name.to_lowercase() == preferred_name)
+        {
+            return Ok(matches.swap_remove(pos).0);
+        }
+        match matches.len() {
+            0 => warn!(
+                "no audio device matches \"{}\", using the default device",
+                preferred_name
+            ),
+            1 => return Ok(matches.pop().unwrap().0),
+            _ => {
+                let candidates: Vec<String> = matches.into_iter().map(|(_, name)| name).collect();
+                return Err(format!(
+                    "audio device \"{}\" is ambiguous, candidates: {}",
+                    preferred_name,
+                    candidates.join(", ")
+                ));
+            }
+        }
+    }
+    cpal::default_host()
+        .default_output_device()
+        .filter(is_stereo)
+        .or_else(|| stereo_output_devices().into_iter().next())
+        .ok_or_else(|| "no suitable audio device was found".to_string())
 }
 
 impl Frontend {
@@ -42,32 +166,14 @@ impl Frontend {
         key_sender: Sender<(KeyInput, bool)>,
         audio_receiver: Receiver<(f32, f32)>,
         fps_receiver: Receiver<f64>,
-    ) -> Frontend {
-        let audio_output_device = cpal::default_host()
-            .devices()
-            .unwrap()
-            .map(|x| {
-                if x.default_output_config().ok()?.channels() == 2 {
-                    if let Some(preferred_name) = audio_device_name {
-                        let preferred_name = preferred_name.to_lowercase();
-                        if let Ok(device_name) = x.name() {
-                            if device_name.to_lowercase().contains(&preferred_name) {
-                                return Some(x);
-                            } else {
-                                return None;
-                            }
-                        }
-                    }
-                    Some(x)
-                } else {
-                    None
-                }
-            })
-            .find(|x| x.is_some())
-            .expect("no suitable audio device was found")
-            .unwrap();
+        toast_receiver: Receiver<String>,
+        fast_forward_sender: Sender<bool>,
+        snapshot_sender: Sender<SnapshotCommand>,
+        show_fps: bool,
+    ) -> Result<Frontend, String> {
+        let audio_output_device = select_audio_device(audio_device_name)?;
         info!("audio device: {}", &audio_output_device.name().unwrap());
-        Frontend {
+        Ok(Frontend {
             gl: None,
             window: None,
             events: None,
@@ -88,21 +194,58 @@ impl Frontend {
                 (Key::Right, KeyInput::Right),
                 (Key::Left, KeyInput::Left),
                 (Key::Space, KeyInput::Speedup),
-                (Key::D1, KeyInput::Save0),
-                (Key::D2, KeyInput::Save1),
-                (Key::D3, KeyInput::Save2),
-                (Key::D4, KeyInput::Save3),
-                (Key::D5, KeyInput::Save4),
             ]),
             key_sender,
 
+            fast_forward_key: Key::Tab,
+            fast_forward_sender,
+
+            snapshot_keys: [
+                Key::D0,
+                Key::D1,
+                Key::D2,
+                Key::D3,
+                Key::D4,
+                Key::D5,
+                Key::D6,
+                Key::D7,
+                Key::D8,
+                Key::D9,
+            ],
+            snapshot_fn_keys: [
+                Key::F1,
+                Key::F2,
+                Key::F3,
+                Key::F4,
+                Key::F5,
+                Key::F6,
+                Key::F7,
+                Key::F8,
+            ],
+            snapshot_sender,
+            shift_held: false,
+
+            save_bank_keys: [
+                (Key::F1, KeyInput::Save0),
+                (Key::F2, KeyInput::Save1),
+                (Key::F3, KeyInput::Save2),
+                (Key::F4, KeyInput::Save3),
+                (Key::F5, KeyInput::Save4),
+            ],
+            ctrl_held: false,
+
             audio_output_device,
             audio_receiver: Some(audio_receiver),
 
             fps_receiver,
             cur_fps: 60f64,
             avg_fps: 60f64,
-        }
+            show_fps,
+            fps_overlay_key: Key::F10,
+
+            toast_receiver,
+            toast: None,
+        })
     }
 
     pub fn get_sample_rate(&self) -> usize {
@@ -173,19 +316,21 @@ impl Frontend {
             while let Ok(buf) = self.screenbuf_receiver.try_recv() {
                 self.last_screenbuf = buf;
             }
+            while let Ok(toast) = self.toast_receiver.try_recv() {
+                self.toast = Some((toast, Instant::now()));
+            }
+            if matches!(&self.toast, Some((_, since)) if since.elapsed() > TOAST_DURATION) {
+                self.toast = None;
+            }
             while let Ok(fps) = self.fps_receiver.try_recv() {
                 self.cur_fps = fps;
                 self.avg_fps = self.avg_fps * 0.8 + 0.2 * self.cur_fps;
                 println!("avg_fps: {}", self.avg_fps);
-                self.window
-                    .as_ref()
-                    .unwrap()
-                    .ctx
-                    .window()
-                    .set_title(&format!(
-                        "{} | FPS ({:5.3},{:5.3})",
-                        self.title, self.cur_fps, self.avg_fps
-                    ));
+                let title = format!(
+                    "{} | FPS ({:5.3},{:5.3})",
+                    self.title, self.cur_fps, self.avg_fps
+                );
+                self.window.as_ref().unwrap().ctx.window().set_title(&title);
             }
             if let Some(args) = e.render_args() {
                 let square = rectangle::square(0.0, 0.0, 2.);
@@ -196,13 +341,61 @@ impl Frontend {
                     for j in 0..160 {
                         for i in 0..240 {
                             let transform = c.transform.trans(i as f64 * 2., j as f64 * 2.);
-                            let pixel = self.last_screenbuf.read_pixel(j, i).to_float();
+                            let pixel = self.last_screenbuf.read_pixel_float(j, i);
                             rectangle([pixel.0, pixel.1, pixel.2, 1.], square, transform, gl);
                         }
                     }
+
+                    if self.show_fps {
+                        draw_text(&overlay::fps_text(self.avg_fps), 0, 0, c.transform, gl);
+                    }
+                    if let Some((toast, _)) = &self.toast {
+                        let y = 160 - (overlay::GLYPH_HEIGHT + 2);
+                        draw_text(toast, 0, y, c.transform, gl);
+                    }
                 });
             }
             if let Some(Button::Keyboard(key)) = e.press_args() {
+                if key == self.fast_forward_key {
+                    if let Err(why) = self.fast_forward_sender.send(true) {
+                        warn!("   fast-forward sending error: {}", why);
+                    }
+                }
+                if key == Key::LShift || key == Key::RShift {
+                    self.shift_held = true;
+                }
+                if key == Key::LCtrl || key == Key::RCtrl {
+                    self.ctrl_held = true;
+                }
+                if key == self.fps_overlay_key {
+                    self.show_fps = !self.show_fps;
+                }
+                // ctrl + function key is a save bank write instead
+                let slot = self
+                    .snapshot_keys
+                    .iter()
+                    .position(|x| *x == key)
+                    .or_else(|| {
+                        let i = self.snapshot_fn_keys.iter().position(|x| *x == key)?;
+                        (!self.ctrl_held).then_some(i + 1)
+                    });
+                if let Some(slot) = slot {
+                    let command = if self.shift_held {
+                        SnapshotCommand::Restore(slot)
+                    } else {
+                        SnapshotCommand::Save(slot)
+                    };
+                    if let Err(why) = self.snapshot_sender.send(command) {
+                        warn!("   snapshot sending error: {}", why);
+                    }
+                }
+                if let Some((_, key_input)) = self.save_bank_keys.iter().find(|x| x.0 == key) {
+                    if self.ctrl_held {
+                        if let Err(why) = self.key_sender.send((*key_input, true)) {
+                            warn!("   keybuf sending error: {}", why);
+                        }
+                    }
+                }
                 if let Some(key_input) = self.key_map.get(&key) {
                     if let Err(why) = self.key_sender.send((*key_input, true)) {
                         warn!("   keybuf sending error: {}", why);
@@ -210,6 +403,23 @@ impl Frontend {
                 }
             }
             if let Some(Button::Keyboard(key)) = e.release_args() {
+                if key == self.fast_forward_key {
+                    if let Err(why) = self.fast_forward_sender.send(false) {
+                        warn!("   fast-forward sending error: {}", why);
+                    }
+                }
+                if key == Key::LShift || key == Key::RShift {
+                    self.shift_held = false;
+                }
+                if key == Key::LCtrl || key == Key::RCtrl {
+                    self.ctrl_held = false;
+                }
+                // released whether or not ctrl is still held, so a bank save never gets stuck
+                if let Some((_, key_input)) = self.save_bank_keys.iter().find(|x| x.0 == key) {
+                    if let Err(why) = self.key_sender.send((*key_input, false)) {
+                        warn!("   keybuf sending error: {}", why);
+                    }
+                }
                 if let Some(key_input) = self.key_map.get(&key) {
                     if let Err(why) = self.key_sender.send((*key_input, false)) {
                         warn!("   keybuf sending error: {}", why);
@@ -1,10 +1,11 @@
-mod config;
 mod frontend;
 mod logger;
+mod overlay;
 
-use clap::Parser;
-use frontend::Frontend;
-use gba_sim::StateLogger;
+use clap::{CommandFactory, ErrorKind, Parser};
+use frontend::{list_audio_devices, Frontend, SnapshotCommand};
+use gba_core::SaveBackend;
+use gba_sim::{InputMovie, StateLogger};
 use log::{info, warn};
 
 use std::{
@@ -13,7 +14,7 @@ use std::{
     path::Path,
     sync::mpsc,
     thread,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use crate::logger::init_logger;
@@ -21,15 +22,15 @@ use crate::logger::init_logger;
 #[derive(Parser)]
 #[clap(about = "GBA emulator written in Rust")]
 struct Arguments {
-    /// Path to .gba ROM
-    #[clap(short = 'o', long)]
-    rom_path: String,
+    /// Path to .gba ROM, or a .zip or .gz archive containing one
+    #[clap(short = 'o', long, required_unless_present = "list-audio-devices")]
+    rom_path: Option<String>,
 
     /// (Optional) Path to .rustsav save file for ROM. Leave empty to use the default save directory, which is relative to the ROM path.
     #[clap(short = 's', long)]
     rom_save_path: Option<String>,
 
-    /// (Optional) Type of cartridge: [SRAM_V, FLASH_V, FLASH512_V, FLASH1M_V, EEPROM_V]. Leave empty for automatic detection.
+    /// (Optional) Type of cartridge: [SRAM_V, FLASH_V, FLASH512_V, FLASH1M_V, EEPROM_V, EEPROM512, EEPROM8192]. The _V suffix is optional. Leave empty for automatic detection.
     #[clap(short, long)]
     cartridge_type_str: Option<String>,
 
@@ -41,15 +42,53 @@ struct Arguments {
     #[clap(short = 'a', long)]
     audio_device: Option<String>,
 
+    /// Print the available audio devices and exit
+    #[clap(long)]
+    list_audio_devices: bool,
+
     /// Path to save sim state
     #[clap(short = 't', long)]
     sim_state_path: Option<String>,
+
+    /// Path to save the recorded inputs as a portable movie on exit
+    #[clap(long)]
+    record_movie: Option<String>,
+
+    /// Path of a movie to replay headless. Exits once the movie has finished
+    #[clap(long)]
+    play_movie: Option<String>,
+
+    /// Path to save the last frame as a PNG on exit
+    #[clap(long)]
+    screenshot_on_exit: Option<String>,
+
+    /// Maximum speed multiplier while the fast-forward key (Tab) is held. Audio is muted while
+    /// fast-forwarding
+    #[clap(long, default_value = "4")]
+    ff_speed: f64,
+
+    /// Show the fps and emulation speed in the top left corner. Can be toggled with F10
+    #[clap(long)]
+    show_fps: bool,
+
+    /// Log level for warnings from the emulation hot paths (undefined instructions, forbidden memory
+    /// accesses, ...): [off, error, warn, info, debug, trace]. These are off by default, since a game
+    /// that triggers one usually does so every frame
+    #[clap(long, default_value = "off")]
+    hot_log_level: log::LevelFilter,
 }
 
 fn main() {
     init_logger().expect("failed to init logger");
 
     let cli = Arguments::parse();
+    if cli.list_audio_devices {
+        for name in list_audio_devices() {
+            println!("{}", name);
+        }
+        return;
+    }
+    let rom_path = cli.rom_path.expect("rom path is required");
     //let rom_path = env::args().nth(1).expect("first argument must be the path to a .gba ROM fle");
     //let rom_save_path = env::args().nth(2);
     //let cartridge_type_str = env::args().nth(3);
@@ -70,48 +109,35 @@ fn main() {
     // finish
     let (tx5, rx5) = mpsc::channel();
 
-    let bios_bin = read(bios_path).expect("did not find BIOS file");
-    let rom_bin = read(&cli.rom_path).expect("did not find ROM");
-    let rom_save_path = match cli.rom_save_path {
-        Some(path) => path,
-        None => {
-            let save_state_dir = Path::new(&cli.rom_path)
-                .parent()
-                .unwrap()
-                .to_str()
-                .expect("invalid rom path")
-                .to_string()
-                + config::SAVE_FILE_DIR;
-            fs::create_dir_all(&save_state_dir).unwrap();
-            let rom_path_filename = Path::new(&cli.rom_path)
-                .file_name()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_string();
-            info!(
-                "save_state_dir: {}, rom_path_filename: {}",
-                save_state_dir, rom_path_filename
-            );
-            let rom_save_path = if rom_path_filename.contains('.') {
-                let pos = rom_path_filename.rfind('.').unwrap();
-                if pos != 0 {
-                    format!("{}{}", &rom_path_filename[0..pos], config::SAVE_FILE_SUF)
-                } else {
-                    format!("{}{}", &rom_path_filename, config::SAVE_FILE_SUF)
-                }
-            } else {
-                format!("{}{}", &rom_path_filename, config::SAVE_FILE_SUF)
-            };
-            save_state_dir + "/" + &rom_save_path
-        }
-    };
-    info!("rom save path: {}", rom_save_path);
-    // read save path into save_state
-    let save_state = fs::read(&rom_save_path)
-        .map(|bin| gba_core::marshall_save_state(&bin))
-        .ok();
+    // toasts
+    let (tx6, rx6) = mpsc::channel();
+
+    // fast-forward
+    let (tx7, rx7) = mpsc::channel();
 
+    // snapshots
+    let (tx8, rx8) = mpsc::channel();
+
+    if let Some(movie_path) = &cli.play_movie {
+        let bios_bin = read(&bios_path).expect("did not find BIOS file");
+        let rom_bin = gba_sim::archive::read_rom(Path::new(&rom_path)).unwrap_or_else(|why| {
+            Arguments::command()
+                .error(ErrorKind::InvalidValue, why)
+                .exit()
+        });
+        let movie = gba_sim::sim::load_movie(movie_path).unwrap_or_else(|why| {
+            Arguments::command()
+                .error(ErrorKind::InvalidValue, why)
+                .exit()
+        });
+        match gba_sim::sim::play_movie(&movie, &bios_bin, &rom_bin) {
+            Ok(_) => info!("movie finished after {} frames", movie.num_frames()),
+            Err(why) => Arguments::command()
+                .error(ErrorKind::InvalidValue, why)
+                .exit(),
+        }
+        return;
+    }
     let mut frontend = Frontend::new(
         "gba_rust frontend".to_string(),
         cli.audio_device.as_deref(),
@@ -119,55 +145,158 @@ fn main() {
         tx2,
         rx3,
         rx4,
-    );
-    let mut gba = gba_core::GBA::new(
-        &bios_bin,
+        rx6,
+        tx7,
+        tx8,
+        cli.show_fps,
+    )
+    .unwrap_or_else(|why| {
+        Arguments::command()
+            .error(ErrorKind::InvalidValue, why)
+            .exit()
+    });
+    let rom_bin = gba_sim::archive::read_rom(Path::new(&rom_path)).unwrap_or_else(|why| {
+        Arguments::command()
+            .error(ErrorKind::InvalidValue, why)
+            .exit()
+    });
+    let (mut gba, mut save_backend) = gba_core::load_with_rom(
         &rom_bin,
-        save_state.clone(),
+        Path::new(&rom_path),
+        Path::new(&bios_path),
+        cli.rom_save_path.as_deref().map(Path::new),
         cli.save_state_bank,
         cli.cartridge_type_str.as_deref(),
         frontend.get_sample_rate(),
-    );
+    )
+    .unwrap_or_else(|why| Arguments::command().error(ErrorKind::InvalidValue, why).exit());
+    if let Some(game_config) = gba_core::GameConfig::lookup(&gba.game_code()) {
+        info!("applying game config: {:?}", game_config);
+        gba.apply_game_config(&game_config);
+    }
+    gba.set_log_level(cli.hot_log_level);
 
     let thread = thread::spawn(move || {
-        let save = match (save_state, cli.save_state_bank) {
-            (Some(save_state), Some(save_state_bank)) => Some((save_state, save_state_bank)),
-            _ => None,
-        };
-        let mut state_logger = StateLogger::new(cli.rom_path, save);
+        // a missing save file was loaded as a blank one, which replays the same
+        let save = cli
+            .save_state_bank
+            .map(|bank| (gba.get_save_state().to_vec(), bank));
+        let mut state_logger = StateLogger::new(rom_path.clone(), &rom_bin, save);
         let current_time = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .as_micros() as u64;
         gba.init(current_time);
         state_logger.init(current_time);
+        let mut last_screen_buffer = None;
+
+        // while fast-forwarding, the emulator runs ahead of the wall clock. the lead is added to the
+        // time given to the emulator, so that it doesn't sleep to catch up once fast-forward stops
+        let ff_frame_duration = Duration::from_secs_f64(
+            gba_core::CPU_EXECUTION_INTERVAL_CLOCKS as f64
+                / (16 * 1024 * 1024) as f64
+                / cli.ff_speed.max(1.),
+        );
+        let mut fast_forward = false;
+        let mut time_offset = 0;
+        let mut last_frame_instant = Instant::now();
         loop {
+            while let Ok(enable) = rx7.try_recv() {
+                fast_forward = enable;
+            }
             let frame = gba.total_frames_passed();
             let current_time = SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
-                .as_micros() as u64;
+                .as_micros() as u64
+                + time_offset;
             state_logger.log_frame(frame, current_time);
             let sleep_micros = gba.process_frame(current_time).unwrap();
-            thread::sleep(Duration::from_micros(sleep_micros));
+            if fast_forward {
+                time_offset += sleep_micros;
+                let next_frame_instant = last_frame_instant + ff_frame_duration;
+                thread::sleep(next_frame_instant.saturating_duration_since(Instant::now()));
+            } else {
+                thread::sleep(Duration::from_micros(sleep_micros));
+            }
+            last_frame_instant = Instant::now();
 
             // video
             if let Some(screen_buffer) = gba.get_screen_buffer() {
                 if let Err(why) = tx1.send(screen_buffer.clone()) {
                     warn!("   screenbuf sending error: {}", why);
                 }
+                if cli.screenshot_on_exit.is_some() {
+                    last_screen_buffer = Some(screen_buffer.clone());
+                }
             }
 
             // audio
             if let Some(it) = gba.get_sound_buffer() {
-                it.for_each(|data| tx3.send(data).unwrap());
+                if !fast_forward {
+                    it.for_each(|data| tx3.send(data).unwrap());
+                }
                 gba.reset_sound_buffer();
             }
 
             // saves
             if let Some(save_state) = gba.get_updated_save_state() {
-                fs::write(&rom_save_path, save_state[..].concat()).unwrap();
-                info!("save written to {}", &rom_save_path);
+                let toast = match save_backend.store(&save_state[..].concat()) {
+                    Ok(()) => {
+                        info!("save written to {}", save_backend.path().display());
+                        "save written".to_string()
+                    }
+                    Err(why) => {
+                        warn!("failed to write save: {}", why);
+                        format!("failed to write save: {}", why)
+                    }
+                };
+                tx6.send(toast).unwrap();
+            }
+
+            // snapshots, stored next to the save file as <rom name>.state<slot>
+            while let Ok(command) = rx8.try_recv() {
+                let toast = match command {
+                    SnapshotCommand::Save(slot) => {
+                        let path = save_backend.path().with_extension(format!("state{}", slot));
+                        // written to a temporary file first, so a failed write keeps the old state
+                        let tmp_path = path.with_extension(format!("state{}.tmp", slot));
+                        match fs::write(&tmp_path, gba.snapshot())
+                            .and_then(|()| fs::rename(&tmp_path, &path))
+                        {
+                            Ok(()) => {
+                                info!("snapshot written to {}", path.display());
+                                format!("saved state {}", slot)
+                            }
+                            Err(why) => {
+                                warn!("failed to write snapshot to {}: {}", path.display(), why);
+                                format!("failed to save state {}: {}", slot, why)
+                            }
+                        }
+                    }
+                    SnapshotCommand::Restore(slot) => {
+                        let path = save_backend.path().with_extension(format!("state{}", slot));
+                        match fs::read(&path)
+                            .map_err(|why| match why.kind() {
+                                std::io::ErrorKind::NotFound => "slot is empty".to_string(),
+                                _ => why.to_string(),
+                            })
+                            .and_then(|bin| gba.restore_snapshot(&bin).map_err(str::to_string))
+                        {
+                            Ok(()) => {
+                                info!("snapshot restored from {}", path.display());
+                                format!("loaded state {}", slot)
+                            }
+                            // restore_snapshot leaves the emulator untouched on error, so it keeps
+                            // running the current game
+                            Err(why) => {
+                                warn!("failed to restore snapshot {}: {}", path.display(), why);
+                                format!("failed to load state {}: {}", slot, why)
+                            }
+                        }
+                    }
+                };
+                tx6.send(toast).unwrap();
             }
 
             // fps
@@ -186,6 +315,20 @@ fn main() {
             //info!("process frame");
             if let Ok(()) = rx5.try_recv() {
                 let state = state_logger.finalize();
+                if let (Some(path), Some(screen_buffer)) =
+                    (&cli.screenshot_on_exit, &last_screen_buffer)
+                {
+                    match gba_sim::sim::save_png(screen_buffer, path) {
+                        Ok(()) => info!("screenshot saved to {}", path),
+                        Err(why) => warn!("failed to save screenshot to {}: {}", path, why),
+                    }
+                }
+                if let Some(movie_path) = cli.record_movie {
+                    let movie = InputMovie::from_state(&state, &rom_bin);
+                    if let Err(why) = gba_sim::sim::save_movie(&movie, &movie_path) {
+                        warn!("{}", why);
+                    }
+                }
                 if let Some(sim_state_path) = cli.sim_state_path {
                     gba_sim::sim::save_state(&state, &sim_state_path);
                 }
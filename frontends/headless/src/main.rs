@@ -1,14 +1,13 @@
-use clap::Parser;
+use clap::{CommandFactory, ErrorKind, Parser};
+use gba_core::SaveBackend;
 use log::{info, warn};
-mod config;
 mod logger;
 
 use std::{
     env,
-    fs::{self, read},
     path::Path,
     thread,
-    time::{Duration, SystemTime, UNIX_EPOCH}, sync::mpsc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH}, sync::mpsc,
 };
 
 use crate::logger::init_logger;
@@ -16,7 +15,7 @@ use crate::logger::init_logger;
 #[derive(Parser)]
 #[clap(about = "GBA emulator written in Rust")]
 struct Arguments {
-    /// Path to .gba ROM
+    /// Path to .gba ROM, or a .zip or .gz archive containing one
     #[clap(short = 'o', long)]
     rom_path: String,
 
@@ -24,7 +23,7 @@ struct Arguments {
     #[clap(short = 's', long)]
     rom_save_path: Option<String>,
 
-    /// (Optional) Type of cartridge: [SRAM_V, FLASH_V, FLASH512_V, FLASH1M_V, EEPROM_V]. Leave empty for automatic detection.
+    /// (Optional) Type of cartridge: [SRAM_V, FLASH_V, FLASH512_V, FLASH1M_V, EEPROM_V, EEPROM512, EEPROM8192]. The _V suffix is optional. Leave empty for automatic detection.
     #[clap(short, long)]
     cartridge_type_str: Option<String>,
 
@@ -35,6 +34,35 @@ struct Arguments {
     /// Name of the preferred audio device
     #[clap(short = 'a', long)]
     audio_device: Option<String>,
+
+    /// Fast-forward through idle loops and halts instead of sleeping a fixed number of cycles
+    #[clap(long)]
+    idle_skip: bool,
+
+    /// Use the accurate timing profile (memory wait states, prefetch buffer and idle skip) instead of
+    /// fixed cycle counts, see GBA::set_accuracy
+    #[clap(long)]
+    accurate: bool,
+
+    /// Run exactly this many frames on the emulated clock (without speedup) and report frames/sec and
+    /// cycles/sec. Ignored with --speedup
+    #[clap(long, default_value = "600")]
+    frames: u64,
+
+    /// Instead of a fixed number of frames, hold the Speedup key and run for 10 seconds of wall clock
+    /// time, reporting the number of frames run. Results depend on the host clock
+    #[clap(long)]
+    speedup: bool,
+
+    /// Path to save the last frame as a PNG on exit
+    #[clap(long)]
+    screenshot_on_exit: Option<String>,
+
+    /// Log level for warnings from the emulation hot paths (undefined instructions, forbidden memory
+    /// accesses, ...): [off, error, warn, info, debug, trace]. These are off by default, since a game
+    /// that triggers one usually does so every frame
+    #[clap(long, default_value = "off")]
+    hot_log_level: log::LevelFilter,
 }
 
 fn main() {
@@ -47,48 +75,6 @@ fn main() {
     let bios_path =
         env::var("GBA_RUST_BIOS_PATH").expect("Env variable GBA_RUST_BIOS_PATH not found");
 
-    let bios_bin = read(bios_path).expect("did not find BIOS file");
-    let rom_bin = read(&cli.rom_path).expect("did not find ROM");
-    let rom_save_path = match cli.rom_save_path {
-        Some(path) => path,
-        None => {
-            let save_state_dir = Path::new(&cli.rom_path)
-                .parent()
-                .unwrap()
-                .to_str()
-                .expect("invalid rom path")
-                .to_string()
-                + config::SAVE_FILE_DIR;
-            fs::create_dir_all(&save_state_dir).unwrap();
-            let rom_path_filename = Path::new(&cli.rom_path)
-                .file_name()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_string();
-            info!(
-                "save_state_dir: {}, rom_path_filename: {}",
-                save_state_dir, rom_path_filename
-            );
-            let rom_save_path = if rom_path_filename.contains('.') {
-                let pos = rom_path_filename.rfind('.').unwrap();
-                if pos != 0 {
-                    format!("{}{}", &rom_path_filename[0..pos], config::SAVE_FILE_SUF)
-                } else {
-                    format!("{}{}", &rom_path_filename, config::SAVE_FILE_SUF)
-                }
-            } else {
-                format!("{}{}", &rom_path_filename, config::SAVE_FILE_SUF)
-            };
-            save_state_dir + "/" + &rom_save_path
-        }
-    };
-    info!("rom save path: {}", rom_save_path);
-    // read save path into save_state
-    let save_state = fs::read(&rom_save_path)
-        .map(|bin| gba_core::marshall_save_state(&bin))
-        .ok();
-
     // screen buffer
     let (tx1, rx1) = mpsc::channel();
 
@@ -100,14 +86,52 @@ fn main() {
     // fps
     let (tx4, rx4) = mpsc::channel();
 
-    let mut gba = gba_core::GBA::new(
-        &bios_bin,
+    let rom_bin = gba_sim::archive::read_rom(Path::new(&cli.rom_path)).unwrap_or_else(|why| {
+        Arguments::command()
+            .error(ErrorKind::InvalidValue, why)
+            .exit()
+    });
+    let (mut gba, mut save_backend) = gba_core::load_with_rom(
         &rom_bin,
-        save_state,
+        Path::new(&cli.rom_path),
+        Path::new(&bios_path),
+        cli.rom_save_path.as_deref().map(Path::new),
         cli.save_state_bank,
         cli.cartridge_type_str.as_deref(),
         4800,
-    );
+    )
+    .unwrap_or_else(|why| Arguments::command().error(ErrorKind::InvalidValue, why).exit());
+    if let Some(game_config) = gba_core::GameConfig::lookup(&gba.game_code()) {
+        info!("applying game config: {:?}", game_config);
+        gba.apply_game_config(&game_config);
+    }
+
+    gba.set_idle_skip(cli.idle_skip);
+    if cli.accurate {
+        gba.set_accuracy(gba_core::Accuracy::Accurate);
+    }
+    gba.set_log_level(cli.hot_log_level);
+
+    if !cli.speedup {
+        let frames = cli.frames;
+        gba.init_deterministic();
+        let start = Instant::now();
+        gba.run_until_frame(frames).unwrap();
+        let elapsed = start.elapsed().as_secs_f64();
+        if let Some(path) = &cli.screenshot_on_exit {
+            if let Some(screen_buffer) = gba.get_screen_buffer() {
+                save_screenshot(screen_buffer, path);
+            }
+        }
+        println!(
+            "frames: {}, elapsed: {:.3}s, frames/sec: {:.3}, cycles/sec: {:.0}",
+            frames,
+            elapsed,
+            frames as f64 / elapsed,
+            (frames * gba_core::CPU_EXECUTION_INTERVAL_CLOCKS as u64) as f64 / elapsed
+        );
+        return;
+    }
 
     gba.init(
         SystemTime::now()
@@ -123,6 +147,7 @@ fn main() {
     .unwrap()
     .as_micros() as u64;
     let mut iters = 0;
+    let mut last_screen_buffer = None;
     loop {
         iters += 1;
         let current_time = SystemTime::now()
@@ -147,6 +172,7 @@ fn main() {
             if let Err(why) = tx1.send(screen_buffer.clone()) {
                 warn!("   screenbuf sending error: {}", why);
             }
+            last_screen_buffer = Some(screen_buffer.clone());
         }
 
         // audio
@@ -157,8 +183,8 @@ fn main() {
 
         // saves
         if let Some(save_state) = gba.get_updated_save_state() {
-            fs::write(&rom_save_path, save_state[..].concat()).unwrap();
-            info!("save written to {}", &rom_save_path);
+            save_backend.store(&save_state[..].concat()).unwrap();
+            info!("save written to {}", save_backend.path().display());
         }
 
         // fps
@@ -176,4 +202,14 @@ fn main() {
         //info!("process frame");
     }
     println!("iters: {}", iters);
+    if let (Some(path), Some(screen_buffer)) = (&cli.screenshot_on_exit, &last_screen_buffer) {
+        save_screenshot(screen_buffer, path);
+    }
+}
+
+fn save_screenshot(screen_buffer: &gba_core::ScreenBuffer, path: &str) {
+    match gba_sim::sim::save_png(screen_buffer, path) {
+        Ok(()) => info!("screenshot saved to {}", path),
+        Err(why) => warn!("failed to save screenshot to {}: {}", path, why),
+    }
 }
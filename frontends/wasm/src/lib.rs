@@ -1,14 +1,39 @@
 use std::convert::TryInto;
 
-use gba_core::{marshall_save_state, KeyInput, GBA};
+use gba_core::{marshall_save_state, GbaError, KeyInput, SaveBackend, GBA};
 use js_sys::{Float32Array, Uint8Array};
 use wasm_bindgen::{prelude::*, Clamped};
 use web_sys::CanvasRenderingContext2d;
 
+// hands saves to a js function, which can persist them anywhere the page can, eg. IndexedDB. the
+// page passes the stored save back in to GbaWasm::new, so load only returns what was stored since
+struct JsSaveBackend {
+    handler: js_sys::Function,
+    bin: Option<Vec<u8>>,
+}
+
+impl SaveBackend for JsSaveBackend {
+    fn load(&mut self) -> Option<Vec<u8>> {
+        self.bin.clone()
+    }
+
+    fn store(&mut self, bin: &[u8]) -> Result<(), GbaError> {
+        self.bin = Some(bin.to_vec());
+        self.handler
+            .call1(&JsValue::NULL, &Uint8Array::from(bin))
+            .map(|_| ())
+            .map_err(|why| GbaError::Io {
+                path: "js save handler".to_string(),
+                reason: format!("{:?}", why),
+            })
+    }
+}
+
 #[wasm_bindgen]
 pub struct GbaWasm {
     gba: GBA,
     raw_screen_buffer: Vec<u8>,
+    save_backend: Option<JsSaveBackend>,
 }
 
 #[wasm_bindgen]
@@ -20,19 +45,21 @@ impl GbaWasm {
         save_state: Option<Uint8Array>,
         save_state_bank: Option<u32>,
         sample_rate: f32,
-    ) -> GbaWasm {
+    ) -> Result<GbaWasm, JsValue> {
         // let x = marshall_save_state(save_state);
-        GbaWasm {
-            gba: GBA::new(
+        Ok(GbaWasm {
+            gba: GBA::try_new(
                 bios_bin,
                 rom_bin,
                 save_state.map(|x| marshall_save_state(&x.to_vec())),
                 save_state_bank.map(|x| x as usize),
                 None,
                 sample_rate as usize,
-            ),
+            )
+            .map_err(|e| Into::<JsValue>::into(e.to_string()))?,
             raw_screen_buffer: vec![0u8; 4 * 320 * 480],
-        }
+            save_backend: None,
+        })
     }
 
     pub fn process_frame(&mut self, current_time: u64) -> Result<u64, JsValue> {
@@ -41,6 +68,14 @@ impl GbaWasm {
             .process_frame(current_time)
             .map_err(|e| Into::<JsValue>::into(e))?;
 
+        if let Some(save_backend) = &mut self.save_backend {
+            if let Some(save_state) = self.gba.get_updated_save_state() {
+                save_backend
+                    .store(&save_state[..].concat())
+                    .map_err(|e| Into::<JsValue>::into(e.to_string()))?;
+            }
+        }
+
         Ok(micros)
     }
 
@@ -53,7 +88,7 @@ impl GbaWasm {
             for i in 0..320 {
                 for j in 0..480 {
                     let ind = i * 480 + j;
-                    let pixel = screen_buffer.read_pixel(i >> 1, j >> 1).to_u8();
+                    let pixel = screen_buffer.read_pixel_u8(i >> 1, j >> 1);
                     self.raw_screen_buffer[ind << 2] = pixel.0;
                     self.raw_screen_buffer[(ind << 2) + 1] = pixel.1;
                     self.raw_screen_buffer[(ind << 2) + 2] = pixel.2;
@@ -102,6 +137,12 @@ impl GbaWasm {
         self.gba.init(current_time)
     }
 
+    // handler is called with a Uint8Array of the whole save (like get_save_state) whenever the game
+    // has written to it
+    pub fn set_save_handler(&mut self, handler: js_sys::Function) {
+        self.save_backend = Some(JsSaveBackend { handler, bin: None });
+    }
+
     pub fn get_save_state(&self) -> Uint8Array {
         self.gba.get_save_state()[..].concat()[..].into()
     }
@@ -0,0 +1,288 @@
+// reading ROMs from .zip and .gz archives, shared by the frontends.
+//
+// only what is needed for ROMs is supported: zip entries that are stored or deflated, without
+// zip64 or encryption.
+
+use std::{fs, io::Read, path::Path};
+
+use flate2::read::{DeflateDecoder, GzDecoder};
+use gba_core::rom::MAX_ROM_SIZE;
+
+// extensions of GBA ROMs inside an archive
+const ROM_EXTENSIONS: [&str; 2] = ["gba", "agb"];
+
+const EOCD_SIGNATURE: u32 = 0x06054b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x02014b50;
+const LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+
+struct ZipEntry {
+    name: String,
+    compression: u16,
+    compressed_size: usize,
+    uncompressed_size: usize,
+    local_header_offset: usize,
+}
+
+fn u16_at(bin: &[u8], offset: usize) -> Result<u16, String> {
+    bin.get(offset..offset + 2)
+        .map(|x| u16::from_le_bytes(x.try_into().unwrap()))
+        .ok_or_else(|| "zip archive is truncated".to_string())
+}
+
+fn u32_at(bin: &[u8], offset: usize) -> Result<u32, String> {
+    bin.get(offset..offset + 4)
+        .map(|x| u32::from_le_bytes(x.try_into().unwrap()))
+        .ok_or_else(|| "zip archive is truncated".to_string())
+}
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|x| x.to_str())
+        .map(|x| extensions.iter().any(|y| x.eq_ignore_ascii_case(y)))
+        .unwrap_or(false)
+}
+
+// entries of the central directory at the end of the archive
+fn zip_entries(bin: &[u8]) -> Result<Vec<ZipEntry>, String> {
+    // the end of central directory record is 22 bytes, followed by a comment of up to 64KiB
+    let eocd = (0..bin.len().saturating_sub(21))
+        .rev()
+        .take(22 + 0xffff)
+        .find(|&i| u32_at(bin, i) == Ok(EOCD_SIGNATURE))
+        .ok_or_else(|| "not a zip archive".to_string())?;
+    let num_entries = u16_at(bin, eocd + 10)? as usize;
+    let mut offset = u32_at(bin, eocd + 16)? as usize;
+
+    let mut res = Vec::with_capacity(num_entries);
+    for _ in 0..num_entries {
+        if u32_at(bin, offset)? != CENTRAL_HEADER_SIGNATURE {
+            return Err("invalid zip central directory".to_string());
+        }
+        let name_len = u16_at(bin, offset + 28)? as usize;
+        let extra_len = u16_at(bin, offset + 30)? as usize;
+        let comment_len = u16_at(bin, offset + 32)? as usize;
+        let name = bin
+            .get(offset + 46..offset + 46 + name_len)
+            .ok_or_else(|| "zip archive is truncated".to_string())?;
+        res.push(ZipEntry {
+            name: String::from_utf8_lossy(name).into_owned(),
+            compression: u16_at(bin, offset + 10)?,
+            compressed_size: u32_at(bin, offset + 20)? as usize,
+            uncompressed_size: u32_at(bin, offset + 24)? as usize,
+            local_header_offset: u32_at(bin, offset + 42)? as usize,
+        });
+        offset += 46 + name_len + extra_len + comment_len;
+    }
+    Ok(res)
+}
+
+fn too_large(name: &str) -> String {
+    format!(
+        "{} is larger than {} bytes, too large for a ROM",
+        name, MAX_ROM_SIZE
+    )
+}
+
+// decompresses at most MAX_ROM_SIZE bytes, so that a small archive can't expand into an
+// arbitrarily large allocation
+fn inflate(reader: impl Read, name: &str, size_hint: usize) -> Result<Vec<u8>, String> {
+    let mut res = Vec::with_capacity(size_hint.min(MAX_ROM_SIZE));
+    reader
+        .take(MAX_ROM_SIZE as u64 + 1)
+        .read_to_end(&mut res)
+        .map_err(|why| format!("failed to decompress {}: {}", name, why))?;
+    if res.len() > MAX_ROM_SIZE {
+        return Err(too_large(name));
+    }
+    Ok(res)
+}
+
+fn read_zip_entry(bin: &[u8], entry: &ZipEntry) -> Result<Vec<u8>, String> {
+    if entry.uncompressed_size > MAX_ROM_SIZE {
+        return Err(too_large(&entry.name));
+    }
+    let offset = entry.local_header_offset;
+    if u32_at(bin, offset)? != LOCAL_HEADER_SIGNATURE {
+        return Err(format!("invalid zip header for {}", entry.name));
+    }
+    let start =
+        offset + 30 + u16_at(bin, offset + 26)? as usize + u16_at(bin, offset + 28)? as usize;
+    let data = bin
+        .get(start..start + entry.compressed_size)
+        .ok_or_else(|| "zip archive is truncated".to_string())?;
+    match entry.compression {
+        0 if data.len() > MAX_ROM_SIZE => Err(too_large(&entry.name)),
+        0 => Ok(data.to_vec()),
+        8 => inflate(
+            DeflateDecoder::new(data),
+            &entry.name,
+            entry.uncompressed_size,
+        ),
+        method => Err(format!(
+            "{} uses unsupported zip compression method {}",
+            entry.name, method
+        )),
+    }
+}
+
+// the single ROM in a zip archive
+fn read_zip_rom(bin: &[u8]) -> Result<Vec<u8>, String> {
+    let entries = zip_entries(bin)?;
+    let roms: Vec<&ZipEntry> = entries
+        .iter()
+        .filter(|x| has_extension(Path::new(&x.name), &ROM_EXTENSIONS))
+        .collect();
+    match roms[..] {
+        [] => Err(format!(
+            "zip archive does not contain a ROM (.{} file)",
+            ROM_EXTENSIONS.join(", .")
+        )),
+        [rom] => read_zip_entry(bin, rom),
+        _ => Err(format!(
+            "zip archive contains more than one ROM: {}",
+            roms.iter()
+                .map(|x| x.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+/// Reads a ROM from disk. `.zip` archives must contain exactly one ROM, and `.gz` files are
+/// decompressed; anything else is read as a raw ROM.
+pub fn read_rom(path: &Path) -> Result<Vec<u8>, String> {
+    let bin =
+        fs::read(path).map_err(|why| format!("failed to read {}: {}", path.display(), why))?;
+    let path_str = path.display();
+    if has_extension(path, &["zip"]) {
+        read_zip_rom(&bin).map_err(|why| format!("{}: {}", path_str, why))
+    } else if has_extension(path, &["gz"]) {
+        inflate(GzDecoder::new(&bin[..]), &path_str.to_string(), 0)
+    } else {
+        Ok(bin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{
+        write::{DeflateEncoder, GzEncoder},
+        Compression,
+    };
+
+    use super::*;
+
+    fn gzip(bin: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(bin).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    // a zip archive with the given (name, compression method, contents) entries. the CRCs are
+    // left at 0, they are not checked
+    fn zip(entries: &[(&str, u16, &[u8])]) -> Vec<u8> {
+        let mut res = Vec::new();
+        let mut central = Vec::new();
+        for &(name, compression, contents) in entries {
+            let data = match compression {
+                8 => {
+                    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+                    encoder.write_all(contents).unwrap();
+                    encoder.finish().unwrap()
+                }
+                _ => contents.to_vec(),
+            };
+            let sizes = [data.len() as u32, contents.len() as u32];
+            let offset = res.len() as u32;
+
+            res.extend(LOCAL_HEADER_SIGNATURE.to_le_bytes());
+            res.extend([20, 0, 0, 0]); // version needed, flags
+            res.extend(compression.to_le_bytes());
+            res.extend([0; 8]); // time, date, crc
+            sizes.iter().for_each(|x| res.extend(x.to_le_bytes()));
+            res.extend((name.len() as u16).to_le_bytes());
+            res.extend([0, 0]); // extra length
+            res.extend(name.as_bytes());
+            res.extend(&data);
+
+            central.extend(CENTRAL_HEADER_SIGNATURE.to_le_bytes());
+            central.extend([20, 0, 20, 0, 0, 0]); // version made by, version needed, flags
+            central.extend(compression.to_le_bytes());
+            central.extend([0; 8]); // time, date, crc
+            sizes.iter().for_each(|x| central.extend(x.to_le_bytes()));
+            central.extend((name.len() as u16).to_le_bytes());
+            central.extend([0; 12]); // extra and comment length, disk, attributes
+            central.extend(offset.to_le_bytes());
+            central.extend(name.as_bytes());
+        }
+        let central_offset = res.len() as u32;
+        res.extend(&central);
+
+        res.extend(EOCD_SIGNATURE.to_le_bytes());
+        res.extend([0; 4]); // disk numbers
+        res.extend([(entries.len() as u16).to_le_bytes(); 2].concat());
+        res.extend((central.len() as u32).to_le_bytes());
+        res.extend(central_offset.to_le_bytes());
+        res.extend([0, 0]); // comment length
+        res
+    }
+
+    #[test]
+    fn reads_the_single_rom_of_a_zip() {
+        let rom: Vec<u8> = (0..0x400).map(|x| (x * 7) as u8).collect();
+        for compression in [0, 8] {
+            let archive = zip(&[
+                ("readme.txt", 0, b"not a rom"),
+                ("game.GBA", compression, &rom),
+            ]);
+            assert_eq!(read_zip_rom(&archive), Ok(rom.clone()));
+        }
+    }
+
+    #[test]
+    fn rejects_zips_without_exactly_one_rom() {
+        let no_rom = zip(&[("readme.txt", 0, b"not a rom")]);
+        assert!(read_zip_rom(&no_rom)
+            .unwrap_err()
+            .contains("does not contain a ROM"));
+
+        let two_roms = zip(&[("a.gba", 0, &[1]), ("b.agb", 8, &[2])]);
+        let why = read_zip_rom(&two_roms).unwrap_err();
+        assert!(why.contains("a.gba, b.agb"), "{}", why);
+    }
+
+    #[test]
+    fn rejects_truncated_zips() {
+        let archive = zip(&[("game.gba", 8, &[0x42; 0x400])]);
+        for len in 0..archive.len() {
+            assert!(read_zip_rom(&archive[..len]).is_err(), "{}", len);
+        }
+        // the directory is intact, but most of the ROM data is missing
+        let mut archive = zip(&[("game.gba", 0, &[0x42; 0x400])]);
+        let missing = 0x200;
+        archive.drain(0x40..0x40 + missing);
+        let eocd = archive.len() - 22;
+        let central_offset = u32_at(&archive, eocd + 16).unwrap() - missing as u32;
+        archive[eocd + 16..eocd + 20].copy_from_slice(&central_offset.to_le_bytes());
+        assert_eq!(
+            read_zip_rom(&archive),
+            Err("zip archive is truncated".to_string())
+        );
+    }
+
+    #[test]
+    fn inflate_keeps_roms_up_to_the_maximum_size() {
+        let rom = vec![0xa5; MAX_ROM_SIZE];
+        let res = inflate(GzDecoder::new(&gzip(&rom)[..]), "rom.gba.gz", 0).unwrap();
+        assert_eq!(res, rom);
+    }
+
+    #[test]
+    fn inflate_rejects_output_past_the_maximum_size() {
+        let bomb = gzip(&vec![0; MAX_ROM_SIZE + 1]);
+        assert!(bomb.len() < MAX_ROM_SIZE / 100);
+        assert!(inflate(GzDecoder::new(&bomb[..]), "bomb.gz", 0).is_err());
+    }
+}
@@ -1,3 +1,6 @@
+pub mod archive;
+pub mod video;
+
 use std::collections::{LinkedList, VecDeque};
 
 use gba_core::KeyInput;
@@ -41,15 +44,76 @@ struct FrameInfo {
     key_input: LinkedList<(KeyInputSerde, bool)>,
 }
 
-// remove default
-#[derive(Clone, Default, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct State {
+    // path of the ROM on the machine it was recorded on, see drive_gba_from_state for replaying
+    // elsewhere
     rom_path: String,
+    rom_hash: [u8; 20],
     save: Option<(Vec<Vec<u8>>, usize)>,
     start_time: u64,
     frame_info: VecDeque<FrameInfo>,
 }
 
+impl State {
+    // a recording with no frames yet
+    pub fn new(rom_path: String, rom_bin: &[u8], save: Option<(Vec<Vec<u8>>, usize)>) -> State {
+        State {
+            rom_path,
+            rom_hash: rom_hash(rom_bin),
+            save,
+            start_time: 0,
+            frame_info: VecDeque::new(),
+        }
+    }
+}
+
+// SHA-1 of the ROM, the same hash `verify` prints and the game database uses. used to check that
+// a movie is replayed with the ROM it was recorded on
+pub fn rom_hash(rom_bin: &[u8]) -> [u8; 20] {
+    gba_core::rom::sha1(rom_bin)
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+// portable input movie: only the inputs of a State, without host timing or paths. the frame of an
+// input is the index of the process_frame call after which it was applied
+#[derive(Clone, Serialize, Deserialize)]
+pub struct InputMovie {
+    rom_hash: [u8; 20],
+    save: Option<(Vec<Vec<u8>>, usize)>,
+    num_frames: u64,
+    inputs: Vec<(u64, KeyInputSerde, bool)>,
+}
+
+impl InputMovie {
+    pub fn from_state(state: &State, rom_bin: &[u8]) -> InputMovie {
+        let inputs = state
+            .frame_info
+            .iter()
+            .enumerate()
+            .flat_map(|(frame, frame_info)| {
+                frame_info
+                    .key_input
+                    .iter()
+                    .map(move |(key_input, is_pressed)| (frame as u64, *key_input, *is_pressed))
+            })
+            .collect();
+        InputMovie {
+            rom_hash: rom_hash(rom_bin),
+            save: state.save.clone(),
+            num_frames: state.frame_info.len() as u64,
+            inputs,
+        }
+    }
+
+    pub fn num_frames(&self) -> u64 {
+        self.num_frames
+    }
+}
+
 #[derive(Clone)]
 pub struct StateLogger {
     state: State,
@@ -57,14 +121,13 @@ pub struct StateLogger {
 }
 
 impl StateLogger {
-    pub fn new(rom_path: String, save: Option<(Vec<Vec<u8>>, usize)>) -> StateLogger {
+    pub fn new(
+        rom_path: String,
+        rom_bin: &[u8],
+        save: Option<(Vec<Vec<u8>>, usize)>,
+    ) -> StateLogger {
         StateLogger {
-            state: State {
-                rom_path,
-                save,
-                start_time: 0,
-                frame_info: VecDeque::new(),
-            },
+            state: State::new(rom_path, rom_bin, save),
             next_expected_frame: 0,
         }
     }
@@ -97,18 +160,97 @@ impl StateLogger {
 
 pub mod sim {
     use core::str;
+    use std::fmt;
     use std::fs::{read, write};
+    use std::path::Path;
     use std::time::{Duration, SystemTime};
     use std::{env, u64};
 
-    use gba_core::ScreenBuffer;
+    use gba_core::{percentile_index, KeyInput, ScreenBuffer, GBA};
 
-    use crate::State;
+    use crate::video::VideoRecorder;
+    use crate::{hex, rom_hash, InputMovie, State};
+
+    // why a replay failed. index is the position of the frame in the recording
+    #[derive(Debug)]
+    pub enum SimError {
+        // the BIOS or ROM could not be loaded
+        Setup(String),
+        // the movie or state was recorded on a different ROM
+        RomMismatch {
+            expected_hash: [u8; 20],
+            actual_hash: [u8; 20],
+        },
+        // the emulator is at a different frame than the recording expects, so the replay has
+        // desynced. inputs are the ones applied right before, which are the likely culprit
+        Desync {
+            index: usize,
+            expected_frame: u64,
+            actual_frame: u64,
+            inputs: Vec<(KeyInput, bool)>,
+        },
+        // process_frame returned an error, eg. from the watchdog
+        Frame {
+            index: usize,
+            frame: u64,
+            reason: &'static str,
+        },
+        // the replay ended without a single frame being rendered
+        NoFrame,
+        // recording the replay to a video failed
+        Record(String),
+    }
+
+    impl fmt::Display for SimError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                SimError::Setup(why) => write!(f, "{}", why),
+                SimError::RomMismatch {
+                    expected_hash,
+                    actual_hash,
+                } => write!(
+                    f,
+                    "ROM hash {} does not match the hash {} in the recording",
+                    hex(actual_hash),
+                    hex(expected_hash)
+                ),
+                SimError::Desync {
+                    index,
+                    expected_frame,
+                    actual_frame,
+                    inputs,
+                } => {
+                    write!(
+                        f,
+                        "desync at recorded frame {}: expected emulator frame {}, got {}",
+                        index, expected_frame, actual_frame
+                    )?;
+                    if inputs.is_empty() {
+                        write!(f, " (no inputs before it)")
+                    } else {
+                        write!(f, " (after inputs {:?})", inputs)
+                    }
+                }
+                SimError::Frame {
+                    index,
+                    frame,
+                    reason,
+                } => write!(
+                    f,
+                    "recorded frame {} (emulator frame {}): {}",
+                    index, frame, reason
+                ),
+                SimError::NoFrame => write!(f, "no frame was rendered"),
+                SimError::Record(why) => write!(f, "failed to record video: {}", why),
+            }
+        }
+    }
+
+    impl std::error::Error for SimError {}
 
     fn print_histogram(items: &mut [Duration]) {
         items.sort();
-        let len = items.len() as f64;
-        let of = |mult| (len * mult) as usize;
+        let of = |mult| percentile_index(items.len(), mult);
         let buckets = vec![
             ("min", 1),
             ("25%", of(0.25)),
@@ -131,9 +273,136 @@ pub mod sim {
         write(path, result).unwrap()
     }
 
-    pub fn load_state(path: &str) -> State {
-        let bytes = read(path).unwrap();
-        bitcode::deserialize(&bytes).unwrap()
+    pub fn load_state(path: &str) -> Result<State, SimError> {
+        let bytes = read(path)
+            .map_err(|why| SimError::Setup(format!("failed to read {}: {}", path, why)))?;
+        let state: State = bitcode::deserialize(&bytes)
+            .map_err(|why| SimError::Setup(format!("failed to decode {}: {}", path, why)))?;
+        if state.rom_path.is_empty() {
+            return Err(SimError::Setup(format!("{} has no ROM path", path)));
+        }
+        Ok(state)
+    }
+
+    pub fn save_movie(movie: &InputMovie, path: &str) -> Result<(), SimError> {
+        let result = bitcode::serialize(movie)
+            .map_err(|why| SimError::Setup(format!("failed to encode {}: {}", path, why)))?;
+        write(path, result)
+            .map_err(|why| SimError::Setup(format!("failed to write {}: {}", path, why)))
+    }
+
+    pub fn load_movie(path: &str) -> Result<InputMovie, SimError> {
+        let bytes = read(path)
+            .map_err(|why| SimError::Setup(format!("failed to read {}: {}", path, why)))?;
+        bitcode::deserialize(&bytes)
+            .map_err(|why| SimError::Setup(format!("failed to decode {}: {}", path, why)))
+    }
+
+    // replays a movie on emulated time, so the result does not depend on the host
+    pub fn play_movie(
+        movie: &InputMovie,
+        bios_bin: &[u8],
+        rom_bin: &[u8],
+    ) -> Result<image::RgbImage, SimError> {
+        let mut screen_buffer = ScreenBuffer::new();
+        run_movie(movie, bios_bin, rom_bin, 4800, |gba| {
+            if let Some(buf) = gba.get_screen_buffer() {
+                screen_buffer = buf.clone();
+            }
+            Ok(())
+        })?;
+        Ok(img_get(&screen_buffer))
+    }
+
+    // replays a movie like play_movie, and records it to a video at output_path with sound. see
+    // VideoRecorder
+    pub fn record_movie(
+        movie: &InputMovie,
+        bios_bin: &[u8],
+        rom_bin: &[u8],
+        output_path: &Path,
+        scale: u32,
+    ) -> Result<(), SimError> {
+        let sample_rate = 48000;
+        let record_error = |why: std::io::Error| SimError::Record(why.to_string());
+        let mut recorder =
+            VideoRecorder::new(output_path, sample_rate, scale).map_err(record_error)?;
+        let mut prev_frames = 0;
+        run_movie(movie, bios_bin, rom_bin, sample_rate, |gba| {
+            let total_frames = gba.total_frames_passed();
+            recorder
+                .push_frame(gba.get_screen_buffer(), total_frames)
+                .map_err(record_error)?;
+            if let Some(it) = gba.get_sound_buffer() {
+                recorder.push_audio(it).map_err(record_error)?;
+            }
+            // speedup mode runs several frames per call, without audio
+            let frames = total_frames - prev_frames;
+            if frames > 1 && gba.last_frame_audio_samples() == 0 {
+                recorder.push_silence(frames).map_err(record_error)?;
+            }
+            prev_frames = total_frames;
+            Ok(())
+        })?;
+        recorder.finish().map_err(record_error)
+    }
+
+    // on_frame is called after every emulated frame, before the inputs of the next one are applied
+    fn run_movie(
+        movie: &InputMovie,
+        bios_bin: &[u8],
+        rom_bin: &[u8],
+        sample_rate: usize,
+        mut on_frame: impl FnMut(&mut GBA) -> Result<(), SimError>,
+    ) -> Result<(), SimError> {
+        if rom_hash(rom_bin) != movie.rom_hash {
+            return Err(SimError::RomMismatch {
+                expected_hash: movie.rom_hash,
+                actual_hash: rom_hash(rom_bin),
+            });
+        }
+        let (save_bin, save_state_bank) = match movie.save.clone() {
+            Some((save_bin, save_state_bank)) => (Some(save_bin), Some(save_state_bank)),
+            None => (None, None),
+        };
+        let mut gba = GBA::try_new(
+            bios_bin,
+            rom_bin,
+            save_bin,
+            save_state_bank,
+            None,
+            sample_rate,
+        )
+        .map_err(|why| SimError::Setup(why.to_string()))?;
+        gba.init_deterministic();
+
+        let mut inputs = movie.inputs.iter().peekable();
+        for frame in 0..movie.num_frames {
+            let current_time = frame * gba_core::CPU_EXECUTION_INTERVAL_CLOCKS as u64 * 1000000
+                / (16 * 1024 * 1024);
+            gba.process_frame(current_time)
+                .map_err(|reason| SimError::Frame {
+                    index: frame as usize,
+                    frame: gba.total_frames_passed(),
+                    reason,
+                })?;
+            on_frame(&mut gba)?;
+            if gba.get_sound_buffer().is_some() {
+                gba.reset_sound_buffer();
+            }
+            gba.input_frame_preprocess();
+            while let Some((_, key_input, is_pressed)) =
+                inputs.next_if(|(input_frame, _, _)| *input_frame == frame)
+            {
+                gba.process_key((*key_input).into(), *is_pressed);
+            }
+        }
+        Ok(())
+    }
+
+    // shared by the frontends for --screenshot-on-exit
+    pub fn save_png(screen_buffer: &ScreenBuffer, path: &str) -> image::ImageResult<()> {
+        img_get(screen_buffer).save_with_format(path, image::ImageFormat::Png)
     }
 
     fn img_get(screen_buffer: &ScreenBuffer) -> image::RgbImage {
@@ -143,24 +412,42 @@ pub mod sim {
         let mut img = RgbImage::new(width, height);
         for y in 0..height {
             for x in 0..width {
-                let pixel = screen_buffer.read_pixel(y as usize, x as usize).to_u8();
+                let pixel = screen_buffer.read_pixel_u8(y as usize, x as usize);
                 img.put_pixel(x as u32, y as u32, Rgb([pixel.0, pixel.1, pixel.2]))
             }
         }
         img
     }
 
-    pub fn drive_gba_from_state(mut state: State) -> image::RgbImage {
-        let bios_path =
-            env::var("GBA_RUST_BIOS_PATH").expect("Env variable GBA_RUST_BIOS_PATH not found");
-        let bios_bin = read(bios_path).expect("did not find BIOS file");
-        let rom_bin = read(state.rom_path).expect("did not find ROM");
+    // the ROM is read from the path it was recorded with. if rom_dir is given, the file of that
+    // name in rom_dir is used instead, so that states can be replayed on other machines. either
+    // way, its hash must match the recorded one
+    pub fn drive_gba_from_state(
+        mut state: State,
+        rom_dir: Option<&Path>,
+    ) -> Result<image::RgbImage, SimError> {
+        let rom_path = Path::new(&state.rom_path);
+        let rom_path = match (rom_dir, rom_path.file_name()) {
+            (Some(rom_dir), Some(file_name)) => rom_dir.join(file_name),
+            _ => rom_path.to_path_buf(),
+        };
+        let rom_bin = crate::archive::read_rom(&rom_path).map_err(SimError::Setup)?;
+        if rom_hash(&rom_bin) != state.rom_hash {
+            return Err(SimError::RomMismatch {
+                expected_hash: state.rom_hash,
+                actual_hash: rom_hash(&rom_bin),
+            });
+        }
+        let bios_path = env::var("GBA_RUST_BIOS_PATH")
+            .map_err(|_| SimError::Setup("Env variable GBA_RUST_BIOS_PATH not found".into()))?;
+        let bios_bin = read(&bios_path)
+            .map_err(|why| SimError::Setup(format!("failed to read {}: {}", bios_path, why)))?;
         let (save_bin, save_state_bank) = match state.save {
             Some((save_bin, save_state_bin)) => (Some(save_bin), Some(save_state_bin)),
             None => (None, None),
         };
-        let mut gba =
-            gba_core::GBA::new(&bios_bin, &rom_bin, save_bin, save_state_bank, None, 4800);
+        let mut gba = GBA::try_new(&bios_bin, &rom_bin, save_bin, save_state_bank, None, 4800)
+            .map_err(|why| SimError::Setup(why.to_string()))?;
         gba.init(state.start_time);
 
         let start_time = SystemTime::now();
@@ -168,13 +455,26 @@ pub mod sim {
         let mut times = Vec::with_capacity(state.frame_info.len());
         let mut prev_frame = 0;
         let mut screen_buffer = None;
+        let mut inputs = Vec::new();
+        let mut index = 0;
 
         while let Some(frame_info) = state.frame_info.pop_front() {
             if gba.total_frames_passed() != frame_info.frame {
-                println!("{} {}", gba.total_frames_passed(), frame_info.frame);
-                assert!(false);
+                return Err(SimError::Desync {
+                    index,
+                    expected_frame: frame_info.frame,
+                    actual_frame: gba.total_frames_passed(),
+                    inputs,
+                });
             }
-            let _sleep_micros: u64 = gba.process_frame(frame_info.current_time).unwrap();
+            let _sleep_micros: u64 =
+                gba.process_frame(frame_info.current_time)
+                    .map_err(|reason| SimError::Frame {
+                        index,
+                        frame: frame_info.frame,
+                        reason,
+                    })?;
+            index += 1;
             let next_time = SystemTime::now();
             let frame_diff = if prev_frame == 0 {
                 1
@@ -182,7 +482,7 @@ pub mod sim {
                 frame_info.frame - prev_frame
             };
             prev_frame = frame_info.frame;
-            let diff = next_time.duration_since(time).unwrap() / frame_diff as u32;
+            let diff = next_time.duration_since(time).unwrap_or_default() / frame_diff as u32;
             time = next_time;
             times.push(diff);
 
@@ -193,24 +493,134 @@ pub mod sim {
                 gba.reset_sound_buffer();
             }
             gba.input_frame_preprocess();
-            frame_info
-                .key_input
-                .into_iter()
-                .for_each(|(key_input, is_pressed)| {
-                    gba.process_key(key_input.into(), is_pressed);
-                })
+            inputs.clear();
+            for (key_input, is_pressed) in frame_info.key_input {
+                gba.process_key(key_input.into(), is_pressed);
+                inputs.push((key_input.into(), is_pressed));
+            }
         }
+        let screen_buffer = screen_buffer.ok_or(SimError::NoFrame)?;
 
         let total_time = SystemTime::now()
             .duration_since(start_time)
-            .unwrap()
-            .as_millis();
+            .unwrap_or_default()
+            .as_millis()
+            .max(1);
 
         println!("total time: {total_time}ms");
         println!("time per frame");
 
         print_histogram(&mut times);
         println!("amortized fps: {}", prev_frame * 1000 / total_time as u64);
-        img_get(&screen_buffer.unwrap())
+        Ok(img_get(&screen_buffer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use gba_core::KeyInput;
+
+    use super::sim::{drive_gba_from_state, load_movie, play_movie, save_movie, SimError};
+    use super::*;
+
+    // a path in the temp dir that is unique to this test process
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("gba_sim_{}_{}", std::process::id(), name))
+    }
+
+    fn movie(rom_bin: &[u8]) -> InputMovie {
+        let mut logger = StateLogger::new("rom.gba".into(), rom_bin, Some((vec![vec![1, 2]], 0)));
+        logger.log_frame(0, 0);
+        logger.log_key_input_for_current_frame(KeyInput::A, true);
+        logger.log_frame(1, 0);
+        logger.log_frame(2, 0);
+        logger.log_key_input_for_current_frame(KeyInput::A, false);
+        logger.log_key_input_for_current_frame(KeyInput::Start, true);
+        InputMovie::from_state(&logger.finalize(), rom_bin)
+    }
+
+    #[test]
+    fn movie_round_trips_through_a_file() {
+        let rom_bin = [0x42; 0x200];
+        let movie = movie(&rom_bin);
+        let path = temp_path("movie_round_trip");
+        save_movie(&movie, path.to_str().unwrap()).unwrap();
+        let loaded = load_movie(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let inputs = |movie: &InputMovie| {
+            movie
+                .inputs
+                .iter()
+                .map(|&(frame, key, is_pressed)| {
+                    let key: u8 = key.0.into();
+                    (frame, key, is_pressed)
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(loaded.num_frames(), 3);
+        assert_eq!(loaded.rom_hash, rom_hash(&rom_bin));
+        assert_eq!(loaded.save, movie.save);
+        assert_eq!(inputs(&loaded), inputs(&movie));
+        assert_eq!(
+            inputs(&loaded),
+            [
+                (0, KeyInput::A.into(), true),
+                (2, KeyInput::A.into(), false),
+                (2, KeyInput::Start.into(), true)
+            ]
+        );
+    }
+
+    #[test]
+    fn loading_a_missing_or_corrupt_movie_is_an_error() {
+        let path = temp_path("movie_corrupt");
+        assert!(matches!(
+            load_movie(path.to_str().unwrap()),
+            Err(SimError::Setup(_))
+        ));
+        std::fs::write(&path, [0xff; 3]).unwrap();
+        let res = load_movie(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(res, Err(SimError::Setup(_))));
+    }
+
+    #[test]
+    fn movie_on_another_rom_is_a_mismatch() {
+        let movie = movie(&[0x42; 0x200]);
+        let other_rom = [0x43; 0x200];
+        match play_movie(&movie, &[], &other_rom) {
+            Err(SimError::RomMismatch {
+                expected_hash,
+                actual_hash,
+            }) => {
+                assert_eq!(expected_hash, rom_hash(&[0x42; 0x200]));
+                assert_eq!(actual_hash, rom_hash(&other_rom));
+            }
+            res => panic!("expected a ROM mismatch, got {:?}", res.err()),
+        }
+    }
+
+    #[test]
+    fn state_replayed_with_another_rom_in_rom_dir_is_a_mismatch() {
+        let recorded_rom = [0x42; 0x200];
+        let state = State::new("/elsewhere/game.gba".into(), &recorded_rom, None);
+        let rom_dir = temp_path("rom_dir");
+        std::fs::create_dir_all(&rom_dir).unwrap();
+        std::fs::write(rom_dir.join("game.gba"), [0x43; 0x200]).unwrap();
+        let res = drive_gba_from_state(state, Some(&rom_dir));
+        std::fs::remove_dir_all(&rom_dir).unwrap();
+        match res {
+            Err(SimError::RomMismatch {
+                expected_hash,
+                actual_hash,
+            }) => {
+                assert_eq!(expected_hash, rom_hash(&recorded_rom));
+                assert_eq!(actual_hash, rom_hash(&[0x43; 0x200]));
+            }
+            res => panic!("expected a ROM mismatch, got {:?}", res.err()),
+        }
     }
 }
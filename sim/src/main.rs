@@ -1,22 +1,129 @@
-use clap::Parser;
+use std::path::Path;
+
+use clap::{Parser, Subcommand};
+use gba_sim::hex;
 use gba_sim::sim::{drive_gba_from_state, load_state};
 
 #[derive(Parser)]
-#[clap(about = "GBA emulator sim")]
+#[clap(about = "GBA emulator sim", subcommand_negates_reqs = true)]
 struct Arguments {
     /// Path to load sim state
-    #[clap(short = 't', long)]
-    sim_state_path: String,
+    #[clap(short = 't', long, required = true)]
+    sim_state_path: Option<String>,
 
     /// Path to save final image buffer
     #[clap(short = 'b', long)]
     image_buffer_path: Option<String>,
+
+    /// Directory to look for the recorded ROM in, instead of the path it was recorded with
+    #[clap(long)]
+    rom_dir: Option<String>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the CRC32, MD5 and SHA1 of a ROM and its header, eg. to match it against a no-intro DAT
+    Verify {
+        /// Path to .gba ROM, or a .zip or .gz archive containing one
+        #[clap(long)]
+        rom: String,
+    },
+    /// Replay an input movie and record it to a video with sound, using ffmpeg (must be on the PATH)
+    Record {
+        /// Path to the input movie
+        #[clap(long)]
+        movie: String,
+        /// Path to .gba ROM the movie was recorded on, or a .zip or .gz archive containing one
+        #[clap(long)]
+        rom: String,
+        /// Path of the video to write. The format follows the extension, eg. .mp4 or .webm
+        #[clap(short, long)]
+        output: String,
+        /// Integer factor to scale the picture up by
+        #[clap(long, default_value = "3")]
+        scale: u32,
+    },
+}
+
+fn verify(rom_path: &str) {
+    let rom_bin = gba_sim::archive::read_rom(Path::new(rom_path)).unwrap_or_else(|why| {
+        eprintln!("{}", why);
+        std::process::exit(1)
+    });
+    let hashes = gba_core::rom::hashes(&rom_bin);
+    println!("size: {} bytes", rom_bin.len());
+    println!("crc32: {:08x}", hashes.crc32);
+    println!("md5: {}", hex(&hashes.md5));
+    println!("sha1: {}", hex(&hashes.sha1));
+    match gba_core::rom::header(&rom_bin) {
+        Some(header) => {
+            println!("title: {}", header.title);
+            println!("game code: {}", String::from_utf8_lossy(&header.game_code));
+            println!(
+                "maker code: {}",
+                String::from_utf8_lossy(&header.maker_code)
+            );
+            println!("version: {}", header.version);
+            println!(
+                "header checksum: {:02x} ({})",
+                header.header_checksum,
+                if header.header_checksum_valid {
+                    "valid"
+                } else {
+                    "invalid"
+                }
+            );
+        }
+        None => println!("header: ROM is too short to have a header"),
+    }
+}
+
+fn fail(why: impl std::fmt::Display) -> ! {
+    eprintln!("{}", why);
+    std::process::exit(1)
+}
+
+fn record(movie_path: &str, rom_path: &str, output: &str, scale: u32) {
+    let bios_path = std::env::var("GBA_RUST_BIOS_PATH")
+        .unwrap_or_else(|_| fail("Env variable GBA_RUST_BIOS_PATH not found"));
+    let bios_bin = std::fs::read(&bios_path)
+        .unwrap_or_else(|why| fail(format!("failed to read {}: {}", bios_path, why)));
+    let rom_bin = gba_sim::archive::read_rom(Path::new(rom_path)).unwrap_or_else(|why| fail(why));
+    let movie = gba_sim::sim::load_movie(movie_path).unwrap_or_else(|why| fail(why));
+    gba_sim::sim::record_movie(&movie, &bios_bin, &rom_bin, Path::new(output), scale)
+        .unwrap_or_else(|why| fail(why));
+    println!("recorded {} frames to {}", movie.num_frames(), output);
 }
 
 fn main() {
     let cli = Arguments::parse();
-    let state = load_state(&cli.sim_state_path);
-    let img = drive_gba_from_state(state);
+    match &cli.command {
+        Some(Command::Verify { rom }) => {
+            verify(rom);
+            return;
+        }
+        Some(Command::Record {
+            movie,
+            rom,
+            output,
+            scale,
+        }) => {
+            record(movie, rom, output, *scale);
+            return;
+        }
+        None => {}
+    }
+    let state = load_state(&cli.sim_state_path.unwrap());
+    let rom_dir = cli.rom_dir.as_deref().map(Path::new);
+    let img = state
+        .and_then(|state| drive_gba_from_state(state, rom_dir))
+        .unwrap_or_else(|why| {
+            eprintln!("{}", why);
+            std::process::exit(1)
+        });
     if let Some(path) = cli.image_buffer_path {
         img.save(&path).unwrap()
     }
@@ -0,0 +1,184 @@
+// records gameplay to a video file with sound, by piping raw frames and samples into ffmpeg, which
+// must be on the PATH. the container and codecs follow the extension of the output (.mp4, .webm,
+// .mkv, ...).
+//
+// the video is encoded while the emulator runs. the audio is buffered to a file next to the output
+// and muxed in by a second ffmpeg run in finish, since a single ffmpeg process can only read one of
+// the two streams from stdin.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+
+use gba_core::{PixelFormat, ScreenBuffer, CPU_EXECUTION_INTERVAL_CLOCKS};
+
+const CPU_CLOCK_HZ: u64 = 16 * 1024 * 1024;
+const WIDTH: usize = 240;
+const HEIGHT: usize = 160;
+
+// the GBA runs at ~59.73 frames per second, players expect a standard rate
+pub const OUTPUT_FPS: u64 = 60;
+
+// number of output frames that cover the first emulated_frames emulated frames. output frame k is
+// shown at k / OUTPUT_FPS seconds, and shows the emulated frame on screen at that time, so about
+// one emulated frame in 220 is shown twice
+pub fn output_frames_for(emulated_frames: u64) -> u64 {
+    (emulated_frames * OUTPUT_FPS * CPU_EXECUTION_INTERVAL_CLOCKS as u64).div_ceil(CPU_CLOCK_HZ)
+}
+
+// number of stereo samples that emulated_frames emulated frames last for at sample_rate
+fn samples_for(emulated_frames: u64, sample_rate: usize) -> u64 {
+    emulated_frames * CPU_EXECUTION_INTERVAL_CLOCKS as u64 * sample_rate as u64 / CPU_CLOCK_HZ
+}
+
+pub struct VideoRecorder {
+    ffmpeg: Child,
+    video_in: BufWriter<ChildStdin>,
+    video_path: PathBuf,
+    audio: BufWriter<File>,
+    audio_path: PathBuf,
+    output_path: PathBuf,
+    sample_rate: usize,
+    // the frame shown on screen, as Rgb888
+    frame: Vec<u8>,
+    emulated_frames: u64,
+    output_frames: u64,
+}
+
+fn ffmpeg_error(what: &str, status: std::process::ExitStatus) -> io::Error {
+    io::Error::other(format!("ffmpeg failed to {} ({})", what, status))
+}
+
+impl VideoRecorder {
+    // sample_rate must be the one the emulator was constructed with. the picture is scaled up by
+    // scale with nearest neighbour, which keeps the pixels sharp after the video is compressed
+    pub fn new(output_path: &Path, sample_rate: usize, scale: u32) -> io::Result<VideoRecorder> {
+        let ext = output_path
+            .extension()
+            .map(|ext| ext.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "mp4".to_string());
+        let video_path = output_path.with_extension(format!("video.{}", ext));
+        let audio_path = output_path.with_extension("audio.f32");
+
+        let mut ffmpeg = Command::new("ffmpeg")
+            .args(["-y", "-loglevel", "error"])
+            .args(["-f", "rawvideo", "-pix_fmt", "rgb24"])
+            .args(["-s", &format!("{}x{}", WIDTH, HEIGHT)])
+            .args(["-r", &OUTPUT_FPS.to_string()])
+            .args(["-i", "-"])
+            .args([
+                "-vf",
+                &format!("scale=iw*{0}:ih*{0}:flags=neighbor", scale.max(1)),
+            ])
+            .args(["-pix_fmt", "yuv420p", "-an"])
+            .arg(&video_path)
+            .stdin(Stdio::piped())
+            .spawn()?;
+        let video_in = BufWriter::new(ffmpeg.stdin.take().unwrap());
+
+        Ok(VideoRecorder {
+            ffmpeg,
+            video_in,
+            video_path,
+            audio: BufWriter::new(File::create(&audio_path)?),
+            audio_path,
+            output_path: output_path.to_path_buf(),
+            sample_rate,
+            frame: vec![0; WIDTH * HEIGHT * PixelFormat::Rgb888.bytes_per_pixel()],
+            emulated_frames: 0,
+            output_frames: 0,
+        })
+    }
+
+    // call after every process_frame, with get_screen_buffer and total_frames_passed. None keeps
+    // the previous picture. in speedup mode a call covers several emulated frames, which the
+    // picture is held for. frames are dropped or repeated as needed to convert to OUTPUT_FPS
+    pub fn push_frame(
+        &mut self,
+        screen_buffer: Option<&ScreenBuffer>,
+        total_frames: u64,
+    ) -> io::Result<()> {
+        if let Some(screen_buffer) = screen_buffer {
+            screen_buffer.convert_into(&mut self.frame, PixelFormat::Rgb888);
+        }
+        self.emulated_frames = total_frames;
+        while self.output_frames < output_frames_for(self.emulated_frames) {
+            self.video_in.write_all(&self.frame)?;
+            self.output_frames += 1;
+        }
+        Ok(())
+    }
+
+    // keeps the audio in step with the video over frames the emulator ran muted, eg. in speedup mode
+    pub fn push_silence(&mut self, emulated_frames: u64) -> io::Result<()> {
+        let samples = samples_for(emulated_frames, self.sample_rate);
+        self.push_audio((0..samples).map(|_| (0.0, 0.0)))
+    }
+
+    // interleaved stereo samples, as returned by get_sound_buffer
+    pub fn push_audio(&mut self, samples: impl Iterator<Item = (f32, f32)>) -> io::Result<()> {
+        for (left, right) in samples {
+            self.audio.write_all(&left.to_le_bytes())?;
+            self.audio.write_all(&right.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    // waits for the video to be encoded, muxes in the audio and removes the intermediate files
+    pub fn finish(mut self) -> io::Result<()> {
+        self.audio.flush()?;
+        self.video_in.flush()?;
+        // closes stdin, so ffmpeg sees the end of the video
+        drop(self.video_in);
+        let status = self.ffmpeg.wait()?;
+        if !status.success() {
+            return Err(ffmpeg_error("encode the video", status));
+        }
+
+        let status = Command::new("ffmpeg")
+            .args(["-y", "-loglevel", "error"])
+            .arg("-i")
+            .arg(&self.video_path)
+            .args(["-f", "f32le", "-ac", "2"])
+            .args(["-ar", &self.sample_rate.to_string()])
+            .arg("-i")
+            .arg(&self.audio_path)
+            .args(["-c:v", "copy", "-shortest"])
+            .arg(&self.output_path)
+            .status()?;
+        if !status.success() {
+            return Err(ffmpeg_error("mux the audio", status));
+        }
+        fs::remove_file(&self.video_path)?;
+        fs::remove_file(&self.audio_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_to_60_fps() {
+        assert_eq!(output_frames_for(0), 0);
+        // output frames 0 and 1 (at 1/60s) are both within the first emulated frame
+        assert_eq!(output_frames_for(1), 2);
+        assert_eq!(output_frames_for(2), 3);
+        // 100s of emulated time is 5973 frames, and 6000 output frames. each emulated frame is
+        // shown once or twice
+        assert_eq!(output_frames_for(5973), 6001);
+        let repeated = (0..5973)
+            .map(|n| output_frames_for(n + 1) - output_frames_for(n))
+            .inspect(|&shown| assert!(shown == 1 || shown == 2))
+            .filter(|&shown| shown == 2)
+            .count();
+        assert_eq!(repeated, 28);
+    }
+
+    #[test]
+    fn silence_lasts_as_long_as_the_frames() {
+        assert_eq!(samples_for(5973, 48000), 4_800_200);
+        assert_eq!(samples_for(1, 48000), 803);
+    }
+}